@@ -22,9 +22,11 @@ criterion_group!(
     sign_verify,
     writebatch_sorting,
     hasher,
+    script_hasher,
     txid_from_hex,
     block_cache,
-    history_multi_get
+    history_multi_get,
+    utxo_multi_get
 );
 criterion_main!(benches);
 
@@ -284,6 +286,54 @@ pub fn sign_verify(c: &mut Criterion) {
         });
 }
 
+pub fn script_hasher(c: &mut Criterion) {
+    use std::cell::RefCell;
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    // the std hasher is SipHash-1-3 like the store one, RandomState holds a random 128-bit key
+    let key = RandomState::new();
+    let keyed = key.build_hasher();
+    thread_local! {
+        static HASHER: RefCell<DefaultHasher> = RefCell::new(DefaultHasher::new());
+    }
+    // a p2wpkh script pubkey
+    let mut script = [0u8; 22];
+    thread_rng().fill_bytes(&mut script);
+
+    c.benchmark_group("script_hasher")
+        .bench_function("new keyed SipHasher13", |b: &mut criterion::Bencher<'_>| {
+            b.iter(|| {
+                let mut hasher = key.build_hasher();
+                hasher.write(black_box(&script));
+                black_box(hasher.finish());
+            });
+        })
+        .bench_function(
+            "clone keyed SipHasher13",
+            |b: &mut criterion::Bencher<'_>| {
+                b.iter(|| {
+                    let mut hasher = keyed.clone();
+                    hasher.write(black_box(&script));
+                    black_box(hasher.finish());
+                });
+            },
+        )
+        .bench_function(
+            "thread local SipHasher13",
+            |b: &mut criterion::Bencher<'_>| {
+                b.iter(|| {
+                    HASHER.with(|hasher| {
+                        let mut hasher = hasher.borrow_mut();
+                        *hasher = key.build_hasher();
+                        hasher.write(black_box(&script));
+                        black_box(hasher.finish());
+                    });
+                });
+            },
+        );
+}
+
 pub fn hasher(c: &mut Criterion) {
     use elements::hashes::Hash;
     use elements::secp256k1_zkp::rand::{thread_rng, RngCore};
@@ -584,6 +634,67 @@ pub fn history_multi_get(c: &mut Criterion) {
     }
 }
 
+pub fn utxo_multi_get(c: &mut Criterion) {
+    const NUM_UTXO_KEYS: u64 = 500_000;
+    const LOOKUP_COUNT: usize = 10_000;
+
+    let cache = Cache::new_hyper_clock_cache(8 * 1024 * 1024, 0);
+    let dir = tempfile::TempDir::new().unwrap();
+    let db = open_cache_bench_db(dir.path(), &cache);
+    populate_cache_bench_db(&db, NUM_UTXO_KEYS, 0);
+
+    let cf = db.cf_handle("utxo").unwrap();
+    let mut rng = thread_rng();
+    // half of the lookups miss, like spent outputs
+    let keys: Vec<[u8; 36]> = (0..LOOKUP_COUNT)
+        .map(|_| {
+            let mut key = [0u8; 36];
+            key[..8].copy_from_slice(&(rng.next_u64() % (NUM_UTXO_KEYS * 2)).to_be_bytes());
+            key
+        })
+        .collect();
+
+    let mut group = c.benchmark_group(format!("utxo_multi_get/{LOOKUP_COUNT}/{NUM_UTXO_KEYS}"));
+    group.sample_size(20);
+
+    group.bench_function("single_gets", |b| {
+        b.iter(|| {
+            let found = keys
+                .iter()
+                .filter(|key| db.get_pinned_cf(&cf, key).unwrap().is_some())
+                .count();
+            black_box(found);
+        });
+    });
+
+    group.bench_function("batched_unsorted", |b| {
+        b.iter(|| {
+            let found = db
+                .batched_multi_get_cf(&cf, keys.iter(), false)
+                .into_iter()
+                .filter(|result| result.as_ref().unwrap().is_some())
+                .count();
+            black_box(found);
+        });
+    });
+
+    group.bench_function("batched_sorted_reordered", |b| {
+        b.iter(|| {
+            let mut indexed_keys: Vec<_> = keys.iter().enumerate().collect();
+            indexed_keys.sort_unstable_by_key(|(_, key)| *key);
+            let sorted_results =
+                db.batched_multi_get_cf(&cf, indexed_keys.iter().map(|(_, key)| key), true);
+            let mut found = vec![false; keys.len()];
+            for ((index, _), result) in indexed_keys.into_iter().zip(sorted_results) {
+                found[index] = result.unwrap().is_some();
+            }
+            black_box(found);
+        });
+    });
+
+    group.finish();
+}
+
 fn bench_raw_history_multi_get_old(
     db: &DB,
     cf: &impl rocksdb::AsColumnFamilyRef,
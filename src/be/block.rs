@@ -34,6 +34,13 @@ impl Block {
         }
     }
 
+    pub(crate) fn family(&self) -> be::Family {
+        match self {
+            Block::Bitcoin(_) => be::Family::Bitcoin,
+            Block::Elements(_) => be::Family::Elements,
+        }
+    }
+
     /// Iterator over transactions without cloning - more efficient for indexing
     pub(crate) fn transactions_iter(&self) -> impl Iterator<Item = be::TransactionRef> {
         match self {
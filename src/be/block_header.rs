@@ -24,6 +24,13 @@ impl BlockHeader {
         }
     }
 
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        match self {
+            BlockHeader::Bitcoin(header) => bitcoin::consensus::encode::serialize(header.as_ref()),
+            BlockHeader::Elements(header) => elements::encode::serialize(header.as_ref()),
+        }
+    }
+
     pub(crate) fn time(&self) -> u32 {
         match self {
             BlockHeader::Bitcoin(header) => header.time,
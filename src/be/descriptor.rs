@@ -97,6 +97,33 @@ impl Descriptor {
         }
     }
 
+    /// The number of keys derived for the scripts at a single index, counting every path of
+    /// multipath keys, which bounds the derivation work of the descriptor
+    pub(crate) fn keys_per_index(&self) -> usize {
+        let mut count = 0;
+        match self {
+            Descriptor::Bitcoin(desc) => desc.for_each_key(|k| {
+                count += match k {
+                    miniscript::DescriptorPublicKey::MultiXPub(x) => {
+                        x.derivation_paths.paths().len()
+                    }
+                    _ => 1,
+                };
+                true
+            }),
+            Descriptor::Elements(desc) => desc.for_each_key(|k| {
+                count += match k {
+                    elements_miniscript::DescriptorPublicKey::MultiXPub(x) => {
+                        x.derivation_paths.paths().len()
+                    }
+                    _ => 1,
+                };
+                true
+            }),
+        };
+        count
+    }
+
     pub(crate) fn normalized_id_string(&self) -> String {
         // ELIP-0152 DWID is the better wallet-level identifier for CT descriptors, but
         // computing it requires the blinding key. Waterfalls intentionally receives only
@@ -415,6 +442,18 @@ mod tests {
         assert!(matches!(result, Err(Error::InvalidDescriptor(_))));
     }
 
+    #[test]
+    fn test_keys_per_index() {
+        let desc_str = "wsh(multi(2,03a0434d9e47f3c86235477c7b1ae6ae5d3442d49b1943c2b752a68e2a47e247c7,03774ae7f858a9411e5ef4246b70c65aac5649980be5c17891bbec17895da008cb,03d01115d548e7561b15c38f004d734633687cf4419620095bc5b0f47070afe85a))";
+        let desc = Descriptor::from_str(desc_str, Network::Bitcoin).unwrap();
+        assert_eq!(desc.keys_per_index(), 3);
+
+        // both the receive and the change paths are derived
+        let desc_str = "elwpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/<0;1>/*)";
+        let desc = Descriptor::from_str(desc_str, Network::LiquidTestnet).unwrap();
+        assert_eq!(desc.keys_per_index(), 2);
+    }
+
     #[test]
     fn test_confidential_descriptor_rejected() {
         let desc_str = "ct(slip77(1bda6cd71a1e206e3eb793e5a4d98a46c3fa473c9ab7bdef9bb9c814764d6614),elwpkh([cb4ba44a/84'/1'/0']tpubDDrybtUajFcgXC85rvwPsh1oU7Azx4kJ9BAiRzMbByqK7UnVXY3gDRJPwEDfaQwguNUZFzrhavJGgEhbsfuebyxUSZQnjLezWVm2Vdqb7UM/<0;1>/*))#za9ktavp";
@@ -6,7 +6,7 @@ pub enum Transaction {
     Elements(elements::Transaction),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MempoolTx {
     inputs: Vec<crate::OutPoint>,
     output_script_hashes: Vec<crate::ScriptHash>,
@@ -123,6 +123,68 @@ impl MempoolTx {
     pub(crate) fn output_script_hashes_iter(&self) -> impl Iterator<Item = crate::ScriptHash> + '_ {
         self.output_script_hashes.iter().copied()
     }
+
+    #[cfg(test)]
+    pub(crate) fn from_parts(
+        inputs: Vec<crate::OutPoint>,
+        output_script_hashes: Vec<crate::ScriptHash>,
+    ) -> Self {
+        MempoolTx {
+            inputs,
+            output_script_hashes,
+        }
+    }
+
+    /// Serialize to bytes using consensus encoding, used to persist the mempool.
+    ///
+    /// Format:
+    /// - Inputs count (u32)
+    /// - For each input: OutPoint (36 bytes)
+    /// - Outputs count (u32)
+    /// - For each output: ScriptHash (8 bytes)
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        use elements::encode::Encodable;
+
+        let mut bytes = Vec::with_capacity(
+            8 + self.inputs.len() * crate::OutPoint::SIZE + self.output_script_hashes.len() * 8,
+        );
+        (self.inputs.len() as u32)
+            .consensus_encode(&mut bytes)
+            .expect("vec don't error");
+        for input in &self.inputs {
+            input.consensus_encode(&mut bytes).expect("vec don't error");
+        }
+        (self.output_script_hashes.len() as u32)
+            .consensus_encode(&mut bytes)
+            .expect("vec don't error");
+        for script_hash in &self.output_script_hashes {
+            script_hash
+                .consensus_encode(&mut bytes)
+                .expect("vec don't error");
+        }
+        bytes
+    }
+
+    /// Deserialize from the bytes returned by [`MempoolTx::to_bytes`]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        use elements::encode::Decodable;
+
+        let mut cursor = std::io::Cursor::new(bytes);
+        let inputs_count = u32::consensus_decode(&mut cursor)? as usize;
+        let mut inputs = Vec::with_capacity(inputs_count);
+        for _ in 0..inputs_count {
+            inputs.push(crate::OutPoint::consensus_decode(&mut cursor)?);
+        }
+        let outputs_count = u32::consensus_decode(&mut cursor)? as usize;
+        let mut output_script_hashes = Vec::with_capacity(outputs_count);
+        for _ in 0..outputs_count {
+            output_script_hashes.push(u64::consensus_decode(&mut cursor)?);
+        }
+        Ok(MempoolTx {
+            inputs,
+            output_script_hashes,
+        })
+    }
 }
 
 impl TransactionRef<'_> {
@@ -228,6 +290,41 @@ impl OutputRef<'_> {
             OutputRef::Elements(output) => output.script_pubkey.as_bytes(),
         }
     }
+
+    /// The value of the output in satoshi, None if it's confidential
+    pub(crate) fn value(&self) -> Option<u64> {
+        match self {
+            OutputRef::Bitcoin(output) => Some(output.value.to_sat()),
+            OutputRef::Elements(output) => output.value.explicit(),
+        }
+    }
+
+    /// The asset of the output, None if it's confidential or a bitcoin output
+    pub(crate) fn asset(&self) -> Option<elements::AssetId> {
+        match self {
+            OutputRef::Bitcoin(_) => None,
+            OutputRef::Elements(output) => output.asset.explicit(),
+        }
+    }
+
+    /// The value commitment of the output, blinded if it's confidential
+    pub(crate) fn value_commitment(&self) -> elements::confidential::Value {
+        match self {
+            OutputRef::Bitcoin(output) => {
+                elements::confidential::Value::Explicit(output.value.to_sat())
+            }
+            OutputRef::Elements(output) => output.value,
+        }
+    }
+
+    /// The asset commitment of the output, blinded if it's confidential and null for bitcoin
+    /// outputs
+    pub(crate) fn asset_commitment(&self) -> elements::confidential::Asset {
+        match self {
+            OutputRef::Bitcoin(_) => elements::confidential::Asset::Null,
+            OutputRef::Elements(output) => output.asset,
+        }
+    }
 }
 
 impl InputRef<'_> {
@@ -1,11 +1,16 @@
 use std::{
     collections::{HashMap, HashSet},
+    future::Future,
     str::FromStr,
     time::Duration,
 };
 
 use anyhow::{anyhow, Context, Result};
-use elements::{encode::Decodable, BlockHash};
+use elements::{
+    encode::Decodable,
+    secp256k1_zkp::rand::{thread_rng, Rng},
+    BlockHash,
+};
 use hyper::StatusCode;
 use serde::Deserialize;
 use serde_json::json;
@@ -58,9 +63,19 @@ pub struct Client {
     esplora_url: String,
 
     rpc_user_password: Option<String>,
+
+    retry_policy: RetryPolicy,
+
+    /// `scantxoutset` goes through the whole UTXO set, so it doesn't use the request timeout
+    scan_utxos_timeout: Duration,
 }
 
 const BS: &str = "https://blockstream.info";
+const DEFAULT_USER_AGENT: &str = concat!("waterfalls/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_RETRY_JITTER_MS: u64 = 250;
 const LOCAL: &str = "http://127.0.0.1";
 
 impl Client {
@@ -98,8 +113,12 @@ impl Client {
         };
         log::info!("connecting to {base_url}");
         let mut builder = reqwest::Client::builder()
+            .user_agent(args.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
             .timeout(Duration::from_secs(args.request_timeout_seconds))
-            .connect_timeout(Duration::from_secs(args.request_timeout_seconds)); // Connection establishment timeout
+            .connect_timeout(Duration::from_secs(
+                args.connect_timeout_seconds
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            ));
         if args.node_disable_conn_pool {
             if use_esplora {
                 // The flag is node-only; applying it to Esplora would force a fresh
@@ -128,12 +147,53 @@ impl Client {
             base_url,
             esplora_url,
             rpc_user_password: args.rpc_user_password.clone(),
+            retry_policy: RetryPolicy::from_args(args),
+            // a scan taking longer than the check interval would overlap with the next check
+            scan_utxos_timeout: Duration::from_secs(
+                args.utxo_check_interval_seconds
+                    .unwrap_or(args.request_timeout_seconds),
+            ),
         })
     }
 
+    /// The block hash at `height`, None if not found, see [`RetryPolicy`] for the retries
+    pub async fn block_hash(&self, height: u32) -> Result<Option<BlockHash>> {
+        self.retry_policy
+            .retry("block hash", || self._block_hash(height))
+            .await
+    }
+
+    /// The block `hash`, see [`RetryPolicy`] for the retries
+    pub async fn block(&self, hash: BlockHash, family: Family) -> Result<be::Block> {
+        self.retry_policy
+            .retry("block", || self._block(hash, family))
+            .await
+    }
+
+    /// The header of block `hash` as json, None if not in the best chain, see [`RetryPolicy`] for
+    /// the retries
+    pub async fn block_header_json(
+        &self,
+        hash: BlockHash,
+        family: Family,
+    ) -> Result<Option<HeaderJson>> {
+        self.retry_policy
+            .retry("block header json", || {
+                self._block_header_json(hash, family)
+            })
+            .await
+    }
+
+    /// The header of block `hash`, see [`RetryPolicy`] for the retries
+    pub async fn block_header(&self, hash: BlockHash, family: Family) -> Result<be::BlockHeader> {
+        self.retry_policy
+            .retry("block header", || self._block_header(hash, family))
+            .await
+    }
+
     // `curl http://127.0.0.1:7041/rest/blockhashbyheight/0.hex`
     // GET /block-height/:height
-    pub async fn block_hash(&self, height: u32) -> Result<Option<BlockHash>> {
+    async fn _block_hash(&self, height: u32) -> Result<Option<BlockHash>> {
         let base = &self.base_url;
         let url = if self.use_esplora {
             format!("{base}/block-height/{height}")
@@ -159,7 +219,7 @@ impl Client {
         } else if response.status() == 404 || response.status() == 503 {
             Ok(None)
         } else {
-            error_panic!("{url} return unexpected status {status} for block_hash");
+            Err(Error::UnexpectedStatus(url, status).into())
         }
     }
 
@@ -196,7 +256,7 @@ impl Client {
 
     /// GET /rest/block/<BLOCK-HASH>.<bin|hex|json>
     /// GET /block/:hash/raw
-    pub async fn block(&self, hash: BlockHash, family: Family) -> Result<be::Block> {
+    async fn _block(&self, hash: BlockHash, family: Family) -> Result<be::Block> {
         let base = &self.base_url;
         let url = if self.use_esplora {
             format!("{base}/block/{hash}/raw")
@@ -232,7 +292,7 @@ impl Client {
         }
     }
 
-    pub async fn block_header_json(
+    async fn _block_header_json(
         &self,
         hash: BlockHash,
         family: Family,
@@ -300,7 +360,7 @@ impl Client {
 
     /// GET /rest/headers/<BLOCK-HASH>.<bin|hex|json>
     /// GET /block/:hash/header
-    pub async fn block_header(&self, hash: BlockHash, family: Family) -> Result<be::BlockHeader> {
+    async fn _block_header(&self, hash: BlockHash, family: Family) -> Result<be::BlockHeader> {
         let base = &self.base_url;
         let url = if self.use_esplora {
             format!("{base}/block/{hash}/header")
@@ -486,6 +546,72 @@ impl Client {
         Ok(txid)
     }
 
+    /// Scan the node UTXO set for the outputs of `scripts` with `scantxoutset`, which goes
+    /// through the whole UTXO set once for all of them, so it takes a while on mainnet.
+    ///
+    /// Only the node is supported, esplora doesn't expose the UTXO set. The request times out
+    /// after `--utxo-check-interval-seconds`, in which case the scan is aborted on the node.
+    pub async fn scan_utxos(&self, scripts: &[Vec<u8>]) -> Result<ScannedUtxos> {
+        if self.use_esplora {
+            anyhow::bail!("scanning the UTXO set requires the node, not esplora");
+        }
+        let rpc_auth = self
+            .rpc_user_password
+            .as_ref()
+            .expect("validated by Arguments");
+        let url = self
+            .base_url
+            .replace("http://", &format!("http://{rpc_auth}@",));
+        let descriptors: Vec<_> = scripts
+            .iter()
+            .map(|script| {
+                format!(
+                    "raw({})",
+                    hex_simd::encode_to_string(script, hex_simd::AsciiCase::Lower)
+                )
+            })
+            .collect();
+        let data = json!({
+            "jsonrpc": "1.0",
+            "id": "scantxoutset",
+            "method": "scantxoutset",
+            "params": ["start", descriptors],
+        });
+        let data = serde_json::to_string(&data)?;
+
+        let response = match self
+            .client
+            .post(&url)
+            .timeout(self.scan_utxos_timeout)
+            .body(data)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() => {
+                // otherwise the node keeps scanning and rejects the next scan as in progress
+                let abort = json!({
+                    "jsonrpc": "1.0",
+                    "id": "scantxoutset",
+                    "method": "scantxoutset",
+                    "params": ["abort"],
+                });
+                let abort = serde_json::to_string(&abort)?;
+                if let Err(abort_err) = self.client.post(&url).body(abort).send().await {
+                    log::warn!("cannot abort the timed out scantxoutset: {abort_err:?}");
+                }
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let status = response.status();
+        let text = response.text().await?;
+        if status != 200 {
+            anyhow::bail!("scantxoutset failed with status:{status}, body is {text}");
+        }
+        parse_scantxoutset_reply(&text)
+    }
+
     pub(crate) async fn get_next(
         &self,
         last: &crate::store::BlockMeta,
@@ -600,6 +726,44 @@ fn parse_fee_estimates_rpc_reply(text: &str) -> anyhow::Result<HashMap<u16, f64>
         .collect())
 }
 
+fn parse_scantxoutset_reply(text: &str) -> anyhow::Result<ScannedUtxos> {
+    #[derive(Deserialize)]
+    struct Reply {
+        result: Option<ScanResult>,
+        error: Option<serde_json::Value>,
+    }
+    #[derive(Deserialize)]
+    struct ScanResult {
+        height: u32,
+        unspents: Vec<Unspent>,
+    }
+    #[derive(Deserialize)]
+    struct Unspent {
+        txid: crate::be::Txid,
+        vout: u32,
+        #[serde(rename = "scriptPubKey")]
+        script_pubkey: String,
+    }
+
+    let reply: Reply = serde_json::from_str(text)?;
+    if let Some(error) = reply.error.filter(|e| !e.is_null()) {
+        anyhow::bail!("scantxoutset returned error {error}");
+    }
+    let result = reply
+        .result
+        .ok_or(anyhow!("unexpected json without result"))?;
+    let mut unspents = Vec::with_capacity(result.unspents.len());
+    for unspent in result.unspents {
+        let script = hex_simd::decode_to_vec(unspent.script_pubkey.as_bytes())
+            .map_err(|e| anyhow!("invalid scriptPubKey {}: {e}", unspent.script_pubkey))?;
+        unspents.push((script, crate::OutPoint::new(unspent.txid, unspent.vout)));
+    }
+    Ok(ScannedUtxos {
+        height: result.height,
+        unspents,
+    })
+}
+
 fn parse_broadcast_response(text: &str, use_esplora: bool) -> anyhow::Result<crate::be::Txid> {
     if use_esplora {
         return crate::be::Txid::from_str(text.trim());
@@ -624,6 +788,15 @@ pub enum ChainStatus {
 #[derive(Deserialize)]
 pub struct Empty {}
 
+/// The outputs found by [`Client::scan_utxos`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedUtxos {
+    /// Height of the node tip when the UTXO set has been scanned
+    pub height: u32,
+    /// The unspent outputs with their script
+    pub unspents: Vec<(Vec<u8>, crate::OutPoint)>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct HeaderJson {
     pub hash: BlockHash,
@@ -638,6 +811,81 @@ pub struct ChainInfo {
     pub bestblockhash: BlockHash,
 }
 
+/// Exponential backoff of the requests to the node or esplora failing with a transient error: the
+/// backend couldn't be reached or answered with a server error. Not found and malformed responses
+/// are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 to fail at the first error
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubled at every following one
+    pub base_delay: Duration,
+
+    /// Upper bound of the random delay added to every retry
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    fn from_args(args: &Arguments) -> Self {
+        RetryPolicy {
+            max_retries: args.backend_max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            base_delay: Duration::from_millis(
+                args.backend_retry_base_delay_ms
+                    .unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS),
+            ),
+            jitter: Duration::from_millis(
+                args.backend_retry_jitter_ms
+                    .unwrap_or(DEFAULT_RETRY_JITTER_MS),
+            ),
+        }
+    }
+
+    /// The delay before the retry number `retry`, starting from 0
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(retry));
+        let jitter_ms = self.jitter.as_millis() as u64;
+        let jitter_ms = if jitter_ms == 0 {
+            0
+        } else {
+            thread_rng().gen_range(0..=jitter_ms)
+        };
+        backoff.saturating_add(Duration::from_millis(jitter_ms))
+    }
+
+    /// Call `f` until it succeeds, fails with a non transient error or the retries are exhausted
+    async fn retry<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(e) if retry < self.max_retries && is_transient(&e) => {
+                    let delay = self.delay(retry);
+                    retry += 1;
+                    log::warn!(
+                        "{what} request failed: {e:#}, retry {retry}/{} in {delay:?}",
+                        self.max_retries
+                    );
+                    sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether the request failing with `e` may succeed if retried
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| match cause.downcast_ref::<Error>() {
+        Some(Error::UnexpectedStatus(_, status)) => status.is_server_error(),
+        Some(_) => false,
+        None => cause.is::<reqwest::Error>(),
+    })
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
@@ -649,7 +897,7 @@ mod test {
         Family,
     };
 
-    use super::{parse_fee_estimates_rpc_reply, Client};
+    use super::{parse_fee_estimates_rpc_reply, parse_scantxoutset_reply, Client};
 
     #[test]
     fn test_parse_fee_estimates_rpc_reply() {
@@ -707,6 +955,29 @@ mod test {
         assert_eq!(result[&6], 2.0);
     }
 
+    #[test]
+    fn test_parse_scantxoutset_reply() {
+        let txid = "0000000000000000000000000000000000000000000000000000000000000001";
+        let json = format!(
+            r#"{{"result": {{"success": true, "height": 101, "unspents": [
+                {{"txid": "{txid}", "vout": 1, "scriptPubKey": "0014aabb", "amount": 0.1, "height": 100}}
+            ]}}, "error": null, "id": "scantxoutset"}}"#
+        );
+        let scanned = parse_scantxoutset_reply(&json).unwrap();
+        assert_eq!(scanned.height, 101);
+        assert_eq!(
+            scanned.unspents,
+            vec![(
+                vec![0x00, 0x14, 0xaa, 0xbb],
+                crate::OutPoint::new(crate::be::Txid::from_str(txid).unwrap(), 1)
+            )]
+        );
+
+        let json = r#"{"result": null, "error": {"code": -8, "message": "Scan already in progress"}, "id": "scantxoutset"}"#;
+        assert!(parse_scantxoutset_reply(json).is_err());
+        assert!(parse_scantxoutset_reply("not json").is_err());
+    }
+
     /// Spawn a minimal HTTP/1.1 server on loopback that counts the TCP
     /// connections it accepts and answers every request with the same canned
     /// blockhash, keeping the connection alive for any follow-up requests.
@@ -779,6 +1050,49 @@ mod test {
         Client::new(&args).unwrap()
     }
 
+    /// Spawn a server answering a single request with a 404, returning the request it received
+    async fn spawn_capturing_node() -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<String>)
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+            socket
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        (addr, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let (addr, request) = spawn_capturing_node().await;
+        node_client(addr, false).block_hash(0).await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.contains(&format!("user-agent: {}", super::DEFAULT_USER_AGENT)));
+
+        let (addr, request) = spawn_capturing_node().await;
+        let mut args = Arguments::default();
+        args.network = Network::Bitcoin;
+        args.node_url = Some(format!("http://{addr}"));
+        args.rpc_user_password = Some("user:pass".to_string());
+        args.request_timeout_seconds = 30;
+        args.user_agent = Some("waterfalls-eu-1".to_string());
+        Client::new(&args).unwrap().block_hash(0).await.unwrap();
+        assert!(request
+            .await
+            .unwrap()
+            .contains("user-agent: waterfalls-eu-1\r\n"));
+    }
+
     /// Default (pooled) behavior: sequential requests reuse a single keep-alive connection.
     #[tokio::test]
     async fn test_conn_pool_enabled_reuses_connection() {
@@ -811,6 +1125,90 @@ mod test {
         assert_eq!(connections.load(Ordering::SeqCst), 3);
     }
 
+    /// Spawn a server answering the first `failures` requests with a 500 and the following ones
+    /// with a blockhash, closing the connection after every response. Returns the bound address
+    /// and the request counter.
+    async fn spawn_flaky_node(
+        failures: usize,
+    ) -> (
+        std::net::SocketAddr,
+        std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    ) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            const HASH_HEX: &str =
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let response = if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+                        .to_string()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{HASH_HEX}",
+                        HASH_HEX.len()
+                    )
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (addr, requests)
+    }
+
+    fn retrying_client(addr: std::net::SocketAddr, max_retries: u32) -> Client {
+        let mut args = Arguments::default();
+        args.network = Network::Bitcoin;
+        args.node_url = Some(format!("http://{addr}"));
+        args.rpc_user_password = Some("user:pass".to_string());
+        args.request_timeout_seconds = 30;
+        args.backend_max_retries = Some(max_retries);
+        args.backend_retry_base_delay_ms = Some(1);
+        args.backend_retry_jitter_ms = Some(1);
+        Client::new(&args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        use std::sync::atomic::Ordering;
+
+        let (addr, requests) = spawn_flaky_node(2).await;
+        let hash = retrying_client(addr, 3).block_hash(0).await.unwrap();
+        assert!(hash.is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let (addr, requests) = spawn_flaky_node(usize::MAX).await;
+        let err = retrying_client(addr, 2).block_hash(0).await.unwrap_err();
+        assert!(err.to_string().contains("500"), "{err}");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // not found is an answer, not a failure
+        let (addr, request) = spawn_capturing_node().await;
+        assert_eq!(retrying_client(addr, 2).block_hash(0).await.unwrap(), None);
+        assert!(request.await.is_ok());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = super::RetryPolicy {
+            max_retries: 3,
+            base_delay: std::time::Duration::from_millis(100),
+            jitter: std::time::Duration::from_millis(10),
+        };
+        for (retry, expected) in [(0, 100), (1, 200), (2, 400)] {
+            let delay = policy.delay(retry).as_millis();
+            assert!((expected..=expected + 10).contains(&delay), "{delay}");
+        }
+    }
+
     #[tokio::test]
     #[ignore = "connects to prod server"]
     async fn test_client_esplora() {
@@ -7,9 +7,9 @@ use elements::BlockHash;
 use lazy_static::lazy_static;
 use minicbor::{Decode, Encode};
 use prometheus::{
-    labels, opts, register_counter, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Counter, HistogramVec,
-    IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    labels, opts, register_counter, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Counter, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 use serde::{Deserialize, Serialize};
 
@@ -67,6 +67,14 @@ pub struct DescriptorRequest {
     /// This can be used to override the GAP_LIMIT
     to_index: u32,
 
+    /// Return only the confirmed entries at this height or above, 0 if not specified
+    /// Scripts with history only below it still count as used for the GAP_LIMIT
+    from_height: Height,
+
+    /// The hash of the block at `from_height` known by the client, if it's not in the chain
+    /// anymore the whole history is returned
+    tip_hash: Option<BlockHash>,
+
     /// If true, does not return txid of transactions having only spent outputs
     utxo_only: bool,
 }
@@ -80,6 +88,13 @@ pub struct AddressesRequest {
     /// The first returned index is equal to `page * 10000`
     page: u16,
 
+    /// Return only the confirmed entries at this height or above, 0 if not specified
+    from_height: Height,
+
+    /// The hash of the block at `from_height` known by the client, if it's not in the chain
+    /// anymore the whole history is returned
+    tip_hash: Option<BlockHash>,
+
     utxo_only: bool,
 }
 
@@ -102,6 +117,18 @@ pub struct WaterfallResponse {
     #[cbor(n(4))]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub has_more: Option<Vec<String>>,
+
+    /// Set if the request `tip_hash` is not in the chain anymore, the history is not filtered by
+    /// `from_height` then
+    #[cbor(n(5))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reorged: Option<bool>,
+
+    /// Set if the server history starts at this height, because its UTXO set has been imported
+    /// from a snapshot, and the request needs history below it: older transactions are missing
+    #[cbor(n(6))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_start_height: Option<Height>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Ord, PartialOrd, Encode, Decode)]
@@ -246,12 +273,57 @@ impl V {
     }
 }
 
+impl V {
+    /// The discriminant of the variant in compact binary encodings, without the index of inputs
+    /// and outputs which is encoded separately: 0=Vin, 1=Vout, 3=Undefined
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            V::Vin(_) => V_VIN,
+            V::Vout(_) => V_VOUT,
+            V::Undefined => V_UNDEFINED,
+        }
+    }
+
+    /// The index of the input or of the output, 0 if undefined
+    pub fn index(&self) -> u32 {
+        match self {
+            V::Vin(index) | V::Vout(index) => *index,
+            V::Undefined => 0,
+        }
+    }
+
+    /// The inverse of [`V::to_u8`] and [`V::index`], fails returning `value` if it's not a known
+    /// discriminant
+    pub fn from_u8_and_index(value: u8, index: u32) -> Result<Self, u8> {
+        Ok(match V::try_from(value)? {
+            V::Vin(_) => V::Vin(index),
+            V::Vout(_) => V::Vout(index),
+            V::Undefined => V::Undefined,
+        })
+    }
+}
+
 impl From<i32> for V {
     fn from(v: i32) -> Self {
         Self::from_raw(v)
     }
 }
 
+/// The variant of the discriminant returned by [`V::to_u8`], with index 0 for inputs and outputs.
+/// Fails returning the value if it's not a known discriminant.
+impl TryFrom<u8> for V {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            V_VIN => Ok(V::Vin(0)),
+            V_VOUT => Ok(V::Vout(0)),
+            V_UNDEFINED => Ok(V::Undefined),
+            _ => Err(value),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Encode, Decode)]
 pub struct TxSeen {
     #[cbor(n(0))]
@@ -302,6 +374,17 @@ impl TxSeen {
     }
 }
 
+/// A [`TxSeen`] with the value received by the output or spent by the input it refers to
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct TxSeenWithValue {
+    #[serde(flatten)]
+    pub tx_seen: TxSeen,
+
+    /// None if the value is confidential, not indexed or the entry is not an input or output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_sat: Option<u64>,
+}
+
 lazy_static! {
     pub(crate) static ref WATERFALLS_COUNTER: Counter = register_counter!(opts!(
         "waterfalls_requests_total",
@@ -343,6 +426,28 @@ lazy_static! {
         &["handler"]
     )
     .unwrap();
+    pub(crate) static ref WATERFALLS_SCRIPTS_HISTOGRAM: Histogram = register_histogram!(
+        "waterfalls_request_scripts",
+        "The number of scripts derived or given per waterfalls request.",
+        prometheus::exponential_buckets(1.0, 4.0, 8).expect("static")
+    )
+    .unwrap();
+    static ref WATERFALLS_RESPONSES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "waterfalls_responses_total",
+        "HTTP responses by status code.",
+        &["status"]
+    )
+    .unwrap();
+    pub(crate) static ref BLOCK_APPLY_HISTOGRAM: Histogram = register_histogram!(
+        "waterfalls_block_apply_duration_seconds",
+        "The duration of indexing a block in the store in seconds."
+    )
+    .unwrap();
+    static ref TIP_AGE: IntGauge = register_int_gauge!(
+        "waterfalls_tip_age_seconds",
+        "Seconds between the timestamp of the last indexed block and when it was indexed."
+    )
+    .unwrap();
     static ref WATERFALLS_CACHE_COUNTER: IntCounterVec = register_int_counter_vec!(
         "waterfalls_cache_counter",
         "Hit/Miss of Waterfalls caches",
@@ -362,6 +467,28 @@ lazy_static! {
             &["event", "result"]
         )
         .unwrap();
+    static ref WATERFALLS_UTXO_COUNT: IntGauge = register_int_gauge!(
+        "waterfalls_utxo_count",
+        "Number of unspent outputs in the index, estimated with the DB store."
+    )
+    .unwrap();
+    static ref WATERFALLS_REORG_DATA_DEPTH: IntGauge = register_int_gauge!(
+        "waterfalls_reorg_data_depth",
+        "Number of blocks that can be rolled back with the available reorg data."
+    )
+    .unwrap();
+    pub(crate) static ref WATERFALLS_REORGED_BLOCKS_COUNTER: IntCounter =
+        register_int_counter!(opts!(
+            "waterfalls_reorged_blocks_total",
+            "The total number of blocks rolled back by reorgs."
+        ))
+        .unwrap();
+    pub(crate) static ref WATERFALLS_UTXO_CHECK_MISMATCHES_COUNTER: IntCounter =
+        register_int_counter!(opts!(
+            "waterfalls_utxo_check_mismatches_total",
+            "The total number of scripts with UTXOs differing from the node UTXO set in the periodic check."
+        ))
+        .unwrap();
     pub(crate) static ref WATERFALLS_UNIQUE_DESCRIPTORS: IntGauge = register_int_gauge!(
         "waterfalls_unique_descriptors",
         "Unique descriptor IDs seen within the last 24 hours."
@@ -395,6 +522,26 @@ pub(crate) fn inc_subscription_notification_counter(event: &str, result: &str) {
         .inc();
 }
 
+pub(crate) fn inc_response_counter(status: hyper::StatusCode) {
+    crate::WATERFALLS_RESPONSES_COUNTER
+        .with_label_values(&[status.as_str()])
+        .inc();
+}
+
+/// Set how far behind the wall clock is the block with `timestamp` just indexed
+pub(crate) fn set_tip_age(timestamp: u32) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    crate::TIP_AGE.set((now - timestamp as i64).max(0));
+}
+
+pub(crate) fn set_store_stats(stats: &store::StoreStats) {
+    crate::WATERFALLS_UTXO_COUNT.set(stats.utxos as i64);
+    crate::WATERFALLS_REORG_DATA_DEPTH.set(stats.reorg_data_depth as i64);
+}
+
 pub(crate) fn set_unique_descriptors(count: usize) {
     crate::WATERFALLS_UNIQUE_DESCRIPTORS.set(count as i64);
 }
@@ -474,6 +621,12 @@ pub struct LastUsedIndexResponse {
     pub tip: Option<BlockHash>,
 }
 
+/// Stable discriminants of the [`V`] variants in the binary encodings of the stores, see
+/// [`V::to_u8`]. Values must never be reassigned, 2 is reserved for coinbase inputs.
+const V_VIN: u8 = 0;
+const V_VOUT: u8 = 1;
+const V_UNDEFINED: u8 = 3;
+
 #[cfg(test)]
 mod tests {
 
@@ -523,6 +676,32 @@ mod tests {
         assert_eq!(len3, 1);
     }
 
+    #[test]
+    fn test_v_raw_roundtrip() {
+        for v in [V::Undefined, V::Vin(0), V::Vin(7), V::Vout(0), V::Vout(7)] {
+            assert_eq!(V::from_raw(v.raw()), v);
+        }
+    }
+
+    #[test]
+    fn test_v_u8_roundtrip() {
+        for v in [V::Vin(0), V::Vout(0), V::Undefined] {
+            assert_eq!(V::try_from(v.to_u8()), Ok(v));
+        }
+        // the index is not part of the discriminant
+        assert_eq!(V::Vin(7).to_u8(), 0);
+        assert_eq!(V::Vout(7).to_u8(), 1);
+        assert_eq!(V::Undefined.to_u8(), 3);
+        assert_eq!(V::try_from(V::Vout(7).to_u8()), Ok(V::Vout(0)));
+        for v in [V::Vin(0), V::Vin(7), V::Vout(0), V::Vout(7), V::Undefined] {
+            assert_eq!(V::from_u8_and_index(v.to_u8(), v.index()), Ok(v));
+        }
+        assert_eq!(V::from_u8_and_index(2, 7), Err(2));
+        assert_eq!(V::try_from(2), Err(2));
+        assert_eq!(V::try_from(4), Err(4));
+        assert_eq!(V::try_from(u8::MAX), Err(u8::MAX));
+    }
+
     #[test]
     fn test_cbor_txseen() {
         let txid = crate::be::Txid::from_str(
@@ -553,11 +732,43 @@ mod tests {
         set_unique_descriptors(7);
         set_descriptor_max_used_index_buckets([20, 30, 40, 1001]);
         inc_subscription_notification_counter("mempool", "queued");
+        inc_response_counter(hyper::StatusCode::BAD_REQUEST);
+        set_tip_age(1);
+        WATERFALLS_SCRIPTS_HISTOGRAM.observe(20.0);
+        BLOCK_APPLY_HISTOGRAM.observe(0.5);
 
-        let metric_names = prometheus::gather()
-            .into_iter()
+        let metric_families = prometheus::gather();
+        let metric_names = metric_families
+            .iter()
             .map(|metric| metric.get_name().to_string())
             .collect::<Vec<_>>();
+        let family = |name: &str| {
+            metric_families
+                .iter()
+                .find(|metric| metric.get_name() == name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        let responses = family("waterfalls_responses_total").get_metric();
+        assert!(responses
+            .iter()
+            .any(|m| m.get_label()[0].get_value() == "400" && m.get_counter().get_value() > 0.0));
+        assert!(
+            family("waterfalls_tip_age_seconds").get_metric()[0]
+                .get_gauge()
+                .get_value()
+                > 0.0
+        );
+        for name in [
+            "waterfalls_request_scripts",
+            "waterfalls_block_apply_duration_seconds",
+        ] {
+            assert!(
+                family(name).get_metric()[0]
+                    .get_histogram()
+                    .get_sample_count()
+                    > 0
+            );
+        }
 
         assert!(metric_names.contains(&"waterfalls_unique_descriptors".to_string()));
         assert!(
@@ -1,7 +1,7 @@
 use clap::Parser;
 use env_logger::Env;
 use std::io::Write;
-use waterfalls::server::{inner_main, Arguments};
+use waterfalls::server::{genkey, inner_main, Arguments, Command};
 
 #[tokio::main]
 async fn main() {
@@ -9,9 +9,74 @@ async fn main() {
 
     let args = Arguments::parse();
 
+    match &args.command {
+        Some(Command::Genkey) => {
+            run_genkey(&args);
+            return;
+        }
+        #[cfg(feature = "db")]
+        Some(Command::Diff { db, other_db }) => {
+            run_diff(db, other_db);
+            return;
+        }
+        #[cfg(feature = "db")]
+        Some(Command::ExportUtxos { db, output }) => {
+            let count = waterfalls::server::utxo_snapshot::export_utxos_to_file(db, output)
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!("{count} utxos exported to {output:?}");
+            return;
+        }
+        #[cfg(feature = "db")]
+        Some(Command::ImportUtxos { db, input }) => {
+            let count = waterfalls::server::utxo_snapshot::import_utxos_from_file(db, input)
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!("{count} utxos imported in {db:?}");
+            return;
+        }
+        None => {}
+    }
+
     inner_main(args, shutdown_signal()).await.unwrap(); // we want to panic in case of error so that the process exit with non-zero value
 }
 
+fn run_genkey(args: &Arguments) {
+    let dir = genkey::default_keys_dir().expect("HOME is not set");
+    let keys = match genkey::genkey(&dir, args.network.as_network_kind()) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("!!! WARNING: no key generated in {dir:?}: {e} !!!");
+            std::process::exit(1);
+        }
+    };
+    println!("age identity written to {:?}", keys.identity_path);
+    println!("private key written to {:?}", keys.privkey_path);
+    println!("server recipient: {}", keys.recipient);
+    println!("server public key: {}", keys.public_key);
+    println!("server address: {}", keys.address);
+}
+
+#[cfg(feature = "db")]
+fn run_diff(db: &std::path::Path, other_db: &std::path::Path) {
+    let differences = waterfalls::server::diff::diff_dbs(db, other_db).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    if differences.is_empty() {
+        println!("no differences");
+        return;
+    }
+    for difference in differences.iter() {
+        println!("{difference}");
+    }
+    std::process::exit(1);
+}
+
+#[cfg(feature = "db")]
+fn exit_with_error(e: waterfalls::server::Error) -> ! {
+    eprintln!("{e}");
+    std::process::exit(1);
+}
+
 async fn shutdown_signal() {
     #[cfg(unix)]
     {
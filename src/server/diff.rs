@@ -0,0 +1,69 @@
+//! Compare the content of two DBs, for example to check that a DB indexed with a new version
+//! matches one indexed with the previous version.
+
+use std::path::Path;
+
+use crate::store::{db::DBStore, Store, MAX_STORE_DIFFERENCES};
+
+use super::Error;
+
+/// Open the DBs at `db` and `other_db` and describe their differences, one per line.
+///
+/// The result is empty if the DBs have the same utxos and history.
+pub fn diff_dbs(db: &Path, other_db: &Path) -> Result<Vec<String>, Error> {
+    let db = open(db)?;
+    let other_db = open(other_db)?;
+    if db.hash(b"") != other_db.hash(b"") {
+        log::warn!("the DBs hash scripts with different salts, the histories are not comparable");
+    }
+
+    let diff = db
+        .diff_with_store(&other_db)
+        .map_err(|e| Error::String(format!("diff failed: {e:?}")))?;
+    let mut lines = vec![];
+    for outpoint in diff.only_in_self.iter() {
+        lines.push(format!("utxo only in the first DB: {outpoint}"));
+    }
+    for outpoint in diff.only_in_other.iter() {
+        lines.push(format!("utxo only in the other DB: {outpoint}"));
+    }
+    for (script_hash, entries) in diff.history_differences.iter() {
+        lines.push(format!(
+            "different history for script hash {script_hash}, {} entries in the first DB",
+            entries.len()
+        ));
+    }
+    if diff.truncated {
+        lines.push(format!("stopped after {MAX_STORE_DIFFERENCES} differences"));
+    }
+    Ok(lines)
+}
+
+/// Open the DB at `path` read-only, so that it's neither created if missing nor locked against
+/// a node indexing in it
+pub(super) fn open(path: &Path) -> Result<DBStore, Error> {
+    DBStore::open_read_only(path, 64).map_err(|e| Error::DBOpen(format!("{path:?}: {e:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_dbs() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let a = tempdir.path().join("a");
+        let b = tempdir.path().join("b");
+        // a DB can be compared while a node indexes in it
+        let writer = DBStore::open(&a, 64, false, 0).unwrap();
+        drop(DBStore::open(&b, 64, false, 0).unwrap());
+
+        assert!(diff_dbs(&a, &b).unwrap().is_empty());
+        drop(writer);
+        assert!(matches!(
+            diff_dbs(&a, &tempdir.path().join("missing")),
+            Err(Error::DBOpen(_))
+        ));
+        assert!(!tempdir.path().join("missing").exists());
+    }
+}
@@ -0,0 +1,118 @@
+//! Generate the keys to pass as `--server-key` and `--wif-key`, so that they are stable across
+//! restarts instead of randomly generated at every start.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use age::{secrecy::ExposeSecret, x25519::Identity};
+use bitcoin::{key::Secp256k1, NetworkKind, PrivateKey};
+
+use super::{sign::p2pkh, Error};
+
+pub const IDENTITY_FILE: &str = "identity.txt";
+pub const PRIVKEY_FILE: &str = "privkey.txt";
+
+/// The public parts of the keys written by [`genkey`]
+pub struct GeneratedKeys {
+    pub identity_path: PathBuf,
+    pub privkey_path: PathBuf,
+    /// The age recipient returned by `/v1/server_recipient`
+    pub recipient: age::x25519::Recipient,
+    pub public_key: bitcoin::PublicKey,
+    /// The address returned by `/v1/server_address`
+    pub address: bitcoin::Address,
+}
+
+/// `~/.waterfalls`, `None` if the home directory is unknown
+pub fn default_keys_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".waterfalls"))
+}
+
+/// Generate a fresh age identity and a private key for `network_kind` and write them in `dir`,
+/// readable only by the owner.
+///
+/// Existing keys are never overwritten, it fails if any of the files already exists.
+pub fn genkey(dir: &Path, network_kind: NetworkKind) -> Result<GeneratedKeys, Error> {
+    let identity_path = dir.join(IDENTITY_FILE);
+    let privkey_path = dir.join(PRIVKEY_FILE);
+    let existing: Vec<_> = [&identity_path, &privkey_path]
+        .into_iter()
+        .filter(|p| p.exists())
+        .collect();
+    if !existing.is_empty() {
+        return Err(Error::String(format!(
+            "refusing to overwrite existing keys: {existing:?}"
+        )));
+    }
+    std::fs::create_dir_all(dir).map_err(|e| Error::String(format!("{dir:?}: {e}")))?;
+
+    let identity = Identity::generate();
+    let wif_key = PrivateKey::generate(network_kind);
+    write_private(&identity_path, identity.to_string().expose_secret())?;
+    write_private(&privkey_path, &wif_key.to_wif())?;
+
+    let secp = Secp256k1::new();
+    Ok(GeneratedKeys {
+        identity_path,
+        privkey_path,
+        recipient: identity.to_public(),
+        public_key: wif_key.public_key(&secp),
+        address: p2pkh(&secp, &wif_key),
+    })
+}
+
+fn write_private(path: &Path, content: &str) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| Error::String(format!("{path:?}: {e}")))?;
+    writeln!(file, "{content}").map_err(|e| Error::String(format!("{path:?}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_genkey() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let dir = tempdir.path().join("keys");
+        let keys = genkey(&dir, NetworkKind::Test).unwrap();
+
+        let identity = std::fs::read_to_string(&keys.identity_path).unwrap();
+        let identity = Identity::from_str(identity.trim()).unwrap();
+        assert_eq!(identity.to_public().to_string(), keys.recipient.to_string());
+
+        let wif = std::fs::read_to_string(&keys.privkey_path).unwrap();
+        let wif_key = PrivateKey::from_wif(wif.trim()).unwrap();
+        assert_eq!(wif_key.network, NetworkKind::Test);
+        assert_eq!(p2pkh(&Secp256k1::new(), &wif_key), keys.address);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&keys.privkey_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(
+            genkey(&dir, NetworkKind::Test).is_err(),
+            "keys are not overwritten"
+        );
+        assert_eq!(std::fs::read_to_string(&keys.privkey_path).unwrap(), wif);
+    }
+}
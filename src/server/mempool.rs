@@ -44,7 +44,9 @@ impl Mempool {
         self.add(db, txs)
     }
 
-    fn remove(&mut self, txids: &[crate::be::Txid]) {
+    /// Forget the given transactions, also used to drop the ones confirmed in a new block without
+    /// waiting for the mempool thread to see them leaving the node mempool
+    pub(crate) fn remove(&mut self, txids: &[crate::be::Txid]) {
         for txid in txids {
             if let Some(hashes) = self.txid_hashes.remove(txid) {
                 for hash in hashes {
@@ -186,6 +188,29 @@ impl Mempool {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
     #[test]
     fn test_mempool() {}
+
+    #[test]
+    fn test_mempool_remove_confirmed() {
+        let db = AnyStore::Mem(MemoryStore::new());
+        let mut mempool = Mempool::new();
+        let txid = crate::be::Txid::from_array([1; 32]);
+        let tx = be::MempoolTx::from_parts(vec![], vec![5]);
+
+        let changed = mempool.update(&db, &[], &[(txid, &tx)]);
+        assert_eq!(changed, HashSet::from([5]));
+        let mut seen = vec![vec![]];
+        mempool.append_seen(&[5], &mut seen);
+        assert_eq!(seen, vec![vec![TxSeen::mempool(txid, V::Vout(0))]]);
+
+        // the block confirming the transaction arrives
+        mempool.remove(&[txid]);
+        assert_eq!(mempool.has_seen(&[5]), vec![false]);
+        assert_eq!(mempool.stats().txids, 0);
+        assert_eq!(mempool.stats().outpoints_created, 0);
+    }
 }
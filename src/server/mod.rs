@@ -10,9 +10,10 @@ use crate::fetch::Client;
 use crate::inc_connection_error_counter;
 use crate::server::preload::headers;
 use crate::store::memory::MemoryStore;
-use crate::store::AnyStore;
+use crate::store::{metadata, AnyStore, DuplicateEntriesPolicy, FutureHeightPolicy, Store};
 use crate::threads::blocks::blocks_infallible;
 use crate::threads::mempool::mempool_sync_infallible;
+use crate::threads::utxo_check::utxo_check_infallible;
 use crate::threads::zmq::rawtx_listener_infallible;
 use age::x25519::Identity;
 use bitcoin::{NetworkKind, PrivateKey};
@@ -24,21 +25,31 @@ use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 
 mod derivation_cache;
+#[cfg(feature = "db")]
+pub mod diff;
 pub mod encryption;
+pub mod genkey;
 mod mempool;
 pub mod preload;
+mod rate_limit;
 pub mod route;
 pub mod sign;
 mod state;
 mod subscription;
+#[cfg(feature = "db")]
+pub mod utxo_snapshot;
 
 pub use mempool::Mempool;
-pub use state::{State, StateConfig, SubscriptionLimits};
-pub(crate) use subscription::SubscriptionEvent;
+pub use state::{ReorgGuard, State, StateConfig, SubscriptionLimits};
+pub(crate) use subscription::{BlockEvent, SubscriptionEvent};
 
 const DEFAULT_MAX_TXS_SEEN: usize = 100;
+const DEFAULT_MAX_DESCRIPTOR_KEYS: usize = 100;
+const DEFAULT_MAX_OUTPOINTS_PER_QUERY: usize = 10_000;
 const DEFAULT_MAX_ACTIVE_SUBSCRIPTIONS: usize = 5_000;
 const DEFAULT_MAX_SCRIPTS_PER_SUBSCRIPTION: usize = 2_000;
+const DEFAULT_REORG_EVENTS_KEEP: usize = 100;
+const DEFAULT_UTXO_CHECK_SAMPLE_SIZE: usize = 20;
 const PERIODIC_LOGGING_INTERVAL: Duration = Duration::from_secs(300);
 
 #[derive(Clone, clap::ValueEnum, Debug, PartialEq, Eq, Copy)]
@@ -55,6 +66,10 @@ pub enum Network {
 #[derive(clap::Parser, Clone, Default)]
 #[command(author, version, about, long_about = None)]
 pub struct Arguments {
+    /// Run a command instead of starting the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// network to use, default on liquid mainnet
     #[arg(env, long)]
     pub network: Network,
@@ -80,6 +95,11 @@ pub struct Arguments {
     #[arg(env, long)]
     pub db_dir: Option<std::path::PathBuf>,
 
+    /// File where the in-memory store is saved on graceful shutdown and loaded from on startup, to
+    /// avoid indexing again from scratch. Not used with --db-dir.
+    #[arg(env, long)]
+    pub memory_store_file: Option<std::path::PathBuf>,
+
     /// An optional age server key to decrypt descriptor query string.
     /// If not provided is randomly generated.
     #[arg(long, env)]
@@ -105,6 +125,10 @@ pub struct Arguments {
     #[arg(env, long, default_value = "100")]
     pub max_addresses: usize,
 
+    /// Maximum number of keys derived for the scripts at a single index of a descriptor, counting every path of multipath keys, so that a descriptor with many keys can't make a request derive a huge number of keys. Default is 100.
+    #[arg(env, long)]
+    pub max_descriptor_keys: Option<usize>,
+
     /// Maximum number of confirmed TxSeen entries returned for a single script.
     #[arg(env, long)]
     pub max_txs_seen: Option<usize>,
@@ -146,15 +170,36 @@ pub struct Arguments {
     #[arg(env, long, default_value = "5")]
     pub cache_control_seconds: u32,
 
-    /// Timeout in seconds for connect and for reques HTTP requests and  to the node or esplora
+    /// Timeout in seconds for HTTP requests to the node or esplora, including the connection
     #[arg(env, long, default_value = "30")]
     pub request_timeout_seconds: u64,
 
+    /// Timeout in seconds for establishing a connection to the node or esplora, so that an
+    /// unreachable host fails fast instead of waiting for the whole request timeout. Default is 10.
+    #[arg(env, long)]
+    pub connect_timeout_seconds: Option<u64>,
+
+    /// User-Agent header sent to the node or esplora, to tell apart instances in their access logs. Default is waterfalls/<version>.
+    #[arg(env, long)]
+    pub user_agent: Option<String>,
+
     /// Disable HTTP keep-alive connection pooling to the node, forcing a fresh connection per request.
     /// Node-only: ignored (with a warning) when --use-esplora is set.
     #[arg(env, long)]
     pub node_disable_conn_pool: bool,
 
+    /// Times a block, block hash or block header request to the node or esplora is retried after a connection error or a server error, with exponential backoff. Default is 3.
+    #[arg(env, long)]
+    pub backend_max_retries: Option<u32>,
+
+    /// Delay in milliseconds before the first retry of a request to the node or esplora, doubled at every following retry. Default is 500.
+    #[arg(env, long)]
+    pub backend_retry_base_delay_ms: Option<u64>,
+
+    /// Upper bound in milliseconds of the random delay added to every retry, so that concurrent failing requests don't retry together. Default is 250.
+    #[arg(env, long)]
+    pub backend_retry_jitter_ms: Option<u64>,
+
     /// Timeout in seconds for reading incoming HTTP request headers (protects against slowloris attacks)
     #[arg(env, long, default_value = "10")]
     pub header_read_timeout_seconds: u64,
@@ -163,10 +208,119 @@ pub struct Arguments {
     #[arg(env, long)]
     pub mempool_sleep_between_cycles_ms: Option<u64>,
 
-    /// Number of recent block heights to keep reorg data for. Older reorg data is automatically deleted. Default is 6.
+    /// Number of recent block heights to keep reorg data for. Older reorg data is automatically deleted. Default is 6, or all the heights with the in-memory store.
     #[cfg(feature = "db")]
     #[arg(env, long)]
     pub reorg_data_keep_heights: Option<u32>,
+
+    /// Persist the mempool in the db, so that after a restart the transactions still in the node mempool are served without waiting to refetch them.
+    #[cfg(feature = "db")]
+    #[arg(env, long)]
+    pub persist_mempool: bool,
+
+    /// Open the db in `--db-dir` read-only and don't index blocks, to serve it next to the instance indexing in it.
+    /// The blocks indexed after the start are not served, restart to see them.
+    #[cfg(feature = "db")]
+    #[arg(env, long)]
+    pub read_only: bool,
+
+    /// Record the time spent in the store operations, exposed on /debug/profile.
+    #[cfg(feature = "profiling")]
+    #[arg(env, long)]
+    pub enable_profiling: bool,
+
+    /// What to do when indexing history entries with a height above the block being indexed:
+    /// store them as they are (allow), lower them to the block height (clamp) or stop indexing (reject). Default is allow.
+    #[arg(env, long)]
+    pub future_height_policy: Option<FutureHeightPolicy>,
+
+    /// What to do when indexing identical history entries for the same script:
+    /// store them as they are (keep), store only one (remove) or store only one and log a warning (warn). Default is remove.
+    #[arg(env, long)]
+    pub duplicate_entries_policy: Option<DuplicateEntriesPolicy>,
+
+    /// Maximum number of outpoints looked up in the UTXO set by a single `utxo_only` request, requests needing more get a 400. Default is 10000.
+    #[arg(env, long)]
+    pub max_outpoints_per_query: Option<usize>,
+
+    /// Maximum number of HTTP requests per second accepted from the same IP address, requests above it get a 429. Default is no limit.
+    #[arg(env, long)]
+    pub max_req_per_second_per_ip: Option<u32>,
+
+    /// HTTP header carrying the client IP address set by a trusted reverse proxy, like X-Forwarded-For, used by --max-req-per-second-per-ip instead of the connection address. The last address of the header is used.
+    #[arg(env, long)]
+    pub rate_limit_ip_header: Option<hyper::header::HeaderName>,
+
+    /// Answer requests with 503 while a reorg is being applied, instead of possibly serving inconsistent data
+    #[arg(env, long)]
+    pub reject_requests_during_reorg: bool,
+
+    /// Number of recent blocks to keep the summary for, independently of the reorg data. Default is to keep all of them.
+    #[arg(env, long)]
+    pub block_summary_keep_heights: Option<u32>,
+
+    /// Number of most recent reorgs listed by `/reorgs`, older ones are forgotten. Default is 100.
+    #[arg(env, long)]
+    pub reorg_events_keep: Option<usize>,
+
+    /// Every this many seconds, compare the UTXOs indexed for a sample of the scripts of the tip block with the node UTXO set, logging and counting in the metrics the mismatches.
+    /// It uses `scantxoutset`, so it requires the node, and a scan taking longer than the interval is aborted. Disabled by default.
+    #[arg(env, long)]
+    pub utxo_check_interval_seconds: Option<u64>,
+
+    /// Number of scripts compared by every UTXO check. Default is 20.
+    #[arg(env, long)]
+    pub utxo_check_sample_size: Option<usize>,
+
+    /// Index the explicit values (and assets on elements) of the outputs and of the outputs spent by the inputs, so that history entries can be returned with their value and balances computed.
+    /// Values of blocks indexed before enabling it are not available.
+    #[arg(env, long)]
+    pub index_values: bool,
+
+    /// Index the height of the block spending every output, to list the outputs of a script spent since a height.
+    /// It grows with every spent output and is never pruned. Outputs spent by blocks indexed before enabling it are missing.
+    #[arg(env, long)]
+    pub index_spent_heights: bool,
+}
+
+#[derive(clap::Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Generate a server key and a WIF key for `--network` in `~/.waterfalls`, to pass them as
+    /// `--server-key` and `--wif-key`
+    Genkey,
+
+    /// Compare the utxos and the history indexed in two DBs, like the `db/<network>` directories
+    /// of two `--db-dir`, to verify a migration. Exits with an error if they differ.
+    #[cfg(feature = "db")]
+    Diff {
+        /// Path of the first DB
+        db: std::path::PathBuf,
+        /// Path of the DB to compare with
+        other_db: std::path::PathBuf,
+    },
+
+    /// Export the UTXO set and the block hashes and timestamps of a DB, like the `db/<network>`
+    /// directory of a `--db-dir`, to start another node from it with `import-utxos`.
+    /// The histories are not exported, the DB is opened read-only and can be in use by a node.
+    #[cfg(feature = "db")]
+    ExportUtxos {
+        /// Path of the DB
+        db: std::path::PathBuf,
+        /// Path of the snapshot file to write
+        #[arg(long)]
+        output: std::path::PathBuf,
+    },
+
+    /// Import a snapshot written by `export-utxos` in an empty DB, the node started on it indexes
+    /// from the snapshot tip and knows only the transactions of the blocks after it.
+    #[cfg(feature = "db")]
+    ImportUtxos {
+        /// Path of the DB, created if missing
+        db: std::path::PathBuf,
+        /// Path of the snapshot file to read
+        #[arg(long)]
+        input: std::path::PathBuf,
+    },
 }
 
 // We can't automatically derive Debug for Arguments because the server_key and wif_key are sensitive data
@@ -174,6 +328,7 @@ impl std::fmt::Debug for Arguments {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut d = f.debug_struct("Arguments");
         let mut d = d
+            .field("command", &self.command)
             .field("network", &self.network)
             .field("use_esplora", &self.use_esplora)
             .field("esplora_url", &self.esplora_url)
@@ -193,6 +348,7 @@ impl std::fmt::Debug for Arguments {
             )
             .field("zmq_endpoint", &self.zmq_endpoint)
             .field("max_addresses", &self.max_addresses)
+            .field("max_descriptor_keys", &self.max_descriptor_keys)
             .field("max_txs_seen", &self.max_txs_seen)
             .field("add_cors", &self.add_cors)
             .field("derivation_cache_capacity", &self.derivation_cache_capacity)
@@ -207,7 +363,15 @@ impl std::fmt::Debug for Arguments {
             .field("enable_db_statistics", &self.enable_db_statistics)
             .field("cache_control_seconds", &self.cache_control_seconds)
             .field("request_timeout_seconds", &self.request_timeout_seconds)
+            .field("connect_timeout_seconds", &self.connect_timeout_seconds)
+            .field("user_agent", &self.user_agent)
             .field("node_disable_conn_pool", &self.node_disable_conn_pool)
+            .field("backend_max_retries", &self.backend_max_retries)
+            .field(
+                "backend_retry_base_delay_ms",
+                &self.backend_retry_base_delay_ms,
+            )
+            .field("backend_retry_jitter_ms", &self.backend_retry_jitter_ms)
             .field(
                 "header_read_timeout_seconds",
                 &self.header_read_timeout_seconds,
@@ -215,13 +379,42 @@ impl std::fmt::Debug for Arguments {
             .field(
                 "mempool_sleep_between_cycles_ms",
                 &self.mempool_sleep_between_cycles_ms,
-            );
+            )
+            .field("future_height_policy", &self.future_height_policy)
+            .field("duplicate_entries_policy", &self.duplicate_entries_policy)
+            .field("max_outpoints_per_query", &self.max_outpoints_per_query)
+            .field("max_req_per_second_per_ip", &self.max_req_per_second_per_ip)
+            .field("rate_limit_ip_header", &self.rate_limit_ip_header)
+            .field(
+                "reject_requests_during_reorg",
+                &self.reject_requests_during_reorg,
+            )
+            .field(
+                "block_summary_keep_heights",
+                &self.block_summary_keep_heights,
+            )
+            .field("reorg_events_keep", &self.reorg_events_keep)
+            .field(
+                "utxo_check_interval_seconds",
+                &self.utxo_check_interval_seconds,
+            )
+            .field("utxo_check_sample_size", &self.utxo_check_sample_size)
+            .field("index_values", &self.index_values)
+            .field("index_spent_heights", &self.index_spent_heights)
+            .field("memory_store_file", &self.memory_store_file);
 
         #[cfg(feature = "db")]
         {
             d = d
                 .field("db_dir", &self.db_dir)
-                .field("reorg_data_keep_heights", &self.reorg_data_keep_heights);
+                .field("reorg_data_keep_heights", &self.reorg_data_keep_heights)
+                .field("persist_mempool", &self.persist_mempool)
+                .field("read_only", &self.read_only);
+        }
+
+        #[cfg(feature = "profiling")]
+        {
+            d = d.field("enable_profiling", &self.enable_profiling);
         }
 
         d.finish()
@@ -230,6 +423,14 @@ impl std::fmt::Debug for Arguments {
 
 impl Arguments {
     pub fn is_valid(&self) -> Result<(), Error> {
+        #[cfg(feature = "db")]
+        if self.read_only && self.db_dir.is_none() {
+            return Err(Error::String("--read-only requires --db-dir".to_string()));
+        } else if self.read_only && (self.persist_mempool || self.do_compaction) {
+            return Err(Error::String(
+                "--read-only can't be used with --persist-mempool or --do-compaction".to_string(),
+            ));
+        }
         if !self.use_esplora && self.rpc_user_password.is_none() {
             Err(Error::String(
                 "When using the node you must specify --rpc-user-password".to_string(),
@@ -242,6 +443,10 @@ impl Arguments {
             Err(Error::String(
                 "Request timeout must be greater than 0".to_string(),
             ))
+        } else if self.connect_timeout_seconds == Some(0) {
+            Err(Error::String(
+                "Connect timeout must be greater than 0".to_string(),
+            ))
         } else if self.mempool_sleep_between_cycles_ms == Some(0) {
             Err(Error::String(
                 "Mempool sleep between cycles must be greater than 0".to_string(),
@@ -254,10 +459,54 @@ impl Arguments {
             Err(Error::String(
                 "Max scripts per subscription must be greater than 0".to_string(),
             ))
+        } else if self.max_outpoints_per_query == Some(0) {
+            Err(Error::String(
+                "Max outpoints per query must be greater than 0".to_string(),
+            ))
+        } else if self.max_req_per_second_per_ip == Some(0) {
+            Err(Error::String(
+                "Max requests per second per IP must be greater than 0".to_string(),
+            ))
+        } else if self.rate_limit_ip_header.is_some() && self.max_req_per_second_per_ip.is_none() {
+            Err(Error::String(
+                "--rate-limit-ip-header requires --max-req-per-second-per-ip".to_string(),
+            ))
+        } else if self.block_summary_keep_heights == Some(0) {
+            Err(Error::String(
+                "Block summary keep heights must be greater than 0".to_string(),
+            ))
+        } else if self.reorg_events_keep == Some(0) {
+            Err(Error::String(
+                "Reorg events keep must be greater than 0".to_string(),
+            ))
+        } else if self.utxo_check_interval_seconds == Some(0) {
+            Err(Error::String(
+                "UTXO check interval must be greater than 0".to_string(),
+            ))
+        } else if self.utxo_check_sample_size == Some(0) {
+            Err(Error::String(
+                "UTXO check sample size must be greater than 0".to_string(),
+            ))
+        } else if self.use_esplora && self.utxo_check_interval_seconds.is_some() {
+            Err(Error::String(
+                "The UTXO check requires the node, it can't be used with --use-esplora".to_string(),
+            ))
         } else {
             Ok(())
         }
     }
+
+    /// Whether the store is opened with `--read-only`, never without the db feature
+    fn is_read_only(&self) -> bool {
+        #[cfg(feature = "db")]
+        {
+            self.read_only
+        }
+        #[cfg(not(feature = "db"))]
+        {
+            false
+        }
+    }
 }
 
 #[cfg(test)]
@@ -280,6 +529,23 @@ mod argument_tests {
         };
         assert!(args.is_valid().is_err());
     }
+
+    #[test]
+    fn utxo_check_requires_the_node() {
+        let args = Arguments {
+            rpc_user_password: Some("user:pass".to_string()),
+            request_timeout_seconds: 30,
+            utxo_check_interval_seconds: Some(3600),
+            ..Default::default()
+        };
+        assert!(args.is_valid().is_ok());
+
+        let args = Arguments {
+            use_esplora: true,
+            ..args
+        };
+        assert!(args.is_valid().is_err());
+    }
 }
 
 impl std::str::FromStr for Network {
@@ -358,6 +624,7 @@ pub enum Error {
     WrongNetwork,
     Other,
     CannotParseHeight,
+    CannotParseTimestamp,
     InvalidTxid,
     CannotFindTx,
     InvalidBlockHash,
@@ -368,6 +635,7 @@ pub enum Error {
     CannotEncrypt,
     InvalidTx,
     InvalidOutpoint,
+    InvalidScript,
     String(String),
     InvalidDescriptor(String),
     InvalidAddress(String),
@@ -378,11 +646,18 @@ pub enum Error {
     TooManyAddresses,
     AddressPageRequiresSingleAddress,
     DescriptorMustHaveWildcard,
+    DescriptorTooComplex,
     DescriptorNotScanned,
     UtxoOnlyHistoryTooLarge,
     BodyTooLarge,
     BodyReadTimeout,
     CannotEstimateFee,
+    TooManyOutpoints,
+    TooManyRequests,
+    TooManyHeaders,
+    InvalidHistoryLimit,
+    InvalidAsset,
+    ReorgInProgress,
 }
 
 impl std::fmt::Display for Error {
@@ -393,25 +668,51 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {}
 
 #[cfg(not(feature = "db"))]
-fn get_store(_args: &Arguments) -> Result<AnyStore, Error> {
-    Ok(AnyStore::Mem(MemoryStore::new()))
+fn get_store(args: &Arguments) -> Result<AnyStore, Error> {
+    Ok(configure_store(AnyStore::Mem(memory_store(args)?), args))
+}
+
+/// A new memory store, or the one saved in `--memory-store-file` if it exists
+fn memory_store(args: &Arguments) -> Result<MemoryStore, Error> {
+    match args.memory_store_file.as_ref() {
+        Some(path) if path.exists() => {
+            log::info!("loading the memory store from {path:?}");
+            MemoryStore::load(path).map_err(|e| Error::DBOpen(format!("{e:?}")))
+        }
+        _ => Ok(MemoryStore::new()),
+    }
+}
+
+/// Save the memory store in `--memory-store-file`, if set
+fn persist_memory_store(store: &AnyStore, args: &Arguments) {
+    if let (AnyStore::Mem(store), Some(path)) = (store, args.memory_store_file.as_ref()) {
+        log::info!("saving the memory store in {path:?}");
+        if let Err(e) = store.persist(path) {
+            log::error!("cannot save the memory store in {path:?}: {e:?}");
+        }
+    }
 }
 #[cfg(feature = "db")]
 fn get_store(args: &Arguments) -> Result<AnyStore, Error> {
     use crate::store;
 
-    Ok(match args.db_dir.as_ref() {
+    let store = match args.db_dir.as_ref() {
         Some(p) => {
             let mut path = p.clone();
             path.push("db");
             path.push(args.network.to_string());
-            let db_store = store::db::DBStore::open(
-                &path,
-                args.shared_db_cache_mb,
-                args.enable_db_statistics,
-                args.reorg_data_keep_heights.unwrap_or(6),
-            )
+            let mut db_store = if args.read_only {
+                store::db::DBStore::open_read_only(&path, args.shared_db_cache_mb)
+            } else {
+                store::db::DBStore::open(
+                    &path,
+                    args.shared_db_cache_mb,
+                    args.enable_db_statistics,
+                    args.reorg_data_keep_heights.unwrap_or(6),
+                )
+            }
             .map_err(|e| Error::DBOpen(format!("{e:?}")))?;
+            db_store.set_persist_mempool(args.persist_mempool);
 
             // Perform manual compaction if requested
             if args.do_compaction {
@@ -422,8 +723,41 @@ fn get_store(args: &Arguments) -> Result<AnyStore, Error> {
 
             AnyStore::Db(db_store)
         }
-        None => AnyStore::Mem(MemoryStore::new()),
-    })
+        None => {
+            let mut memory_store = memory_store(args)?;
+            memory_store.set_reorg_data_keep_heights(args.reorg_data_keep_heights);
+            AnyStore::Mem(memory_store)
+        }
+    };
+    Ok(configure_store(store, args))
+}
+
+/// Apply the settings common to every store kind
+fn configure_store(mut store: AnyStore, args: &Arguments) -> AnyStore {
+    store.set_future_height_policy(args.future_height_policy.unwrap_or_default());
+    store.set_duplicate_entries_policy(args.duplicate_entries_policy.unwrap_or_default());
+    store.set_index_spent_heights(args.index_spent_heights);
+    store.set_block_summary_keep_heights(args.block_summary_keep_heights);
+    store
+}
+
+/// Record the network on the first use of the store, and refuse to use it for a different one
+fn check_indexed_network(store: &AnyStore, network: Network, read_only: bool) -> Result<(), Error> {
+    let network = network.to_string();
+    let indexed = store
+        .read_metadata(metadata::INDEXED_NETWORK)
+        .map_err(|e| Error::DBOpen(format!("{e:?}")))?;
+    match indexed {
+        Some(indexed) if indexed == network.as_bytes() => Ok(()),
+        Some(indexed) => Err(Error::DBOpen(format!(
+            "data indexed for network {}, cannot be used for {network}",
+            String::from_utf8_lossy(&indexed)
+        ))),
+        None if read_only => Ok(()),
+        None => store
+            .write_metadata(metadata::INDEXED_NETWORK, network.as_bytes())
+            .map_err(|e| Error::DBOpen(format!("{e:?}"))),
+    }
 }
 
 pub async fn inner_main(
@@ -433,6 +767,11 @@ pub async fn inner_main(
     log::info!("starting waterfalls with args: {:?}", args);
 
     let store = get_store(&args)?;
+    check_indexed_network(&store, args.network, args.is_read_only())?;
+    log::info!("{} blocks indexed", store.count_indexed_blocks()?);
+
+    #[cfg(feature = "profiling")]
+    crate::store::profile::set_enabled(args.enable_profiling);
 
     let key = args.server_key.clone().unwrap_or_else(Identity::generate);
 
@@ -457,9 +796,19 @@ pub async fn inner_main(
         wif_key,
         StateConfig {
             max_addresses: args.max_addresses,
+            max_descriptor_keys: args
+                .max_descriptor_keys
+                .unwrap_or(DEFAULT_MAX_DESCRIPTOR_KEYS),
             max_txs_seen: args.max_txs_seen.unwrap_or(DEFAULT_MAX_TXS_SEEN),
+            max_outpoints_per_query: args
+                .max_outpoints_per_query
+                .unwrap_or(DEFAULT_MAX_OUTPOINTS_PER_QUERY),
             cache_control_seconds: args.cache_control_seconds,
             derivation_cache_capacity: args.derivation_cache_capacity,
+            max_req_per_second_per_ip: args.max_req_per_second_per_ip,
+            rate_limit_ip_header: args.rate_limit_ip_header.clone(),
+            reject_requests_during_reorg: args.reject_requests_during_reorg,
+            reorg_events_keep: args.reorg_events_keep.unwrap_or(DEFAULT_REORG_EVENTS_KEEP),
             subscription_limits: SubscriptionLimits {
                 max_active_subscriptions: args
                     .max_active_subscriptions
@@ -486,7 +835,12 @@ pub async fn inner_main(
         last_report: Instant::now(),
     }));
 
-    let h1 = {
+    let h1 = if args.is_read_only() {
+        // the blocks are indexed by another instance, the mempool can be synced right away
+        log::info!("read-only store, blocks are not indexed");
+        let _ = initial_sync_tx.send(());
+        None
+    } else {
         let state = state.clone();
         let client: Client =
             Client::new(&args).unwrap_or_else(|e| error_panic!("Failed to create client: {e}"));
@@ -495,7 +849,7 @@ pub async fn inner_main(
             client.chain_info().await
         );
         let shutdown_rx = shutdown_tx.subscribe();
-        tokio::spawn(async move {
+        Some(tokio::spawn(async move {
             let shutdown_future = async {
                 let mut rx = shutdown_rx;
                 let _ = rx.recv().await;
@@ -507,9 +861,10 @@ pub async fn inner_main(
                 initial_sync_tx,
                 shutdown_future,
                 args.logs_rocksdb_stat_every,
+                args.index_values,
             )
             .await
-        })
+        }))
     };
 
     let h2 = {
@@ -563,6 +918,32 @@ pub async fn inner_main(
         })
     };
 
+    let h5 = args.utxo_check_interval_seconds.map(|seconds| {
+        let state = state.clone();
+        let client =
+            Client::new(&args).unwrap_or_else(|e| error_panic!("Failed to create client: {e}"));
+        let family = args.network.into();
+        let sample_size = args
+            .utxo_check_sample_size
+            .unwrap_or(DEFAULT_UTXO_CHECK_SAMPLE_SIZE);
+        let shutdown_rx = shutdown_tx.subscribe();
+        tokio::spawn(async move {
+            let shutdown_future = async {
+                let mut rx = shutdown_rx;
+                let _ = rx.recv().await;
+            };
+            utxo_check_infallible(
+                state,
+                client,
+                family,
+                Duration::from_secs(seconds),
+                sample_size,
+                shutdown_future,
+            )
+            .await
+        })
+    });
+
     let addr = args.listen.unwrap_or(SocketAddr::from((
         [127, 0, 0, 1],
         args.network.default_listen_port(),
@@ -593,7 +974,7 @@ pub async fn inner_main(
                     let header_read_timeout = args.header_read_timeout_seconds;
                     let client = &client;
 
-                    let service = service_fn(move |req| infallible_route(state, client, req, network, add_cors));
+                    let service = service_fn(move |req| infallible_route(state, client, req, peer_addr.ip(), network, add_cors));
 
                     let result = http1::Builder::new()
                         .timer(TokioTimer::new())
@@ -625,12 +1006,18 @@ pub async fn inner_main(
         }
     }
 
-    h1.await.unwrap();
+    if let Some(h1) = h1 {
+        h1.await.unwrap();
+    }
     h2.await.unwrap();
     if let Some(h3) = h3 {
         h3.await.unwrap();
     }
     h4.await.unwrap();
+    if let Some(h5) = h5 {
+        h5.await.unwrap();
+    }
+    persist_memory_store(&state.store, &args);
 
     log::info!("shutting down gracefully");
     Ok(())
@@ -718,4 +1105,24 @@ mod tests {
             assert_eq!(parsed, network);
         }
     }
+
+    #[test]
+    fn test_check_indexed_network() {
+        let store = AnyStore::Mem(MemoryStore::new());
+        check_indexed_network(&store, Network::LiquidTestnet, true).unwrap();
+        assert_eq!(
+            store.read_metadata(metadata::INDEXED_NETWORK).unwrap(),
+            None
+        );
+        check_indexed_network(&store, Network::LiquidTestnet, false).unwrap();
+        assert_eq!(
+            store.read_metadata(metadata::INDEXED_NETWORK).unwrap(),
+            Some(b"liquid-testnet".to_vec())
+        );
+        check_indexed_network(&store, Network::LiquidTestnet, true).unwrap();
+        assert!(matches!(
+            check_indexed_network(&store, Network::Liquid, false),
+            Err(Error::DBOpen(_))
+        ));
+    }
 }
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::header::{HeaderMap, HeaderName};
+
+const WINDOW: Duration = Duration::from_secs(1);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the number of requests per second coming from the same IP address.
+///
+/// Uses a sliding window counter: the count of the previous window is weighted by how much of it
+/// still overlaps the last second, which smooths bursts at window boundaries without keeping a
+/// timestamp per request.
+///
+/// IPv6 clients are limited by their /64 prefix, the smallest block usually assigned to a host,
+/// since a single host can otherwise send every request from a different address.
+///
+/// Addresses idle for more than two windows are periodically forgotten to bound memory usage.
+///
+/// Behind a reverse proxy every connection comes from the proxy address, so the client address is
+/// read from `ip_header` when configured: the last address of the header, the one appended by the
+/// proxy, is used.
+pub struct IpRateLimiter {
+    max_req_per_second: u32,
+    ip_header: Option<HeaderName>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    windows: HashMap<IpAddr, SlidingWindow>,
+    last_cleanup: Instant,
+}
+
+struct SlidingWindow {
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl SlidingWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    /// Move the window forward so that it contains `now`
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= WINDOW * 2 {
+            *self = SlidingWindow::new(now);
+        } else if elapsed >= WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.start += WINDOW;
+        }
+    }
+
+    fn estimate(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        let previous_weight = 1.0 - (elapsed / WINDOW.as_secs_f64()).min(1.0);
+        self.previous as f64 * previous_weight + self.current as f64
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) >= WINDOW * 2
+    }
+}
+
+impl IpRateLimiter {
+    pub fn new(max_req_per_second: u32, ip_header: Option<HeaderName>) -> Self {
+        Self {
+            max_req_per_second,
+            ip_header,
+            inner: Mutex::new(Inner {
+                windows: HashMap::new(),
+                last_cleanup: Instant::now(),
+            }),
+        }
+    }
+
+    /// Record a request, returns false if it exceeds the limit and must be rejected
+    pub fn check(&self, headers: &HeaderMap, peer_ip: IpAddr) -> bool {
+        self.check_at(self.client_ip(headers, peer_ip), Instant::now())
+    }
+
+    /// The address of the client, `peer_ip` if the trusted header is not configured or missing
+    fn client_ip(&self, headers: &HeaderMap, peer_ip: IpAddr) -> IpAddr {
+        self.ip_header
+            .as_ref()
+            .and_then(|name| headers.get_all(name).iter().last())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(peer_ip)
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let ip = rate_limit_key(ip);
+        let mut inner = self.inner.lock().unwrap();
+        if now.saturating_duration_since(inner.last_cleanup) >= CLEANUP_INTERVAL {
+            inner.windows.retain(|_, w| !w.is_idle(now));
+            inner.last_cleanup = now;
+        }
+        let window = inner
+            .windows
+            .entry(ip)
+            .or_insert_with(|| SlidingWindow::new(now));
+        window.advance(now);
+        if window.estimate(now) >= self.max_req_per_second as f64 {
+            return false;
+        }
+        window.current += 1;
+        true
+    }
+
+    #[cfg(test)]
+    fn tracked_ips(&self) -> usize {
+        self.inner.lock().unwrap().windows.len()
+    }
+}
+
+/// The address counting the requests of `ip`: IPv6 addresses are truncated to their /64 prefix,
+/// IPv4-mapped ones are counted as the IPv4 address
+fn rate_limit_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(ip),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_per_ip() {
+        let limiter = IpRateLimiter::new(3, None);
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(a, now));
+        }
+        assert!(!limiter.check_at(a, now));
+        assert!(limiter.check_at(b, now), "other ips are not affected");
+
+        // half of the previous window still counts: 1.5 + 2 requests exceed the limit
+        let half = now + WINDOW + WINDOW / 2;
+        assert!(limiter.check_at(a, half));
+        assert!(limiter.check_at(a, half));
+        assert!(!limiter.check_at(a, half));
+
+        // after two windows the ip starts fresh
+        let later = now + WINDOW * 3;
+        for _ in 0..3 {
+            assert!(limiter.check_at(a, later));
+        }
+        assert!(!limiter.check_at(a, later));
+    }
+
+    #[test]
+    fn test_rate_limit_ipv6_prefix() {
+        let limiter = IpRateLimiter::new(3, None);
+        let now = Instant::now();
+        let same_prefix = |i: u16| IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, i));
+
+        for i in 0..3 {
+            assert!(limiter.check_at(same_prefix(i), now));
+        }
+        assert!(
+            !limiter.check_at(same_prefix(3), now),
+            "addresses of the same /64 share the limit"
+        );
+        let other_prefix = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 0));
+        assert!(limiter.check_at(other_prefix, now));
+        assert_eq!(limiter.tracked_ips(), 2);
+
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(rate_limit_key(mapped), IpAddr::from([10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_rate_limit_cleanup() {
+        let limiter = IpRateLimiter::new(3, None);
+        let now = Instant::now();
+        for i in 0..10u8 {
+            limiter.check_at([10, 0, 0, i].into(), now);
+        }
+        assert_eq!(limiter.tracked_ips(), 10);
+
+        let later = now + CLEANUP_INTERVAL;
+        limiter.check_at([10, 0, 0, 0].into(), later);
+        assert_eq!(limiter.tracked_ips(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_ip_header() {
+        let peer: IpAddr = [10, 0, 0, 1].into();
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append("x-forwarded-for", "3.3.3.3".parse().unwrap());
+
+        let limiter = IpRateLimiter::new(3, None);
+        assert_eq!(limiter.client_ip(&headers, peer), peer);
+
+        let limiter = IpRateLimiter::new(3, Some(HeaderName::from_static("x-forwarded-for")));
+        let client: IpAddr = [3, 3, 3, 3].into();
+        assert_eq!(limiter.client_ip(&headers, peer), client);
+        assert_eq!(limiter.client_ip(&HeaderMap::new(), peer), peer);
+
+        let mut invalid = HeaderMap::new();
+        invalid.insert("x-forwarded-for", "1.1.1.1, unknown".parse().unwrap());
+        assert_eq!(limiter.client_ip(&invalid, peer), peer);
+
+        for _ in 0..3 {
+            assert!(limiter.check(&headers, peer));
+        }
+        assert!(!limiter.check(&headers, peer));
+        assert!(
+            limiter.check(&HeaderMap::new(), peer),
+            "other clients are not affected"
+        );
+    }
+}
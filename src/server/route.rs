@@ -2,14 +2,17 @@ use crate::{
     be,
     fetch::Client,
     server::{derivation_cache::DerivationCache, sign::sign_response, Error, State},
-    store::Store,
-    AddressesRequest, DescriptorRequest, Family, LastUsedIndexResponse, TxSeen, WaterfallRequest,
-    WaterfallResponse, V,
+    store::{Balance, HistoryPage, Store},
+    AddressesRequest, DescriptorRequest, Family, Height, LastUsedIndexResponse, Timestamp, TxSeen,
+    WaterfallRequest, WaterfallResponse, V,
 };
 use age::x25519::Identity;
 use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
 use elements::BlockHash;
-use futures_util::{stream, StreamExt};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, Limited, StreamBody};
 use hyper::{
     body::{Bytes, Frame, Incoming},
@@ -22,16 +25,17 @@ use std::{
     collections::{BTreeMap, HashSet},
     convert::Infallible,
     hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
     str::FromStr,
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 
 use super::{
     encryption,
     sign::MsgSigAddress,
-    subscription::{SubscriptionEvent, SubscriptionId, SubscriptionReceiver},
+    subscription::{BlockEvent, SubscriptionEvent, SubscriptionId, SubscriptionReceiver},
     Network,
 };
 
@@ -52,8 +56,10 @@ const MAX_BATCH: u32 = 50;
 const MAX_ADDRESSES: u32 = GAP_LIMIT * MAX_BATCH;
 const MAX_ADDRESS_LENGTH: usize = 100; // max characters for an address (excessive to be conservative)
 const MAX_TX_BODY_SIZE: usize = 1024 * 1024; // 1MB limit for transaction broadcast body
+const MAX_QUERY_BODY_SIZE: usize = 64 * 1024; // limit for url-encoded parameters sent in the body
 const BODY_READ_TIMEOUT: Duration = Duration::from_secs(30); // timeout for reading request body
 const FEE_ESTIMATES_TTL: u32 = 30; // cache fee estimates for 30 seconds
+const MAX_HEADERS: u32 = 100; // max headers returned by a single /headers request, the uncached ones are fetched one by one from the node
 
 type RespBody = BoxBody<Bytes, Infallible>;
 type Resp = Response<RespBody>;
@@ -84,6 +90,7 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::No, false, network).await
@@ -94,6 +101,7 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::Hash, false, network).await
@@ -107,6 +115,7 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::No, true, network).await
@@ -117,6 +126,7 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::Hash, true, network).await
@@ -130,6 +140,7 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::All, false, network).await
@@ -140,20 +151,44 @@ pub async fn route(
                 &state.key,
                 is_testnet_or_regtest,
                 state.max_addresses,
+                state.max_descriptor_keys,
                 network,
             )?;
             handle_waterfalls_req(state, inputs, WithTip::All, true, network).await
         }
         (&Method::GET, "/v1/last_used_index", Some(query)) => {
-            let descriptor =
-                parse_descriptor_query(query, &state.key, is_testnet_or_regtest, network)?;
+            let descriptor = parse_descriptor_query(
+                query,
+                &state.key,
+                is_testnet_or_regtest,
+                state.max_descriptor_keys,
+                network,
+            )?;
             handle_last_used_index(state, descriptor).await
         }
         (&Method::GET, "/v1/subscribe", Some(query)) => {
-            let descriptor =
-                parse_descriptor_query(query, &state.key, is_testnet_or_regtest, network)?;
+            let descriptor = parse_descriptor_query(
+                query,
+                &state.key,
+                is_testnet_or_regtest,
+                state.max_descriptor_keys,
+                network,
+            )?;
             handle_subscribe_req(state, descriptor).await
         }
+        (&Method::GET, "/v1/stream", Some(query)) => {
+            let request = parse_query(
+                query,
+                &state.key,
+                is_testnet_or_regtest,
+                state.max_addresses,
+                state.max_descriptor_keys,
+                network,
+            )?;
+            handle_stream_req(state, request).await
+        }
+        (&Method::GET, "/events/blocks", None) => block_events_resp(state.subscribe_block_events()),
+        (&Method::GET, "/reorgs", None) => reorgs_resp(state.recent_reorgs().await),
         (&Method::GET, "/v1/time_since_last_block", None) => {
             // this method return the seconds since last block
             // and a static string for a simple freshness check,
@@ -203,10 +238,56 @@ pub async fn route(
                 None,
             )
         }
+        (&Method::GET, "/headers", Some(query)) => {
+            let (start, count) = parse_headers_query(query)?;
+            handle_headers(state, client, start, count, network.into()).await
+        }
         (&Method::GET, "/blocks/tip/hash", None) => {
             let block_hash = state.tip_hash().await;
             block_hash_resp(block_hash)
         }
+        (&Method::POST, "/v1/balance", None) => {
+            let whole_body = tokio::time::timeout(
+                BODY_READ_TIMEOUT,
+                Limited::new(req.into_body(), MAX_QUERY_BODY_SIZE).collect(),
+            )
+            .await
+            .map_err(|_| Error::BodyReadTimeout)?
+            .map_err(|_| Error::BodyTooLarge)?
+            .to_bytes();
+            let query =
+                std::str::from_utf8(&whole_body).map_err(|e| Error::String(e.to_string()))?;
+            let inputs = parse_query(
+                query,
+                &state.key,
+                is_testnet_or_regtest,
+                state.max_addresses,
+                state.max_descriptor_keys,
+                network,
+            )?;
+            handle_balance_req(state, inputs).await
+        }
+        (&Method::POST, "/v1/utxos", None) => {
+            let whole_body = tokio::time::timeout(
+                BODY_READ_TIMEOUT,
+                Limited::new(req.into_body(), MAX_QUERY_BODY_SIZE).collect(),
+            )
+            .await
+            .map_err(|_| Error::BodyReadTimeout)?
+            .map_err(|_| Error::BodyTooLarge)?
+            .to_bytes();
+            let query =
+                std::str::from_utf8(&whole_body).map_err(|e| Error::String(e.to_string()))?;
+            let inputs = parse_query(
+                query,
+                &state.key,
+                is_testnet_or_regtest,
+                state.max_addresses,
+                state.max_descriptor_keys,
+                network,
+            )?;
+            handle_utxos_req(state, inputs).await
+        }
         (&Method::POST, "/tx", None) => {
             let whole_body = tokio::time::timeout(
                 BODY_READ_TIMEOUT,
@@ -229,10 +310,28 @@ pub async fn route(
                 }
             }
         }
+        #[cfg(feature = "profiling")]
+        (&Method::GET, "/debug/profile", None) => {
+            let buffer =
+                crate::store::profile::encode().map_err(|e| Error::String(e.to_string()))?;
+            any_resp(buffer, StatusCode::OK, Some("text/plain"), None, None)
+        }
+        (&Method::GET, "/stats", None) => {
+            let stats = state.with_store(|db| db.stats()).await;
+            let json = serde_json::to_string(&stats).map_err(|e| Error::String(e.to_string()))?;
+            any_resp(
+                json.into_bytes(),
+                StatusCode::OK,
+                Some("application/json"),
+                Some(state.cache_control_seconds),
+                None,
+            )
+        }
         (&Method::GET, "/metrics", None) => {
             let encoder = prometheus::TextEncoder::new();
 
             state.update_descriptor_max_used_index_metrics().await;
+            crate::set_store_stats(&state.with_store(|db| db.stats()).await);
             let metric_families = prometheus::gather();
             let mut buffer = vec![];
             encoder
@@ -246,7 +345,7 @@ pub async fn route(
                 None,
             )
         }
-        (&Method::GET, path, None) => {
+        (&Method::GET, path, query) => {
             let mut s = path.split('/');
             match (s.next(), s.next(), s.next(), s.next(), s.next()) {
                 (Some(""), Some("block-height"), Some(v), None, None) => {
@@ -254,11 +353,48 @@ pub async fn route(
                     let block_hash = state.block_hash(height).await;
                     block_hash_resp(block_hash)
                 }
+                (Some(""), Some("blocks"), Some("timestamp"), Some(v), None) => {
+                    let timestamp: u32 = v.parse().map_err(|_| Error::CannotParseTimestamp)?;
+                    match state.height_for_timestamp(timestamp).await {
+                        Some(height) => str_resp(height.to_string(), StatusCode::OK),
+                        None => str_resp("cannot find it".to_string(), StatusCode::NOT_FOUND),
+                    }
+                }
                 //address/ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh/txs
                 (Some(""), Some("address"), Some(addr), Some("txs"), None) => {
                     let addr = be::Address::from_str(addr, network)?;
+                    let (page, confirmations) = parse_address_txs_query(query, state.max_txs_seen)?;
 
-                    handle_single_address(state, &addr).await
+                    handle_single_address(state, &addr, page, confirmations).await
+                }
+                (Some(""), Some("asset"), Some(asset), Some("history"), None) => {
+                    let asset =
+                        elements::AssetId::from_str(asset).map_err(|_| Error::InvalidAsset)?;
+                    let (page, _) = parse_address_txs_query(query, state.max_txs_seen)?;
+                    handle_asset_history(state, asset, page).await
+                }
+                (Some(""), Some("address"), Some(addr), Some("history.ndjson"), None) => {
+                    let addr = be::Address::from_str(addr, network)?;
+                    let script_hash = state.store.hash(addr.script_pubkey().as_bytes());
+                    // the DB reads the whole value of the script before streaming it
+                    let history = state
+                        .with_store(move |db| db.stream_history(script_hash))
+                        .await;
+                    let mut resp = history_ndjson_resp(history)?;
+                    insert_history_start_header(&mut resp, state, 0)?;
+                    Ok(resp)
+                }
+                (Some(""), Some("script"), Some(script), Some("utxos"), None) => {
+                    // note this method only considers confirmed utxos
+                    // script is the hex of the script pubkey, script hashes are salted per node
+                    let script = hex_simd::decode_to_vec(script.as_bytes())
+                        .map_err(|_| Error::InvalidScript)?;
+                    handle_script_utxos(state, &script).await
+                }
+                (Some(""), Some("script"), Some(script), Some("subscribe"), None) => {
+                    let script = hex_simd::decode_to_vec(script.as_bytes())
+                        .map_err(|_| Error::InvalidScript)?;
+                    handle_script_subscribe(state, &script).await
                 }
 
                 (Some(""), Some("tx"), Some(v), Some("raw"), None) => {
@@ -298,6 +434,22 @@ pub async fn route(
                         None,
                     )
                 }
+                (Some(""), Some("block"), Some(v), Some("coinbase"), None) => {
+                    let height: u32 = v.parse().map_err(|_| Error::CannotParseHeight)?;
+                    let txids = state
+                        .with_store(move |db| db.get_coinbase_txids_at_height(height))
+                        .await
+                        .map_err(|e| Error::String(e.to_string()))?;
+                    let result =
+                        serde_json::to_string(&txids).map_err(|e| Error::String(e.to_string()))?;
+                    any_resp(
+                        result.into_bytes(),
+                        StatusCode::OK,
+                        Some("application/json"),
+                        Some(state.cache_control_seconds),
+                        None,
+                    )
+                }
                 (Some(""), Some("v1"), Some("unspent"), Some(outpoint), None) => {
                     // note this method only considers confirmed utxos
                     // outpoint is of the form txid:vout
@@ -397,10 +549,13 @@ fn parse_query(
     key: &Identity,
     is_testnet_or_regtest: bool,
     max_addresses: usize,
+    max_descriptor_keys: usize,
     network: Network,
 ) -> Result<WaterfallRequest, Error> {
     let mut page = 0u16;
     let mut to_index = 0u32;
+    let mut from_height = 0;
+    let mut tip_hash = None;
     let mut utxo_only = false;
     let mut descriptor = None;
     let mut addresses = None;
@@ -409,6 +564,10 @@ fn parse_query(
         match key.as_ref() {
             "page" => page = value.parse().unwrap_or(0),
             "to_index" => to_index = value.parse().unwrap_or(0),
+            "from_height" => from_height = value.parse().unwrap_or(0),
+            "tip_hash" => {
+                tip_hash = Some(BlockHash::from_str(&value).map_err(|_| Error::InvalidBlockHash)?)
+            }
             "utxo_only" => utxo_only = value.parse().unwrap_or(false),
             "descriptor" => descriptor = Some(value.into_owned()),
             "addresses" => addresses = Some(value.into_owned()),
@@ -430,10 +589,15 @@ fn parse_query(
             if is_testnet_or_regtest == descriptor.is_mainnet() {
                 return Err(Error::WrongNetwork);
             }
+            if descriptor.keys_per_index() > max_descriptor_keys {
+                return Err(Error::DescriptorTooComplex);
+            }
             Ok(WaterfallRequest::Descriptor(DescriptorRequest {
                 descriptor,
                 page,
                 to_index,
+                from_height,
+                tip_hash,
                 utxo_only,
             }))
         }
@@ -459,6 +623,8 @@ fn parse_query(
             Ok(WaterfallRequest::Addresses(AddressesRequest {
                 addresses,
                 page,
+                from_height,
+                tip_hash,
                 utxo_only,
             }))
         }
@@ -471,6 +637,7 @@ fn parse_descriptor_query(
     query: &str,
     key: &Identity,
     is_testnet_or_regtest: bool,
+    max_descriptor_keys: usize,
     network: Network,
 ) -> Result<be::Descriptor, Error> {
     let mut descriptor = None;
@@ -498,6 +665,10 @@ fn parse_descriptor_query(
         return Err(Error::WrongNetwork);
     }
 
+    if descriptor.keys_per_index() > max_descriptor_keys {
+        return Err(Error::DescriptorTooComplex);
+    }
+
     Ok(descriptor)
 }
 
@@ -540,21 +711,26 @@ fn full_body(bytes: Vec<u8>) -> RespBody {
 }
 
 fn error_resp(status: StatusCode, error: &Error) -> Resp {
-    Response::builder()
-        .status(status)
+    let mut builder = Response::builder().status(status);
+    if matches!(error, Error::TooManyRequests | Error::ReorgInProgress) {
+        builder = builder.header(header::RETRY_AFTER, "1");
+    }
+    builder
         .body(full_body(error.to_string().into_bytes()))
         .unwrap()
 }
 
 fn error_status(error: &Error) -> StatusCode {
     match error {
-        Error::CannotDecrypt => StatusCode::UNPROCESSABLE_ENTITY,
-        Error::WrongNetwork
+        Error::CannotDecrypt
+        | Error::WrongNetwork
         | Error::CannotParseHeight
+        | Error::CannotParseTimestamp
         | Error::InvalidTxid
         | Error::InvalidBlockHash
         | Error::InvalidTx
         | Error::InvalidOutpoint
+        | Error::InvalidScript
         | Error::InvalidDescriptor(_)
         | Error::InvalidAddress(_)
         | Error::CannotSpecifyBothDescriptorAndAddresses
@@ -562,16 +738,126 @@ fn error_status(error: &Error) -> StatusCode {
         | Error::AddressCannotBeBlinded
         | Error::TooManyAddresses
         | Error::DescriptorMustHaveWildcard
+        | Error::DescriptorTooComplex
         | Error::AddressPageRequiresSingleAddress
         | Error::UtxoOnlyHistoryTooLarge
+        | Error::TooManyOutpoints
+        | Error::TooManyHeaders
+        | Error::InvalidHistoryLimit
+        | Error::InvalidAsset
         | Error::DescriptorNotScanned => StatusCode::BAD_REQUEST,
         Error::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
         Error::BodyReadTimeout => StatusCode::REQUEST_TIMEOUT,
+        Error::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        Error::ReorgInProgress => StatusCode::SERVICE_UNAVAILABLE,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
-async fn handle_single_address(state: &Arc<State>, address: &be::Address) -> Result<Resp, Error> {
+/// Parse the `start` height and the optional `count` of the /headers endpoint, count defaults to
+/// the maximum
+fn parse_headers_query(query: &str) -> Result<(u32, u32), Error> {
+    let mut start = None;
+    let mut count = MAX_HEADERS;
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "start" => start = Some(value.parse().map_err(|_| Error::CannotParseHeight)?),
+            "count" => count = value.parse().map_err(|_| Error::TooManyHeaders)?,
+            _ => {}
+        }
+    }
+    if count == 0 || count > MAX_HEADERS {
+        return Err(Error::TooManyHeaders);
+    }
+    Ok((start.ok_or(Error::CannotParseHeight)?, count))
+}
+
+/// Parse the optional `from_height`, `limit` and `confirmations` of `/address/:address/txs`, the
+/// limit defaults to and can't exceed `max_txs_seen`. Also used by `/asset/:asset_id/history`,
+/// which ignores `confirmations`.
+fn parse_address_txs_query(
+    query: Option<&str>,
+    max_txs_seen: usize,
+) -> Result<(HistoryPage, bool), Error> {
+    let mut page = HistoryPage {
+        from_height: None,
+        limit: Some(max_txs_seen),
+    };
+    let mut confirmations = false;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "from_height" => {
+                page.from_height = Some(value.parse().map_err(|_| Error::CannotParseHeight)?)
+            }
+            "limit" => {
+                let limit: usize = value.parse().map_err(|_| Error::InvalidHistoryLimit)?;
+                if limit == 0 || limit > max_txs_seen {
+                    return Err(Error::InvalidHistoryLimit);
+                }
+                page.limit = Some(limit);
+            }
+            "confirmations" => confirmations = value == "true",
+            _ => {}
+        }
+    }
+    Ok((page, confirmations))
+}
+
+/// Return the consensus serialized headers of `count` blocks starting at height `start`,
+/// concatenated. Less headers are returned if the range goes beyond the tip.
+async fn handle_headers(
+    state: &Arc<State>,
+    client: &Arc<Mutex<Client>>,
+    start: u32,
+    count: u32,
+    family: Family,
+) -> Result<Resp, Error> {
+    let hashes: Vec<BlockHash> = state
+        .blocks_hash_ts
+        .lock()
+        .await
+        .iter()
+        .skip(start as usize)
+        .take(count as usize)
+        .map(|(hash, _)| *hash)
+        .collect();
+
+    let mut result = vec![];
+    for hash in hashes {
+        let cached = state.header_cache.lock().await.get(&hash).cloned();
+        crate::cache_counter("header_cache", cached.is_some());
+        let bytes = match cached {
+            Some(bytes) => bytes,
+            None => {
+                let header = client
+                    .lock()
+                    .await
+                    .block_header(hash, family)
+                    .await
+                    .map_err(|_| Error::CannotFindBlockHeader)?;
+                let bytes = header.serialize();
+                state.header_cache.lock().await.push(hash, bytes.clone());
+                bytes
+            }
+        };
+        result.extend(bytes);
+    }
+
+    any_resp(
+        result,
+        StatusCode::OK,
+        Some("application/octet-stream"),
+        Some(state.cache_control_seconds),
+        None,
+    )
+}
+
+async fn handle_single_address(
+    state: &Arc<State>,
+    address: &be::Address,
+    page: HistoryPage,
+    with_confirmations: bool,
+) -> Result<Resp, Error> {
     #[derive(Serialize)]
     struct EsploraTx {
         txid: crate::be::Txid,
@@ -582,38 +868,58 @@ async fn handle_single_address(state: &Arc<State>, address: &be::Address) -> Res
     struct Status {
         block_height: Option<i32>,
         block_hash: Option<BlockHash>,
+        /// Only if requested, 0 for mempool transactions
+        #[serde(skip_serializing_if = "Option::is_none")]
+        confirmations: Option<u32>,
     }
 
-    let db = &state.store;
     let script_pubkey = address.script_pubkey();
 
-    let script_hash = [db.hash(script_pubkey.as_bytes())];
-    let mut seen_blockchain = db.get_history(&script_hash).unwrap();
-    // TODO add pagination for `/address/:address/txs`; for now we only return the first capped page.
-    truncate_history_page(&mut seen_blockchain, 0, state.max_txs_seen);
+    let script_hash = [state.store.hash(script_pubkey.as_bytes())];
+    let script = script_hash[0];
+    let (seen_blockchain, next_height, confirmations) = state
+        .with_store(move |db| -> anyhow::Result<_> {
+            let (seen_blockchain, next_height) = db.get_history_page(script, page)?;
+            let confirmations = if with_confirmations {
+                db.get_confirmations(&seen_blockchain)?
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            } else {
+                vec![None; seen_blockchain.len()]
+            };
+            Ok((seen_blockchain, next_height, confirmations))
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?;
     let mut result: Vec<_> = seen_blockchain
-        .remove(0)
         .iter()
-        .map(|e| EsploraTx {
+        .zip(confirmations)
+        .map(|(e, confirmations)| EsploraTx {
             txid: e.txid,
             status: Status {
                 block_height: Some(e.height as i32),
                 block_hash: None,
+                confirmations,
             },
         })
         .collect();
 
+    // like in esplora, mempool transactions are returned only with the first page
     let mut seen_mempool = vec![Vec::new()];
-    state
-        .mempool
-        .lock()
-        .await
-        .append_seen(&script_hash, &mut seen_mempool);
+    if page.from_height.is_none() {
+        state
+            .mempool
+            .lock()
+            .await
+            .append_seen(&script_hash, &mut seen_mempool);
+    }
     result.extend(seen_mempool[0].iter().map(|tx_seen| EsploraTx {
         txid: tx_seen.txid,
         status: Status {
             block_height: Some(-1),
             block_hash: None,
+            confirmations: with_confirmations.then_some(0),
         },
     }));
 
@@ -630,13 +936,97 @@ async fn handle_single_address(state: &Arc<State>, address: &be::Address) -> Res
         }
     }
 
-    any_resp(
+    let mut resp = any_resp(
         serde_json::to_vec(&result).unwrap(),
         hyper::StatusCode::OK,
         Some("application/json"),
         Some(state.cache_control_seconds),
         None,
-    )
+    )?;
+    if let Some(next_height) = next_height {
+        let limit = page.limit.unwrap_or(state.max_txs_seen);
+        insert_next_page_link(&mut resp, "txs", next_height, limit)?;
+    }
+    insert_history_start_header(&mut resp, state, page.from_height.unwrap_or(0))?;
+    Ok(resp)
+}
+
+/// Add the `Link` header of the page starting at `next_height` of the paginated `endpoint`
+fn insert_next_page_link(
+    resp: &mut Resp,
+    endpoint: &str,
+    next_height: u32,
+    limit: usize,
+) -> Result<(), Error> {
+    // relative to the request path, so that it works behind a proxy adding a prefix
+    let link = format!("<{endpoint}?from_height={next_height}&limit={limit}>; rel=\"next\"");
+    resp.headers_mut()
+        .insert(header::LINK, link.parse().map_err(|_| Error::Other)?);
+    Ok(())
+}
+
+/// The height the indexed history starts at, if the history requested from `from_height` is
+/// incomplete because the store has been imported from a UTXO snapshot
+fn missing_history_start(state: &State, from_height: Height) -> Option<Height> {
+    state
+        .history_start_height
+        .filter(|start| from_height < *start)
+}
+
+/// Add the `X-History-Start-Height` header if the history requested from `from_height` is
+/// incomplete, see [`missing_history_start`]
+fn insert_history_start_header(
+    resp: &mut Resp,
+    state: &State,
+    from_height: Height,
+) -> Result<(), Error> {
+    if let Some(start) = missing_history_start(state, from_height) {
+        resp.headers_mut().insert(
+            "X-History-Start-Height",
+            start.to_string().parse().map_err(|_| Error::Other)?,
+        );
+    }
+    Ok(())
+}
+
+/// The transactions creating or spending outputs with the explicit asset `asset`, with the value
+/// of the output or of the input when explicit. Indexed only with `--index-values`.
+async fn handle_asset_history(
+    state: &Arc<State>,
+    asset: elements::AssetId,
+    page: HistoryPage,
+) -> Result<Resp, Error> {
+    let (mut entries, next_height) = state
+        .with_store(move |db| -> anyhow::Result<_> {
+            let (entries, next_height) = db.get_asset_history_page(asset, page)?;
+            Ok((db.add_values(entries)?, next_height))
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?;
+    {
+        let blocks_hash_ts = state.blocks_hash_ts.lock().await;
+        for entry in entries.iter_mut() {
+            let tx_seen = &mut entry.tx_seen;
+            if let Some((hash, ts)) = blocks_hash_ts.get(tx_seen.height as usize) {
+                tx_seen.block_hash = Some(*hash);
+                tx_seen.block_timestamp = Some(*ts);
+            }
+        }
+    }
+
+    let mut resp = any_resp(
+        serde_json::to_vec(&entries).unwrap(),
+        hyper::StatusCode::OK,
+        Some("application/json"),
+        Some(state.cache_control_seconds),
+        None,
+    )?;
+    if let Some(next_height) = next_height {
+        let limit = page.limit.unwrap_or(state.max_txs_seen);
+        insert_next_page_link(&mut resp, "history", next_height, limit)?;
+    }
+    insert_history_start_header(&mut resp, state, page.from_height.unwrap_or(0))?;
+    Ok(resp)
 }
 
 enum WithTip {
@@ -647,7 +1037,7 @@ enum WithTip {
 
 async fn handle_waterfalls_req(
     state: &Arc<State>,
-    inputs: WaterfallRequest,
+    mut inputs: WaterfallRequest,
     with_tip: WithTip,
     cbor: bool,
     network: Network,
@@ -667,17 +1057,44 @@ async fn handle_waterfalls_req(
     let utxo_only_req;
     let id;
 
+    let (reorged, request_from_height) = match &mut inputs {
+        WaterfallRequest::Descriptor(DescriptorRequest {
+            from_height,
+            tip_hash,
+            ..
+        })
+        | WaterfallRequest::Addresses(AddressesRequest {
+            from_height,
+            tip_hash,
+            ..
+        }) => {
+            let reorged = match tip_hash {
+                Some(tip_hash) if *from_height > 0 => {
+                    let blocks_hash_ts = state.blocks_hash_ts.lock().await;
+                    !is_in_chain(&blocks_hash_ts, *from_height, tip_hash)
+                }
+                _ => false,
+            };
+            if reorged {
+                // the client history above the fork is stale, it gets the whole history
+                *from_height = 0;
+            }
+            (reorged, *from_height)
+        }
+    };
+
     match inputs {
         WaterfallRequest::Descriptor(DescriptorRequest {
             descriptor,
             page,
             to_index,
+            from_height,
             utxo_only,
         }) => {
             id = string_hash(&descriptor.normalized_id_string());
             state.record_descriptor_access(id).await;
-            if page != 0 || to_index != 0 || utxo_only {
-                log::info!("{id:x}: page={page}, to_index={to_index}, utxo_only={utxo_only}");
+            if page != 0 || to_index != 0 || from_height != 0 || utxo_only {
+                log::info!("{id:x}: page={page}, to_index={to_index}, from_height={from_height}, utxo_only={utxo_only}");
             }
             utxo_only_req = utxo_only;
             for desc in descriptor.into_single_descriptors().unwrap().iter() {
@@ -690,7 +1107,8 @@ async fn handle_waterfalls_req(
                         derive_script_hashes_batch(state, desc, batch_start, GAP_LIMIT).await;
                     derivations_duration += batch_derivations_duration;
 
-                    let find_result = find_scripts(state, db, &mut result, scripts, 0, true).await;
+                    let find_result =
+                        find_scripts(state, &mut result, scripts, 0, from_height, true).await;
                     let max_used_index = find_result
                         .max_used_offset
                         .map(|offset| batch_start + offset);
@@ -721,7 +1139,7 @@ async fn handle_waterfalls_req(
                     }
                 }
                 if utxo_only {
-                    filter_utxo_only(&mut result, db)?;
+                    result = filter_utxo_only_with_store(state, result).await?;
                 }
                 map.insert(desc.to_string(), result);
             }
@@ -729,6 +1147,7 @@ async fn handle_waterfalls_req(
         WaterfallRequest::Addresses(AddressesRequest {
             addresses,
             page,
+            from_height,
             utxo_only,
         }) => {
             id = string_hash(&format!("{:?}", addresses));
@@ -747,13 +1166,20 @@ async fn handle_waterfalls_req(
                 0
             };
             let append_mempool = page == 0;
-            let find_result =
-                find_scripts(state, db, &mut result, scripts, page, append_mempool).await;
+            let find_result = find_scripts(
+                state,
+                &mut result,
+                scripts,
+                page,
+                from_height,
+                append_mempool,
+            )
+            .await;
             if utxo_only && find_result.has_more.iter().any(|has_more| *has_more) {
                 return Err(Error::UtxoOnlyHistoryTooLarge);
             }
             if utxo_only {
-                filter_utxo_only(&mut result, db)?;
+                result = filter_utxo_only_with_store(state, result).await?;
             }
             for (addr, has_more_for_addr) in addresses.iter().zip(find_result.has_more.iter()) {
                 if *has_more_for_addr {
@@ -789,6 +1215,7 @@ async fn handle_waterfalls_req(
     }
 
     let elements: usize = map.values().map(|v| v.len()).sum();
+    crate::WATERFALLS_SCRIPTS_HISTOGRAM.observe(elements as f64);
 
     let (tip_hash, tip_meta) = match with_tip {
         WithTip::No => (None, None),
@@ -809,6 +1236,8 @@ async fn handle_waterfalls_req(
         } else {
             Some(has_more)
         },
+        reorged: reorged.then_some(true),
+        history_start_height: missing_history_start(state, request_from_height),
     };
     let content = if cbor {
         "application/cbor"
@@ -844,6 +1273,142 @@ async fn handle_waterfalls_req(
     )
 }
 
+/// Whether `hash` is the hash of the block at `height` in the chain described by `blocks_hash_ts`
+fn is_in_chain(
+    blocks_hash_ts: &[(BlockHash, Timestamp)],
+    height: Height,
+    hash: &BlockHash,
+) -> bool {
+    blocks_hash_ts
+        .get(height as usize)
+        .is_some_and(|(h, _)| h == hash)
+}
+
+/// Handle the balance endpoint request
+///
+/// Scripts of a descriptor are scanned like in the waterfalls endpoint, until a batch without
+/// history, and the balance of each of them is returned in derivation order.
+async fn handle_balance_req(state: &Arc<State>, inputs: WaterfallRequest) -> Result<Resp, Error> {
+    #[derive(Serialize)]
+    struct BalanceResponse {
+        balances: BTreeMap<String, Vec<Balance>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tip: Option<BlockHash>,
+    }
+
+    let scripts = request_scripts(state, inputs).await?;
+    let balances = state
+        .with_store(move |db| {
+            scripts
+                .into_iter()
+                .map(|(key, scripts)| Ok((key, db.get_balance(&scripts)?)))
+                .collect::<anyhow::Result<BTreeMap<_, _>>>()
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?;
+
+    let response = BalanceResponse {
+        balances,
+        tip: state.tip_hash().await,
+    };
+    let result = serde_json::to_vec(&response).expect("serialization cannot fail");
+    any_resp(
+        result,
+        StatusCode::OK,
+        Some("application/json"),
+        Some(state.cache_control_seconds),
+        None,
+    )
+}
+
+/// Handle the utxos endpoint request
+///
+/// Scripts are scanned like in the balance endpoint and the confirmed unspent outputs of each of
+/// them are returned in derivation order.
+async fn handle_utxos_req(state: &Arc<State>, inputs: WaterfallRequest) -> Result<Resp, Error> {
+    #[derive(Serialize)]
+    struct UtxosResponse {
+        utxos: BTreeMap<String, Vec<Vec<String>>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tip: Option<BlockHash>,
+    }
+
+    let scripts = request_scripts(state, inputs).await?;
+    let utxos = state
+        .with_store(move |db| {
+            scripts
+                .into_iter()
+                .map(|(key, scripts)| {
+                    let outpoints = db
+                        .get_utxos_by_script(&scripts)?
+                        .iter()
+                        .map(|outpoints| outpoints.iter().map(|o| o.to_string()).collect())
+                        .collect();
+                    Ok((key, outpoints))
+                })
+                .collect::<anyhow::Result<BTreeMap<_, Vec<Vec<String>>>>>()
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?;
+
+    let response = UtxosResponse {
+        utxos,
+        tip: state.tip_hash().await,
+    };
+    let result = serde_json::to_vec(&response).expect("serialization cannot fail");
+    any_resp(
+        result,
+        StatusCode::OK,
+        Some("application/json"),
+        Some(state.cache_control_seconds),
+        None,
+    )
+}
+
+/// The script hashes of the request, keyed by descriptor or by `addresses`.
+///
+/// Descriptor scripts are derived until a batch of [`GAP_LIMIT`] without history.
+async fn request_scripts(
+    state: &Arc<State>,
+    inputs: WaterfallRequest,
+) -> Result<Vec<(String, Vec<u64>)>, Error> {
+    let mut result = vec![];
+    match inputs {
+        WaterfallRequest::Descriptor(DescriptorRequest { descriptor, .. }) => {
+            let id = string_hash(&descriptor.normalized_id_string());
+            state.record_descriptor_access(id).await;
+            for desc in descriptor.into_single_descriptors()?.iter() {
+                let count = if desc.has_wildcard() { GAP_LIMIT } else { 1 };
+                let mut scripts = vec![];
+                for batch in 0..MAX_BATCH {
+                    let (batch_scripts, _) =
+                        derive_script_hashes_batch(state, desc, batch * GAP_LIMIT, count).await;
+                    let (batch_scripts, used) = state
+                        .with_store(move |db| {
+                            let used = db.has_history(&batch_scripts);
+                            (batch_scripts, used)
+                        })
+                        .await;
+                    let used = used.map_err(|e| Error::String(e.to_string()))?;
+                    scripts.extend(batch_scripts);
+                    if count == 1 || !used.contains(&true) {
+                        break;
+                    }
+                }
+                result.push((desc.to_string(), scripts));
+            }
+        }
+        WaterfallRequest::Addresses(AddressesRequest { addresses, .. }) => {
+            let scripts: Vec<_> = addresses
+                .iter()
+                .map(|addr| state.store.hash(addr.script_pubkey().as_bytes()))
+                .collect();
+            result.push(("addresses".to_string(), scripts));
+        }
+    }
+    Ok(result)
+}
+
 /// Handle the last_used_index endpoint request
 ///
 /// This endpoint efficiently finds the highest derivation index that has been used
@@ -852,7 +1417,6 @@ async fn handle_last_used_index(
     state: &Arc<State>,
     descriptor: be::Descriptor,
 ) -> Result<Resp, Error> {
-    let db = &state.store;
     let start = Instant::now();
     let id = string_hash(&descriptor.normalized_id_string());
     state.record_descriptor_access(id).await;
@@ -878,7 +1442,12 @@ async fn handle_last_used_index(
                 derive_script_hashes_batch(state, desc, batch_start, GAP_LIMIT).await;
 
             // Check which scripts have history (either confirmed or mempool)
-            let seen_blockchain = db.has_history(&scripts).unwrap();
+            let (scripts, seen_blockchain) = state
+                .with_store(move |db| {
+                    let seen_blockchain = db.has_history(&scripts).unwrap();
+                    (scripts, seen_blockchain)
+                })
+                .await;
             let seen_mempool = state.mempool.lock().await.has_seen(&scripts);
 
             // Find the max index with activity in this batch
@@ -931,12 +1500,57 @@ async fn handle_last_used_index(
     )
 }
 
-fn filter_utxo_only(result: &mut [Vec<TxSeen>], db: &crate::store::AnyStore) -> Result<(), Error> {
+async fn handle_script_utxos(state: &Arc<State>, script: &[u8]) -> Result<Resp, Error> {
+    let script_hash = state.store.hash(script);
+    let outpoints: Vec<String> = state
+        .with_store(move |db| {
+            db.iter_utxos_for_script(script_hash)
+                .map(|utxo| utxo.map(|(outpoint, _)| outpoint.to_string()))
+                .collect::<anyhow::Result<_>>()
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?;
+    let result = serde_json::to_string(&outpoints).map_err(|e| Error::String(e.to_string()))?;
+    any_resp(
+        result.into_bytes(),
+        StatusCode::OK,
+        Some("application/json"),
+        Some(state.cache_control_seconds),
+        None,
+    )
+}
+
+/// [`filter_utxo_only`] with the `--max-outpoints-per-query` limit, reading the store through
+/// [`State::with_store`]
+async fn filter_utxo_only_with_store(
+    state: &Arc<State>,
+    mut result: Vec<Vec<TxSeen>>,
+) -> Result<Vec<Vec<TxSeen>>, Error> {
+    let max_outpoints = state.max_outpoints_per_query;
+    state
+        .with_store(move |db| {
+            filter_utxo_only(&mut result, db, max_outpoints)?;
+            Ok(result)
+        })
+        .await
+}
+
+/// Keep only the unspent outputs, looking up at most `max_outpoints` in the UTXO set
+fn filter_utxo_only(
+    result: &mut [Vec<TxSeen>],
+    db: &crate::store::AnyStore,
+    max_outpoints: usize,
+) -> Result<(), Error> {
     let outpoints = result
         .iter()
         .flat_map(|e| e.iter().filter_map(|f| f.outpoint()))
         .collect::<Vec<_>>();
-    let utxos = db.get_utxos(&outpoints).unwrap();
+    if outpoints.len() > max_outpoints {
+        return Err(Error::TooManyOutpoints);
+    }
+    let utxos = db
+        .get_utxos(&outpoints)
+        .map_err(|e| Error::String(e.to_string()))?;
     let unspent: HashSet<_> = utxos
         .iter()
         .zip(outpoints.iter())
@@ -976,25 +1590,46 @@ async fn subscribe_descriptor(
     state: &Arc<State>,
     descriptor: be::Descriptor,
 ) -> Result<(SubscriptionId, SubscriptionReceiver), Error> {
+    let scripts = descriptor_watched_scripts(state, descriptor)
+        .await?
+        .into_iter()
+        .map(|(script_hash, _, _)| script_hash)
+        .collect();
+
+    state
+        .subscribe_scripts(scripts)
+        .await
+        .map_err(|e| Error::String(format!("{e:?}")))
+}
+
+/// The scripts watched by the subscriptions of a descriptor, with the position of their single
+/// descriptor and their derivation index
+async fn descriptor_watched_scripts(
+    state: &Arc<State>,
+    descriptor: be::Descriptor,
+) -> Result<Vec<(crate::ScriptHash, u32, u32)>, Error> {
     let mut scripts = Vec::new();
-    for desc in descriptor.into_single_descriptors().unwrap().iter() {
+    for (i, desc) in descriptor
+        .into_single_descriptors()
+        .unwrap()
+        .iter()
+        .enumerate()
+    {
         let single_descriptor_id = string_hash(&desc.normalized_id_string());
         let max_used_index = state
             .descriptor_max_used_index(single_descriptor_id)
             .await
             .ok_or(Error::DescriptorNotScanned)?;
         let watch_count = subscription_watch_count(max_used_index)?;
+        let (script_hashes, _) = derive_script_hashes_batch(state, desc, 0, watch_count).await;
         scripts.extend(
-            derive_script_hashes_batch(state, desc, 0, watch_count)
-                .await
-                .0,
+            script_hashes
+                .into_iter()
+                .zip(0..)
+                .map(|(script_hash, index)| (script_hash, i as u32, index)),
         );
     }
-
-    state
-        .subscribe_scripts(scripts)
-        .await
-        .map_err(|e| Error::String(format!("{e:?}")))
+    Ok(scripts)
 }
 
 fn sse_resp(
@@ -1019,8 +1654,262 @@ fn sse_resp(
             )
         })
     });
-    let body = BodyExt::boxed(StreamBody::new(ready.chain(events)));
+    event_stream_resp(BodyExt::boxed(StreamBody::new(ready.chain(events))))
+}
+
+/// Stream the history entries of `script` seen after the subscription, see [`ScriptEvents`]
+async fn handle_script_subscribe(state: &Arc<State>, script: &[u8]) -> Result<Resp, Error> {
+    let script_hash = state.store.hash(script);
+    let events = ScriptEvents::subscribe(state, vec![script_hash]).await?;
+    let frames = events.stream(|changes| {
+        let mut frames = vec![];
+        if changes.reorg {
+            frames.push("event: reorg\ndata: {}\n\n".to_string());
+        }
+        for (_, tx_seen) in changes.entries.iter() {
+            let data = serde_json::to_string(tx_seen).expect("TxSeen is serializable");
+            frames.push(format!("event: tx\ndata: {data}\n\n"));
+        }
+        frames
+    });
+    event_stream_resp(BodyExt::boxed(StreamBody::new(frames)))
+}
+
+/// Sent by `/v1/stream` for every new entry of a watched script
+#[derive(Serialize)]
+struct StreamEvent {
+    txid: be::Txid,
+    /// 0 for mempool entries
+    height: Height,
+    /// The derivation index of the script, or its position in the addresses list
+    script_index: u32,
+    /// The position of the single descriptor of a multipath descriptor, None for addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    descriptor_index: Option<u32>,
+}
+
+/// Stream the new entries of the scripts of a descriptor or a list of addresses as
+/// [`StreamEvent`]s, see [`ScriptEvents`]
+///
+/// If too many entries changed at once or a reorg happened, a `resync` event is sent instead, the
+/// client should scan again with the waterfalls endpoint.
+async fn handle_stream_req(state: &Arc<State>, request: WaterfallRequest) -> Result<Resp, Error> {
+    let (scripts, labels): (Vec<_>, Vec<_>) = match request {
+        WaterfallRequest::Descriptor(DescriptorRequest { descriptor, .. }) => {
+            descriptor_watched_scripts(state, descriptor)
+                .await?
+                .into_iter()
+                .map(|(script_hash, descriptor_index, index)| {
+                    (script_hash, (index, Some(descriptor_index)))
+                })
+                .unzip()
+        }
+        WaterfallRequest::Addresses(AddressesRequest { addresses, .. }) => addresses
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let script_hash = state.store.hash(addr.script_pubkey().as_bytes());
+                (script_hash, (i as u32, None))
+            })
+            .unzip(),
+    };
+    let max_events = state.max_txs_seen;
+    let events = ScriptEvents::subscribe(state, scripts).await?;
+    let frames = events.stream(move |changes| {
+        if changes.reorg || changes.entries.len() > max_events {
+            return vec!["event: resync\ndata: {}\n\n".to_string()];
+        }
+        changes
+            .entries
+            .iter()
+            .map(|(i, tx_seen)| {
+                let (script_index, descriptor_index) = labels[*i];
+                let event = StreamEvent {
+                    txid: tx_seen.txid,
+                    height: tx_seen.height,
+                    script_index,
+                    descriptor_index,
+                };
+                let data = serde_json::to_string(&event).expect("StreamEvent is serializable");
+                format!("event: tx\ndata: {data}\n\n")
+            })
+            .collect()
+    });
+    event_stream_resp(BodyExt::boxed(StreamBody::new(frames)))
+}
+
+/// The state of a subscription streaming the new history entries of some scripts.
+///
+/// Notifications of the subscription only tell that something changed and may be coalesced, so
+/// at every notification the confirmed entries above the last height sent and the mempool
+/// entries not sent yet are looked up. A slow client never blocks the indexing, and it receives
+/// all the entries when it catches up.
+struct ScriptEvents {
+    cleanup: SubscriptionCleanup,
+    receiver: SubscriptionReceiver,
+    scripts: Vec<crate::ScriptHash>,
+    /// Confirmed entries below this height have already been sent
+    next_height: Height,
+    /// The mempool entries sent and still in the mempool at the last notification, by position
+    /// in `scripts`
+    sent_mempool: BTreeSet<(usize, be::Txid, i32)>,
+}
+
+/// The entries of the watched scripts since the previous notification
+struct ScriptChanges {
+    reorg: bool,
+    /// By position in the watched scripts, confirmed ones first
+    entries: Vec<(usize, TxSeen)>,
+}
+
+impl ScriptEvents {
+    async fn subscribe(state: &Arc<State>, scripts: Vec<crate::ScriptHash>) -> Result<Self, Error> {
+        let (id, receiver) = state
+            .subscribe_scripts(scripts.clone())
+            .await
+            .map_err(|e| Error::String(format!("{e:?}")))?;
+        Ok(ScriptEvents {
+            next_height: state.tip_height().await.map_or(0, |height| height + 1),
+            cleanup: SubscriptionCleanup {
+                state: state.clone(),
+                id,
+            },
+            receiver,
+            scripts,
+            sent_mempool: BTreeSet::new(),
+        })
+    }
+
+    /// The SSE frames, starting with the `: ready` comment, then the events returned by `format`
+    /// for every notification
+    fn stream<F>(
+        self,
+        format: F,
+    ) -> impl futures_util::Stream<Item = Result<Frame<Bytes>, Infallible>>
+    where
+        F: Fn(&ScriptChanges) -> Vec<String> + Send + Sync + 'static,
+    {
+        let ready = stream::once(async {
+            Ok::<Frame<Bytes>, Infallible>(Frame::data(Bytes::from_static(b": ready\n\n")))
+        });
+        let events = stream::unfold((self, format), |(mut events, format)| async move {
+            let changes = events.next_changes().await?;
+            let frames = format(&changes)
+                .into_iter()
+                .map(|frame| Ok(Frame::data(Bytes::from(frame))));
+            Some((stream::iter(frames.collect::<Vec<_>>()), (events, format)))
+        })
+        .flatten();
+        ready.chain(events)
+    }
+
+    /// Wait for the next notification, None when the subscription is closed
+    async fn next_changes(&mut self) -> Option<ScriptChanges> {
+        let event = self.receiver.recv().await?;
+        let state = &self.cleanup.state;
+        let reorg = event == SubscriptionEvent::Reorg;
+        if reorg {
+            // entries at the rolled back heights will be sent again once confirmed
+            self.next_height = state.tip_height().await.map_or(0, |height| height + 1);
+        }
+
+        let mut entries = vec![];
+        let scripts = self.scripts.clone();
+        let next_height = self.next_height;
+        match state
+            .with_store(move |db| db.get_history_from(&scripts, next_height))
+            .await
+        {
+            Ok(history) => {
+                for (i, script_history) in history.into_iter().enumerate() {
+                    entries.extend(script_history.into_iter().map(|tx_seen| (i, tx_seen)));
+                }
+            }
+            Err(e) => log::warn!("error getting history for script subscription: {e:?}"),
+        }
+        if let Some(max_height) = entries.iter().map(|(_, tx_seen)| tx_seen.height).max() {
+            self.next_height = max_height + 1;
+        }
+
+        let mut mempool = vec![vec![]; self.scripts.len()];
+        state
+            .mempool
+            .lock()
+            .await
+            .append_seen(&self.scripts, &mut mempool);
+        let previously_sent = std::mem::take(&mut self.sent_mempool);
+        for (i, script_mempool) in mempool.into_iter().enumerate() {
+            for tx_seen in script_mempool {
+                let key = (i, tx_seen.txid, tx_seen.v.raw());
+                if !previously_sent.contains(&key) {
+                    entries.push((i, tx_seen));
+                }
+                self.sent_mempool.insert(key);
+            }
+        }
+
+        Some(ScriptChanges { reorg, entries })
+    }
+}
+
+fn block_events_resp(receiver: broadcast::Receiver<BlockEvent>) -> Result<Resp, Error> {
+    let ready = stream::once(async {
+        Ok::<Frame<Bytes>, Infallible>(Frame::data(Bytes::from_static(b": ready\n\n")))
+    });
+    // the receiver is dropped with the stream when the client disconnects
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let frame = Frame::data(Bytes::from(sse_block_event(&event)));
+                    return Some((Ok::<Frame<Bytes>, Infallible>(frame), receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("block events stream lagging, skipped {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    event_stream_resp(BodyExt::boxed(StreamBody::new(ready.chain(events))))
+}
+
+/// The recently reorged blocks as a json array, the most recent first
+fn reorgs_resp(reorgs: Vec<crate::BlockMeta>) -> Result<Resp, Error> {
+    let json = serde_json::to_string(&reorgs).map_err(|e| Error::String(e.to_string()))?;
+    any_resp(
+        json.into_bytes(),
+        StatusCode::OK,
+        Some("application/json"),
+        None,
+        None,
+    )
+}
+
+/// Stream the confirmed history of a script, one json encoded [`TxSeen`] per line
+fn history_ndjson_resp(history: BoxStream<'static, anyhow::Result<TxSeen>>) -> Result<Resp, Error> {
+    // once the response started the status can't change anymore, on error the stream is truncated
+    let lines = history
+        .take_while(|tx_seen| {
+            if let Err(e) = tx_seen {
+                log::warn!("error streaming history: {e:?}");
+            }
+            std::future::ready(tx_seen.is_ok())
+        })
+        .filter_map(|tx_seen| std::future::ready(tx_seen.ok()))
+        .map(|tx_seen| {
+            let mut line = serde_json::to_vec(&tx_seen).expect("TxSeen is serializable");
+            line.push(b'\n');
+            Ok::<Frame<Bytes>, Infallible>(Frame::data(Bytes::from(line)))
+        });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(BodyExt::boxed(StreamBody::new(lines)))
+        .map_err(|_| Error::Other)
+}
 
+fn event_stream_resp(body: RespBody) -> Result<Resp, Error> {
     Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_TYPE, "text/event-stream")
@@ -1054,6 +1943,11 @@ fn sse_event(event: SubscriptionEvent) -> String {
     )
 }
 
+fn sse_block_event(event: &BlockEvent) -> String {
+    let data = serde_json::to_string(event.block_meta()).expect("BlockMeta is serializable");
+    format!("event: {}\ndata: {data}\n\n", event.as_str())
+}
+
 fn subscription_event_reason(event: SubscriptionEvent) -> &'static str {
     match event {
         SubscriptionEvent::Block => "block",
@@ -1074,13 +1968,29 @@ fn subscription_watch_count(max_used_index: Option<u32>) -> Result<u32, Error> {
 
 async fn find_scripts(
     state: &Arc<State>,
-    db: &crate::store::AnyStore,
     result: &mut Vec<Vec<TxSeen>>,
     scripts: Vec<u64>,
     address_history_page: usize,
+    from_height: Height,
     append_mempool: bool,
 ) -> FindScriptsResult {
-    let mut seen_blockchain = db.get_history(&scripts).unwrap();
+    let (scripts, mut seen_blockchain, used_below_from_height) = state
+        .with_store(move |db| {
+            let seen_blockchain = if from_height > 0 {
+                db.get_history_from(&scripts, from_height).unwrap()
+            } else {
+                db.get_history(&scripts).unwrap()
+            };
+            // scripts with history only below `from_height` are used even if nothing is returned
+            // for them
+            let used_below_from_height = if from_height > 0 {
+                db.has_history(&scripts).unwrap()
+            } else {
+                vec![false; scripts.len()]
+            };
+            (scripts, seen_blockchain, used_below_from_height)
+        })
+        .await;
     let has_more = truncate_history_page(
         &mut seen_blockchain,
         address_history_page,
@@ -1095,16 +2005,17 @@ async fn find_scripts(
     }
     let max_used_offset = seen_blockchain
         .iter()
+        .zip(used_below_from_height.iter())
         .enumerate()
-        .filter_map(|(index, txs_seen)| {
-            if txs_seen.is_empty() {
+        .filter_map(|(index, (txs_seen, used))| {
+            if txs_seen.is_empty() && !used {
                 None
             } else {
                 Some(index as u32)
             }
         })
         .max();
-    let is_last = seen_blockchain.iter().all(|e| e.is_empty());
+    let is_last = max_used_offset.is_none();
     result.extend(seen_blockchain);
     FindScriptsResult {
         is_last,
@@ -1197,13 +2108,27 @@ pub async fn infallible_route(
     state: &Arc<State>,
     client: &Arc<Mutex<Client>>,
     req: Request<Incoming>,
+    peer_ip: IpAddr,
     network: Network,
     add_cors: bool,
 ) -> Result<Resp, hyper::Error> {
-    let mut response = match route(state, client, req, network).await {
+    let rate_limited = state
+        .rate_limiter
+        .as_ref()
+        .is_some_and(|limiter| !limiter.check(req.headers(), peer_ip));
+    let result = if rate_limited {
+        crate::inc_connection_error_counter("rate_limited");
+        Err(Error::TooManyRequests)
+    } else if let Err(e) = state.check_reorg_in_progress() {
+        Err(e)
+    } else {
+        route(state, client, req, network).await
+    };
+    let mut response = match result {
         Ok(r) => r,
         Err(e) => error_resp(error_status(&e), &e),
     };
+    crate::inc_response_counter(response.status());
 
     // Add CORS headers if enabled
     if add_cors {
@@ -1244,6 +2169,7 @@ fn get_build_info() -> BuildInfo {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use elements::hashes::Hash;
 
     const MAINNET_DESC: &str = "elwpkh([a12b02f4/44'/0'/0']xpub6BzhLAQUDcBUfHRQHZxDF2AbcJqp4Kaeq6bzJpXrjrWuK26ymTFwkEFbxPra2bJ7yeZKbDjfDeFwxe93JMqpo5SsPJH6dZdvV9kMzJkAZ69/0/*)#20ufqv7z";
     const TESTNET_DESC: &str = "elwpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/<0;1>/*)#v7pu3vak";
@@ -1251,26 +2177,40 @@ mod tests {
     const BITCOIN_TESTNET_DESC: &str = "wpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/<0;1>/*)#v7pu3vak";
     const CONFIDENTIAL_TESTNET_DESC: &str = "ct(slip77(1bda6cd71a1e206e3eb793e5a4d98a46c3fa473c9ab7bdef9bb9c814764d6614),elwpkh([cb4ba44a/84'/1'/0']tpubDDrybtUajFcgXC85rvwPsh1oU7Azx4kJ9BAiRzMbByqK7UnVXY3gDRJPwEDfaQwguNUZFzrhavJGgEhbsfuebyxUSZQnjLezWVm2Vdqb7UM/<0;1>/*))#za9ktavp";
 
+    #[test]
+    fn test_is_in_chain() {
+        let hash = |i| BlockHash::from_byte_array([i; 32]);
+        let blocks_hash_ts = [(hash(0), 0), (hash(1), 1), (hash(2), 2)];
+        assert!(is_in_chain(&blocks_hash_ts, 1, &hash(1)));
+        assert!(is_in_chain(&blocks_hash_ts, 2, &hash(2)));
+        // the client block at height 2 has been reorged
+        assert!(!is_in_chain(&blocks_hash_ts, 2, &hash(3)));
+        // the client is ahead, or the tip has been reorged to a lower height
+        assert!(!is_in_chain(&blocks_hash_ts, 3, &hash(3)));
+        assert!(!is_in_chain(&[], 0, &hash(0)));
+    }
+
     #[test]
     fn test_parse_query() {
         // Test missing descriptor field
         let key = age::x25519::Identity::generate();
-        let result = parse_query("", &key, false, 100, Network::Liquid);
+        let result = parse_query("", &key, false, 100, 100, Network::Liquid);
         assert!(matches!(result, Err(Error::AtLeastOneFieldMandatory)));
 
         // Test invalid descriptor
         let result =
-            parse_query("descriptor=invalid", &key, false, 100, Network::Liquid).unwrap_err();
+            parse_query("descriptor=invalid", &key, false, 100, 100, Network::Liquid).unwrap_err();
         let bad_descriptor = "BadDescriptor(\"Not an Elements Descriptor\")".to_string();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test empty descriptor
-        let result = parse_query("descriptor=", &key, false, 100, Network::Liquid).unwrap_err();
+        let result =
+            parse_query("descriptor=", &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor));
 
         // Test valid clear descriptor
         let query = encode_query(MAINNET_DESC, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
         assert_eq!(
             result.descriptor().unwrap().descriptor.to_string(),
             MAINNET_DESC
@@ -1280,7 +2220,7 @@ mod tests {
         // Test valid encrypted descriptor
         let encrypted = encryption::encrypt(MAINNET_DESC, key.to_public()).unwrap();
         let query = encode_query(&encrypted, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
         assert_eq!(
             result.descriptor().unwrap().descriptor.to_string(),
             MAINNET_DESC
@@ -1288,14 +2228,42 @@ mod tests {
 
         // Test with page parameter
         let query = encode_query(MAINNET_DESC, Some(5));
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
         assert_eq!(result.page(), 5);
 
+        // Test with from_height parameter
+        let query = format!("{}&from_height=1000", encode_query(MAINNET_DESC, None));
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
+        assert_eq!(result.descriptor().unwrap().from_height, 1000);
+        let result = parse_query(
+            &encode_query(MAINNET_DESC, None),
+            &key,
+            false,
+            100,
+            100,
+            Network::Liquid,
+        )
+        .unwrap();
+        assert_eq!(result.descriptor().unwrap().from_height, 0);
+        assert_eq!(result.descriptor().unwrap().tip_hash, None);
+
+        // Test with tip_hash parameter
+        let hash = BlockHash::from_byte_array([1; 32]);
+        let query = format!(
+            "{}&from_height=1000&tip_hash={hash}",
+            encode_query(MAINNET_DESC, None)
+        );
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
+        assert_eq!(result.descriptor().unwrap().tip_hash, Some(hash));
+        let query = format!("{}&tip_hash=00", encode_query(MAINNET_DESC, None));
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap_err();
+        assert_eq!(result, Error::InvalidBlockHash);
+
         // Test wrong network (mainnet xpub on testnet) and then right network
         let query = encode_query(MAINNET_DESC, None);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap_err();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap_err();
         assert_eq!(result, Error::WrongNetwork);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap();
         assert_eq!(
             result.descriptor().unwrap().descriptor.to_string(),
             MAINNET_DESC
@@ -1303,16 +2271,23 @@ mod tests {
 
         // Test wrong network (testnet xpub on mainnet) and then right network
         let query = encode_query(TESTNET_DESC, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap_err();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::WrongNetwork);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap();
         assert_eq!(
             result.descriptor().unwrap().descriptor.to_string(),
             TESTNET_DESC
         );
 
+        // the receive and change paths need two derivations per index
+        let result = parse_query(&query, &key, true, 100, 1, Network::LiquidTestnet).unwrap_err();
+        assert_eq!(result, Error::DescriptorTooComplex);
+        let result = parse_descriptor_query(&query, &key, true, 1, Network::LiquidTestnet);
+        assert_eq!(result.unwrap_err(), Error::DescriptorTooComplex);
+
         // Test Invalid Address
-        let result = parse_query("addresses=ciao", &key, false, 100, Network::Liquid).unwrap_err();
+        let result =
+            parse_query("addresses=ciao", &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert!(matches!(result, Error::String(_)));
 
         // Test Valid mainnet Address
@@ -1322,6 +2297,7 @@ mod tests {
             &key,
             false,
             100,
+            100,
             Network::Liquid,
         )
         .unwrap();
@@ -1338,6 +2314,7 @@ mod tests {
             &key,
             true,
             100,
+            100,
             Network::LiquidTestnet,
         )
         .unwrap();
@@ -1352,58 +2329,60 @@ mod tests {
             &key,
             false,
             100,
+            100,
             Network::Liquid,
         )
         .unwrap_err();
         assert_eq!(result, Error::AddressPageRequiresSingleAddress);
 
         // Test Invalid Address (blinding key)
-        let result = parse_query("addresses=lq1qqgyxa469eaugae2sz3q8qzaqy0v57ecuekzyngfac5nw4z87yqskc5tp2wtueqq6am0x062zewkrl9lr0cqwvw0j9633xqe2e", &key, false, 100, Network::Liquid).unwrap_err();
+        let result = parse_query("addresses=lq1qqgyxa469eaugae2sz3q8qzaqy0v57ecuekzyngfac5nw4z87yqskc5tp2wtueqq6am0x062zewkrl9lr0cqwvw0j9633xqe2e", &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::AddressCannotBeBlinded);
 
         // Test too many addresses
-        let result = parse_query("addresses=ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh,ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh,ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh", &key, false, 2, Network::Liquid).unwrap_err();
+        let result = parse_query("addresses=ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh,ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh,ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh", &key, false, 2, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::TooManyAddresses);
 
         // Test too many addresses, fast checks
-        let result = parse_query("addresses=,,,", &key, false, 2, Network::Liquid).unwrap_err();
+        let result =
+            parse_query("addresses=,,,", &key, false, 2, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::TooManyAddresses);
         let long_str: String = "a".repeat(400);
         let query = format!("addresses={long_str}");
-        let result = parse_query(&query, &key, false, 2, Network::Liquid).unwrap_err();
+        let result = parse_query(&query, &key, false, 2, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::TooManyAddresses);
 
         // Test Bitcoin mainnet descriptor (should fail as it's not an Elements descriptor)
         let query = encode_query(BITCOIN_MAINNET_DESC, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap_err();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap_err();
         let bad_descriptor = "BadDescriptor(\"Not an Elements Descriptor\")".to_string();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test Bitcoin testnet descriptor (should fail as it's not an Elements descriptor)
         let query = encode_query(BITCOIN_TESTNET_DESC, None);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap_err();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test Bitcoin mainnet descriptor on testnet network (should fail as it's not an Elements descriptor)
         let query = encode_query(BITCOIN_MAINNET_DESC, None);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap_err();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test Bitcoin testnet descriptor on mainnet network (should fail as it's not an Elements descriptor)
         let query = encode_query(BITCOIN_TESTNET_DESC, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap_err();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test encrypted Bitcoin mainnet descriptor (should fail as it's not an Elements descriptor)
         let encrypted = encryption::encrypt(BITCOIN_MAINNET_DESC, key.to_public()).unwrap();
         let query = encode_query(&encrypted, None);
-        let result = parse_query(&query, &key, false, 100, Network::Liquid).unwrap_err();
+        let result = parse_query(&query, &key, false, 100, 100, Network::Liquid).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor.clone()));
 
         // Test encrypted Bitcoin testnet descriptor (should fail as it's not an Elements descriptor)
         let encrypted = encryption::encrypt(BITCOIN_TESTNET_DESC, key.to_public()).unwrap();
         let query = encode_query(&encrypted, None);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap_err();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap_err();
         assert_eq!(result, Error::InvalidDescriptor(bad_descriptor));
     }
 
@@ -1417,7 +2396,7 @@ mod tests {
     fn test_confidential_descriptor_is_bad_request() {
         let key = age::x25519::Identity::generate();
         let query = encode_query(CONFIDENTIAL_TESTNET_DESC, None);
-        let result = parse_query(&query, &key, true, 100, Network::LiquidTestnet).unwrap_err();
+        let result = parse_query(&query, &key, true, 100, 100, Network::LiquidTestnet).unwrap_err();
 
         assert!(matches!(
             result,
@@ -1464,6 +2443,59 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_block_events_stream() {
+        let (sender, receiver) = broadcast::channel(16);
+        let resp = block_events_resp(receiver).unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/event-stream");
+        let mut body = resp.into_body();
+        assert_eq!(next_frame(&mut body).await, ": ready\n\n");
+
+        let meta = crate::BlockMeta {
+            b: BlockHash::from_str(&"11".repeat(32)).unwrap(),
+            t: 1_700_000_000,
+            h: 42,
+        };
+        let json = serde_json::to_string(&meta).unwrap();
+        sender.send(BlockEvent::Block(meta.clone())).unwrap();
+        assert_eq!(
+            next_frame(&mut body).await,
+            format!("event: block\ndata: {json}\n\n")
+        );
+
+        sender.send(BlockEvent::Reorg(meta)).unwrap();
+        assert_eq!(
+            next_frame(&mut body).await,
+            format!("event: reorg\ndata: {json}\n\n")
+        );
+
+        drop(sender);
+        assert!(body.frame().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reorgs_resp() {
+        let meta = crate::BlockMeta {
+            b: BlockHash::from_str(&"11".repeat(32)).unwrap(),
+            t: 1_700_000_000,
+            h: 42,
+        };
+        let resp = reorgs_resp(vec![meta.clone()]).unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        let reorgs: Vec<crate::BlockMeta> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(reorgs, vec![meta]);
+
+        let resp = reorgs_resp(vec![]).unwrap();
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"[]");
+    }
+
+    async fn next_frame(body: &mut RespBody) -> String {
+        let frame = body.frame().await.unwrap().unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
     #[test]
     fn test_get_build_info() {
         let build_info = get_build_info();
@@ -1522,4 +2554,88 @@ mod tests {
             vec![3]
         );
     }
+
+    #[test]
+    fn test_reorg_in_progress_resp() {
+        let error = Error::ReorgInProgress;
+        let resp = error_resp(error_status(&error), &error);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[test]
+    fn test_parse_address_txs_query() {
+        assert_eq!(
+            parse_address_txs_query(None, 10).unwrap(),
+            (
+                HistoryPage {
+                    from_height: None,
+                    limit: Some(10)
+                },
+                false
+            )
+        );
+        assert_eq!(
+            parse_address_txs_query(Some("from_height=5&limit=3&confirmations=true"), 10).unwrap(),
+            (
+                HistoryPage {
+                    from_height: Some(5),
+                    limit: Some(3)
+                },
+                true
+            )
+        );
+        assert_eq!(
+            parse_address_txs_query(Some("from_height=x"), 10),
+            Err(Error::CannotParseHeight)
+        );
+        for limit in ["0", "11", "x"] {
+            assert_eq!(
+                parse_address_txs_query(Some(&format!("limit={limit}")), 10),
+                Err(Error::InvalidHistoryLimit)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_headers_query() {
+        assert_eq!(parse_headers_query("start=10&count=5").unwrap(), (10, 5));
+        assert_eq!(parse_headers_query("start=10").unwrap(), (10, MAX_HEADERS));
+        assert!(matches!(
+            parse_headers_query("count=5"),
+            Err(Error::CannotParseHeight)
+        ));
+        assert!(matches!(
+            parse_headers_query("start=10&count=0"),
+            Err(Error::TooManyHeaders)
+        ));
+        assert!(matches!(
+            parse_headers_query(&format!("start=10&count={}", MAX_HEADERS + 1)),
+            Err(Error::TooManyHeaders)
+        ));
+    }
+
+    #[test]
+    fn test_filter_utxo_only_max_outpoints() {
+        use crate::store::{memory::MemoryStore, AnyStore};
+        let store = AnyStore::Mem(MemoryStore::new());
+        let txid = crate::be::Txid::from_array([1; 32]);
+        let history = |n: u32| {
+            vec![(0..n)
+                .map(|vout| TxSeen::new(txid, 1, V::Vout(vout)))
+                .collect()]
+        };
+
+        let mut at_limit: Vec<Vec<TxSeen>> = history(2);
+        filter_utxo_only(&mut at_limit, &store, 2).unwrap();
+        assert!(
+            at_limit[0].is_empty(),
+            "none of the outputs is in the utxo set"
+        );
+
+        let mut over_limit: Vec<Vec<TxSeen>> = history(3);
+        let err = filter_utxo_only(&mut over_limit, &store, 2).unwrap_err();
+        assert_eq!(err, Error::TooManyOutpoints);
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+    }
 }
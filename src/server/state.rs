@@ -1,30 +1,39 @@
 use std::{
     cmp::Ordering,
-    collections::HashMap,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use crate::{
     server::{
         derivation_cache::DerivationCache,
+        rate_limit::IpRateLimiter,
         subscription::{
-            SubscriptionError, SubscriptionEvent, SubscriptionId, SubscriptionReceiver,
+            BlockEvent, SubscriptionError, SubscriptionEvent, SubscriptionId, SubscriptionReceiver,
             Subscriptions,
         },
         Mempool,
     },
-    store::{AnyStore, BlockMeta},
-    ScriptHash, Timestamp,
+    store::{AnyStore, BlockMeta, Store},
+    Height, ScriptHash, Timestamp,
 };
 use age::x25519::Identity;
 use bitcoin::{key::Secp256k1, secp256k1::All, PrivateKey};
 use elements::BlockHash;
-use tokio::sync::{Mutex, RwLock};
+use hyper::header::HeaderName;
+use lrumap::LruHashMap;
+use tokio::sync::{broadcast, Mutex, RwLock};
 
 use super::{sign::p2pkh, Error};
 
 const DESCRIPTOR_METRICS_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
 const DESCRIPTOR_METRICS_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BLOCK_EVENTS_CAPACITY: usize = 16;
+const HEADER_CACHE_CAPACITY: usize = 10_000;
 
 pub struct State {
     /// An asymmetric encryption key, the public key is used to optionally encrypt the descriptor field so that it's harder to leak it.
@@ -41,17 +50,34 @@ pub struct State {
     pub secp: Secp256k1<All>,
 
     pub max_addresses: usize,
+    pub max_descriptor_keys: usize,
     pub max_txs_seen: usize,
+    /// Maximum number of outpoints looked up in the UTXO set for a single request
+    pub max_outpoints_per_query: usize,
 
     pub cache_control_seconds: u32,
 
+    /// The first height with indexed history if the store has been imported from a UTXO snapshot
+    pub history_start_height: Option<Height>,
+
     pub derivation_cache: Mutex<DerivationCache>,
 
     pub cached_fee_estimates: RwLock<(HashMap<u16, f64>, Option<Instant>)>,
 
+    pub rate_limiter: Option<IpRateLimiter>,
+
+    /// Serialized block headers fetched from the node, by block hash so that it's unaffected by reorgs
+    pub header_cache: Mutex<LruHashMap<BlockHash, Vec<u8>, RandomState>>,
+
+    /// Whether requests are rejected while `reorg_in_progress` is set
+    reject_requests_during_reorg: bool,
+    reorg_in_progress: AtomicBool,
+
     descriptor_metrics: Mutex<DescriptorMetrics>,
     descriptor_max_used_index: Mutex<HashMap<u64, Option<u32>>>,
     subscriptions: Mutex<Subscriptions>,
+    block_events: broadcast::Sender<BlockEvent>,
+    recent_reorgs: Mutex<RecentReorgs>,
 }
 
 impl State {
@@ -61,6 +87,9 @@ impl State {
         wif_key: PrivateKey,
         config: StateConfig,
     ) -> Result<Self, Error> {
+        let history_start_height = store
+            .history_start_height()
+            .map_err(|e| Error::DBOpen(format!("{e:?}")))?;
         Ok(State {
             key,
             wif_key,
@@ -70,16 +99,30 @@ impl State {
             blocks_hash_ts: Mutex::new(Vec::new()),
             secp: bitcoin::key::Secp256k1::new(),
             max_addresses: config.max_addresses,
+            max_descriptor_keys: config.max_descriptor_keys,
             max_txs_seen: config.max_txs_seen,
+            max_outpoints_per_query: config.max_outpoints_per_query,
             cache_control_seconds: config.cache_control_seconds,
+            history_start_height,
             derivation_cache: Mutex::new(DerivationCache::new(config.derivation_cache_capacity)),
             cached_fee_estimates: RwLock::new((HashMap::new(), None)),
+            rate_limiter: config
+                .max_req_per_second_per_ip
+                .map(|max| IpRateLimiter::new(max, config.rate_limit_ip_header)),
+            header_cache: Mutex::new(LruHashMap::with_hasher(
+                HEADER_CACHE_CAPACITY,
+                RandomState::new(),
+            )),
+            reject_requests_during_reorg: config.reject_requests_during_reorg,
+            reorg_in_progress: AtomicBool::new(false),
             descriptor_metrics: Mutex::new(DescriptorMetrics::new()),
             descriptor_max_used_index: Mutex::new(HashMap::new()),
             subscriptions: Mutex::new(Subscriptions::new(
                 config.subscription_limits.max_active_subscriptions,
                 config.subscription_limits.max_scripts_per_subscription,
             )),
+            block_events: broadcast::channel(BLOCK_EVENTS_CAPACITY).0,
+            recent_reorgs: Mutex::new(RecentReorgs::new(config.reorg_events_keep)),
         })
     }
 
@@ -117,6 +160,45 @@ impl State {
         let blocks_hash_ts = self.blocks_hash_ts.lock().await;
         blocks_hash_ts.get(height as usize).map(|e| e.0)
     }
+
+    /// The height of the last block with timestamp lower or equal than `timestamp`, see
+    /// [`height_for_timestamp`]
+    pub async fn height_for_timestamp(&self, timestamp: Timestamp) -> Option<Height> {
+        height_for_timestamp(&self.blocks_hash_ts.lock().await, timestamp)
+    }
+    /// Mark a reorg as in progress until the returned guard is dropped
+    pub fn start_reorg(&self) -> ReorgGuard<'_> {
+        self.reorg_in_progress.store(true, atomic::Ordering::SeqCst);
+        ReorgGuard { state: self }
+    }
+
+    /// Fails if requests must be rejected because a reorg is being applied
+    pub fn check_reorg_in_progress(&self) -> Result<(), Error> {
+        if self.reject_requests_during_reorg
+            && self.reorg_in_progress.load(atomic::Ordering::SeqCst)
+        {
+            Err(Error::ReorgInProgress)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run `f` on the store without blocking the async runtime: the DB store does blocking I/O,
+    /// so it runs on the blocking thread pool, the memory store runs in place.
+    pub(crate) async fn with_store<R, F>(self: &Arc<Self>, f: F) -> R
+    where
+        F: FnOnce(&AnyStore) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        if let AnyStore::Mem(_) = self.store {
+            return f(&self.store);
+        }
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || f(&state.store))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
     pub async fn set_hash_ts(&self, meta: &BlockMeta) {
         let mut blocks_hash_ts = self.blocks_hash_ts.lock().await;
         update_hash_ts(&mut blocks_hash_ts, meta);
@@ -186,6 +268,26 @@ impl State {
     pub(crate) async fn unsubscribe(&self, id: SubscriptionId) -> bool {
         self.subscriptions.lock().await.unsubscribe(id)
     }
+
+    /// Send the event to every open `/events/blocks` stream, returns the number of receivers
+    pub(crate) fn publish_block_event(&self, event: BlockEvent) -> usize {
+        // an error only means nobody is listening
+        self.block_events.send(event).unwrap_or(0)
+    }
+
+    pub(crate) fn subscribe_block_events(&self) -> broadcast::Receiver<BlockEvent> {
+        self.block_events.subscribe()
+    }
+
+    /// Remember the reorged block for `/reorgs`, forgetting the oldest one if there are too many
+    pub(crate) async fn record_reorg(&self, reorged_block: crate::BlockMeta) {
+        self.recent_reorgs.lock().await.push(reorged_block);
+    }
+
+    /// The reorged blocks recorded by [`State::record_reorg`], the most recent first
+    pub(crate) async fn recent_reorgs(&self) -> Vec<crate::BlockMeta> {
+        self.recent_reorgs.lock().await.latest()
+    }
 }
 
 fn record_descriptor_scan_max_used_index(
@@ -218,6 +320,23 @@ fn descriptor_max_used_index_snapshot(
     (descriptor_max_used_index.len(), top_indexes)
 }
 
+/// Binary search the last height whose block has timestamp lower or equal than `timestamp`: 0 if
+/// it's before the genesis block, the tip if it's after it, None only without blocks.
+///
+/// Block timestamps are not strictly increasing, a block can be older than the previous ones up
+/// to the median time past. The search assumes they are, so around such blocks it returns one of
+/// the heights whose timestamp is close to `timestamp`.
+fn height_for_timestamp(
+    blocks_hash_ts: &[(BlockHash, Timestamp)],
+    timestamp: Timestamp,
+) -> Option<Height> {
+    if blocks_hash_ts.is_empty() {
+        return None;
+    }
+    let after = blocks_hash_ts.partition_point(|(_, ts)| *ts <= timestamp);
+    Some(after.saturating_sub(1) as Height)
+}
+
 fn update_hash_ts(blocks_hash_ts: &mut Vec<(BlockHash, u32)>, meta: &BlockMeta) {
     match blocks_hash_ts.len().cmp(&(meta.height() as usize)) {
         Ordering::Less => {
@@ -280,17 +399,63 @@ pub struct SubscriptionLimits {
     pub max_scripts_per_subscription: usize,
 }
 
+/// Returned by [`State::start_reorg`], clears the reorg in progress flag when dropped
+pub struct ReorgGuard<'a> {
+    state: &'a State,
+}
+
+impl Drop for ReorgGuard<'_> {
+    fn drop(&mut self) {
+        self.state
+            .reorg_in_progress
+            .store(false, atomic::Ordering::SeqCst);
+    }
+}
+
 pub struct StateConfig {
     pub max_addresses: usize,
+    pub max_descriptor_keys: usize,
     pub max_txs_seen: usize,
+    pub max_outpoints_per_query: usize,
     pub cache_control_seconds: u32,
     pub derivation_cache_capacity: usize,
+    pub max_req_per_second_per_ip: Option<u32>,
+    pub rate_limit_ip_header: Option<HeaderName>,
+    pub reject_requests_during_reorg: bool,
+    pub reorg_events_keep: usize,
     pub subscription_limits: SubscriptionLimits,
 }
 
+/// The most recently reorged blocks, bounded so that it doesn't grow on a chain reorging often
+struct RecentReorgs {
+    keep: usize,
+    blocks: VecDeque<crate::BlockMeta>,
+}
+
+impl RecentReorgs {
+    fn new(keep: usize) -> Self {
+        Self {
+            keep,
+            blocks: VecDeque::with_capacity(keep),
+        }
+    }
+
+    fn push(&mut self, block: crate::BlockMeta) {
+        self.blocks.push_back(block);
+        while self.blocks.len() > self.keep {
+            self.blocks.pop_front();
+        }
+    }
+
+    fn latest(&self) -> Vec<crate::BlockMeta> {
+        self.blocks.iter().rev().cloned().collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use elements::hashes::Hash;
     use std::str::FromStr;
 
     #[test]
@@ -350,6 +515,23 @@ mod tests {
         assert_eq!(blocks_hash_ts[1].1, meta2.timestamp());
     }
 
+    #[test]
+    fn test_height_for_timestamp() {
+        let blocks_hash_ts: Vec<_> = [100, 200, 200, 200, 300]
+            .into_iter()
+            .map(|ts| (BlockHash::all_zeros(), ts))
+            .collect();
+        let height = |ts| height_for_timestamp(&blocks_hash_ts, ts);
+        assert_eq!(height(0), Some(0), "before genesis");
+        assert_eq!(height(100), Some(0));
+        assert_eq!(height(199), Some(0));
+        assert_eq!(height(200), Some(3), "the last of the equal timestamps");
+        assert_eq!(height(250), Some(3));
+        assert_eq!(height(300), Some(4));
+        assert_eq!(height(u32::MAX), Some(4), "after the tip");
+        assert_eq!(height_for_timestamp(&[], 100), None);
+    }
+
     #[test]
     fn test_descriptor_metrics_record_and_prune() {
         let base = Instant::now();
@@ -202,6 +202,30 @@ fn deduplicate(scripts: Vec<ScriptHash>) -> Vec<ScriptHash> {
         .collect()
 }
 
+/// Event broadcast to every `/events/blocks` stream, not filtered by script
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum BlockEvent {
+    /// The block has been indexed
+    Block(crate::BlockMeta),
+    /// The block has been rolled back
+    Reorg(crate::BlockMeta),
+}
+
+impl BlockEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BlockEvent::Block(_) => "block",
+            BlockEvent::Reorg(_) => "reorg",
+        }
+    }
+
+    pub(crate) fn block_meta(&self) -> &crate::BlockMeta {
+        match self {
+            BlockEvent::Block(meta) | BlockEvent::Reorg(meta) => meta,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
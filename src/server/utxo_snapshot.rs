@@ -0,0 +1,84 @@
+//! Export the UTXO set of a DB in a file and import it in a new DB, to start a node from the
+//! tip of another one instead of indexing from genesis, see [`crate::store::utxo_snapshot`].
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use crate::store::{
+    db::DBStore,
+    utxo_snapshot::{export_utxos, UtxoSnapshot},
+};
+
+use super::{diff, Error};
+
+/// Write the UTXO set and the block metas of the DB at `db` in `output`, returns the number of
+/// UTXOs written.
+///
+/// The DB is opened read-only, so it can be exported while a node indexes in it: the snapshot
+/// stops at the tip seen when opening. The file is written next to `output` and then renamed, so
+/// an existing file is replaced only if the export succeeds.
+pub fn export_utxos_to_file(db: &Path, output: &Path) -> Result<u64, Error> {
+    let db = diff::open(db)?;
+    let tmp_path = output.with_extension("tmp");
+    let file = File::create(&tmp_path).map_err(|e| Error::String(format!("{tmp_path:?}: {e}")))?;
+    let count = export_utxos(&db, db.salt(), &mut BufWriter::new(file))
+        .map_err(|e| Error::String(format!("export failed: {e:?}")))?;
+    std::fs::rename(&tmp_path, output).map_err(|e| Error::String(format!("{output:?}: {e}")))?;
+    Ok(count)
+}
+
+/// Import the UTXOs and the block metas of a file written by [`export_utxos_to_file`] in the
+/// DB at `db`, which is created if missing and must be empty. Returns the number of UTXOs
+/// imported.
+///
+/// The histories are not part of the snapshot, the node started on the DB knows only the
+/// transactions of the blocks it indexes after the snapshot tip.
+pub fn import_utxos_from_file(db: &Path, input: &Path) -> Result<u64, Error> {
+    let file = File::open(input).map_err(|e| Error::String(format!("{input:?}: {e}")))?;
+    let snapshot = UtxoSnapshot::read(BufReader::new(file))
+        .map_err(|e| Error::String(format!("{input:?}: {e:?}")))?;
+    let mut db =
+        DBStore::open(db, 64, false, 0).map_err(|e| Error::DBOpen(format!("{db:?}: {e:?}")))?;
+    db.set_salt(snapshot.salt())
+        .map_err(|e| Error::String(format!("{e:?}")))?;
+    snapshot
+        .import(&db)
+        .map_err(|e| Error::String(format!("import failed: {e:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+
+    use super::*;
+
+    #[test]
+    fn test_utxo_snapshot_files() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = tempdir.path().join("db");
+        let snapshot = tempdir.path().join("utxos.bin");
+        let writer = DBStore::open(&db, 64, false, 0).unwrap();
+        let salt = writer.salt();
+
+        // exported while the DB is held by a node indexing in it
+        assert_eq!(export_utxos_to_file(&db, &snapshot).unwrap(), 0);
+        drop(writer);
+        let new_db = tempdir.path().join("new_db");
+        assert_eq!(import_utxos_from_file(&new_db, &snapshot).unwrap(), 0);
+        let new_db_store = DBStore::open(&new_db, 64, false, 0).unwrap();
+        assert_eq!(new_db_store.salt(), salt);
+        assert_eq!(
+            new_db_store.hash(b"script"),
+            diff::open(&db).unwrap().hash(b"script")
+        );
+        drop(new_db_store);
+
+        assert!(matches!(
+            export_utxos_to_file(&tempdir.path().join("missing"), &snapshot),
+            Err(Error::DBOpen(_))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Derive the changes to apply to the store from a block, see [`super::Store::apply_block`]
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
+
+use elements::Txid;
+use lazy_static::lazy_static;
+
+use crate::{be, Height, OutPoint, ScriptHash, TxSeen, V};
+
+use super::{BlockMeta, Store, Utxo};
+
+lazy_static! {
+    /// Outputs spent in some blocks that have never been created in a block, so they are not
+    /// in the utxo set
+    static ref SKIP_OUTPOINTS: HashSet<OutPoint> = {
+        let outpoint =
+            |txid, vout| OutPoint::new(Txid::from_str(txid).expect("static").into(), vout);
+        let mut skip_outpoints = HashSet::new();
+
+        // policy asset emission in testnet
+        let s = "0c52d2526a5c9f00e9fb74afd15dd3caaf17c823159a514f929ae25193a43a52";
+        skip_outpoints.insert(outpoint(s, 0));
+
+        // policy asset emission in regtest
+        let s = "50cdc410c9d0d61eeacc531f52d2c70af741da33af127c364e52ac1ee7c030a5";
+        skip_outpoints.insert(outpoint(s, 0));
+
+        skip_outpoints
+    };
+}
+
+/// The arguments of [`Store::update`] and the values to index for a block
+#[derive(Debug, Default)]
+pub(crate) struct BlockChanges {
+    pub(crate) txids: Vec<be::Txid>,
+    pub(crate) utxo_spent: Vec<(u32, OutPoint, be::Txid)>,
+    pub(crate) history_map: BTreeMap<ScriptHash, Vec<TxSeen>>,
+    pub(crate) utxo_created: BTreeMap<OutPoint, Utxo>,
+    /// Txids of the coinbase transactions, indexed apart from the script history
+    pub(crate) coinbase_txids: Vec<be::Txid>,
+    pub(crate) values_created: BTreeMap<OutPoint, u64>,
+    pub(crate) values_spent: BTreeMap<OutPoint, u64>,
+    pub(crate) assets_created: BTreeMap<OutPoint, elements::AssetId>,
+    /// Assets of the outputs spent by the inputs, keyed like `values_spent`
+    pub(crate) assets_spent: BTreeMap<OutPoint, elements::AssetId>,
+    /// Inputs spending outputs of previous blocks, with the spent output, their value must be
+    /// read from the store
+    pub(crate) spent_from_previous_blocks: Vec<(OutPoint, OutPoint)>,
+}
+
+impl BlockChanges {
+    /// Parse `block`, values and assets are collected only if `index_values` is set.
+    ///
+    /// Outputs spent in the same block are resolved here, the others are left in `utxo_spent`
+    /// for [`Store::update`] to resolve them from the utxo set.
+    pub(crate) fn new<S: Store + ?Sized>(
+        store: &S,
+        block: &be::Block,
+        block_meta: &BlockMeta,
+        index_values: bool,
+    ) -> Self {
+        let mut changes = BlockChanges::default();
+
+        // The bitcoin genesis coinbase output is not in the UTXO set, on elements the genesis
+        // coinbase may carry spendable initial free coins instead
+        let family = block.family();
+        let is_genesis = family == be::Family::Bitcoin && block_meta.is_genesis(family);
+
+        for tx in block.transactions_iter() {
+            let txid = tx.txid();
+            changes.txids.push(txid);
+            let skip_utxos = is_genesis && tx.is_coinbase();
+            for (j, output) in tx.outputs_iter().enumerate() {
+                let utxo = |script_hash| Utxo {
+                    script_hash,
+                    value: output.value_commitment(),
+                    asset: output.asset_commitment(),
+                };
+                if index_values && !output.skip_utxo() {
+                    if let Some(value) = output.value() {
+                        changes
+                            .values_created
+                            .insert(OutPoint::new(txid, j as u32), value);
+                    }
+                    if let Some(asset) = output.asset() {
+                        changes
+                            .assets_created
+                            .insert(OutPoint::new(txid, j as u32), asset);
+                    }
+                }
+                if !output.skip_utxo() && !skip_utxos {
+                    // Use an empty-bytes hash as a placeholder: outputs that are spendable
+                    // but non-standard (e.g. bare OP_TRUE) won't pass skip_indexing() below,
+                    // so their real script hash never overwrites this. When spent, the
+                    // spending tx lands under this dummy hash that no wallet will ever query.
+                    let out_point = OutPoint::new(txid, j as u32);
+                    changes
+                        .utxo_created
+                        .insert(out_point, utxo(store.hash(b"")));
+                }
+                if output.skip_indexing() {
+                    continue;
+                }
+                let script_hash = store.hash(output.script_pubkey_bytes());
+                let el = changes.history_map.entry(script_hash).or_insert(vec![]);
+                el.push(TxSeen::new(txid, block_meta.height, V::Vout(j as u32)));
+
+                if skip_utxos {
+                    continue;
+                }
+                let out_point = OutPoint::new(txid, j as u32);
+                log::debug!("inserting {out_point}");
+                changes.utxo_created.insert(out_point, utxo(script_hash));
+            }
+
+            if tx.is_coinbase() {
+                changes.coinbase_txids.push(txid);
+            } else {
+                for (vin, input) in tx.inputs_iter().enumerate() {
+                    if input.skip_indexing() {
+                        continue;
+                    }
+                    let previous_output = input.previous_output();
+                    if index_values {
+                        let input_key = OutPoint::new(txid, vin as u32);
+                        if let Some(asset) = changes.assets_created.get(&previous_output) {
+                            changes.assets_spent.insert(input_key, *asset);
+                        }
+                        match changes.values_created.get(&previous_output) {
+                            Some(value) => {
+                                changes.values_spent.insert(input_key, *value);
+                            }
+                            None => changes
+                                .spent_from_previous_blocks
+                                .push((input_key, previous_output)),
+                        }
+                    }
+                    match changes.utxo_created.remove(&previous_output) {
+                        Some(Utxo { script_hash, .. }) => {
+                            // also the spending tx must be indexed
+                            let el = changes.history_map.entry(script_hash).or_insert(vec![]);
+                            el.push(TxSeen::new(txid, block_meta.height, V::Vin(vin as u32)));
+                        }
+                        None => {
+                            log::debug!("removing {}", &previous_output);
+                            if !SKIP_OUTPOINTS.contains(&previous_output) {
+                                changes.utxo_spent.push((vin as u32, previous_output, txid))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    /// The asset history entries at `height`: an output entry for every created output with an
+    /// explicit asset and an input entry for every input spending one
+    pub(crate) fn asset_history(&self, height: Height) -> BTreeMap<elements::AssetId, Vec<TxSeen>> {
+        let mut history: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (outpoint, asset) in self.assets_created.iter() {
+            history.entry(*asset).or_default().push(TxSeen::new(
+                outpoint.txid,
+                height,
+                V::Vout(outpoint.vout),
+            ));
+        }
+        for (input, asset) in self.assets_spent.iter() {
+            history.entry(*asset).or_default().push(TxSeen::new(
+                input.txid,
+                height,
+                V::Vin(input.vout),
+            ));
+        }
+        history
+    }
+}
@@ -1,11 +1,14 @@
 use anyhow::{Context, Result};
 use elements::{
-    encode::Encodable,
+    encode::{Decodable, Encodable},
     hashes::Hash,
     secp256k1_zkp::rand::{thread_rng, Rng},
     BlockHash,
 };
-use fxhash::FxHasher;
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use rocksdb::{
     BlockBasedOptions, BoundColumnFamily, Cache, DBCompressionType, DBPinnableSlice, MergeOperands,
     Options, DB,
@@ -13,7 +16,11 @@ use rocksdb::{
 
 use crate::V;
 
-use super::reorg_data::ReorgData;
+use super::{
+    hll::HyperLogLog,
+    reorg_data::ReorgData,
+    siphash::{Salt, SipHasher13},
+};
 use prefix_uvarint::PrefixVarInt;
 use std::{
     collections::BTreeMap,
@@ -21,13 +28,17 @@ use std::{
     path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 
 use crate::{
-    error_panic,
-    store::{BlockMeta, Store, TxSeen},
+    store::{
+        apply_duplicate_entries_policy, apply_future_height_policy, check_contiguous_heights,
+        height_to_prune, is_above_tip, metadata, paginate_history, split_created_utxos, BlockMeta,
+        BlockSummary, Commitments, DuplicateEntriesPolicy, FutureHeightPolicy, HistoryPage,
+        ReorgSummary, Store, StoreError, StoreStats, TxSeen, Utxo,
+    },
     Height, OutPoint, ScriptHash,
 };
 
@@ -36,7 +47,7 @@ use crate::{
 #[derive(Debug)]
 pub struct DBStore {
     db: DB,
-    salt: u64,
+    salt: Salt,
 
     /// Whether we are in Initial Block Download mode.
     /// During initial block download we skip reorg data writes, since reorgs are
@@ -45,6 +56,25 @@ pub struct DBStore {
 
     /// Number of recent block heights to keep reorg data for. Older reorg data is automatically deleted.
     reorg_data_keep_heights: u32,
+
+    /// What to do with history entries above the height of the block being indexed
+    future_height_policy: FutureHeightPolicy,
+    duplicate_entries_policy: DuplicateEntriesPolicy,
+
+    /// Whether the heights spending the outputs are written in `SPENT_HEIGHTS_CF`
+    index_spent_heights: bool,
+
+    /// Estimator of the number of distinct txids, persisted in `OTHER_CF`
+    txids: Mutex<HyperLogLog>,
+
+    /// Whether the mempool transactions are written in `MEMPOOL_CF`
+    persist_mempool: bool,
+
+    /// Number of recent block heights to keep the summary for in `SUMMARY_CF`, all if None
+    block_summary_keep_heights: Option<u32>,
+
+    /// Whether the DB is opened with [`DBStore::open_read_only`], every write fails
+    read_only: bool,
 }
 
 // Can txid be indexed by u32? At the time of writing (2025-02-06) there are about 1B txs on mainnet, so it's possible to have u32 -> txid (u32 is 4B).
@@ -67,12 +97,84 @@ const HASHES_CF: &str = "hashesv2"; // Height -> (BlockHash, Timestamp) // This
 // Reorg data for each block to enable rollback on chain reorganization
 const REORG_CF: &str = "reorg"; // Height -> ReorgData (serialized)
 
-const COLUMN_FAMILIES: &[&str] = &[UTXO_CF, HISTORY_CF, OTHER_CF, HASHES_CF, REORG_CF];
+// Secondary index of the coinbase transactions, they are not part of any script history
+const COINBASE_CF: &str = "coinbase"; // Height -> Vec<Txid>
+
+// Mempool transactions, written only when the mempool persistence is enabled
+const MEMPOOL_CF: &str = "mempool"; // Txid -> MempoolTx (serialized)
+
+// Summary of each indexed block, pruned independently of the reorg data
+const SUMMARY_CF: &str = "summary"; // Height -> BlockSummary (serialized)
+
+// Explicit values of the outputs, written only when values indexing is enabled
+const VALUES_CF: &str = "values"; // OutPoint -> u64
+
+// Explicit values spent by the inputs, written only when values indexing is enabled
+const SPENT_VALUES_CF: &str = "spent_values"; // (Txid, vin) -> u64
+
+// Explicit assets of the elements outputs, written only when values indexing is enabled
+const ASSETS_CF: &str = "assets"; // OutPoint -> AssetId
+
+// Transactions creating or spending outputs with an explicit asset, written only when values
+// indexing is enabled, the key is the whole entry so that the history of an asset is a range
+const ASSET_HISTORY_CF: &str = "asset_history"; // (AssetId, Height, Txid, V) -> ()
+
+// Assets with asset history entries at each height, to remove them on reorg
+const ASSET_HEIGHTS_CF: &str = "asset_heights"; // Height -> Vec<AssetId>
+
+// Height of the indexed blocks by hash, entries of hashes replaced at their height are ignored
+const BLOCK_HEIGHTS_CF: &str = "block_heights"; // BlockHash -> Height
+
+// Arbitrary annotations written with Store::write_metadata, by operators or by waterfalls itself
+const METADATA_CF: &str = "metadata"; // String -> Vec<u8>
+
+// Secondary index of UTXO_CF, the unspent outputs of a script are read with a prefix iterator
+const UTXO_BY_SCRIPT_CF: &str = "utxo_by_script"; // (ScriptHash, OutPoint) -> ()
+
+// Height of the block spending each output, to tell when an output left the UTXO set. Written
+// only with `--index-spent-heights`.
+const SPENT_HEIGHTS_CF: &str = "spent_heights"; // OutPoint -> Height
+
+// Value and asset commitments of the unspent outputs, deleted with them from UTXO_CF
+const COMMITMENTS_CF: &str = "commitments"; // OutPoint -> (confidential::Value, confidential::Asset)
+
+const COLUMN_FAMILIES: &[&str] = &[
+    UTXO_CF,
+    HISTORY_CF,
+    OTHER_CF,
+    HASHES_CF,
+    REORG_CF,
+    COINBASE_CF,
+    MEMPOOL_CF,
+    SUMMARY_CF,
+    VALUES_CF,
+    SPENT_VALUES_CF,
+    ASSETS_CF,
+    ASSET_HISTORY_CF,
+    ASSET_HEIGHTS_CF,
+    BLOCK_HEIGHTS_CF,
+    METADATA_CF,
+    UTXO_BY_SCRIPT_CF,
+    SPENT_HEIGHTS_CF,
+    COMMITMENTS_CF,
+];
 
 // height key for indexed blocks
 // const INDEXED_KEY: &[u8] = b"I";
 // height key for salting
 const SALT_KEY: &[u8] = b"S";
+// layout of the column families and script hash function, a DB with a different one must be
+// indexed again. Compared to the DBs without schema version, version 2 hashes the scripts with
+// SipHash-1-3 keyed with a 128-bit salt instead of FxHash fed with a 64-bit one, and adds the
+// METADATA_CF, UTXO_BY_SCRIPT_CF, SPENT_HEIGHTS_CF and COMMITMENTS_CF.
+const DB_SCHEMA_VERSION: &str = "2";
+
+// key for the distinct txids estimator
+const TXIDS_HLL_KEY: &[u8] = b"H";
+
+// The txids estimator is persisted every this many blocks and when the store is dropped, after
+// a crash the transactions of the blocks indexed since are missing from the estimate
+const TXIDS_HLL_PERSIST_EVERY: Height = 100;
 
 const VEC_TX_SEEN_MAX_SIZE: usize = 50; // 32 bytes (txid) + 9 bytes (height) + 9 bytes (v) (most of the time height/v is much less)
 const VEC_TX_SEEN_MIN_SIZE: usize = 34; // 32 bytes (txid) + 1 byte (height) + 1 byte (v)
@@ -203,14 +305,90 @@ impl DBStore {
         )
         .with_context(|| format!("failed to open DB: {}", path.display()))?;
         log::info!("DB opened at path: {}", path.display());
+        init_schema_version(&db)?;
         let salt = get_or_init_salt(&db)?;
-        let store = DBStore {
+        let txids = load_txids_hll(&db)?;
+        let store = Self::with_db(db, salt, txids, reorg_data_keep_heights);
+        store.init_block_heights()?;
+        Ok(store)
+    }
+
+    /// Open the DB at `path` without taking its lock, so that it can be served by more processes
+    /// while another one indexes in it.
+    ///
+    /// The DB must have been initialized by [`DBStore::open`]. The blocks indexed by the writer
+    /// after the opening are not seen, and every write returns an error. Following the writer
+    /// with a RocksDB secondary instance is left for later.
+    pub fn open_read_only(path: &Path, shared_db_cache_mb: u64) -> Result<Self> {
+        let db = rocksdb::DB::open_cf_descriptors_read_only(
+            &Options::default(),
+            path,
+            Self::create_cf_descriptors(shared_db_cache_mb),
+            false,
+        )
+        .with_context(|| format!("failed to open DB read-only: {}", path.display()))?;
+        log::info!("DB opened read-only at path: {}", path.display());
+        let salt = read_salt(&db)?
+            .ok_or_else(|| anyhow::anyhow!("DB not initialized: {}", path.display()))?;
+        if !check_schema_version(&db)? {
+            anyhow::bail!("DB without schema version: {}", path.display());
+        }
+        let txids = load_txids_hll(&db)?;
+        let mut store = Self::with_db(db, salt, txids, 0);
+        store.read_only = true;
+        Ok(store)
+    }
+
+    fn with_db(db: DB, salt: Salt, txids: HyperLogLog, reorg_data_keep_heights: u32) -> Self {
+        DBStore {
             db,
             salt,
             ibd: AtomicBool::new(true),
             reorg_data_keep_heights,
-        };
-        Ok(store)
+            future_height_policy: FutureHeightPolicy::default(),
+            duplicate_entries_policy: DuplicateEntriesPolicy::default(),
+            index_spent_heights: false,
+            txids: Mutex::new(txids),
+            persist_mempool: false,
+            block_summary_keep_heights: None,
+            read_only: false,
+        }
+    }
+
+    pub(crate) fn salt(&self) -> Salt {
+        self.salt
+    }
+
+    /// Hash scripts with `salt` from now on, for example to import script hashes computed by
+    /// another store. Only allowed while the DB is empty.
+    pub(crate) fn set_salt(&mut self, salt: Salt) -> Result<()> {
+        if self.tip_block_meta()?.is_some() || self.iter_utxos().next().is_some() {
+            anyhow::bail!("the salt of a non-empty DB can't be changed");
+        }
+        self.check_writable()?;
+        self.db.put_cf(&self.other_cf(), SALT_KEY, salt)?;
+        self.salt = salt;
+        Ok(())
+    }
+
+    pub(crate) fn set_persist_mempool(&mut self, persist_mempool: bool) {
+        self.persist_mempool = persist_mempool;
+    }
+
+    pub(crate) fn set_block_summary_keep_heights(&mut self, keep_heights: Option<u32>) {
+        self.block_summary_keep_heights = keep_heights;
+    }
+
+    pub(crate) fn set_future_height_policy(&mut self, policy: FutureHeightPolicy) {
+        self.future_height_policy = policy;
+    }
+
+    pub(crate) fn set_duplicate_entries_policy(&mut self, policy: DuplicateEntriesPolicy) {
+        self.duplicate_entries_policy = policy;
+    }
+
+    pub(crate) fn set_index_spent_heights(&mut self, index_spent_heights: bool) {
+        self.index_spent_heights = index_spent_heights;
     }
 
     fn utxo_cf(&self) -> Arc<BoundColumnFamily> {
@@ -225,10 +403,82 @@ impl DBStore {
         self.db.cf_handle(HASHES_CF).expect("missing HASHES_CF")
     }
 
-    fn hasher(&self) -> FxHasher {
-        let mut hasher = FxHasher::default();
-        hasher.write_u64(self.salt);
-        hasher
+    fn mempool_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(MEMPOOL_CF).expect("missing MEMPOOL_CF")
+    }
+
+    fn coinbase_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(COINBASE_CF).expect("missing COINBASE_CF")
+    }
+
+    fn summary_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(SUMMARY_CF).expect("missing SUMMARY_CF")
+    }
+
+    fn values_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(VALUES_CF).expect("missing VALUES_CF")
+    }
+
+    fn spent_values_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(SPENT_VALUES_CF)
+            .expect("missing SPENT_VALUES_CF")
+    }
+
+    fn assets_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(ASSETS_CF).expect("missing ASSETS_CF")
+    }
+
+    fn asset_history_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(ASSET_HISTORY_CF)
+            .expect("missing ASSET_HISTORY_CF")
+    }
+
+    fn asset_heights_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(ASSET_HEIGHTS_CF)
+            .expect("missing ASSET_HEIGHTS_CF")
+    }
+
+    fn block_heights_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(BLOCK_HEIGHTS_CF)
+            .expect("missing BLOCK_HEIGHTS_CF")
+    }
+
+    fn metadata_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(METADATA_CF).expect("missing METADATA_CF")
+    }
+
+    fn utxo_by_script_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(UTXO_BY_SCRIPT_CF)
+            .expect("missing UTXO_BY_SCRIPT_CF")
+    }
+
+    fn spent_heights_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(SPENT_HEIGHTS_CF)
+            .expect("missing SPENT_HEIGHTS_CF")
+    }
+
+    fn commitments_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db
+            .cf_handle(COMMITMENTS_CF)
+            .expect("missing COMMITMENTS_CF")
+    }
+
+    fn other_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(OTHER_CF).expect("missing OTHER_CF")
+    }
+
+    fn reorg_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(REORG_CF).expect("missing REORG_CF")
+    }
+
+    fn hasher(&self) -> SipHasher13 {
+        SipHasher13::new_with_salt(&self.salt)
     }
     /// Add block hash and timestamp to an existing batch (does not write to DB).
     fn set_hash_ts_batch(&self, batch: &mut rocksdb::WriteBatch, meta: &BlockMeta) {
@@ -236,6 +486,51 @@ impl DBStore {
         buffer.extend(meta.hash().as_byte_array());
         buffer.extend(&meta.timestamp().to_be_bytes());
         batch.put_cf(&self.hashes_cf(), meta.height().to_be_bytes(), buffer);
+        batch.put_cf(
+            &self.block_heights_cf(),
+            meta.hash().as_byte_array(),
+            meta.height().to_be_bytes(),
+        );
+    }
+
+    /// Add to `batch` the removal of the block meta at `height`
+    fn delete_block_meta_batch(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        height: Height,
+    ) -> Result<()> {
+        let key = height.to_be_bytes();
+        if let Some(value) = self.db.get_pinned_cf(&self.hashes_cf(), key)? {
+            let meta = block_meta_from_kv(&key, &value);
+            batch.delete_cf(&self.block_heights_cf(), meta.hash().as_byte_array());
+        }
+        batch.delete_cf(&self.hashes_cf(), key);
+        Ok(())
+    }
+
+    /// Fill `BLOCK_HEIGHTS_CF` for the blocks indexed before it existed
+    fn init_block_heights(&self) -> Result<()> {
+        if self
+            .db
+            .iterator_cf(&self.block_heights_cf(), rocksdb::IteratorMode::Start)
+            .next()
+            .is_some()
+        {
+            return Ok(());
+        }
+        let mut batch = rocksdb::WriteBatch::default();
+        for meta in self.iter_hash_ts() {
+            batch.put_cf(
+                &self.block_heights_cf(),
+                meta.hash().as_byte_array(),
+                meta.height().to_be_bytes(),
+            );
+        }
+        if !batch.is_empty() {
+            log::info!("indexing the heights of {} block hashes", batch.len());
+            self.write(batch)?;
+        }
+        Ok(())
     }
 
     fn insert_utxos<'a, I>(&self, batch: &mut rocksdb::WriteBatch, adds: I) -> Result<()>
@@ -245,54 +540,96 @@ impl DBStore {
     {
         let iter = adds.into_iter();
         let cf = self.utxo_cf();
+        let by_script_cf = self.utxo_by_script_cf();
         let mut key_buf = vec![0u8; 36];
         for (outpoint, script_hash) in iter {
             key_buf.clear();
             outpoint.consensus_encode(&mut key_buf)?;
             let val = script_hash.to_be_bytes();
             batch.put_cf(&cf, &key_buf, val);
+            batch.put_cf(
+                &by_script_cf,
+                utxo_by_script_key(*script_hash, &key_buf),
+                [],
+            );
         }
 
         Ok(())
     }
 
-    /// Look up UTXOs and return their script hashes, panicking if any UTXO doesn't exist.
+    /// Look up UTXOs and return their script hashes, failing with [`StoreError::MissingUtxo`]
+    /// if any UTXO doesn't exist.
     /// This is a read-only operation.
     fn get_utxos_for_spending(
         &self,
         outpoints: &[OutPoint],
     ) -> Result<Vec<(OutPoint, ScriptHash)>> {
-        let result: Vec<ScriptHash> = self
-            .get_utxos(outpoints)?
+        let script_hashes = self.multi_get_utxos(outpoints)?;
+        outpoints
             .iter()
-            .enumerate()
-            .map(|(i, e)| {
-                e.unwrap_or_else(|| {
-                    error_panic!(
-                        "every utxo must exist when spent, can't find {}",
-                        outpoints[i]
-                    );
-                })
+            .zip(script_hashes)
+            .map(|(outpoint, script_hash)| {
+                Ok((
+                    *outpoint,
+                    script_hash.ok_or(StoreError::MissingUtxo(*outpoint))?,
+                ))
             })
-            .collect();
-        Ok(Vec::from_iter(
-            outpoints.iter().cloned().zip(result.iter().cloned()),
-        ))
+            .collect()
     }
 
-    /// Add UTXO deletions to an existing batch (does not write to DB).
-    fn delete_utxos_batch<'a, I>(&self, batch: &mut rocksdb::WriteBatch, outpoints: I) -> Result<()>
+    /// Add UTXO deletions to an existing batch (does not write to DB). The script hashes are
+    /// needed to delete the entries of `UTXO_BY_SCRIPT_CF` too.
+    fn delete_utxos_batch<'a, I>(&self, batch: &mut rocksdb::WriteBatch, utxos: I) -> Result<()>
     where
-        I: IntoIterator<Item = &'a OutPoint>,
+        I: IntoIterator<Item = (&'a OutPoint, &'a ScriptHash)>,
     {
         let cf = self.utxo_cf();
+        let by_script_cf = self.utxo_by_script_cf();
+        let commitments_cf = self.commitments_cf();
         let mut key_buf: Vec<u8> = vec![0u8; 36];
 
-        for outpoint in outpoints {
+        for (outpoint, script_hash) in utxos {
             key_buf.clear();
             outpoint.consensus_encode(&mut key_buf)?;
             batch.delete_cf(&cf, &key_buf);
+            batch.delete_cf(&by_script_cf, utxo_by_script_key(*script_hash, &key_buf));
+            batch.delete_cf(&commitments_cf, &key_buf);
+        }
+        Ok(())
+    }
+
+    fn insert_commitments(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        commitments: &[(OutPoint, Commitments)],
+    ) -> Result<()> {
+        let cf = self.commitments_cf();
+        for (outpoint, (value, asset)) in commitments {
+            let mut bytes = Vec::with_capacity(66);
+            value.consensus_encode(&mut bytes)?;
+            asset.consensus_encode(&mut bytes)?;
+            batch.put_cf(&cf, serialize_outpoint(outpoint), bytes);
+        }
+        Ok(())
+    }
+
+    /// Add the deletions of the asset history entries at `height` to an existing batch
+    fn delete_asset_history_batch(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        height: Height,
+    ) -> Result<()> {
+        let key = height.to_be_bytes();
+        let Some(assets) = self.db.get_pinned_cf(&self.asset_heights_cf(), key)? else {
+            return Ok(());
+        };
+        let cf = self.asset_history_cf();
+        for asset in assets.chunks(32) {
+            let from = [asset, &key[..]].concat();
+            let to = [asset, &(height + 1).to_be_bytes()[..]].concat();
+            batch.delete_range_cf(&cf, from, to);
         }
+        batch.delete_cf(&self.asset_heights_cf(), key);
         Ok(())
     }
 
@@ -302,8 +639,8 @@ impl DBStore {
     fn remove_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<(OutPoint, ScriptHash)>> {
         let result = self.get_utxos_for_spending(outpoints)?;
 
-        let mut batch = rocksdb::WriteBatch::with_capacity_bytes(outpoints.len() * 36);
-        self.delete_utxos_batch(&mut batch, outpoints.iter())?;
+        let mut batch = rocksdb::WriteBatch::with_capacity_bytes(outpoints.len() * 80);
+        self.delete_utxos_batch(&mut batch, result.iter().map(|(o, s)| (o, s)))?;
         self.write(batch)?;
 
         Ok(result)
@@ -387,7 +724,7 @@ impl DBStore {
         Ok(())
     }
 
-    pub(crate) fn stats(&self) -> Option<String> {
+    pub(crate) fn rocksdb_stats(&self) -> Option<String> {
         let mut result = String::new();
 
         // Column family specific information
@@ -492,24 +829,74 @@ impl DBStore {
         Ok(())
     }
 
+    /// Look up the given outpoints in the UTXO set without limits on their number
+    fn multi_get_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<ScriptHash>>> {
+        // sorted like in `raw_history_multi_get`, see the utxo_multi_get bench
+        let cf = self.utxo_cf();
+        let mut indexed_keys: Vec<_> = outpoints
+            .iter()
+            .map(serialize_outpoint)
+            .enumerate()
+            .collect();
+        indexed_keys.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
+        let sorted_results =
+            self.db
+                .batched_multi_get_cf(&cf, indexed_keys.iter().map(|(_, key)| key), true);
+        let mut result = vec![None; outpoints.len()];
+        for ((index, _), db_result) in indexed_keys.into_iter().zip(sorted_results) {
+            result[index] = db_result?
+                .map(|e| -> Result<ScriptHash> { Ok(u64::from_be_bytes(e.as_ref().try_into()?)) })
+                .transpose()?;
+        }
+        Ok(result)
+    }
+
+    fn multi_get_values(
+        &self,
+        cf: &Arc<BoundColumnFamily>,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<Option<u64>>> {
+        let keys: Vec<_> = outpoints.iter().map(serialize_outpoint).collect();
+        let mut result = Vec::with_capacity(outpoints.len());
+        for value in self.db.batched_multi_get_cf(cf, keys.iter(), false) {
+            let value = match value? {
+                Some(value) => Some(u64::from_be_bytes(value.as_ref().try_into()?)),
+                None => None,
+            };
+            result.push(value);
+        }
+        Ok(result)
+    }
+
     fn write(&self, batch: rocksdb::WriteBatch) -> Result<()> {
+        self.check_writable()?;
         self.db.write(batch)?;
         Ok(())
     }
 
-    fn _reorg(&self, height: Height) -> Result<()> {
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("the DB is opened read-only");
+        }
+        Ok(())
+    }
+
+    fn _reorg(&self, height: Height) -> Result<ReorgSummary> {
         log::warn!("reorg: reading reorg data for height {}", height);
 
         // Read reorg data from database
-        let reorg_cf = self.db.cf_handle(REORG_CF).expect("missing REORG_CF");
+        let reorg_cf = self.reorg_cf();
         let reorg_bytes = self
             .db
             .get_cf(&reorg_cf, height.to_be_bytes())?
             .ok_or_else(|| {
                 anyhow::anyhow!(
-                    "No reorg data found for height {}. This likely means the server restarted \
-                    after indexing a block that was later reorged. A reindex may be required.",
-                    height
+                    "No reorg data found for height {}. Reorg data is kept for the last {} \
+                    heights and isn't written during the initial block download, this likely \
+                    means the server restarted after indexing a block that was later reorged. \
+                    A reindex may be required.",
+                    height,
+                    self.reorg_data_keep_heights
                 )
             })?;
 
@@ -524,6 +911,13 @@ impl DBStore {
             reorg_data.history.len()
         );
 
+        let summary = ReorgSummary::new(
+            height,
+            &reorg_data.spent,
+            &reorg_data.history,
+            &reorg_data.utxos_created,
+        );
+
         let mut batch = rocksdb::WriteBatch::default();
 
         // Restore UTXOs that were spent in the reorged block
@@ -534,10 +928,19 @@ impl DBStore {
                 .iter()
                 .map(|(outpoint, script_hash)| (outpoint, script_hash)),
         )?;
+        self.insert_commitments(&mut batch, &reorg_data.spent_commitments)?;
+
+        // The restored UTXOs are not spent anymore
+        if self.index_spent_heights {
+            let spent_heights_cf = self.spent_heights_cf();
+            for (outpoint, _) in reorg_data.spent.iter() {
+                batch.delete_cf(&spent_heights_cf, serialize_outpoint(outpoint));
+            }
+        }
 
         // Remove UTXOs that were created in the reorged block
         if !reorg_data.utxos_created.is_empty() {
-            self.delete_utxos_batch(&mut batch, reorg_data.utxos_created.keys())?;
+            self.delete_utxos_batch(&mut batch, reorg_data.utxos_created.iter())?;
         }
 
         // Remove history entries that were added in the reorged block
@@ -545,9 +948,16 @@ impl DBStore {
             self.remove_history_entries_batch(&mut batch, &reorg_data.history)?;
         }
 
+        self.delete_asset_history_batch(&mut batch, height)?;
+
         // Delete the reorg data entry from the database since it's been applied
         batch.delete_cf(&reorg_cf, height.to_be_bytes());
 
+        // The block is no longer indexed
+        self.delete_block_meta_batch(&mut batch, height)?;
+        batch.delete_cf(&self.coinbase_cf(), height.to_be_bytes());
+        batch.delete_cf(&self.summary_cf(), height.to_be_bytes());
+
         self.write(batch)?;
 
         log::info!(
@@ -555,7 +965,7 @@ impl DBStore {
             height
         );
 
-        Ok(())
+        Ok(summary)
     }
 }
 
@@ -583,28 +993,20 @@ impl Store for DBStore {
                 .iterator_cf_opt(&self.hashes_cf(), opts, mode)
                 .map(|kv| {
                     let kv = kv.expect("iterator failed");
-                    let height = u32::from_be_bytes((&kv.0[..]).try_into().expect("schema"));
-                    let hash = BlockHash::from_slice(&kv.1[..32]).expect("schema");
-                    let ts = u32::from_be_bytes((&kv.1[32..]).try_into().expect("schema"));
-                    BlockMeta::new(height, hash, ts)
+                    block_meta_from_kv(&kv.0, &kv.1)
                 }),
         )
     }
 
+    fn count_indexed_blocks(&self) -> Result<u64> {
+        // blocks are indexed from genesis without gaps, see `preload::headers`
+        Ok(self
+            .tip_block_meta()?
+            .map_or(0, |meta| meta.height() as u64 + 1))
+    }
+
     fn get_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<ScriptHash>>> {
-        let cf = self.utxo_cf();
-        let keys: Vec<_> = outpoints.iter().map(serialize_outpoint).collect();
-        let db_results = self.db.batched_multi_get_cf(&cf, keys.iter(), false);
-        let result: Vec<_> = db_results
-            .into_iter()
-            .map(|e| {
-                e.unwrap().map(|e| {
-                    let bytes = e.as_ref().try_into().unwrap();
-                    u64::from_be_bytes(bytes)
-                })
-            })
-            .collect();
-        Ok(result)
+        self.multi_get_utxos(outpoints)
     }
 
     /// get the block heights where the given scripts hash have been seen
@@ -628,6 +1030,68 @@ impl Store for DBStore {
         Ok(result)
     }
 
+    fn get_history_from(
+        &self,
+        scripts: &[ScriptHash],
+        min_height: Height,
+    ) -> Result<Vec<Vec<TxSeen>>> {
+        let timer = crate::WATERFALLS_DB_HISTORY_HISTOGRAM
+            .with_label_values(&["from"])
+            .start_timer();
+
+        let db_results = self.raw_history_multi_get(scripts)?;
+        let mut result = Vec::with_capacity(scripts.len());
+        for db_result in db_results {
+            let mut txs_seen = vec![];
+            if let Some(e) = db_result {
+                // entries are variable length so they must be decoded anyway, but the ones below
+                // `min_height` are not collected
+                for tx_seen in TxSeenDecoder::new(&e) {
+                    let tx_seen = tx_seen?;
+                    if tx_seen.height >= min_height {
+                        txs_seen.push(tx_seen);
+                    }
+                }
+            }
+            result.push(txs_seen);
+        }
+        timer.observe_duration();
+        Ok(result)
+    }
+
+    fn get_history_page(
+        &self,
+        script: ScriptHash,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)> {
+        let timer = crate::WATERFALLS_DB_HISTORY_HISTOGRAM
+            .with_label_values(&["page"])
+            .start_timer();
+
+        let Some(value) = self.raw_history_multi_get(&[script])?.pop().flatten() else {
+            return Ok((vec![], None));
+        };
+        // all the entries of a script are in a single value so there is nothing to seek: the
+        // whole value is still read from the DB, the entries below `from_height` are decoded and
+        // skipped, the ones after the page not decoded
+        let from_height = page.from_height.unwrap_or(0);
+        let entries = TxSeenDecoder::new(&value[..])
+            .filter(|e| !matches!(e, Ok(e) if e.height < from_height));
+        let result = paginate_history(entries, page.limit);
+
+        timer.observe_duration();
+        result
+    }
+
+    fn stream_history(&self, script: ScriptHash) -> BoxStream<'static, Result<TxSeen>> {
+        // all the entries of a script are in a single value, they are decoded lazily
+        match self.db.get_cf(&self.history_cf(), script.to_be_bytes()) {
+            Ok(Some(bytes)) => stream::iter(TxSeenDecoder::new(bytes)).boxed(),
+            Ok(None) => stream::empty().boxed(),
+            Err(e) => stream::once(async move { Err(e.into()) }).boxed(),
+        }
+    }
+
     fn has_history(&self, scripts: &[ScriptHash]) -> Result<Vec<bool>> {
         let timer = crate::WATERFALLS_DB_HISTORY_HISTOGRAM
             .with_label_values(&["all"])
@@ -648,13 +1112,34 @@ impl Store for DBStore {
         block_meta: &BlockMeta,
         utxo_spent: Vec<(u32, OutPoint, crate::be::Txid)>,
         history_map: BTreeMap<ScriptHash, Vec<TxSeen>>,
-        utxo_created: BTreeMap<OutPoint, ScriptHash>,
+        utxo_created: BTreeMap<OutPoint, Utxo>,
     ) -> Result<Vec<ScriptHash>> {
+        let key = block_meta.height().to_be_bytes();
+        let indexed = self.db.get_pinned_cf(&self.hashes_cf(), key)?;
+        if indexed.is_some_and(|value| block_meta_from_kv(&key, &value).hash() == block_meta.hash())
+        {
+            log::warn!("block {} already indexed, skipping it", block_meta.hash());
+            return Ok(vec![]);
+        }
         let mut history_map = history_map;
+        apply_future_height_policy(self.future_height_policy, block_meta, &mut history_map)?;
+        apply_duplicate_entries_policy(self.duplicate_entries_policy, &mut history_map);
+        let (utxo_created, commitments) = split_created_utxos(utxo_created);
 
         // First, read the script hashes for spent UTXOs (read-only operation)
         let only_outpoints: Vec<_> = utxo_spent.iter().map(|e| e.1).collect();
         let outpoint_script_hashes = self.get_utxos_for_spending(&only_outpoints)?;
+        // deleted with the spent outputs, read only to restore them on reorg
+        let ibd = self.ibd.load(Ordering::Relaxed);
+        let spent_commitments = if ibd {
+            vec![]
+        } else {
+            only_outpoints
+                .iter()
+                .zip(self.get_commitments(&only_outpoints)?)
+                .filter_map(|(outpoint, commitments)| Some((*outpoint, commitments?)))
+                .collect()
+        };
 
         // Build the history entries for spending transactions
         let script_hashes = outpoint_script_hashes.iter().map(|e| e.1);
@@ -664,34 +1149,75 @@ impl Store for DBStore {
         }
 
         let changed_script_hashes = history_map.keys().copied().collect::<Vec<_>>();
+        let summary = BlockSummary::new(only_outpoints.len(), &history_map, utxo_created.len());
+
+        let txids_hll = {
+            let mut txids = self.txids.lock().unwrap();
+            for tx_seen in history_map.values().flatten() {
+                txids.insert_txid(&tx_seen.txid);
+            }
+            let persist = block_meta.height() % TXIDS_HLL_PERSIST_EVERY == 0;
+            persist.then(|| txids.to_bytes())
+        };
 
         // Create a single batch for ALL writes (atomic operation)
         // This ensures that either all data is written or none, preventing
         // inconsistent state if the process is killed mid-update.
         let history_size = estimate_history_size(&history_map);
-        let utxo_delete_size = only_outpoints.len() * 36;
-        let utxo_create_size = utxo_created.len() * 44;
+        // outpoint keys in UTXO_CF, COMMITMENTS_CF and SPENT_HEIGHTS_CF, (script hash, outpoint)
+        // keys in UTXO_BY_SCRIPT_CF
+        let utxo_delete_size = only_outpoints.len() * 160;
+        let utxo_create_size = utxo_created.len() * 88;
+        let commitments_size = commitments.len() * (36 + 66);
         let hash_ts_size = 40; // 4 bytes key + 36 bytes value
         let mut batch = rocksdb::WriteBatch::with_capacity_bytes(
-            history_size + utxo_delete_size + utxo_create_size + hash_ts_size,
+            history_size + utxo_delete_size + utxo_create_size + commitments_size + hash_ts_size,
         );
 
         // Add all operations to the batch
-        self.delete_utxos_batch(&mut batch, only_outpoints.iter())
-            .with_context(|| format!("failed to delete spent utxos for block {block_meta:?}"))?;
+        self.delete_utxos_batch(
+            &mut batch,
+            outpoint_script_hashes.iter().map(|(o, s)| (o, s)),
+        )
+        .with_context(|| format!("failed to delete spent utxos for block {block_meta:?}"))?;
+        if self.index_spent_heights {
+            let spent_heights_cf = self.spent_heights_cf();
+            for outpoint in only_outpoints.iter() {
+                batch.put_cf(
+                    &spent_heights_cf,
+                    serialize_outpoint(outpoint),
+                    block_meta.height().to_be_bytes(),
+                );
+            }
+        }
         self.set_hash_ts_batch(&mut batch, block_meta);
         self.update_history(&mut batch, &history_map)
             .with_context(|| format!("failed to update history for block {block_meta:?}"))?;
         self.insert_utxos(&mut batch, &utxo_created)
             .with_context(|| format!("failed to insert utxos for block {block_meta:?}"))?;
+        self.insert_commitments(&mut batch, &commitments)?;
+        if let Some(txids_hll) = txids_hll {
+            batch.put_cf(&self.other_cf(), TXIDS_HLL_KEY, txids_hll);
+        }
+        let summary_cf = self.summary_cf();
+        batch.put_cf(
+            &summary_cf,
+            block_meta.height().to_be_bytes(),
+            summary.to_bytes(),
+        );
+        if let Some(height) = height_to_prune(block_meta.height(), self.block_summary_keep_heights)
+        {
+            batch.delete_cf(&summary_cf, height.to_be_bytes());
+        }
 
         // Store reorg data for potential blockchain reorganization correction
         // Skip during IBD (Initial Block Download) as reorgs are extremely unlikely for old blocks
         // and this saves significant write overhead during initial sync
-        if !self.ibd.load(Ordering::Relaxed) {
+        if !ibd {
             // Create ReorgData and persist it to the database
             let reorg_data = ReorgData {
                 spent: outpoint_script_hashes,
+                spent_commitments,
                 history: history_map,
                 utxos_created: utxo_created,
             };
@@ -700,7 +1226,7 @@ impl Store for DBStore {
             let reorg_bytes = reorg_data.to_bytes().with_context(|| {
                 format!("failed to serialize reorg data for block {block_meta:?}")
             })?;
-            let reorg_cf = self.db.cf_handle(REORG_CF).expect("missing REORG_CF");
+            let reorg_cf = self.reorg_cf();
             batch.put_cf(&reorg_cf, block_meta.height().to_be_bytes(), reorg_bytes);
 
             // Delete old reorg data that exceeds the retention period
@@ -717,82 +1243,614 @@ impl Store for DBStore {
         Ok(changed_script_hashes)
     }
 
-    fn reorg(&self, height: Height) {
-        if let Err(e) = self._reorg(height) {
-            error_panic!("reorg failed: {e}");
-        }
+    fn reorg(&self, height: Height) -> Result<ReorgSummary> {
+        self._reorg(height)
     }
 
-    fn ibd_finished(&self) {
-        log::info!("Initial block download finished, enabling reorg data writes");
-        self.ibd.store(false, Ordering::Relaxed);
+    fn get_block_summary(&self, height: Height) -> Result<Option<BlockSummary>> {
+        self.db
+            .get_pinned_cf(&self.summary_cf(), height.to_be_bytes())?
+            .map(|value| BlockSummary::from_bytes(&value))
+            .transpose()
     }
-}
 
-fn serialize_outpoint(o: &OutPoint) -> Vec<u8> {
-    let mut v = Vec::with_capacity(36);
-    o.consensus_encode(&mut v).expect("vec don't error");
-    v
-}
+    fn upsert_block_meta(&self, meta: &BlockMeta) -> Result<bool> {
+        let is_new = self
+            .db
+            .get_pinned_cf(&self.hashes_cf(), meta.height().to_be_bytes())?
+            .is_none();
+        let mut batch = rocksdb::WriteBatch::default();
+        self.set_hash_ts_batch(&mut batch, meta);
+        self.write(batch)?;
+        Ok(is_new)
+    }
 
-pub(super) fn vec_tx_seen_to_be_bytes(v: &[TxSeen]) -> Vec<u8> {
-    let mut result = vec![0u8; v.len() * VEC_TX_SEEN_MAX_SIZE];
-    let len = vec_tx_seen_to_be_bytes_on_buffer(v, &mut result);
-    result.truncate(len);
-    result
-}
+    fn tip_block_meta(&self) -> Result<Option<BlockMeta>> {
+        let mut iter = self
+            .db
+            .iterator_cf(&self.hashes_cf(), rocksdb::IteratorMode::End);
+        match iter.next() {
+            Some(kv) => {
+                let kv = kv?;
+                Ok(Some(block_meta_from_kv(&kv.0, &kv.1)))
+            }
+            None => Ok(None),
+        }
+    }
 
-/// panics if buf is too small
-fn vec_tx_seen_to_be_bytes_on_buffer(v: &[TxSeen], buf: &mut [u8]) -> usize {
-    let mut offset = 0;
-    for TxSeen {
-        txid, height, v, ..
-    } in v
-    {
-        buf[offset..offset + 32].copy_from_slice(txid.as_byte_array());
-        offset += 32;
-        offset += height.encode_prefix_varint(&mut buf[offset..]);
-        offset += v.raw().encode_prefix_varint(&mut buf[offset..]);
+    fn get_block_meta_by_hash(&self, hash: BlockHash) -> Result<Option<BlockMeta>> {
+        let Some(height) = self
+            .db
+            .get_pinned_cf(&self.block_heights_cf(), hash.as_byte_array())?
+        else {
+            return Ok(None);
+        };
+        let key: [u8; 4] = height.as_ref().try_into()?;
+        let meta = self
+            .db
+            .get_pinned_cf(&self.hashes_cf(), key)?
+            .map(|value| block_meta_from_kv(&key, &value));
+        Ok(meta.filter(|meta| meta.hash() == hash))
     }
-    offset
-}
 
-pub(super) fn vec_tx_seen_from_be_bytes(s: &[u8]) -> Result<Vec<TxSeen>> {
-    if s.is_empty() {
-        return Ok(vec![]);
+    fn get_all_block_metas_since(&self, height: Height) -> Result<Vec<BlockMeta>> {
+        let start = height.to_be_bytes();
+        let mode = rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward);
+        self.db
+            .iterator_cf(&self.hashes_cf(), mode)
+            .map(|kv| {
+                let (key, value) = kv?;
+                Ok(block_meta_from_kv(&key, &value))
+            })
+            .collect()
     }
-    let mut result = Vec::with_capacity(s.len() / VEC_TX_SEEN_MIN_SIZE);
-    let mut offset = 0;
 
-    loop {
-        let txid = crate::be::Txid::from_slice(&s[offset..offset + 32])?;
-        offset += 32;
-        let (height, byte_len) = Height::decode_prefix_varint(&s[offset..])?;
-        offset += byte_len;
-        let (v, byte_len) = i32::decode_prefix_varint(&s[offset..])?;
-        offset += byte_len;
-        result.push(TxSeen::new(txid, height, V::from_raw(v)));
-        if offset >= s.len() {
-            break;
+    fn has_reorg_data(&self, height: Height) -> Result<bool> {
+        Ok(self
+            .db
+            .get_pinned_cf(&self.reorg_cf(), height.to_be_bytes())?
+            .is_some())
+    }
+
+    fn insert_coinbase_txids(&self, height: Height, txids: &[crate::be::Txid]) -> Result<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let key = height.to_be_bytes();
+        if txids.is_empty() {
+            batch.delete_cf(&self.coinbase_cf(), key);
+        } else {
+            let value: Vec<u8> = txids
+                .iter()
+                .flat_map(|txid| txid.as_byte_array().iter().copied())
+                .collect();
+            batch.put_cf(&self.coinbase_cf(), key, value);
+        }
+        self.write(batch)
+    }
+
+    fn get_coinbase_txids_at_height(&self, height: Height) -> Result<Vec<crate::be::Txid>> {
+        match self
+            .db
+            .get_pinned_cf(&self.coinbase_cf(), height.to_be_bytes())?
+        {
+            Some(value) => value.chunks(32).map(crate::be::Txid::from_slice).collect(),
+            None => Ok(vec![]),
         }
     }
+
+    fn insert_values(
+        &self,
+        created: &BTreeMap<OutPoint, u64>,
+        spent: &BTreeMap<OutPoint, u64>,
+    ) -> Result<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let cf = self.values_cf();
+        for (outpoint, value) in created {
+            batch.put_cf(&cf, serialize_outpoint(outpoint), value.to_be_bytes());
+        }
+        let cf = self.spent_values_cf();
+        for (input, value) in spent {
+            batch.put_cf(&cf, serialize_outpoint(input), value.to_be_bytes());
+        }
+        self.write(batch)
+    }
+
+    fn get_values(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<u64>>> {
+        self.multi_get_values(&self.values_cf(), outpoints)
+    }
+
+    fn get_spent_values(&self, inputs: &[OutPoint]) -> Result<Vec<Option<u64>>> {
+        self.multi_get_values(&self.spent_values_cf(), inputs)
+    }
+
+    fn get_commitments(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Commitments>>> {
+        let keys: Vec<_> = outpoints.iter().map(serialize_outpoint).collect();
+        let cf = self.commitments_cf();
+        let mut result = Vec::with_capacity(outpoints.len());
+        for value in self.db.batched_multi_get_cf(&cf, keys.iter(), false) {
+            let commitments = match value? {
+                Some(value) => {
+                    let mut bytes = value.as_ref();
+                    Some((
+                        elements::confidential::Value::consensus_decode(&mut bytes)?,
+                        elements::confidential::Asset::consensus_decode(&mut bytes)?,
+                    ))
+                }
+                None => None,
+            };
+            result.push(commitments);
+        }
+        Ok(result)
+    }
+
+    fn get_spent_heights(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Height>>> {
+        if !self.index_spent_heights {
+            return Err(StoreError::SpentHeightsNotIndexed.into());
+        }
+        let keys: Vec<_> = outpoints.iter().map(serialize_outpoint).collect();
+        let cf = self.spent_heights_cf();
+        let mut result = Vec::with_capacity(outpoints.len());
+        for value in self.db.batched_multi_get_cf(&cf, keys.iter(), false) {
+            let height = match value? {
+                Some(value) => Some(Height::from_be_bytes(value.as_ref().try_into()?)),
+                None => None,
+            };
+            result.push(height);
+        }
+        Ok(result)
+    }
+
+    fn insert_assets(&self, created: &BTreeMap<OutPoint, elements::AssetId>) -> Result<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let cf = self.assets_cf();
+        for (outpoint, asset) in created {
+            batch.put_cf(&cf, serialize_outpoint(outpoint), asset.into_inner().0);
+        }
+        self.write(batch)
+    }
+
+    fn get_assets(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<elements::AssetId>>> {
+        let keys: Vec<_> = outpoints.iter().map(serialize_outpoint).collect();
+        let mut result = Vec::with_capacity(outpoints.len());
+        for value in self
+            .db
+            .batched_multi_get_cf(&self.assets_cf(), keys.iter(), false)
+        {
+            let asset = match value? {
+                Some(value) => Some(elements::AssetId::from_slice(&value)?),
+                None => None,
+            };
+            result.push(asset);
+        }
+        Ok(result)
+    }
+
+    fn insert_asset_history(
+        &self,
+        height: Height,
+        history: &BTreeMap<elements::AssetId, Vec<TxSeen>>,
+    ) -> Result<()> {
+        // entries of a block previously indexed at this height may be left if the process
+        // stopped before it was marked indexed
+        let mut batch = rocksdb::WriteBatch::default();
+        self.delete_asset_history_batch(&mut batch, height)?;
+        if !history.is_empty() {
+            let cf = self.asset_history_cf();
+            let mut assets = Vec::with_capacity(history.len() * 32);
+            for (asset, entries) in history {
+                for entry in entries {
+                    batch.put_cf(&cf, asset_history_key(asset, entry), []);
+                }
+                assets.extend(asset.into_inner().0);
+            }
+            batch.put_cf(&self.asset_heights_cf(), height.to_be_bytes(), assets);
+        }
+        self.write(batch)
+    }
+
+    fn get_asset_history_page(
+        &self,
+        asset: elements::AssetId,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)> {
+        let prefix = asset.into_inner().0;
+        let mut start = prefix.to_vec();
+        start.extend(page.from_height.unwrap_or(0).to_be_bytes());
+        let mode = rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward);
+        let entries = self
+            .db
+            .iterator_cf(&self.asset_history_cf(), mode)
+            .map_while(|kv| match kv {
+                Ok((key, _)) if key.starts_with(&prefix) => Some(asset_history_entry(&key)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            });
+        paginate_history(entries, page.limit)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        Ok(self.db.put_cf(&self.metadata_cf(), key, value)?)
+    }
+
+    fn read_metadata(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get_cf(&self.metadata_cf(), key)?)
+    }
+
+    fn persist_mempool_changes(
+        &self,
+        added: &[(crate::be::Txid, &crate::be::MempoolTx)],
+        removed: &[crate::be::Txid],
+    ) -> Result<()> {
+        if !self.persist_mempool || (added.is_empty() && removed.is_empty()) {
+            return Ok(());
+        }
+        let cf = self.mempool_cf();
+        let mut batch = rocksdb::WriteBatch::default();
+        for txid in removed {
+            batch.delete_cf(&cf, txid.as_byte_array());
+        }
+        for (txid, tx) in added {
+            batch.put_cf(&cf, txid.as_byte_array(), tx.to_bytes());
+        }
+        self.write(batch)
+    }
+
+    fn load_persisted_mempool(&self) -> Result<Vec<(crate::be::Txid, crate::be::MempoolTx)>> {
+        if !self.persist_mempool {
+            return Ok(vec![]);
+        }
+        let mut result = vec![];
+        for kv in self
+            .db
+            .iterator_cf(&self.mempool_cf(), rocksdb::IteratorMode::Start)
+        {
+            let (key, value) = kv?;
+            let txid = crate::be::Txid::from_slice(&key)?;
+            let tx = crate::be::MempoolTx::from_bytes(&value)
+                .with_context(|| format!("failed to deserialize mempool tx {txid}"))?;
+            result.push((txid, tx));
+        }
+        Ok(result)
+    }
+
+    fn ibd_finished(&self) {
+        log::info!("Initial block download finished, enabling reorg data writes");
+        self.ibd.store(false, Ordering::Relaxed);
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        // the writes are in the WAL, syncing it is enough to survive a crash
+        self.db.flush_wal(true)?;
+        Ok(())
+    }
+
+    fn count_distinct_txids(&self) -> Result<u64> {
+        Ok(self.txids.lock().unwrap().count())
+    }
+
+    fn stats(&self) -> StoreStats {
+        let estimate_num_keys = |cf: Arc<BoundColumnFamily>| {
+            self.db
+                .property_int_value_cf(&cf, "rocksdb.estimate-num-keys")
+                .ok()
+                .flatten()
+                .unwrap_or(0)
+        };
+        // reorg data is kept only for the last blocks, counting the keys is cheap
+        let reorg_data_depth = self
+            .db
+            .iterator_cf(&self.reorg_cf(), rocksdb::IteratorMode::Start)
+            .take_while(|kv| kv.is_ok())
+            .count() as u64;
+        StoreStats {
+            approximate_tx_count: self.txids.lock().unwrap().count(),
+            utxos: estimate_num_keys(self.utxo_cf()),
+            scripts_with_history: estimate_num_keys(self.history_cf()),
+            history_entries: None,
+            reorg_data_depth,
+        }
+    }
+
+    fn iter_utxos(&self) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_> {
+        Box::new(
+            self.db
+                .iterator_cf(&self.utxo_cf(), rocksdb::IteratorMode::Start)
+                .map(|kv| {
+                    let (key, value) = kv?;
+                    let outpoint: OutPoint = elements::encode::deserialize(&key)?;
+                    Ok((
+                        outpoint,
+                        ScriptHash::from_be_bytes((&value[..]).try_into()?),
+                    ))
+                }),
+        )
+    }
+
+    fn iter_utxos_for_script(
+        &self,
+        script_hash: ScriptHash,
+    ) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_> {
+        let prefix = script_hash.to_be_bytes();
+        let mode = rocksdb::IteratorMode::From(&prefix, rocksdb::Direction::Forward);
+        Box::new(
+            self.db
+                .iterator_cf(&self.utxo_by_script_cf(), mode)
+                .map_while(move |kv| match kv {
+                    Ok((key, _)) if key.starts_with(&prefix) => Some(utxo_by_script_entry(&key)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                }),
+        )
+    }
+
+    fn iter_history(&self) -> Box<dyn Iterator<Item = Result<(ScriptHash, Vec<TxSeen>)>> + '_> {
+        Box::new(
+            self.db
+                .iterator_cf(&self.history_cf(), rocksdb::IteratorMode::Start)
+                .map(|kv| {
+                    let (key, value) = kv?;
+                    let script_hash = ScriptHash::from_be_bytes((&key[..]).try_into()?);
+                    Ok((script_hash, vec_tx_seen_from_be_bytes(&value)?))
+                }),
+        )
+    }
+
+    fn bulk_import(
+        &self,
+        utxos: BTreeMap<OutPoint, ScriptHash>,
+        history: BTreeMap<ScriptHash, Vec<TxSeen>>,
+        block_metas: Vec<BlockMeta>,
+    ) -> Result<()> {
+        let tip = self.tip_block_meta()?.map(|meta| meta.height());
+        check_contiguous_heights(&block_metas, tip)?;
+
+        let txids_hll = {
+            let mut txids = self.txids.lock().unwrap();
+            for tx_seen in history.values().flatten() {
+                txids.insert_txid(&tx_seen.txid);
+            }
+            txids.to_bytes()
+        };
+
+        let mut batch = rocksdb::WriteBatch::with_capacity_bytes(
+            estimate_history_size(&history) + utxos.len() * 88 + block_metas.len() * 40,
+        );
+        self.insert_utxos(&mut batch, &utxos)
+            .context("failed to insert imported utxos")?;
+        self.update_history(&mut batch, &history)
+            .context("failed to insert imported history")?;
+        for meta in block_metas.iter() {
+            self.set_hash_ts_batch(&mut batch, meta);
+        }
+        batch.put_cf(&self.other_cf(), TXIDS_HLL_KEY, txids_hll);
+        self.write(batch)
+    }
+
+    fn validate(&self) -> Result<Vec<(ScriptHash, TxSeen)>> {
+        let tip_height = self.tip_block_meta()?.map(|meta| meta.height());
+        let mut result = vec![];
+        for kv in self
+            .db
+            .iterator_cf(&self.history_cf(), rocksdb::IteratorMode::Start)
+        {
+            let (key, value) = kv?;
+            let script_hash = ScriptHash::from_be_bytes((&key[..]).try_into()?);
+            for tx_seen in vec_tx_seen_from_be_bytes(&value)? {
+                if is_above_tip(tx_seen.height, tip_height) {
+                    result.push((script_hash, tx_seen));
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for DBStore {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let txids_hll = self.txids.get_mut().map(|txids| txids.to_bytes());
+        if let Ok(txids_hll) = txids_hll {
+            if let Err(e) = self.db.put_cf(&self.other_cf(), TXIDS_HLL_KEY, txids_hll) {
+                log::warn!("error persisting the txids estimator: {e:?}");
+            }
+        }
+    }
+}
+
+/// Decode a key-value pair of the hashes column family
+fn block_meta_from_kv(key: &[u8], value: &[u8]) -> BlockMeta {
+    let height = u32::from_be_bytes(key.try_into().expect("schema"));
+    let hash = BlockHash::from_slice(&value[..32]).expect("schema");
+    let ts = u32::from_be_bytes((&value[32..]).try_into().expect("schema"));
+    BlockMeta::new(height, hash, ts)
+}
+
+fn serialize_outpoint(o: &OutPoint) -> Vec<u8> {
+    let mut v = Vec::with_capacity(36);
+    o.consensus_encode(&mut v).expect("vec don't error");
+    v
+}
+
+/// The key of an entry of `UTXO_BY_SCRIPT_CF`, the script hash followed by the serialized outpoint
+fn utxo_by_script_key(script_hash: ScriptHash, outpoint: &[u8]) -> Vec<u8> {
+    [&script_hash.to_be_bytes()[..], outpoint].concat()
+}
+
+fn utxo_by_script_entry(key: &[u8]) -> Result<(OutPoint, ScriptHash)> {
+    anyhow::ensure!(key.len() == 44, "invalid utxo by script key");
+    let script_hash = ScriptHash::from_be_bytes(key[..8].try_into()?);
+    let outpoint = elements::encode::deserialize(&key[8..])?;
+    Ok((outpoint, script_hash))
+}
+
+/// The key of an asset history entry, prefixed by the asset and the big-endian height so that
+/// the entries of an asset are sorted by height
+fn asset_history_key(asset: &elements::AssetId, entry: &TxSeen) -> Vec<u8> {
+    let mut key = Vec::with_capacity(73);
+    key.extend(asset.into_inner().0);
+    key.extend(entry.height.to_be_bytes());
+    key.extend(entry.txid.as_byte_array());
+    key.push(entry.v.to_u8());
+    key.extend(entry.v.index().to_be_bytes());
+    key
+}
+
+fn asset_history_entry(key: &[u8]) -> Result<TxSeen> {
+    anyhow::ensure!(key.len() == 73, "invalid asset history key");
+    let height = Height::from_be_bytes(key[32..36].try_into()?);
+    let txid = crate::be::Txid::from_slice(&key[36..68])?;
+    let index = u32::from_be_bytes(key[69..73].try_into()?);
+    let v = V::from_u8_and_index(key[68], index)
+        .map_err(|v| anyhow::anyhow!("invalid v {v} in asset history key"))?;
+    Ok(TxSeen::new(txid, height, v))
+}
+
+pub(super) fn vec_tx_seen_to_be_bytes(v: &[TxSeen]) -> Vec<u8> {
+    let mut result = vec![0u8; v.len() * VEC_TX_SEEN_MAX_SIZE];
+    let len = vec_tx_seen_to_be_bytes_on_buffer(v, &mut result);
+    result.truncate(len);
+    result
+}
+
+/// panics if buf is too small
+fn vec_tx_seen_to_be_bytes_on_buffer(v: &[TxSeen], buf: &mut [u8]) -> usize {
+    let mut offset = 0;
+    for TxSeen {
+        txid, height, v, ..
+    } in v
+    {
+        buf[offset..offset + 32].copy_from_slice(txid.as_byte_array());
+        offset += 32;
+        offset += height.encode_prefix_varint(&mut buf[offset..]);
+        offset += v.raw().encode_prefix_varint(&mut buf[offset..]);
+    }
+    offset
+}
+
+pub(super) fn vec_tx_seen_from_be_bytes(s: &[u8]) -> Result<Vec<TxSeen>> {
+    let mut result = Vec::with_capacity(s.len() / VEC_TX_SEEN_MIN_SIZE);
+    for tx_seen in TxSeenDecoder::new(s) {
+        result.push(tx_seen?);
+    }
     Ok(result)
 }
 
-fn get_or_init_salt(db: &DB) -> Result<u64> {
+/// Decode one at a time the entries encoded with [`vec_tx_seen_to_be_bytes`], stops after the
+/// first error
+struct TxSeenDecoder<B> {
+    bytes: B,
+    offset: usize,
+}
+
+impl<B: AsRef<[u8]>> TxSeenDecoder<B> {
+    fn new(bytes: B) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn decode_next(&mut self) -> Result<TxSeen> {
+        let s = &self.bytes.as_ref()[self.offset..];
+        let txid = crate::be::Txid::from_slice(s.get(..32).context("truncated txid")?)?;
+        let mut offset = 32;
+        let (height, byte_len) = Height::decode_prefix_varint(&s[offset..])?;
+        offset += byte_len;
+        let (v, byte_len) = i32::decode_prefix_varint(&s[offset..])?;
+        offset += byte_len;
+        self.offset += offset;
+        Ok(TxSeen::new(txid, height, V::from_raw(v)))
+    }
+}
+
+impl<B: AsRef<[u8]>> Iterator for TxSeenDecoder<B> {
+    type Item = Result<TxSeen>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.bytes.as_ref().len();
+        if self.offset >= len {
+            return None;
+        }
+        let result = self.decode_next();
+        if result.is_err() {
+            self.offset = len;
+        }
+        Some(result)
+    }
+}
+
+/// Record the layout version on a new DB, or check it's the supported one on an existing DB
+fn init_schema_version(db: &DB) -> Result<()> {
+    if !check_schema_version(db)? {
+        if has_indexed_blocks(db) {
+            anyhow::bail!(
+                "DB indexed by a version without schema version, delete it to index again"
+            );
+        }
+        let cf = db.cf_handle(METADATA_CF).expect("missing METADATA_CF");
+        db.put_cf(&cf, metadata::SCHEMA_VERSION, DB_SCHEMA_VERSION.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Whether the DB has the supported schema version, false if it has none
+fn check_schema_version(db: &DB) -> Result<bool> {
+    let cf = db.cf_handle(METADATA_CF).expect("missing METADATA_CF");
+    match db.get_cf(&cf, metadata::SCHEMA_VERSION)? {
+        Some(version) if version == DB_SCHEMA_VERSION.as_bytes() => Ok(true),
+        Some(version) => Err(anyhow::anyhow!(
+            "unsupported DB schema version {}, expected {DB_SCHEMA_VERSION}, delete the DB to index again",
+            String::from_utf8_lossy(&version)
+        )),
+        None => Ok(false),
+    }
+}
+
+fn read_salt(db: &DB) -> Result<Option<Salt>> {
     let cf = db.cf_handle(OTHER_CF).expect("missing OTHER_CF");
-    let res = db.get_cf(&cf, SALT_KEY)?;
-    match res {
-        Some(e) => Ok(u64::from_be_bytes(e.try_into().unwrap())),
+    db.get_cf(&cf, SALT_KEY)?
+        .map(|e| {
+            let len = e.len();
+            Salt::try_from(e).map_err(|_| anyhow::anyhow!("invalid script hash salt length {len}"))
+        })
+        .transpose()
+}
+
+fn get_or_init_salt(db: &DB) -> Result<Salt> {
+    match read_salt(db)? {
+        Some(salt) => Ok(salt),
         None => {
-            let mut bytes = [0u8; 8];
-            thread_rng().fill(&mut bytes);
+            let cf = db.cf_handle(OTHER_CF).expect("missing OTHER_CF");
+            // the salt is written the first time a DB is opened, before anything is indexed: a
+            // new salt wouldn't match the script hashes already stored
+            if has_indexed_blocks(db) {
+                anyhow::bail!("DB has indexed blocks but no script hash salt");
+            }
+            let mut bytes = Salt::default();
+            thread_rng().fill(&mut bytes[..]);
             db.put_cf(&cf, SALT_KEY, bytes)?;
             get_or_init_salt(db)
         }
     }
 }
 
+fn has_indexed_blocks(db: &DB) -> bool {
+    let hashes_cf = db.cf_handle(HASHES_CF).expect("missing HASHES_CF");
+    db.iterator_cf(&hashes_cf, rocksdb::IteratorMode::Start)
+        .next()
+        .is_some()
+}
+
+fn load_txids_hll(db: &DB) -> Result<HyperLogLog> {
+    let cf = db.cf_handle(OTHER_CF).expect("missing OTHER_CF");
+    Ok(match db.get_cf(&cf, TXIDS_HLL_KEY)? {
+        Some(bytes) => HyperLogLog::from_bytes_or_new(&bytes),
+        None => HyperLogLog::new(),
+    })
+}
+
 fn concat_merge(
     _new_key: &[u8],
     existing_val: Option<&[u8]>,
@@ -819,9 +1877,9 @@ mod test {
     use crate::store::{
         db::{
             estimate_history_size, get_or_init_salt, serialize_outpoint, vec_tx_seen_from_be_bytes,
-            vec_tx_seen_to_be_bytes, TxSeen,
+            vec_tx_seen_to_be_bytes, TxSeen, TxSeenDecoder,
         },
-        Store,
+        metadata, BlockSummary, Store, StoreError,
     };
     use crate::OutPoint;
     use crate::V;
@@ -836,12 +1894,71 @@ mod test {
         // creating explicitly to set the salt to a fixed value
         let db = DBStore {
             db: DB::open(&opts, tempdir.path()).unwrap(),
-            salt: 0,
+            salt: [0; 16],
             ibd: AtomicBool::new(true),
             reorg_data_keep_heights: 6,
+            future_height_policy: Default::default(),
+            duplicate_entries_policy: Default::default(),
+            index_spent_heights: false,
+            txids: std::sync::Mutex::new(super::HyperLogLog::new()),
+            persist_mempool: false,
+            block_summary_keep_heights: None,
+            read_only: false,
         };
         let hash = db.hash(b"test");
-        assert_eq!(hash, 2879782050633127044);
+        assert_eq!(hash, 16183295663280961421);
+
+        // the DBs without schema version hashed with FxHash fed with their salt, they are
+        // refused since their script hashes don't match
+        assert_eq!(legacy_fx_hash(0, b"test"), 2879782050633127044);
+        assert_ne!(legacy_fx_hash(0, b"test"), hash);
+    }
+
+    /// The script hash of the DBs without schema version, the `FxHasher` of the fxhash crate
+    /// fed with the salt and then with the script
+    fn legacy_fx_hash(salt: u64, script: &[u8]) -> u64 {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+        let add = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+        let mut hash = add(0, salt);
+        let mut bytes = script;
+        while bytes.len() >= 8 {
+            hash = add(hash, u64::from_le_bytes(bytes[..8].try_into().unwrap()));
+            bytes = &bytes[8..];
+        }
+        if bytes.len() >= 4 {
+            hash = add(
+                hash,
+                u32::from_le_bytes(bytes[..4].try_into().unwrap()) as u64,
+            );
+            bytes = &bytes[4..];
+        }
+        for byte in bytes {
+            hash = add(hash, *byte as u64);
+        }
+        hash
+    }
+
+    #[test]
+    fn test_hash_salt() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let other_tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        let other = DBStore::open(other_tempdir.path(), 64, true, 6).unwrap();
+        let hash = db.hash(b"test");
+        assert_ne!(hash, other.hash(b"test"));
+
+        let block_meta = crate::store::BlockMeta::new(0, BlockHash::all_zeros(), 0);
+        db.update(&block_meta, vec![], BTreeMap::new(), BTreeMap::new())
+            .unwrap();
+        drop(db);
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        assert_eq!(db.hash(b"test"), hash);
+
+        // a DB with indexed blocks and no salt is damaged, any salt would miss its scripts
+        let cf = db.db.cf_handle(super::OTHER_CF).unwrap();
+        db.db.delete_cf(&cf, super::SALT_KEY).unwrap();
+        assert!(get_or_init_salt(&db.db).is_err());
+        assert!(db.db.get_cf(&cf, super::SALT_KEY).unwrap().is_none());
     }
 
     #[test]
@@ -850,7 +1967,7 @@ mod test {
         let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
 
         let salt = get_or_init_salt(&db.db).unwrap();
-        assert_ne!(salt, 0);
+        assert_ne!(salt, [0; 16]);
         let salt2 = get_or_init_salt(&db.db).unwrap();
         assert_eq!(salt, salt2);
 
@@ -867,6 +1984,14 @@ mod test {
         let mut batch = rocksdb::WriteBatch::with_capacity_bytes(v.len() * 44);
         db.insert_utxos(&mut batch, &v).unwrap();
         db.db.write(batch).unwrap();
+
+        // results follow the input order, with holes for the missing utxos
+        let missing = OutPoint::new(crate::be::Txid::from_array([1; 32]), 0);
+        assert_eq!(
+            db.get_utxos(&[o1, missing, o, o1]).unwrap(),
+            vec![Some(expected + 1), None, Some(expected), Some(expected + 1)]
+        );
+
         let res = db.remove_utxos(&[o]).unwrap();
         assert_eq!(1, res.len());
         assert_eq!(expected, res[0].1);
@@ -874,6 +1999,11 @@ mod test {
         let res = db.remove_utxos(&[o1]).unwrap();
         assert_eq!(expected + 1, res[0].1);
         assert_eq!(1, res.len());
+        let err = db.remove_utxos(&[o1]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StoreError>(),
+            Some(&StoreError::MissingUtxo(o1))
+        );
 
         let txid = crate::be::Txid::all_zeros();
 
@@ -911,6 +2041,300 @@ mod test {
         // assert_eq!(r, vec![BlockHash::all_zeros(); 3]);
     }
 
+    #[test]
+    fn test_coinbase_txids() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        db.ibd_finished();
+
+        let coinbase_txid = crate::be::Txid::from_array([1; 32]);
+        let history_map = BTreeMap::from([(7u64, vec![TxSeen::new(coinbase_txid, 1, V::Vout(0))])]);
+        let block_meta = crate::store::BlockMeta::new(1, BlockHash::all_zeros(), 0);
+        db.insert_coinbase_txids(1, &[coinbase_txid]).unwrap();
+        db.update(&block_meta, vec![], history_map, BTreeMap::new())
+            .unwrap();
+
+        assert_eq!(
+            db.get_coinbase_txids_at_height(1).unwrap(),
+            vec![coinbase_txid]
+        );
+        assert!(db.get_coinbase_txids_at_height(0).unwrap().is_empty());
+
+        assert_eq!(db.reorg(1).unwrap().height, 1);
+        assert!(db.get_coinbase_txids_at_height(1).unwrap().is_empty());
+        assert!(db.get_history(&[7]).unwrap()[0].is_empty());
+        assert!(db.reorg(1).is_err(), "reorg data is consumed");
+    }
+
+    #[test]
+    fn test_utxos_by_script_and_spent_heights() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        db.set_index_spent_heights(true);
+        db.ibd_finished();
+
+        let funding_txid = crate::be::Txid::from_array([1; 32]);
+        let spending_txid = crate::be::Txid::from_array([2; 32]);
+        let o0 = OutPoint::new(funding_txid, 0);
+        let o1 = OutPoint::new(funding_txid, 1);
+        let o2 = OutPoint::new(funding_txid, 2);
+        let o3 = OutPoint::new(spending_txid, 0);
+        let block_meta = |height| {
+            crate::store::BlockMeta::new(height, BlockHash::from_byte_array([height as u8; 32]), 0)
+        };
+
+        let history_map = BTreeMap::from([
+            (
+                7u64,
+                vec![
+                    TxSeen::new(funding_txid, 1, V::Vout(0)),
+                    TxSeen::new(funding_txid, 1, V::Vout(1)),
+                ],
+            ),
+            (8u64, vec![TxSeen::new(funding_txid, 1, V::Vout(2))]),
+        ]);
+        let utxo_created = BTreeMap::from([
+            (o0, Utxo::from(7)),
+            (o1, Utxo::from(7)),
+            (o2, Utxo::from(8)),
+        ]);
+        db.update(&block_meta(1), vec![], history_map, utxo_created)
+            .unwrap();
+        let history_map = BTreeMap::from([(7u64, vec![TxSeen::new(spending_txid, 2, V::Vout(0))])]);
+        db.update(
+            &block_meta(2),
+            vec![(0, o0, spending_txid)],
+            history_map,
+            BTreeMap::from([(o3, Utxo::from(7))]),
+        )
+        .unwrap();
+
+        // the prefix iteration doesn't go past the outputs of the script
+        assert_eq!(
+            db.get_utxos_by_script(&[7, 8, 6]).unwrap(),
+            vec![vec![o1, o3], vec![o2], vec![]]
+        );
+
+        assert_eq!(
+            db.get_spent_heights(&[o0, o1]).unwrap(),
+            vec![Some(2), None]
+        );
+
+        db.reorg(2).unwrap();
+        assert_eq!(
+            db.get_utxos_by_script(&[7, 8]).unwrap(),
+            vec![vec![o0, o1], vec![o2]]
+        );
+        assert_eq!(db.get_spent_heights(&[o0]).unwrap(), vec![None]);
+        db.reorg(1).unwrap();
+        assert_eq!(
+            db.get_utxos_by_script(&[7, 8]).unwrap(),
+            vec![vec![], vec![]]
+        );
+        assert_eq!(db.iter_utxos_for_script(7).count(), 0);
+    }
+
+    #[test]
+    fn test_txids_estimator_persisted_on_drop() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        let history_map = BTreeMap::from([(
+            7u64,
+            vec![TxSeen::new(
+                crate::be::Txid::from_array([1; 32]),
+                1,
+                V::Vout(0),
+            )],
+        )]);
+        let block_meta = crate::store::BlockMeta::new(1, BlockHash::all_zeros(), 0);
+        db.update(&block_meta, vec![], history_map, BTreeMap::new())
+            .unwrap();
+        // height 1 is not a multiple of TXIDS_HLL_PERSIST_EVERY
+        assert_eq!(load_txids_hll(&db.db).unwrap().count(), 0);
+        drop(db);
+
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        assert_eq!(db.count_distinct_txids().unwrap(), 1);
+
+        // an estimator that can't be decoded doesn't prevent opening the DB
+        let mut db = db;
+        let cf = db.db.cf_handle(super::OTHER_CF).unwrap();
+        db.db.put_cf(&cf, super::TXIDS_HLL_KEY, b"invalid").unwrap();
+        drop(cf);
+        // skip persisting the estimator on drop, which would replace the invalid one
+        db.read_only = true;
+        drop(db);
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        assert_eq!(db.count_distinct_txids().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_block_summary_retention() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        db.set_block_summary_keep_heights(Some(100));
+        db.ibd_finished();
+
+        for height in 0..20 {
+            let history_map = BTreeMap::from([(
+                7u64,
+                vec![TxSeen::new(
+                    crate::be::Txid::from_array([height as u8; 32]),
+                    height,
+                    V::Vout(0),
+                )],
+            )]);
+            let block_meta = crate::store::BlockMeta::new(height, BlockHash::all_zeros(), 0);
+            db.update(&block_meta, vec![], history_map, BTreeMap::new())
+                .unwrap();
+        }
+
+        assert_eq!(db.count_indexed_blocks().unwrap(), 20);
+        assert_eq!(db.iter_hash_ts().count(), 20);
+        let from_height = db.get_history_from(&[7, 8], 15).unwrap();
+        assert_eq!(from_height[0].len(), 5);
+        assert_eq!(from_height[0][0].height, 15);
+        assert!(from_height[1].is_empty());
+
+        // well outside the reorg window the summary is still there
+        assert!(!db.has_reorg_data(1).unwrap());
+        let summary = db.get_block_summary(1).unwrap().unwrap();
+        assert_eq!(summary.history_entries, 1);
+        assert_eq!(summary.scripts_changed, 1);
+        assert_eq!(
+            BlockSummary::from_bytes(&summary.to_bytes()).unwrap(),
+            summary
+        );
+
+        db.set_block_summary_keep_heights(Some(10));
+        let block_meta = crate::store::BlockMeta::new(20, BlockHash::all_zeros(), 0);
+        db.update(&block_meta, vec![], BTreeMap::new(), BTreeMap::new())
+            .unwrap();
+        assert!(db.get_block_summary(10).unwrap().is_none());
+        assert!(db.get_block_summary(11).unwrap().is_some());
+        assert!(db.get_block_summary(21).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_values() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+
+        let output = OutPoint::new(crate::be::Txid::from_array([1; 32]), 0);
+        let input = OutPoint::new(crate::be::Txid::from_array([2; 32]), 3);
+        db.insert_values(
+            &BTreeMap::from([(output, 1000)]),
+            &BTreeMap::from([(input, 2000)]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.get_values(&[output, input]).unwrap(),
+            vec![Some(1000), None]
+        );
+        assert_eq!(
+            db.get_spent_values(&[output, input]).unwrap(),
+            vec![None, Some(2000)]
+        );
+
+        let asset = elements::AssetId::from_slice(&[7; 32]).unwrap();
+        db.insert_assets(&BTreeMap::from([(output, asset)]))
+            .unwrap();
+        assert_eq!(
+            db.get_assets(&[output, input]).unwrap(),
+            vec![Some(asset), None]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        assert_eq!(
+            db.read_metadata(metadata::SCHEMA_VERSION).unwrap(),
+            Some(super::DB_SCHEMA_VERSION.as_bytes().to_vec())
+        );
+        assert_eq!(db.read_metadata("note").unwrap(), None);
+        db.write_metadata("note", b"migrated by hand").unwrap();
+        drop(db);
+
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        assert_eq!(
+            db.read_metadata("note").unwrap(),
+            Some(b"migrated by hand".to_vec())
+        );
+        db.write_metadata(metadata::SCHEMA_VERSION, b"999").unwrap();
+        drop(db);
+
+        assert!(DBStore::open(tempdir.path(), 64, true, 6).is_err());
+    }
+
+    #[test]
+    fn test_init_block_heights() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        let hash = BlockHash::from_byte_array([1; 32]);
+        let block_meta = crate::store::BlockMeta::new(0, hash, 0);
+        db.update(&block_meta, vec![], BTreeMap::new(), BTreeMap::new())
+            .unwrap();
+        // like a DB indexed before the block heights were
+        db.db
+            .delete_cf(&db.block_heights_cf(), hash.as_byte_array())
+            .unwrap();
+        assert!(db.get_block_meta_by_hash(hash).unwrap().is_none());
+        drop(db);
+
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        let meta = db.get_block_meta_by_hash(hash).unwrap().unwrap();
+        assert_eq!(meta.height(), 0);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        assert!(
+            DBStore::open_read_only(tempdir.path(), 64).is_err(),
+            "not initialized"
+        );
+
+        let db = DBStore::open(tempdir.path(), 64, true, 6).unwrap();
+        db.ibd_finished();
+        let txid = crate::be::Txid::from_array([1; 32]);
+        let outpoint = OutPoint::new(txid, 0);
+        let history_map = BTreeMap::from([(7u64, vec![TxSeen::new(txid, 1, V::Vout(0))])]);
+        let block_meta = crate::store::BlockMeta::new(1, BlockHash::all_zeros(), 0);
+        db.update(
+            &block_meta,
+            vec![],
+            history_map,
+            BTreeMap::from([(outpoint, Utxo::from(7))]),
+        )
+        .unwrap();
+
+        let read_only = DBStore::open_read_only(tempdir.path(), 64).unwrap();
+        assert_eq!(read_only.hash(b"test"), db.hash(b"test"));
+        let metas = |store: &DBStore| {
+            store
+                .iter_hash_ts()
+                .map(|m| (m.height(), m.hash(), m.timestamp()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(metas(&read_only), metas(&db));
+        assert_eq!(read_only.get_utxos(&[outpoint]).unwrap(), vec![Some(7)]);
+        assert_eq!(
+            read_only.get_history(&[7]).unwrap(),
+            db.get_history(&[7]).unwrap()
+        );
+
+        let block_meta = crate::store::BlockMeta::new(2, BlockHash::all_zeros(), 0);
+        assert!(read_only
+            .update(&block_meta, vec![], BTreeMap::new(), BTreeMap::new())
+            .is_err());
+        assert!(read_only.reorg(1).is_err());
+        assert!(read_only.write_metadata("note", b"").is_err());
+        assert_eq!(metas(&read_only), metas(&db));
+    }
+
     #[test]
     fn test_static_txseen_round_trip() {
         let txseen = TxSeen::new(crate::be::Txid::all_zeros(), 0, V::Undefined);
@@ -940,6 +2364,21 @@ mod test {
         assert_eq!(txs, deserialized, "v must be serialized");
     }
 
+    #[test]
+    fn test_tx_seen_decoder_truncated() {
+        let txs = vec![
+            TxSeen::new(crate::be::Txid::all_zeros(), 1, V::Vout(0)),
+            TxSeen::new(crate::be::Txid::all_zeros(), 2, V::Vin(1)),
+        ];
+        let serialized = vec_tx_seen_to_be_bytes(&txs);
+
+        let mut decoder = TxSeenDecoder::new(&serialized[..serialized.len() - 10]);
+        assert_eq!(decoder.next().unwrap().unwrap(), txs[0]);
+        assert!(decoder.next().unwrap().is_err());
+        assert!(decoder.next().is_none(), "decoding stops after an error");
+        assert!(vec_tx_seen_from_be_bytes(&serialized[..40]).is_err());
+    }
+
     #[test]
     fn test_outpoint_ordering_matches_encoding() {
         use elements::secp256k1_zkp::rand::{thread_rng, RngCore};
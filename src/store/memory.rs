@@ -1,56 +1,149 @@
-use std::{collections::BTreeMap, hash::Hasher, sync::Mutex};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    hash::Hasher,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Mutex,
+};
 
-use fxhash::FxHasher;
+use anyhow::Context;
+use elements::{
+    encode::{Decodable, Encodable},
+    hashes::Hash,
+    secp256k1_zkp::rand::{thread_rng, Rng},
+};
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
 
-use crate::{error_panic, Height, OutPoint, ScriptHash};
+use crate::{Height, OutPoint, ScriptHash};
 
-use super::{BlockMeta, Store, TxSeen};
+use super::{
+    apply_duplicate_entries_policy, apply_future_height_policy, check_contiguous_heights,
+    height_to_prune,
+    hll::HyperLogLog,
+    is_above_tip, paginate_history,
+    siphash::{Salt, SipHasher13},
+    split_created_utxos, BlockMeta, BlockSummary, Commitments, DuplicateEntriesPolicy,
+    FutureHeightPolicy, HistoryPage, ReorgSummary, Store, StoreError, StoreStats, TxSeen, Utxo,
+};
 use crate::V;
 
 #[derive(Debug)]
 pub struct MemoryStore {
     utxos: Mutex<BTreeMap<OutPoint, ScriptHash>>,
+    /// Secondary index of `utxos`, locked after it
+    utxos_by_script: Mutex<BTreeMap<ScriptHash, BTreeSet<OutPoint>>>,
+    /// Filled only if `index_spent_heights`
+    spent_heights: Mutex<BTreeMap<OutPoint, Height>>,
     history: Mutex<BTreeMap<ScriptHash, Vec<TxSeen>>>,
     reorg_data: Mutex<BTreeMap<Height, MemoryReorgData>>,
+    block_metas: Mutex<BTreeMap<Height, BlockMeta>>,
+    coinbase_txids: Mutex<BTreeMap<Height, Vec<crate::be::Txid>>>,
+    values: Mutex<BTreeMap<OutPoint, u64>>,
+    spent_values: Mutex<BTreeMap<OutPoint, u64>>,
+    commitments: Mutex<BTreeMap<OutPoint, Commitments>>,
+    assets: Mutex<BTreeMap<OutPoint, elements::AssetId>>,
+    asset_history: Mutex<BTreeMap<elements::AssetId, BTreeMap<Height, Vec<TxSeen>>>>,
+    metadata: Mutex<BTreeMap<String, Vec<u8>>>,
+    block_summaries: Mutex<BTreeMap<Height, BlockSummary>>,
+    block_summary_keep_heights: Option<u32>,
+    /// Number of recent block heights to keep reorg data for, all if None
+    reorg_data_keep_heights: Option<u32>,
+    future_height_policy: FutureHeightPolicy,
+    duplicate_entries_policy: DuplicateEntriesPolicy,
+    index_spent_heights: bool,
+    txids: Mutex<HyperLogLog>,
+    /// Random key of the script hashes, so that colliding scripts can't be crafted
+    salt: Salt,
+    /// Hasher already keyed with `salt`, cloned for every hash
+    hasher: SipHasher13,
 }
 
+/// Version of the file written by [`MemoryStore::persist`]
+const PERSIST_VERSION: u8 = 1;
+
 impl Store for MemoryStore {
     fn hash(&self, script: &[u8]) -> ScriptHash {
-        let mut hasher = FxHasher::default();
-        // TODO should be salted
+        let mut hasher = self.hasher.clone();
         hasher.write(script);
         hasher.finish()
     }
 
     fn iter_hash_ts(&self) -> Box<dyn Iterator<Item = BlockMeta> + '_> {
-        // it's not needed to preload
-        Box::new(vec![].into_iter())
+        // copied so the lock isn't held while the caller iterates
+        let block_metas: Vec<_> = self.block_metas.lock().unwrap().values().cloned().collect();
+        Box::new(block_metas.into_iter())
+    }
+
+    fn count_indexed_blocks(&self) -> anyhow::Result<u64> {
+        Ok(self.block_metas.lock().unwrap().len() as u64)
     }
 
     fn get_utxos(&self, outpoints: &[OutPoint]) -> anyhow::Result<Vec<Option<ScriptHash>>> {
-        let mut result = Vec::with_capacity(outpoints.len());
-        for outpoint in outpoints {
-            result.push(self.utxos.lock().unwrap().get(outpoint).cloned());
-        }
-        Ok(result)
+        let utxos = self.utxos.lock().unwrap();
+        Ok(outpoints.iter().map(|o| utxos.get(o).cloned()).collect())
     }
 
     fn get_history(
         &self,
         scripts: &[crate::ScriptHash],
     ) -> anyhow::Result<Vec<Vec<super::TxSeen>>> {
-        let mut result = Vec::with_capacity(scripts.len());
-        for script in scripts {
-            result.push(
-                self.history
-                    .lock()
-                    .unwrap()
+        let history = self.history.lock().unwrap();
+        Ok(scripts
+            .iter()
+            .map(|script| history.get(script).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    fn get_history_from(
+        &self,
+        scripts: &[ScriptHash],
+        min_height: Height,
+    ) -> anyhow::Result<Vec<Vec<TxSeen>>> {
+        let history = self.history.lock().unwrap();
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                history
                     .get(script)
-                    .cloned()
-                    .unwrap_or(vec![]),
-            );
-        }
-        Ok(result)
+                    .map(|entries| {
+                        entries
+                            .iter()
+                            .filter(|e| e.height >= min_height)
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    fn get_history_page(
+        &self,
+        script: ScriptHash,
+        page: HistoryPage,
+    ) -> anyhow::Result<(Vec<TxSeen>, Option<Height>)> {
+        let history = self.history.lock().unwrap();
+        let entries = history.get(&script).map(Vec::as_slice).unwrap_or_default();
+        // entries are sorted by height, see `update_history`
+        let start = page
+            .from_height
+            .map_or(0, |height| entries.partition_point(|e| e.height < height));
+        paginate_history(entries[start..].iter().cloned().map(Ok), page.limit)
+    }
+
+    fn stream_history(&self, script: ScriptHash) -> BoxStream<'static, anyhow::Result<TxSeen>> {
+        let entries = self
+            .history
+            .lock()
+            .unwrap()
+            .get(&script)
+            .cloned()
+            .unwrap_or_default();
+        stream::iter(entries.into_iter().map(Ok)).boxed()
     }
 
     fn has_history(&self, scripts: &[crate::ScriptHash]) -> anyhow::Result<Vec<bool>> {
@@ -66,16 +159,124 @@ impl Store for MemoryStore {
         Ok(result)
     }
 
+    fn insert_values(
+        &self,
+        created: &BTreeMap<OutPoint, u64>,
+        spent: &BTreeMap<OutPoint, u64>,
+    ) -> anyhow::Result<()> {
+        self.values.lock().unwrap().extend(created);
+        self.spent_values.lock().unwrap().extend(spent);
+        Ok(())
+    }
+
+    fn get_values(&self, outpoints: &[OutPoint]) -> anyhow::Result<Vec<Option<u64>>> {
+        let values = self.values.lock().unwrap();
+        Ok(outpoints.iter().map(|o| values.get(o).cloned()).collect())
+    }
+
+    fn get_spent_values(&self, inputs: &[OutPoint]) -> anyhow::Result<Vec<Option<u64>>> {
+        let spent_values = self.spent_values.lock().unwrap();
+        Ok(inputs
+            .iter()
+            .map(|i| spent_values.get(i).cloned())
+            .collect())
+    }
+
+    fn get_commitments(&self, outpoints: &[OutPoint]) -> anyhow::Result<Vec<Option<Commitments>>> {
+        let commitments = self.commitments.lock().unwrap();
+        Ok(outpoints
+            .iter()
+            .map(|o| commitments.get(o).copied())
+            .collect())
+    }
+
+    fn get_spent_heights(&self, outpoints: &[OutPoint]) -> anyhow::Result<Vec<Option<Height>>> {
+        if !self.index_spent_heights {
+            return Err(StoreError::SpentHeightsNotIndexed.into());
+        }
+        let spent_heights = self.spent_heights.lock().unwrap();
+        Ok(outpoints
+            .iter()
+            .map(|o| spent_heights.get(o).copied())
+            .collect())
+    }
+
+    fn insert_assets(&self, created: &BTreeMap<OutPoint, elements::AssetId>) -> anyhow::Result<()> {
+        self.assets.lock().unwrap().extend(created);
+        Ok(())
+    }
+
+    fn get_assets(&self, outpoints: &[OutPoint]) -> anyhow::Result<Vec<Option<elements::AssetId>>> {
+        let assets = self.assets.lock().unwrap();
+        Ok(outpoints.iter().map(|o| assets.get(o).cloned()).collect())
+    }
+
+    fn insert_asset_history(
+        &self,
+        height: Height,
+        history: &BTreeMap<elements::AssetId, Vec<TxSeen>>,
+    ) -> anyhow::Result<()> {
+        self.remove_asset_history(height);
+        let mut asset_history = self.asset_history.lock().unwrap();
+        for (asset, entries) in history {
+            asset_history
+                .entry(*asset)
+                .or_default()
+                .insert(height, entries.clone());
+        }
+        Ok(())
+    }
+
+    fn get_asset_history_page(
+        &self,
+        asset: elements::AssetId,
+        page: HistoryPage,
+    ) -> anyhow::Result<(Vec<TxSeen>, Option<Height>)> {
+        let asset_history = self.asset_history.lock().unwrap();
+        let Some(heights) = asset_history.get(&asset) else {
+            return Ok((vec![], None));
+        };
+        let entries = heights
+            .range(page.from_height.unwrap_or(0)..)
+            .flat_map(|(_, entries)| entries.iter().cloned().map(Ok));
+        paginate_history(entries, page.limit)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.metadata
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.metadata.lock().unwrap().get(key).cloned())
+    }
+
     fn update(
         &self,
         block_meta: &BlockMeta,
         utxo_spent: Vec<(u32, OutPoint, crate::be::Txid)>,
         history_map: std::collections::BTreeMap<ScriptHash, Vec<TxSeen>>,
-        utxo_created: std::collections::BTreeMap<OutPoint, ScriptHash>,
+        utxo_created: std::collections::BTreeMap<OutPoint, Utxo>,
     ) -> anyhow::Result<Vec<ScriptHash>> {
+        let already_indexed = self
+            .block_metas
+            .lock()
+            .unwrap()
+            .get(&block_meta.height())
+            .is_some_and(|meta| meta.hash() == block_meta.hash());
+        if already_indexed {
+            log::warn!("block {} already indexed, skipping it", block_meta.hash());
+            return Ok(vec![]);
+        }
         let mut history_map = history_map;
+        apply_future_height_policy(self.future_height_policy, block_meta, &mut history_map)?;
+        apply_duplicate_entries_policy(self.duplicate_entries_policy, &mut history_map);
+        let (utxo_created, commitments) = split_created_utxos(utxo_created);
         let only_outpoints: Vec<_> = utxo_spent.iter().map(|e| e.1).collect();
-        let script_hashes = self.remove_utxos(&only_outpoints);
+        let script_hashes = self.spent_script_hashes(&only_outpoints)?;
 
         let spent = Vec::from_iter(
             only_outpoints
@@ -83,6 +284,12 @@ impl Store for MemoryStore {
                 .cloned()
                 .zip(script_hashes.iter().cloned()),
         );
+        // removed with the spent outputs, kept to restore them on reorg
+        let spent_commitments = only_outpoints
+            .iter()
+            .zip(self.get_commitments(&only_outpoints)?)
+            .filter_map(|(outpoint, commitments)| Some((*outpoint, commitments?)))
+            .collect();
 
         for (script_hash, (vin, _, txid)) in script_hashes.into_iter().zip(utxo_spent) {
             let el = history_map.entry(script_hash).or_default();
@@ -91,188 +298,2035 @@ impl Store for MemoryStore {
 
         let changed_script_hashes = history_map.keys().copied().collect::<Vec<_>>();
 
+        {
+            let summary = BlockSummary::new(spent.len(), &history_map, utxo_created.len());
+            let mut block_summaries = self.block_summaries.lock().unwrap();
+            block_summaries.insert(block_meta.height(), summary);
+            if let Some(height) =
+                height_to_prune(block_meta.height(), self.block_summary_keep_heights)
+            {
+                block_summaries.remove(&height);
+            }
+        }
+
+        {
+            let mut txids = self.txids.lock().unwrap();
+            for tx_seen in history_map.values().flatten() {
+                txids.insert_txid(&tx_seen.txid);
+            }
+        }
+
         // TODO: handle unwraps on the lock
-        self.reorg_data.lock().unwrap().insert(
-            block_meta.height(),
-            MemoryReorgData {
-                spent,
-                history: history_map.clone(),
-                utxos_created: utxo_created.clone(),
-            },
-        );
+        {
+            let mut reorg_data = self.reorg_data.lock().unwrap();
+            reorg_data.insert(
+                block_meta.height(),
+                MemoryReorgData {
+                    spent,
+                    spent_commitments,
+                    history: history_map.clone(),
+                    utxos_created: utxo_created.clone(),
+                },
+            );
+            if let Some(height) = height_to_prune(block_meta.height(), self.reorg_data_keep_heights)
+            {
+                if reorg_data.remove(&height).is_some() {
+                    log::debug!("dropped reorg data for height {height}, no longer reorgable");
+                }
+            }
+        }
+        // The spending entries are added before the spent outputs are removed, so that a reader
+        // getting the utxos and then the history sees a spent output in at least one of them
         self.update_history(history_map);
+        self.remove_utxos(&only_outpoints);
+        if self.index_spent_heights {
+            self.spent_heights.lock().unwrap().extend(
+                only_outpoints
+                    .iter()
+                    .map(|outpoint| (*outpoint, block_meta.height())),
+            );
+        }
         self.insert_utxos(&utxo_created);
+        self.commitments.lock().unwrap().extend(commitments);
+        self.block_metas
+            .lock()
+            .unwrap()
+            .insert(block_meta.height(), block_meta.clone());
         Ok(changed_script_hashes)
     }
 
-    fn reorg(&self, height: crate::Height) {
+    fn reorg(&self, height: crate::Height) -> anyhow::Result<ReorgSummary> {
         let reorg_data = self
             .reorg_data
             .lock()
             .unwrap()
             .remove(&height)
-            .unwrap_or_else(|| {
-                error_panic!("missing reorg data for height {height}");
-            });
+            .ok_or_else(|| match self.reorg_data_keep_heights {
+                Some(keep_heights) => anyhow::anyhow!(
+                    "missing reorg data for height {height}, kept only for the last {keep_heights} heights"
+                ),
+                None => anyhow::anyhow!("missing reorg data for height {height}"),
+            })?;
+        let summary = ReorgSummary::new(
+            height,
+            &reorg_data.spent,
+            &reorg_data.history,
+            &reorg_data.utxos_created,
+        );
+        // the history is checked before changing anything, the reorg can be retried on failure
+        if let Err(e) = self.remove_history_entries(&reorg_data.history) {
+            self.reorg_data.lock().unwrap().insert(height, reorg_data);
+            return Err(e.into());
+        }
         self.insert_utxos_vec(&reorg_data.spent);
+        self.commitments
+            .lock()
+            .unwrap()
+            .extend(reorg_data.spent_commitments.iter().copied());
+        {
+            let mut spent_heights = self.spent_heights.lock().unwrap();
+            for (outpoint, _) in reorg_data.spent.iter() {
+                spent_heights.remove(outpoint);
+            }
+        }
         self.remove_utxos_map(&reorg_data.utxos_created);
-        self.remove_history_entries(reorg_data.history);
+        self.remove_asset_history(height);
+        self.coinbase_txids.lock().unwrap().remove(&height);
+        self.block_summaries.lock().unwrap().remove(&height);
+        self.block_metas.lock().unwrap().remove(&height);
+        Ok(summary)
+    }
+
+    fn get_block_summary(&self, height: Height) -> anyhow::Result<Option<BlockSummary>> {
+        Ok(self.block_summaries.lock().unwrap().get(&height).copied())
+    }
+
+    fn upsert_block_meta(&self, meta: &BlockMeta) -> anyhow::Result<bool> {
+        Ok(self
+            .block_metas
+            .lock()
+            .unwrap()
+            .insert(meta.height(), meta.clone())
+            .is_none())
+    }
+
+    fn tip_block_meta(&self) -> anyhow::Result<Option<BlockMeta>> {
+        Ok(self
+            .block_metas
+            .lock()
+            .unwrap()
+            .last_key_value()
+            .map(|(_, meta)| meta.clone()))
+    }
+
+    fn get_block_meta_by_hash(
+        &self,
+        hash: elements::BlockHash,
+    ) -> anyhow::Result<Option<BlockMeta>> {
+        Ok(self
+            .block_metas
+            .lock()
+            .unwrap()
+            .values()
+            .rev()
+            .find(|meta| meta.hash() == hash)
+            .cloned())
+    }
+
+    fn get_all_block_metas_since(&self, height: Height) -> anyhow::Result<Vec<BlockMeta>> {
+        Ok(self
+            .block_metas
+            .lock()
+            .unwrap()
+            .range(height..)
+            .map(|(_, meta)| meta.clone())
+            .collect())
+    }
+
+    fn has_reorg_data(&self, height: Height) -> anyhow::Result<bool> {
+        Ok(self.reorg_data.lock().unwrap().contains_key(&height))
+    }
+
+    fn insert_coinbase_txids(
+        &self,
+        height: Height,
+        txids: &[crate::be::Txid],
+    ) -> anyhow::Result<()> {
+        let mut coinbase_txids = self.coinbase_txids.lock().unwrap();
+        if txids.is_empty() {
+            coinbase_txids.remove(&height);
+        } else {
+            coinbase_txids.insert(height, txids.to_vec());
+        }
+        Ok(())
+    }
+
+    fn get_coinbase_txids_at_height(&self, height: Height) -> anyhow::Result<Vec<crate::be::Txid>> {
+        Ok(self
+            .coinbase_txids
+            .lock()
+            .unwrap()
+            .get(&height)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn persist_mempool_changes(
+        &self,
+        _added: &[(crate::be::Txid, &crate::be::MempoolTx)],
+        _removed: &[crate::be::Txid],
+    ) -> anyhow::Result<()> {
+        // nothing survives a restart of the memory store
+        Ok(())
+    }
+
+    fn load_persisted_mempool(
+        &self,
+    ) -> anyhow::Result<Vec<(crate::be::Txid, crate::be::MempoolTx)>> {
+        Ok(vec![])
+    }
+
+    fn ibd_finished(&self) {
+        // the vectors grew by doubling while indexing
+        let before = self.estimated_bytes();
+        self.shrink_to_fit();
+        log::info!(
+            "Initial block download finished, history shrunk from {before} to {} bytes",
+            self.estimated_bytes()
+        );
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn count_distinct_txids(&self) -> anyhow::Result<u64> {
+        Ok(self.txids.lock().unwrap().count())
+    }
+
+    fn iter_utxos(&self) -> Box<dyn Iterator<Item = anyhow::Result<(OutPoint, ScriptHash)>> + '_> {
+        // cloned to not hold the lock while iterating
+        let utxos: Vec<_> = self
+            .utxos
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(outpoint, script_hash)| Ok((*outpoint, *script_hash)))
+            .collect();
+        Box::new(utxos.into_iter())
+    }
+
+    fn iter_utxos_for_script(
+        &self,
+        script_hash: ScriptHash,
+    ) -> Box<dyn Iterator<Item = anyhow::Result<(OutPoint, ScriptHash)>> + '_> {
+        // cloned to not hold the lock while iterating
+        let outpoints: Vec<_> = self
+            .utxos_by_script
+            .lock()
+            .unwrap()
+            .get(&script_hash)
+            .into_iter()
+            .flatten()
+            .map(|outpoint| Ok((*outpoint, script_hash)))
+            .collect();
+        Box::new(outpoints.into_iter())
+    }
+
+    fn iter_history(
+        &self,
+    ) -> Box<dyn Iterator<Item = anyhow::Result<(ScriptHash, Vec<TxSeen>)>> + '_> {
+        let history: Vec<_> = self
+            .history
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(script_hash, entries)| Ok((*script_hash, entries.clone())))
+            .collect();
+        Box::new(history.into_iter())
+    }
+
+    fn bulk_import(
+        &self,
+        utxos: BTreeMap<OutPoint, ScriptHash>,
+        history: BTreeMap<ScriptHash, Vec<TxSeen>>,
+        block_metas: Vec<BlockMeta>,
+    ) -> anyhow::Result<()> {
+        let tip = self.block_metas.lock().unwrap().keys().next_back().copied();
+        check_contiguous_heights(&block_metas, tip)?;
+        {
+            let mut txids = self.txids.lock().unwrap();
+            for tx_seen in history.values().flatten() {
+                txids.insert_txid(&tx_seen.txid);
+            }
+        }
+        self.update_history(history);
+        self.insert_utxos(&utxos);
+        // last, so that the imported data is complete once the tip moves
+        self.block_metas
+            .lock()
+            .unwrap()
+            .extend(block_metas.into_iter().map(|meta| (meta.height(), meta)));
+        Ok(())
+    }
+
+    fn stats(&self) -> StoreStats {
+        let history = self.history.lock().unwrap();
+        StoreStats {
+            approximate_tx_count: self.txids.lock().unwrap().count(),
+            utxos: self.utxos.lock().unwrap().len() as u64,
+            scripts_with_history: history.len() as u64,
+            history_entries: Some(history.values().map(|e| e.len() as u64).sum()),
+            reorg_data_depth: self.reorg_data.lock().unwrap().len() as u64,
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<Vec<(ScriptHash, TxSeen)>> {
+        let tip_height = self.tip_block_meta()?.map(|meta| meta.height());
+        let history = self.history.lock().unwrap();
+        Ok(history
+            .iter()
+            .flat_map(|(script_hash, entries)| {
+                entries
+                    .iter()
+                    .filter(|e| is_above_tip(e.height, tip_height))
+                    .map(|e| (*script_hash, e.clone()))
+            })
+            .collect())
     }
+}
 
-    fn ibd_finished(&self) {}
+/// Remove the outpoint from the unspent outputs of the script, and the script if it has none left
+fn remove_from_script_index(
+    utxos_by_script: &mut BTreeMap<ScriptHash, BTreeSet<OutPoint>>,
+    script_hash: ScriptHash,
+    outpoint: &OutPoint,
+) {
+    if let Some(outpoints) = utxos_by_script.get_mut(&script_hash) {
+        outpoints.remove(outpoint);
+        if outpoints.is_empty() {
+            utxos_by_script.remove(&script_hash);
+        }
+    }
 }
 
 impl MemoryStore {
-    fn remove_utxos(&self, outpoints: &[OutPoint]) -> Vec<ScriptHash> {
-        let mut result = Vec::with_capacity(outpoints.len());
+    fn spent_script_hashes(&self, outpoints: &[OutPoint]) -> Result<Vec<ScriptHash>, StoreError> {
+        let utxos = self.utxos.lock().unwrap();
+        outpoints
+            .iter()
+            .map(|outpoint| {
+                utxos
+                    .get(outpoint)
+                    .copied()
+                    .ok_or(StoreError::MissingUtxo(*outpoint))
+            })
+            .collect()
+    }
+    fn remove_utxos(&self, outpoints: &[OutPoint]) {
+        let mut utxos = self.utxos.lock().unwrap();
+        let mut utxos_by_script = self.utxos_by_script.lock().unwrap();
+        let mut commitments = self.commitments.lock().unwrap();
         for outpoint in outpoints {
-            result.push(
-                self.utxos
-                    .lock()
-                    .unwrap()
-                    .remove(outpoint)
-                    .unwrap_or_else(|| {
-                        error_panic!("{outpoint} must be unspent");
-                    }),
-            );
+            if let Some(script_hash) = utxos.remove(outpoint) {
+                remove_from_script_index(&mut utxos_by_script, script_hash, outpoint);
+            }
+            commitments.remove(outpoint);
         }
-        result
     }
+    /// Append the entries keeping every history sorted by height, which they usually already
+    /// are since blocks are indexed in order
     fn update_history(&self, add: BTreeMap<ScriptHash, Vec<TxSeen>>) {
         let mut history = self.history.lock().unwrap();
         for (k, v) in add {
-            history.entry(k).or_default().extend(v);
+            let entries = history.entry(k).or_default();
+            // the existing entries are sorted, only the appended ones are checked
+            let in_order = v.windows(2).all(|w| w[0].height <= w[1].height)
+                && match (entries.last(), v.first()) {
+                    (Some(last), Some(first)) => last.height <= first.height,
+                    _ => true,
+                };
+            entries.extend(v);
+            if !in_order {
+                entries.sort_by_key(|e| e.height);
+            }
         }
     }
     fn insert_utxos(&self, adds: &BTreeMap<OutPoint, ScriptHash>) {
-        self.utxos.lock().unwrap().extend(adds);
+        self.insert_utxos_iter(adds.iter().map(|(o, s)| (*o, *s)));
     }
     fn insert_utxos_vec(&self, adds: &[(OutPoint, ScriptHash)]) {
-        self.utxos.lock().unwrap().extend(adds.iter().cloned());
+        self.insert_utxos_iter(adds.iter().copied());
     }
-    fn remove_utxos_map(&self, removes: &BTreeMap<OutPoint, ScriptHash>) {
+    fn insert_utxos_iter(&self, adds: impl Iterator<Item = (OutPoint, ScriptHash)>) {
         let mut utxos = self.utxos.lock().unwrap();
-        for outpoint in removes.keys() {
-            utxos.remove(outpoint);
+        let mut utxos_by_script = self.utxos_by_script.lock().unwrap();
+        for (outpoint, script_hash) in adds {
+            utxos.insert(outpoint, script_hash);
+            utxos_by_script
+                .entry(script_hash)
+                .or_default()
+                .insert(outpoint);
         }
     }
-    fn remove_history_entries(&self, removes: BTreeMap<ScriptHash, Vec<TxSeen>>) {
+    fn remove_utxos_map(&self, removes: &BTreeMap<OutPoint, ScriptHash>) {
+        let outpoints: Vec<_> = removes.keys().copied().collect();
+        self.remove_utxos(&outpoints);
+    }
+    /// Remove the last entries of the histories, nothing is removed if any of them doesn't match
+    fn remove_history_entries(
+        &self,
+        removes: &BTreeMap<ScriptHash, Vec<TxSeen>>,
+    ) -> Result<(), StoreError> {
         let mut history = self.history.lock().unwrap();
-        for (script_hash, entries_to_remove) in removes {
-            let existing = history.get_mut(&script_hash).unwrap_or_else(|| {
-                error_panic!("missing history for script hash {script_hash}");
-            });
+        let mut new_lens = Vec::with_capacity(removes.len());
+        for (script_hash, entries_to_remove) in removes.iter() {
+            let mismatch = || StoreError::ReorgHistoryMismatch(*script_hash);
+            let existing = history.get(script_hash).ok_or_else(mismatch)?;
             let new_len = existing
                 .len()
                 .checked_sub(entries_to_remove.len())
-                .unwrap_or_else(|| {
-                    error_panic!(
-                        "history underflow for script hash {script_hash}: existing {} remove {}",
-                        existing.len(),
-                        entries_to_remove.len()
-                    );
-                });
-            if existing[new_len..] != entries_to_remove {
-                error_panic!("history mismatch while reorging script hash {script_hash}");
+                .ok_or_else(mismatch)?;
+            if existing[new_len..] != entries_to_remove[..] {
+                return Err(mismatch());
             }
-            existing.truncate(new_len);
-            if existing.is_empty() {
+            new_lens.push((*script_hash, new_len));
+        }
+        for (script_hash, new_len) in new_lens {
+            if new_len == 0 {
                 history.remove(&script_hash);
+            } else if let Some(existing) = history.get_mut(&script_hash) {
+                existing.truncate(new_len);
             }
         }
+        Ok(())
+    }
+
+    /// Remove the asset history entries at `height`
+    fn remove_asset_history(&self, height: Height) {
+        self.asset_history.lock().unwrap().retain(|_, heights| {
+            heights.remove(&height);
+            !heights.is_empty()
+        });
     }
 
     pub(crate) fn new() -> Self {
         Self {
             utxos: Mutex::new(BTreeMap::new()),
+            utxos_by_script: Mutex::new(BTreeMap::new()),
+            spent_heights: Mutex::new(BTreeMap::new()),
             history: Mutex::new(BTreeMap::new()),
             reorg_data: Mutex::new(BTreeMap::new()),
+            block_metas: Mutex::new(BTreeMap::new()),
+            coinbase_txids: Mutex::new(BTreeMap::new()),
+            values: Mutex::new(BTreeMap::new()),
+            spent_values: Mutex::new(BTreeMap::new()),
+            commitments: Mutex::new(BTreeMap::new()),
+            assets: Mutex::new(BTreeMap::new()),
+            asset_history: Mutex::new(BTreeMap::new()),
+            metadata: Mutex::new(BTreeMap::new()),
+            block_summaries: Mutex::new(BTreeMap::new()),
+            block_summary_keep_heights: None,
+            reorg_data_keep_heights: None,
+            future_height_policy: FutureHeightPolicy::default(),
+            duplicate_entries_policy: DuplicateEntriesPolicy::default(),
+            index_spent_heights: false,
+            txids: Mutex::new(HyperLogLog::new()),
+            salt: Salt::default(),
+            hasher: SipHasher13::new_with_salt(&Salt::default()),
         }
+        .salted(thread_rng().gen())
     }
-}
 
-#[derive(Debug)]
-struct MemoryReorgData {
-    spent: Vec<(OutPoint, ScriptHash)>,
-    history: BTreeMap<ScriptHash, Vec<TxSeen>>,
-    utxos_created: BTreeMap<OutPoint, ScriptHash>,
-}
+    /// Like [`MemoryStore::new`] but hashing scripts with the given salt instead of a random one
+    pub(crate) fn with_salt(salt: Salt) -> Self {
+        Self::new().salted(salt)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::be::Txid;
-    use std::str::FromStr;
+    fn salted(self, salt: Salt) -> Self {
+        Self {
+            salt,
+            hasher: SipHasher13::new_with_salt(&salt),
+            ..self
+        }
+    }
 
-    #[test]
-    fn test_memory_store_reorg_restores_utxos_and_history() {
-        let store = MemoryStore::new();
-        let source_script_hash = 11;
-        let recipient_script_hash = 22;
-        let one = "1111111111111111111111111111111111111111111111111111111111111111";
-        let source_outpoint = OutPoint::new(crate::be::Txid::from_str(one).unwrap(), 0);
-        let two = "2222222222222222222222222222222222222222222222222222222222222222";
-        let created_outpoint = OutPoint::new(crate::be::Txid::from_str(two).unwrap(), 1);
-        store
-            .utxos
-            .lock()
-            .unwrap()
-            .insert(source_outpoint, source_script_hash);
+    /// Save the indexed data in `path`, to restore it with [`MemoryStore::load`] instead of
+    /// indexing again from scratch.
+    ///
+    /// The file is written next to `path` and then renamed, so an existing file is replaced only
+    /// if writing succeeds. Settings like the retention policies are not saved.
+    pub(crate) fn persist(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let file = File::create(&tmp_path).with_context(|| format!("{tmp_path:?}"))?;
+        let mut w = BufWriter::new(file);
+        PERSIST_VERSION.consensus_encode(&mut w)?;
+        w.write_all(&self.salt)?;
 
-        let three = "3333333333333333333333333333333333333333333333333333333333333333";
-        let block_meta = BlockMeta::new(1, elements::BlockHash::from_str(three).unwrap(), 123);
-        let four = "4444444444444444444444444444444444444444444444444444444444444444";
-        let spending_txid = Txid::from_str(four).unwrap();
-        let mut history_map = BTreeMap::new();
-        history_map.insert(
-            recipient_script_hash,
-            vec![TxSeen::new(spending_txid, block_meta.height(), V::Vout(1))],
-        );
-        let mut utxo_created = BTreeMap::new();
-        utxo_created.insert(created_outpoint, recipient_script_hash);
+        let utxos = self.utxos.lock().unwrap();
+        write_utxos(&mut w, utxos.len(), utxos.iter())?;
+        drop(utxos);
+        write_history(&mut w, &self.history.lock().unwrap())?;
 
-        let changed_script_hashes = store
-            .update(
-                &block_meta,
-                vec![(0, source_outpoint, spending_txid)],
-                history_map,
-                utxo_created,
-            )
-            .unwrap();
-        assert_eq!(
-            changed_script_hashes,
-            vec![source_script_hash, recipient_script_hash]
-        );
+        let reorg_data = self.reorg_data.lock().unwrap();
+        write_len(&mut w, reorg_data.len())?;
+        for (height, data) in reorg_data.iter() {
+            height.consensus_encode(&mut w)?;
+            write_utxos(
+                &mut w,
+                data.spent.len(),
+                data.spent.iter().map(|(o, s)| (o, s)),
+            )?;
+            write_commitments(
+                &mut w,
+                data.spent_commitments.len(),
+                data.spent_commitments.iter().map(|(o, c)| (o, c)),
+            )?;
+            write_history(&mut w, &data.history)?;
+            write_utxos(&mut w, data.utxos_created.len(), data.utxos_created.iter())?;
+        }
+        drop(reorg_data);
 
-        assert_eq!(store.utxos.lock().unwrap().get(&source_outpoint), None);
-        assert_eq!(
-            store.utxos.lock().unwrap().get(&created_outpoint),
-            Some(&recipient_script_hash)
-        );
-        assert_eq!(
-            store.history.lock().unwrap().get(&source_script_hash),
-            Some(&vec![TxSeen::new(
-                spending_txid,
-                block_meta.height(),
-                V::Vin(0)
-            )])
-        );
-        assert_eq!(
-            store.history.lock().unwrap().get(&recipient_script_hash),
-            Some(&vec![TxSeen::new(
-                spending_txid,
-                block_meta.height(),
-                V::Vout(1)
-            )])
-        );
+        let block_metas = self.block_metas.lock().unwrap();
+        write_len(&mut w, block_metas.len())?;
+        for meta in block_metas.values() {
+            meta.height.consensus_encode(&mut w)?;
+            w.write_all(meta.hash.as_ref())?;
+            meta.timestamp.consensus_encode(&mut w)?;
+        }
+        drop(block_metas);
 
-        store.reorg(block_meta.height());
+        let coinbase_txids = self.coinbase_txids.lock().unwrap();
+        write_len(&mut w, coinbase_txids.len())?;
+        for (height, txids) in coinbase_txids.iter() {
+            height.consensus_encode(&mut w)?;
+            write_len(&mut w, txids.len())?;
+            for txid in txids {
+                w.write_all(txid.as_byte_array())?;
+            }
+        }
+        drop(coinbase_txids);
 
-        assert_eq!(
-            store.utxos.lock().unwrap().get(&source_outpoint),
-            Some(&source_script_hash)
-        );
-        assert_eq!(store.utxos.lock().unwrap().get(&created_outpoint), None);
-        assert!(store.history.lock().unwrap().is_empty());
+        write_values(&mut w, &self.values.lock().unwrap())?;
+        write_values(&mut w, &self.spent_values.lock().unwrap())?;
+
+        let assets = self.assets.lock().unwrap();
+        write_len(&mut w, assets.len())?;
+        for (outpoint, asset) in assets.iter() {
+            outpoint.consensus_encode(&mut w)?;
+            w.write_all(&asset.into_inner().0)?;
+        }
+        drop(assets);
+
+        let metadata = self.metadata.lock().unwrap();
+        write_len(&mut w, metadata.len())?;
+        for (key, value) in metadata.iter() {
+            write_bytes(&mut w, key.as_bytes())?;
+            write_bytes(&mut w, value)?;
+        }
+        drop(metadata);
+
+        let block_summaries = self.block_summaries.lock().unwrap();
+        write_len(&mut w, block_summaries.len())?;
+        for (height, summary) in block_summaries.iter() {
+            height.consensus_encode(&mut w)?;
+            summary.history_entries.consensus_encode(&mut w)?;
+            summary.scripts_changed.consensus_encode(&mut w)?;
+            summary.utxos_created.consensus_encode(&mut w)?;
+            summary.utxos_spent.consensus_encode(&mut w)?;
+        }
+        drop(block_summaries);
+
+        write_bytes(&mut w, &self.txids.lock().unwrap().to_bytes())?;
+
+        let asset_history = self.asset_history.lock().unwrap();
+        write_len(&mut w, asset_history.len())?;
+        for (asset, heights) in asset_history.iter() {
+            w.write_all(&asset.into_inner().0)?;
+            write_len(&mut w, heights.values().map(Vec::len).sum())?;
+            for entry in heights.values().flatten() {
+                write_tx_seen(&mut w, entry)?;
+            }
+        }
+        drop(asset_history);
+
+        let spent_heights = self.spent_heights.lock().unwrap();
+        write_len(&mut w, spent_heights.len())?;
+        for (outpoint, height) in spent_heights.iter() {
+            outpoint.consensus_encode(&mut w)?;
+            height.consensus_encode(&mut w)?;
+        }
+        drop(spent_heights);
+
+        let commitments = self.commitments.lock().unwrap();
+        write_commitments(&mut w, commitments.len(), commitments.iter())?;
+        drop(commitments);
+
+        w.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path).with_context(|| format!("{path:?}"))?;
+        Ok(())
+    }
+
+    /// Restore a store saved with [`MemoryStore::persist`], with default settings
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("{path:?}"))?;
+        let mut r = BufReader::new(file);
+        let version = u8::consensus_decode(&mut r)?;
+        match version {
+            PERSIST_VERSION => {}
+            _ => anyhow::bail!("{path:?}: unknown memory store file version {version}"),
+        }
+        let store = Self::new().salted(read_array(&mut r)?);
+
+        store.insert_utxos_vec(&read_utxos(&mut r)?);
+        *store.history.lock().unwrap() = read_history(&mut r)?;
+
+        let mut reorg_data = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let height = Height::consensus_decode(&mut r)?;
+            let data = MemoryReorgData {
+                spent: read_utxos(&mut r)?,
+                spent_commitments: read_commitments(&mut r)?,
+                history: read_history(&mut r)?,
+                utxos_created: read_utxos(&mut r)?.into_iter().collect(),
+            };
+            reorg_data.insert(height, data);
+        }
+        *store.reorg_data.lock().unwrap() = reorg_data;
+
+        let mut block_metas = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let height = Height::consensus_decode(&mut r)?;
+            let hash = elements::BlockHash::from_byte_array(read_array(&mut r)?);
+            let timestamp = u32::consensus_decode(&mut r)?;
+            block_metas.insert(height, BlockMeta::new(height, hash, timestamp));
+        }
+        *store.block_metas.lock().unwrap() = block_metas;
+
+        let mut coinbase_txids = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let height = Height::consensus_decode(&mut r)?;
+            let txids = (0..read_len(&mut r)?)
+                .map(|_| read_txid(&mut r))
+                .collect::<anyhow::Result<_>>()?;
+            coinbase_txids.insert(height, txids);
+        }
+        *store.coinbase_txids.lock().unwrap() = coinbase_txids;
+
+        *store.values.lock().unwrap() = read_values(&mut r)?;
+        *store.spent_values.lock().unwrap() = read_values(&mut r)?;
+
+        let mut assets = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let outpoint = OutPoint::consensus_decode(&mut r)?;
+            let asset = elements::AssetId::from_slice(&read_array::<32>(&mut r)?)?;
+            assets.insert(outpoint, asset);
+        }
+        *store.assets.lock().unwrap() = assets;
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let key = String::from_utf8(read_bytes(&mut r)?)?;
+            metadata.insert(key, read_bytes(&mut r)?);
+        }
+        *store.metadata.lock().unwrap() = metadata;
+
+        let mut block_summaries = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let height = Height::consensus_decode(&mut r)?;
+            let summary = BlockSummary {
+                history_entries: u32::consensus_decode(&mut r)?,
+                scripts_changed: u32::consensus_decode(&mut r)?,
+                utxos_created: u32::consensus_decode(&mut r)?,
+                utxos_spent: u32::consensus_decode(&mut r)?,
+            };
+            block_summaries.insert(height, summary);
+        }
+        *store.block_summaries.lock().unwrap() = block_summaries;
+
+        *store.txids.lock().unwrap() = HyperLogLog::from_bytes_or_new(&read_bytes(&mut r)?);
+
+        let mut asset_history = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let asset = elements::AssetId::from_slice(&read_array::<32>(&mut r)?)?;
+            let mut heights: BTreeMap<Height, Vec<TxSeen>> = BTreeMap::new();
+            for _ in 0..read_len(&mut r)? {
+                let entry = read_tx_seen(&mut r)?;
+                heights.entry(entry.height).or_default().push(entry);
+            }
+            asset_history.insert(asset, heights);
+        }
+        *store.asset_history.lock().unwrap() = asset_history;
+
+        let mut spent_heights = BTreeMap::new();
+        for _ in 0..read_len(&mut r)? {
+            let outpoint = OutPoint::consensus_decode(&mut r)?;
+            spent_heights.insert(outpoint, Height::consensus_decode(&mut r)?);
+        }
+        *store.spent_heights.lock().unwrap() = spent_heights;
+
+        *store.commitments.lock().unwrap() = read_commitments(&mut r)?.into_iter().collect();
+
+        Ok(store)
+    }
+
+    pub(crate) fn set_block_summary_keep_heights(&mut self, keep_heights: Option<u32>) {
+        self.block_summary_keep_heights = keep_heights;
+    }
+
+    pub(crate) fn set_reorg_data_keep_heights(&mut self, keep_heights: Option<u32>) {
+        self.reorg_data_keep_heights = keep_heights;
+    }
+
+    pub(crate) fn set_future_height_policy(&mut self, policy: FutureHeightPolicy) {
+        self.future_height_policy = policy;
+    }
+
+    pub(crate) fn set_index_spent_heights(&mut self, index_spent_heights: bool) {
+        self.index_spent_heights = index_spent_heights;
+    }
+
+    pub(crate) fn set_duplicate_entries_policy(&mut self, policy: DuplicateEntriesPolicy) {
+        self.duplicate_entries_policy = policy;
+    }
+
+    /// Release the capacity kept by the vectors after entries are removed from them, for example
+    /// by a large reorg, or left over by their growth while indexing.
+    ///
+    /// The `BTreeMap`s free their nodes on removal, so only the vectors they contain are shrunk.
+    pub(crate) fn shrink_to_fit(&self) {
+        for entries in self.history.lock().unwrap().values_mut() {
+            entries.shrink_to_fit();
+        }
+        for reorg_data in self.reorg_data.lock().unwrap().values_mut() {
+            reorg_data.spent.shrink_to_fit();
+            reorg_data.spent_commitments.shrink_to_fit();
+            for entries in reorg_data.history.values_mut() {
+                entries.shrink_to_fit();
+            }
+        }
+        for heights in self.asset_history.lock().unwrap().values_mut() {
+            for entries in heights.values_mut() {
+                entries.shrink_to_fit();
+            }
+        }
+        for txids in self.coinbase_txids.lock().unwrap().values_mut() {
+            txids.shrink_to_fit();
+        }
+    }
+
+    /// Approximate number of bytes allocated for the script histories, counting the whole
+    /// capacity of their vectors
+    pub(crate) fn estimated_bytes(&self) -> usize {
+        let history = self.history.lock().unwrap();
+        let entries: usize = history.values().map(|entries| entries.capacity()).sum();
+        history.len() * std::mem::size_of::<(ScriptHash, Vec<TxSeen>)>()
+            + entries * std::mem::size_of::<TxSeen>()
+    }
+}
+
+fn write_len(w: &mut impl Write, len: usize) -> anyhow::Result<()> {
+    (len as u64).consensus_encode(w)?;
+    Ok(())
+}
+
+fn read_len(r: &mut impl Read) -> anyhow::Result<u64> {
+    Ok(u64::consensus_decode(r)?)
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> anyhow::Result<()> {
+    write_len(w, bytes.len())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes(r: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let len = read_len(r)?;
+    let mut bytes = vec![];
+    r.take(len).read_to_end(&mut bytes)?;
+    anyhow::ensure!(bytes.len() as u64 == len, "truncated memory store file");
+    Ok(bytes)
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_txid(r: &mut impl Read) -> anyhow::Result<crate::be::Txid> {
+    Ok(crate::be::Txid::from_array(read_array(r)?))
+}
+
+fn write_utxos<'a>(
+    w: &mut impl Write,
+    len: usize,
+    utxos: impl Iterator<Item = (&'a OutPoint, &'a ScriptHash)>,
+) -> anyhow::Result<()> {
+    write_len(w, len)?;
+    for (outpoint, script_hash) in utxos {
+        outpoint.consensus_encode(&mut *w)?;
+        script_hash.consensus_encode(&mut *w)?;
+    }
+    Ok(())
+}
+
+fn read_utxos(r: &mut impl Read) -> anyhow::Result<Vec<(OutPoint, ScriptHash)>> {
+    (0..read_len(r)?)
+        .map(|_| {
+            Ok((
+                OutPoint::consensus_decode(&mut *r)?,
+                u64::consensus_decode(&mut *r)?,
+            ))
+        })
+        .collect()
+}
+
+/// Only the txid, height and position of the entries are kept, the store doesn't fill the others
+fn write_history(
+    w: &mut impl Write,
+    history: &BTreeMap<ScriptHash, Vec<TxSeen>>,
+) -> anyhow::Result<()> {
+    write_len(w, history.len())?;
+    for (script_hash, entries) in history.iter() {
+        script_hash.consensus_encode(&mut *w)?;
+        write_len(w, entries.len())?;
+        for entry in entries {
+            write_tx_seen(w, entry)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_tx_seen(w: &mut impl Write, entry: &TxSeen) -> anyhow::Result<()> {
+    w.write_all(entry.txid.as_byte_array())?;
+    entry.height.consensus_encode(&mut *w)?;
+    entry.v.to_u8().consensus_encode(&mut *w)?;
+    entry.v.index().consensus_encode(&mut *w)?;
+    Ok(())
+}
+
+fn read_tx_seen(r: &mut impl Read) -> anyhow::Result<TxSeen> {
+    let txid = read_txid(r)?;
+    let height = Height::consensus_decode(&mut *r)?;
+    let v = u8::consensus_decode(&mut *r)?;
+    let index = u32::consensus_decode(&mut *r)?;
+    let v = V::from_u8_and_index(v, index).map_err(|v| anyhow::anyhow!("invalid v {v}"))?;
+    Ok(TxSeen::new(txid, height, v))
+}
+
+fn read_history(r: &mut impl Read) -> anyhow::Result<BTreeMap<ScriptHash, Vec<TxSeen>>> {
+    let mut history = BTreeMap::new();
+    for _ in 0..read_len(r)? {
+        let script_hash = u64::consensus_decode(&mut *r)?;
+        let entries = (0..read_len(r)?)
+            .map(|_| read_tx_seen(r))
+            .collect::<anyhow::Result<_>>()?;
+        history.insert(script_hash, entries);
+    }
+    Ok(history)
+}
+
+fn write_values(w: &mut impl Write, values: &BTreeMap<OutPoint, u64>) -> anyhow::Result<()> {
+    write_len(w, values.len())?;
+    for (outpoint, value) in values.iter() {
+        outpoint.consensus_encode(&mut *w)?;
+        value.consensus_encode(&mut *w)?;
+    }
+    Ok(())
+}
+
+fn read_values(r: &mut impl Read) -> anyhow::Result<BTreeMap<OutPoint, u64>> {
+    (0..read_len(r)?)
+        .map(|_| {
+            Ok((
+                OutPoint::consensus_decode(&mut *r)?,
+                u64::consensus_decode(&mut *r)?,
+            ))
+        })
+        .collect()
+}
+
+fn write_commitments<'a>(
+    w: &mut impl Write,
+    len: usize,
+    commitments: impl Iterator<Item = (&'a OutPoint, &'a Commitments)>,
+) -> anyhow::Result<()> {
+    write_len(w, len)?;
+    for (outpoint, (value, asset)) in commitments {
+        outpoint.consensus_encode(&mut *w)?;
+        value.consensus_encode(&mut *w)?;
+        asset.consensus_encode(&mut *w)?;
+    }
+    Ok(())
+}
+
+fn read_commitments(r: &mut impl Read) -> anyhow::Result<Vec<(OutPoint, Commitments)>> {
+    (0..read_len(r)?)
+        .map(|_| {
+            let outpoint = OutPoint::consensus_decode(&mut *r)?;
+            let value = elements::confidential::Value::consensus_decode(&mut *r)?;
+            let asset = elements::confidential::Asset::consensus_decode(&mut *r)?;
+            Ok((outpoint, (value, asset)))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct MemoryReorgData {
+    spent: Vec<(OutPoint, ScriptHash)>,
+    /// Commitments of the `spent` outputs that had them
+    spent_commitments: Vec<(OutPoint, Commitments)>,
+    history: BTreeMap<ScriptHash, Vec<TxSeen>>,
+    utxos_created: BTreeMap<OutPoint, ScriptHash>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::be::Txid;
+    use crate::store::Balance;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_memory_store_reorg_restores_utxos_and_history() {
+        let store = MemoryStore::new();
+        let source_script_hash = 11;
+        let recipient_script_hash = 22;
+        let one = "1111111111111111111111111111111111111111111111111111111111111111";
+        let source_outpoint = OutPoint::new(crate::be::Txid::from_str(one).unwrap(), 0);
+        let two = "2222222222222222222222222222222222222222222222222222222222222222";
+        let created_outpoint = OutPoint::new(crate::be::Txid::from_str(two).unwrap(), 1);
+        store
+            .utxos
+            .lock()
+            .unwrap()
+            .insert(source_outpoint, source_script_hash);
+
+        let three = "3333333333333333333333333333333333333333333333333333333333333333";
+        let block_meta = BlockMeta::new(1, elements::BlockHash::from_str(three).unwrap(), 123);
+        let four = "4444444444444444444444444444444444444444444444444444444444444444";
+        let spending_txid = Txid::from_str(four).unwrap();
+        let mut history_map = BTreeMap::new();
+        history_map.insert(
+            recipient_script_hash,
+            vec![TxSeen::new(spending_txid, block_meta.height(), V::Vout(1))],
+        );
+        let mut utxo_created = BTreeMap::new();
+        utxo_created.insert(created_outpoint, recipient_script_hash.into());
+
+        let changed_script_hashes = store
+            .update(
+                &block_meta,
+                vec![(0, source_outpoint, spending_txid)],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+        assert_eq!(
+            changed_script_hashes,
+            vec![source_script_hash, recipient_script_hash]
+        );
+
+        assert_eq!(store.utxos.lock().unwrap().get(&source_outpoint), None);
+        assert_eq!(
+            store.utxos.lock().unwrap().get(&created_outpoint),
+            Some(&recipient_script_hash)
+        );
+        assert_eq!(
+            store.history.lock().unwrap().get(&source_script_hash),
+            Some(&vec![TxSeen::new(
+                spending_txid,
+                block_meta.height(),
+                V::Vin(0)
+            )])
+        );
+        assert_eq!(
+            store.history.lock().unwrap().get(&recipient_script_hash),
+            Some(&vec![TxSeen::new(
+                spending_txid,
+                block_meta.height(),
+                V::Vout(1)
+            )])
+        );
+
+        assert_eq!(store.stats().approximate_tx_count, 1);
+
+        let summary = store.reorg(block_meta.height()).unwrap();
+        assert_eq!(
+            summary,
+            ReorgSummary {
+                height: block_meta.height(),
+                affected_scripts: [source_script_hash, recipient_script_hash].into(),
+                affected_txids: [spending_txid].into(),
+                utxos_restored: vec![source_outpoint],
+                utxos_removed: vec![created_outpoint],
+            }
+        );
+        assert!(store.reorg(block_meta.height()).is_err());
+
+        assert_eq!(
+            store.utxos.lock().unwrap().get(&source_outpoint),
+            Some(&source_script_hash)
+        );
+        assert_eq!(store.utxos.lock().unwrap().get(&created_outpoint), None);
+        assert!(store.history.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_inconsistent_changes_are_errors() {
+        let store = MemoryStore::new();
+        let txid = Txid::from_str(&"11".repeat(32)).unwrap();
+        let missing = OutPoint::new(txid, 0);
+        let block_meta = BlockMeta::new(1, elements::BlockHash::all_zeros(), 0);
+
+        let err = store
+            .update(
+                &block_meta,
+                vec![(0, missing, txid)],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StoreError>(),
+            Some(&StoreError::MissingUtxo(missing))
+        );
+        assert!(store.tip_block_meta().unwrap().is_none());
+
+        let history = BTreeMap::from([(7, vec![TxSeen::new(txid, 1, V::Vout(0))])]);
+        store
+            .update(&block_meta, vec![], history, BTreeMap::new())
+            .unwrap();
+        // the history changed behind the reorg data
+        store.history.lock().unwrap().get_mut(&7).unwrap()[0].height = 2;
+        let err = store.reorg(1).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StoreError>(),
+            Some(&StoreError::ReorgHistoryMismatch(7))
+        );
+        assert!(
+            store.reorg_data.lock().unwrap().contains_key(&1),
+            "the reorg can be retried"
+        );
+        assert_eq!(store.tip_block_meta().unwrap().map(|m| m.height()), Some(1));
+    }
+
+    #[test]
+    fn test_apply_reorg_up_to_rewinds_until_hash() {
+        let store = MemoryStore::new();
+        let hashes: Vec<_> = ["aa", "bb", "cc", "dd"]
+            .iter()
+            .map(|b| elements::BlockHash::from_str(&b.repeat(32)).unwrap())
+            .collect();
+        for (height, hash) in hashes.iter().enumerate() {
+            let meta = BlockMeta::new(height as Height, *hash, 100 + height as u32);
+            store
+                .update(&meta, vec![], BTreeMap::new(), BTreeMap::new())
+                .unwrap();
+        }
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().hash(), hashes[3]);
+
+        assert_eq!(store.apply_reorg_up_to(hashes[1]).unwrap(), 2);
+        let tip = store.tip_block_meta().unwrap().unwrap();
+        assert_eq!(tip.height(), 1);
+        assert_eq!(tip.hash(), hashes[1]);
+
+        assert_eq!(store.apply_reorg_up_to(hashes[1]).unwrap(), 0);
+
+        // the rewound block is not indexed anymore, nothing is rolled back
+        let err = store.apply_reorg_up_to(hashes[3]).unwrap_err();
+        assert!(err.to_string().contains("unknown block"), "{err}");
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().hash(), hashes[1]);
+    }
+
+    #[test]
+    fn test_future_height_entries_policy() {
+        let hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let block_meta = BlockMeta::new(0, hash, 100);
+        let txid = Txid::from_str(&"11".repeat(32)).unwrap();
+        let future_history = || {
+            let mut history_map = BTreeMap::new();
+            history_map.insert(7, vec![TxSeen::new(txid, 5, V::Vout(0))]);
+            history_map
+        };
+
+        // the default lets the entry in, validate reports it
+        let store = MemoryStore::new();
+        store
+            .update(&block_meta, vec![], future_history(), BTreeMap::new())
+            .unwrap();
+        assert_eq!(
+            store.validate().unwrap(),
+            vec![(7, TxSeen::new(txid, 5, V::Vout(0)))]
+        );
+
+        let mut store = MemoryStore::new();
+        store.set_future_height_policy(FutureHeightPolicy::Reject);
+        assert!(store
+            .update(&block_meta, vec![], future_history(), BTreeMap::new())
+            .is_err());
+        assert!(store.history.lock().unwrap().is_empty());
+
+        let mut store = MemoryStore::new();
+        store.set_future_height_policy(FutureHeightPolicy::Clamp);
+        store
+            .update(&block_meta, vec![], future_history(), BTreeMap::new())
+            .unwrap();
+        assert!(store.validate().unwrap().is_empty());
+        assert_eq!(
+            store.get_history(&[7]).unwrap(),
+            vec![vec![TxSeen::new(txid, 0, V::Vout(0))]]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_entries_policy() {
+        let block_meta = BlockMeta::new(
+            1,
+            elements::BlockHash::from_str(&"aa".repeat(32)).unwrap(),
+            0,
+        );
+        let txid = Txid::from_array([1; 32]);
+        let history = || {
+            BTreeMap::from([(
+                7,
+                vec![
+                    TxSeen::new(txid, 1, V::Vout(0)),
+                    TxSeen::new(txid, 1, V::Vout(1)),
+                    TxSeen::new(txid, 1, V::Vout(0)),
+                ],
+            )])
+        };
+
+        let store = MemoryStore::new();
+        store
+            .update(&block_meta, vec![], history(), BTreeMap::new())
+            .unwrap();
+        assert_eq!(
+            store.get_history(&[7]).unwrap(),
+            vec![vec![
+                TxSeen::new(txid, 1, V::Vout(0)),
+                TxSeen::new(txid, 1, V::Vout(1)),
+            ]],
+            "outputs paying the same script are kept, the identical entry is removed"
+        );
+
+        let mut store = MemoryStore::new();
+        store.set_duplicate_entries_policy(DuplicateEntriesPolicy::Keep);
+        store
+            .update(&block_meta, vec![], history(), BTreeMap::new())
+            .unwrap();
+        assert_eq!(
+            store.get_history(&[7]).unwrap(),
+            vec![history()[&7].clone()]
+        );
+    }
+
+    #[test]
+    fn test_get_all_block_metas_since() {
+        let store = MemoryStore::new();
+        for height in 0..5 {
+            let hash = elements::BlockHash::from_str(&format!("{height:02}").repeat(32)).unwrap();
+            let meta = BlockMeta::new(height, hash, 100 + height);
+            store
+                .update(&meta, vec![], BTreeMap::new(), BTreeMap::new())
+                .unwrap();
+        }
+
+        let heights = |since| {
+            store
+                .get_all_block_metas_since(since)
+                .unwrap()
+                .iter()
+                .map(|meta| meta.height())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(heights(0), vec![0, 1, 2, 3, 4]);
+        assert_eq!(heights(3), vec![3, 4]);
+        assert_eq!(heights(4), vec![4]);
+        assert!(heights(5).is_empty());
+
+        store.reorg(4).unwrap();
+        assert!(heights(4).is_empty());
+    }
+
+    #[test]
+    fn test_upsert_block_meta() {
+        let store = MemoryStore::new();
+        let hash_a = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let hash_b = elements::BlockHash::from_str(&"bb".repeat(32)).unwrap();
+
+        assert!(store
+            .upsert_block_meta(&BlockMeta::new(1, hash_a, 10))
+            .unwrap());
+        assert!(!store
+            .upsert_block_meta(&BlockMeta::new(1, hash_b, 20))
+            .unwrap());
+        let tip = store.tip_block_meta().unwrap().unwrap();
+        assert_eq!(tip.hash(), hash_b);
+        assert_eq!(tip.timestamp(), 20);
+        assert_eq!(store.count_indexed_blocks().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_hash_salt() {
+        let store = MemoryStore::with_salt([1; 16]);
+        let hash = store.hash(b"test");
+        assert_ne!(hash, MemoryStore::with_salt([2; 16]).hash(b"test"));
+        assert_eq!(hash, MemoryStore::with_salt([1; 16]).hash(b"test"));
+        assert_ne!(
+            MemoryStore::new().hash(b"test"),
+            MemoryStore::new().hash(b"test")
+        );
+    }
+
+    #[test]
+    fn test_get_coinbase_txids_at_height() {
+        let store = MemoryStore::new();
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let coinbase_txid = Txid::from_array([1; 32]);
+        let other_txid = Txid::from_array([2; 32]);
+        let mut history_map = BTreeMap::new();
+        history_map.insert(
+            7,
+            vec![
+                TxSeen::new(coinbase_txid, 1, V::Vout(0)),
+                TxSeen::new(other_txid, 1, V::Vout(0)),
+            ],
+        );
+
+        store.insert_coinbase_txids(1, &[coinbase_txid]).unwrap();
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                history_map,
+                BTreeMap::new(),
+            )
+            .unwrap();
+        assert_eq!(
+            store.get_coinbase_txids_at_height(1).unwrap(),
+            vec![coinbase_txid]
+        );
+        assert!(store.get_coinbase_txids_at_height(2).unwrap().is_empty());
+
+        store.reorg(1).unwrap();
+        assert!(store.get_coinbase_txids_at_height(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_unspent_history() {
+        let store = MemoryStore::new();
+        let script_hash = 5;
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let funding_txid = Txid::from_array([1; 32]);
+        let spending_txid = Txid::from_array([2; 32]);
+        let spent = OutPoint::new(funding_txid, 0);
+        let unspent = OutPoint::new(funding_txid, 1);
+
+        let history_map = BTreeMap::from([(
+            script_hash,
+            vec![
+                TxSeen::new(funding_txid, 1, V::Vout(0)),
+                TxSeen::new(funding_txid, 1, V::Vout(1)),
+            ],
+        )]);
+        let utxo_created =
+            BTreeMap::from([(spent, script_hash.into()), (unspent, script_hash.into())]);
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+        store
+            .update(
+                &BlockMeta::new(2, block_hash, 0),
+                vec![(0, spent, spending_txid)],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )
+            .unwrap();
+        assert_eq!(store.get_history(&[script_hash]).unwrap()[0].len(), 3);
+
+        let result = store.get_unspent_history(&[script_hash, 6]).unwrap();
+        assert_eq!(
+            result,
+            vec![vec![TxSeen::new(funding_txid, 1, V::Vout(1))], vec![]]
+        );
+    }
+
+    #[test]
+    fn test_reorg_cycle_three_blocks_deep() {
+        let store = MemoryStore::new();
+
+        // every block creates an output to its own script and spends the output of the
+        // previous block
+        let index_block = |height: Height, txid: Txid, script_hash: ScriptHash| {
+            let block_hash =
+                elements::BlockHash::from_str(&format!("{height:02x}").repeat(32)).unwrap();
+            let created = OutPoint::new(txid, 0);
+            let spent = store.utxos.lock().unwrap().keys().next().copied();
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash, height),
+                    spent.map(|o| (0, o, txid)).into_iter().collect(),
+                    BTreeMap::from([(script_hash, vec![TxSeen::new(txid, height, V::Vout(0))])]),
+                    BTreeMap::from([(created, script_hash)]),
+                )
+                .unwrap();
+            created
+        };
+        let old_txid = |height: Height| Txid::from_array([height as u8 + 1; 32]);
+        let new_txid = |height: Height| Txid::from_array([height as u8 + 0x80; 32]);
+
+        let old_outpoints: Vec<_> = (0..10)
+            .map(|h| index_block(h, old_txid(h), 100 + h as u64))
+            .collect();
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 9);
+
+        for height in [9, 8, 7] {
+            store.reorg(height).unwrap();
+        }
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 6);
+
+        // (a) history of the rewound blocks is removed, including the spend of block 6 output
+        let rewound = store.get_history(&[107, 108, 109]).unwrap();
+        assert!(rewound.iter().all(|h| h.is_empty()));
+        assert_eq!(
+            store.get_history(&[106]).unwrap()[0],
+            vec![TxSeen::new(old_txid(6), 6, V::Vout(0))]
+        );
+        // (b) utxos created in rewound blocks are absent
+        let utxos = store.get_utxos(&old_outpoints[7..]).unwrap();
+        assert!(utxos.iter().all(|u| u.is_none()));
+        // (c) the utxo spent in the first rewound block is restored
+        assert_eq!(
+            store.get_utxos(&old_outpoints[6..7]).unwrap(),
+            vec![Some(106)]
+        );
+
+        let new_outpoints: Vec<_> = (7..=10)
+            .map(|h| index_block(h, new_txid(h), 200 + h as u64))
+            .collect();
+
+        // (d) the replacement blocks are indexed
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 10);
+        assert_eq!(
+            store.get_history(&[106]).unwrap()[0],
+            vec![
+                TxSeen::new(old_txid(6), 6, V::Vout(0)),
+                TxSeen::new(new_txid(7), 7, V::Vin(0)),
+            ]
+        );
+        assert_eq!(
+            store.get_history(&[210]).unwrap()[0],
+            vec![TxSeen::new(new_txid(10), 10, V::Vout(0))]
+        );
+        assert_eq!(
+            store.get_utxos(&new_outpoints).unwrap(),
+            vec![None, None, None, Some(210)]
+        );
+        assert!(store
+            .get_utxos(&old_outpoints)
+            .unwrap()
+            .iter()
+            .all(|u| u.is_none()));
+    }
+
+    #[test]
+    fn test_get_utxos_filtered() {
+        let store = MemoryStore::new();
+        let txid = Txid::from_array([1; 32]);
+        let outpoints: Vec<_> = (0..4).map(|vout| OutPoint::new(txid, vout)).collect();
+        {
+            let mut utxos = store.utxos.lock().unwrap();
+            utxos.insert(outpoints[0], 10);
+            utxos.insert(outpoints[1], 20);
+            utxos.insert(outpoints[2], 30);
+            // outpoints[3] is spent
+        }
+
+        let owned = store.get_utxos_filtered(&outpoints, &[10, 30, 40]).unwrap();
+        assert_eq!(owned, vec![outpoints[0], outpoints[2]]);
+        assert!(store
+            .get_utxos_filtered(&outpoints, &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_history_with_values() {
+        let store = MemoryStore::new();
+        let script_hash = 5;
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let funding_txid = Txid::from_array([1; 32]);
+        let spending_txid = Txid::from_array([2; 32]);
+        let explicit = OutPoint::new(funding_txid, 0);
+        let confidential = OutPoint::new(funding_txid, 1);
+
+        let history_map = BTreeMap::from([(
+            script_hash,
+            vec![
+                TxSeen::new(funding_txid, 1, V::Vout(0)),
+                TxSeen::new(funding_txid, 1, V::Vout(1)),
+            ],
+        )]);
+        let utxo_created = BTreeMap::from([
+            (explicit, script_hash.into()),
+            (confidential, script_hash.into()),
+        ]);
+        store
+            .insert_values(&BTreeMap::from([(explicit, 1000)]), &BTreeMap::new())
+            .unwrap();
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+        store
+            .insert_values(
+                &BTreeMap::new(),
+                &BTreeMap::from([(OutPoint::new(spending_txid, 0), 1000)]),
+            )
+            .unwrap();
+        store
+            .update(
+                &BlockMeta::new(2, block_hash, 0),
+                vec![(0, explicit, spending_txid)],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )
+            .unwrap();
+
+        let result = store.get_history_with_values(script_hash).unwrap();
+        let values: Vec<_> = result
+            .iter()
+            .map(|e| (e.tx_seen.v.clone(), e.value_sat))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (V::Vout(0), Some(1000)),
+                (V::Vout(1), None),
+                (V::Vin(0), Some(1000)),
+            ]
+        );
+        assert!(store.get_history_with_values(6).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_summary_retention() {
+        let mut store = MemoryStore::new();
+        store.set_block_summary_keep_heights(Some(3));
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        for height in 0..5 {
+            let history_map = BTreeMap::from([(
+                5,
+                vec![TxSeen::new(Txid::from_array([1; 32]), height, V::Vout(0))],
+            )]);
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash, 0),
+                    vec![],
+                    history_map,
+                    BTreeMap::new(),
+                )
+                .unwrap();
+        }
+
+        assert!(store.get_block_summary(1).unwrap().is_none());
+        assert_eq!(
+            store.get_block_summary(2).unwrap(),
+            Some(BlockSummary {
+                history_entries: 1,
+                scripts_changed: 1,
+                utxos_created: 0,
+                utxos_spent: 0,
+            })
+        );
+        store.reorg(4).unwrap();
+        assert!(store.get_block_summary(4).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_deep_reorg() {
+        // every block spends the output created by the previous one
+        let apply_blocks = |store: &MemoryStore, heights: std::ops::Range<Height>| {
+            for height in heights {
+                let txid = Txid::from_array(height.to_be_bytes().repeat(8).try_into().unwrap());
+                let script_hash = (height % 5) as ScriptHash;
+                let history_map =
+                    BTreeMap::from([(script_hash, vec![TxSeen::new(txid, height, V::Vout(0))])]);
+                let mut utxo_spent = vec![];
+                if height > 0 {
+                    let previous = height - 1;
+                    let previous_txid =
+                        Txid::from_array(previous.to_be_bytes().repeat(8).try_into().unwrap());
+                    utxo_spent.push((0, OutPoint::new(previous_txid, 0), txid));
+                }
+                let meta = BlockMeta::new(height, block_hash(height), 0);
+                let utxo_created = BTreeMap::from([(OutPoint::new(txid, 0), script_hash.into())]);
+                store
+                    .update(&meta, utxo_spent, history_map, utxo_created)
+                    .unwrap();
+            }
+        };
+        fn block_hash(height: Height) -> elements::BlockHash {
+            elements::BlockHash::from_str(&format!("{:064x}", height + 1)).unwrap()
+        }
+
+        let store = MemoryStore::new();
+        apply_blocks(&store, 0..30);
+        let utxos = store.utxos.lock().unwrap().clone();
+        let history = store.history.lock().unwrap().clone();
+
+        apply_blocks(&store, 30..150);
+        assert_eq!(store.apply_reorg_up_to(block_hash(29)).unwrap(), 120);
+        assert_eq!(*store.utxos.lock().unwrap(), utxos);
+        assert_eq!(*store.history.lock().unwrap(), history);
+
+        // with a limited depth only the most recent blocks can be rolled back
+        let mut store = MemoryStore::new();
+        store.set_reorg_data_keep_heights(Some(10));
+        apply_blocks(&store, 0..150);
+        assert!(!store.has_reorg_data(139).unwrap());
+        assert!(store.has_reorg_data(140).unwrap());
+        let err = store.apply_reorg_up_to(block_hash(29)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("missing reorg data for height 139"),
+            "{err}"
+        );
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 149);
+        assert_eq!(store.apply_reorg_up_to(block_hash(139)).unwrap(), 10);
+        assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 139);
+    }
+
+    #[test]
+    fn test_persist_and_load() {
+        let one = Txid::from_array([1; 32]);
+        let two = Txid::from_array([2; 32]);
+        let block_hash = |i| elements::BlockHash::from_byte_array([i; 32]);
+        let mut store = MemoryStore::new();
+        store.set_index_spent_heights(true);
+        store
+            .update(
+                &BlockMeta::new(1, block_hash(1), 10),
+                vec![],
+                BTreeMap::from([(5, vec![TxSeen::new(one, 1, V::Vout(0))])]),
+                BTreeMap::from([(OutPoint::new(one, 0), Utxo::from(5))]),
+            )
+            .unwrap();
+        store.insert_coinbase_txids(1, &[one]).unwrap();
+        store
+            .insert_values(
+                &BTreeMap::from([(OutPoint::new(one, 0), 1000)]),
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        store.write_metadata("note", b"value").unwrap();
+        let asset = elements::AssetId::from_slice(&[3; 32]).unwrap();
+        let asset_history = BTreeMap::from([(asset, vec![TxSeen::new(one, 1, V::Vout(0))])]);
+        store.insert_asset_history(1, &asset_history).unwrap();
+        store
+            .update(
+                &BlockMeta::new(2, block_hash(2), 20),
+                vec![(0, OutPoint::new(one, 0), two)],
+                BTreeMap::from([(6, vec![TxSeen::new(two, 2, V::Vout(0))])]),
+                BTreeMap::from([(OutPoint::new(two, 0), Utxo::from(6))]),
+            )
+            .unwrap();
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("memory_store");
+        store.persist(&path).unwrap();
+        let loaded = MemoryStore::load(&path).unwrap();
+
+        let outpoints = [OutPoint::new(one, 0), OutPoint::new(two, 0)];
+        let scripts = [5, 6];
+        assert_eq!(loaded.hash(b"script"), store.hash(b"script"));
+        assert_eq!(
+            loaded.get_utxos(&outpoints).unwrap(),
+            store.get_utxos(&outpoints).unwrap()
+        );
+        assert_eq!(
+            loaded.get_history(&scripts).unwrap(),
+            store.get_history(&scripts).unwrap()
+        );
+        let tip = loaded.tip_block_meta().unwrap().unwrap();
+        assert_eq!((tip.height(), tip.hash()), (2, block_hash(2)));
+        assert_eq!(
+            loaded.get_values(&outpoints).unwrap(),
+            vec![Some(1000), None]
+        );
+        assert_eq!(
+            *loaded.spent_heights.lock().unwrap(),
+            BTreeMap::from([(OutPoint::new(one, 0), 2)])
+        );
+        assert_eq!(
+            loaded.get_commitments(&outpoints).unwrap(),
+            store.get_commitments(&outpoints).unwrap()
+        );
+        assert_eq!(
+            loaded.read_metadata("note").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            loaded.count_distinct_txids().unwrap(),
+            store.count_distinct_txids().unwrap()
+        );
+        assert_eq!(
+            loaded
+                .get_asset_history_page(asset, HistoryPage::default())
+                .unwrap(),
+            (asset_history[&asset].clone(), None)
+        );
+        assert!(loaded.diff_with_store(&store).unwrap().is_empty());
+
+        // the reorg data is restored too
+        assert_eq!(loaded.reorg(2).unwrap(), store.reorg(2).unwrap());
+        assert_eq!(loaded.get_utxos(&outpoints).unwrap(), vec![Some(5), None]);
+        assert_eq!(
+            loaded.get_history(&scripts).unwrap(),
+            store.get_history(&scripts).unwrap()
+        );
+        assert!(loaded.diff_with_store(&store).unwrap().is_empty());
+
+        std::fs::write(&path, [PERSIST_VERSION + 1]).unwrap();
+        let err = MemoryStore::load(&path).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("unknown memory store file version"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_persist_and_load_resume_block_metas() {
+        let block_hash = |i| elements::BlockHash::from_byte_array([i; 32]);
+        let store = MemoryStore::new();
+        for height in 0..3 {
+            let meta = BlockMeta::new(height, block_hash(height as u8), height);
+            store
+                .update(&meta, vec![], BTreeMap::new(), BTreeMap::new())
+                .unwrap();
+        }
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let path = tempdir.path().join("memory_store");
+        store.persist(&path).unwrap();
+        let loaded = MemoryStore::load(&path).unwrap();
+
+        // the headers are preloaded from these, so that indexing resumes after the saved tip
+        let metas: Vec<_> = loaded
+            .iter_hash_ts()
+            .map(|m| (m.height(), m.hash(), m.timestamp()))
+            .collect();
+        assert_eq!(
+            metas,
+            (0..3)
+                .map(|h| (h, block_hash(h as u8), h))
+                .collect::<Vec<_>>()
+        );
+        let tip = loaded.tip_block_meta().unwrap().unwrap();
+        assert_eq!((tip.height(), tip.hash()), (2, block_hash(2)));
+    }
+
+    #[test]
+    fn test_concurrent_reads_during_update() {
+        // block h creates the output h for script h, spent by block h + 1
+        let txid = |height: Height| Txid::from_array([height as u8; 32]);
+        let block_hash = |height: Height| elements::BlockHash::from_byte_array([height as u8; 32]);
+        let store = MemoryStore::new();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let blocks = 200;
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut reads = 0u64;
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) || reads < 1000 {
+                        let height = (reads % blocks as u64) as Height;
+                        let outpoint = OutPoint::new(txid(height), 0);
+                        let utxo = store.get_utxos(&[outpoint]).unwrap()[0];
+                        let history = store.get_history(&[height as ScriptHash]).unwrap();
+                        match utxo {
+                            Some(script_hash) => {
+                                assert_eq!(script_hash, height as ScriptHash);
+                                assert!(!history[0].is_empty(), "utxo {height} without history");
+                            }
+                            None => assert_ne!(
+                                history[0].len(),
+                                1,
+                                "spent output {height} in neither utxos nor history"
+                            ),
+                        }
+                        reads += 1;
+                    }
+                });
+            }
+
+            for height in 0..blocks {
+                let history_map = BTreeMap::from([(
+                    height as ScriptHash,
+                    vec![TxSeen::new(txid(height), height, V::Vout(0))],
+                )]);
+                let utxo_spent = match height {
+                    0 => vec![],
+                    _ => vec![(0, OutPoint::new(txid(height - 1), 0), txid(height))],
+                };
+                store
+                    .update(
+                        &BlockMeta::new(height, block_hash(height), 0),
+                        utxo_spent,
+                        history_map,
+                        BTreeMap::from([(
+                            OutPoint::new(txid(height), 0),
+                            Utxo::from(height as ScriptHash),
+                        )]),
+                    )
+                    .unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        assert_eq!(store.stats().utxos, 1);
+    }
+
+    #[test]
+    fn test_reorg_data_keep_heights() {
+        let mut store = MemoryStore::new();
+        store.set_reorg_data_keep_heights(Some(2));
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        for height in 1..=3 {
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash, 0),
+                    vec![],
+                    BTreeMap::new(),
+                    BTreeMap::new(),
+                )
+                .unwrap();
+        }
+        assert!(!store.has_reorg_data(1).unwrap());
+        assert!(store.has_reorg_data(2).unwrap());
+        assert!(store.has_reorg_data(3).unwrap());
+
+        let err = store.reorg(1).unwrap_err();
+        assert!(err.to_string().contains("last 2 heights"), "{err}");
+    }
+
+    #[test]
+    fn test_stats() {
+        let store = MemoryStore::new();
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let funding_txid = Txid::from_array([1; 32]);
+        let spending_txid = Txid::from_array([2; 32]);
+        let funding: Vec<_> = (0..2)
+            .map(|vout| OutPoint::new(funding_txid, vout))
+            .collect();
+        let history_map = BTreeMap::from([(
+            5,
+            vec![
+                TxSeen::new(funding_txid, 1, V::Vout(0)),
+                TxSeen::new(funding_txid, 1, V::Vout(1)),
+            ],
+        )]);
+        let utxo_created = funding.iter().map(|o| (*o, Utxo::from(5))).collect();
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+
+        let history_map = BTreeMap::from([
+            (5, vec![TxSeen::new(spending_txid, 2, V::Vin(0))]),
+            (6, vec![TxSeen::new(spending_txid, 2, V::Vout(0))]),
+        ]);
+        let utxo_created = BTreeMap::from([(OutPoint::new(spending_txid, 0), Utxo::from(6))]);
+        store
+            .update(
+                &BlockMeta::new(2, block_hash, 0),
+                vec![(0, funding[0], spending_txid)],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.stats(),
+            StoreStats {
+                approximate_tx_count: 2,
+                utxos: 2,
+                scripts_with_history: 2,
+                history_entries: Some(4),
+                reorg_data_depth: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_utxos_for_script() {
+        let store = MemoryStore::new();
+        let script_hash = 5;
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let funding_txid = Txid::from_array([1; 32]);
+        let outpoints: Vec<_> = (0..3)
+            .map(|vout| OutPoint::new(funding_txid, vout))
+            .collect();
+
+        let history_map = BTreeMap::from([(
+            script_hash,
+            (0..3)
+                .map(|vout| TxSeen::new(funding_txid, 1, V::Vout(vout)))
+                .collect(),
+        )]);
+        let utxo_created = outpoints.iter().map(|o| (*o, script_hash.into())).collect();
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                history_map,
+                utxo_created,
+            )
+            .unwrap();
+        store
+            .update(
+                &BlockMeta::new(2, block_hash, 0),
+                vec![(0, outpoints[1], Txid::from_array([2; 32]))],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.count_utxos_for_script(&[script_hash, 6]).unwrap(),
+            vec![2, 0]
+        );
+        assert_eq!(
+            store
+                .iter_utxos_for_script(script_hash)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap(),
+            vec![(outpoints[0], script_hash), (outpoints[2], script_hash)]
+        );
+        assert_eq!(store.iter_utxos_for_script(6).count(), 0);
+
+        // the spent output is unspent again once its block is rolled back, and the created ones
+        // are gone with theirs
+        store.reorg(2).unwrap();
+        assert_eq!(
+            store.get_utxos_by_script(&[script_hash]).unwrap(),
+            vec![outpoints.clone()]
+        );
+        store.reorg(1).unwrap();
+        assert_eq!(
+            store.get_utxos_by_script(&[script_hash]).unwrap(),
+            vec![vec![]]
+        );
+        assert!(store.utxos_by_script.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_history_until() {
+        let store = MemoryStore::new();
+        let script_hash = 5;
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let index = |height: Height| {
+            let txid = Txid::from_array([height as u8; 32]);
+            let history_map =
+                BTreeMap::from([(script_hash, vec![TxSeen::new(txid, height, V::Vout(0))])]);
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash, 0),
+                    vec![],
+                    history_map,
+                    BTreeMap::new(),
+                )
+                .unwrap();
+        };
+        index(1);
+        index(2);
+
+        // the client pins the tip at the first page
+        let ceiling = store.tip_block_meta().unwrap().unwrap().height();
+        let first_page = store.get_history_until(&[script_hash], ceiling).unwrap();
+        assert_eq!(first_page[0].len(), 2);
+
+        index(3);
+        let second_page = store.get_history_until(&[script_hash], ceiling).unwrap();
+        assert_eq!(second_page, first_page);
+        assert_eq!(store.get_history(&[script_hash]).unwrap()[0].len(), 3);
+        assert_eq!(
+            store.get_history_until(&[script_hash, 6], 1).unwrap(),
+            vec![
+                vec![TxSeen::new(Txid::from_array([1; 32]), 1, V::Vout(0))],
+                vec![]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_balance() {
+        let store = MemoryStore::new();
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let txid = Txid::from_array([1; 32]);
+        let outpoints: Vec<_> = (0..3).map(|vout| OutPoint::new(txid, vout)).collect();
+        let asset = elements::AssetId::from_slice(&[7; 32]).unwrap();
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                BTreeMap::from([(
+                    5,
+                    (0..3)
+                        .map(|vout| TxSeen::new(txid, 1, V::Vout(vout)))
+                        .collect(),
+                )]),
+                outpoints.iter().map(|o| (*o, Utxo::from(5))).collect(),
+            )
+            .unwrap();
+        // the second output is confidential
+        store
+            .insert_values(
+                &BTreeMap::from([(outpoints[0], 1000), (outpoints[2], 500)]),
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        store
+            .insert_assets(&BTreeMap::from([(outpoints[0], asset)]))
+            .unwrap();
+
+        let expected = Balance {
+            value_sat: 500,
+            assets: BTreeMap::from([(asset, 1000)]),
+            confidential: 1,
+        };
+        assert_eq!(
+            store.get_balance(&[5, 6]).unwrap(),
+            vec![expected.clone(), Balance::default()]
+        );
+
+        let spending_txid = Txid::from_array([2; 32]);
+        store
+            .update(
+                &BlockMeta::new(2, block_hash, 0),
+                vec![(0, outpoints[2], spending_txid)],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )
+            .unwrap();
+        assert_eq!(store.get_balance(&[5]).unwrap()[0].value_sat, 0);
+
+        store.reorg(2).unwrap();
+        assert_eq!(store.get_balance(&[5]).unwrap(), vec![expected]);
+    }
+
+    #[test]
+    fn test_get_history_from() {
+        let store = MemoryStore::new();
+        let block_hash = elements::BlockHash::from_str(&"aa".repeat(32)).unwrap();
+        let entry =
+            |height: Height| TxSeen::new(Txid::from_array([height as u8; 32]), height, V::Vout(0));
+        for height in 1..=3 {
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash, 0),
+                    vec![],
+                    BTreeMap::from([(5, vec![entry(height)])]),
+                    BTreeMap::new(),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            store.get_history_from(&[5], 2).unwrap(),
+            vec![vec![entry(2), entry(3)]],
+            "the entry at min_height is included"
+        );
+        assert_eq!(
+            store.get_history_from(&[5], 0).unwrap(),
+            store.get_history(&[5]).unwrap()
+        );
+        assert_eq!(
+            store.get_history_between_heights(5, 2, 2).unwrap(),
+            vec![entry(2)]
+        );
+        assert!(store
+            .get_history_between_heights(5, 3, 2)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store.get_history_from(&[5, 6], 4).unwrap(),
+            vec![vec![], vec![]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_history() {
+        use futures_util::StreamExt;
+
+        let store = MemoryStore::new();
+        let txid = Txid::from_array([1; 32]);
+        let entries = vec![
+            TxSeen::new(txid, 1, V::Vout(0)),
+            TxSeen::new(txid, 2, V::Vin(0)),
+        ];
+        store
+            .update(
+                &BlockMeta::new(
+                    1,
+                    elements::BlockHash::from_str(&"aa".repeat(32)).unwrap(),
+                    0,
+                ),
+                vec![],
+                BTreeMap::from([(5, entries.clone())]),
+                BTreeMap::new(),
+            )
+            .unwrap();
+
+        let streamed: Vec<_> = store.stream_history(5).collect().await;
+        let streamed: Vec<_> = streamed.into_iter().map(Result::unwrap).collect();
+        assert_eq!(streamed, entries);
+        assert_eq!(store.stream_history(6).count().await, 0);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let store = MemoryStore::new();
+        let txid = Txid::from_array([1; 32]);
+        for height in 1..=2 {
+            let block_hash = elements::BlockHash::from_str(&format!("{height:02}").repeat(32));
+            let entries = (0..100_000)
+                .map(|vout| TxSeen::new(txid, height, V::Vout(vout)))
+                .collect();
+            store
+                .update(
+                    &BlockMeta::new(height, block_hash.unwrap(), 0),
+                    vec![],
+                    BTreeMap::from([(7, entries)]),
+                    BTreeMap::new(),
+                )
+                .unwrap();
+        }
+        store.reorg(2).unwrap();
+        assert_eq!(store.get_history(&[7]).unwrap()[0].len(), 100_000);
+
+        let before = store.estimated_bytes();
+        store.shrink_to_fit();
+        let after = store.estimated_bytes();
+        assert!(after < before, "{after} not less than {before}");
+        assert!(after >= 100_000 * std::mem::size_of::<TxSeen>());
+        assert_eq!(store.get_history(&[7]).unwrap()[0].len(), 100_000);
     }
 }
@@ -1,7 +1,14 @@
-use crate::{Height, OutPoint, ScriptHash, Timestamp, TxSeen};
-use anyhow::Result;
-use elements::BlockHash;
-use std::collections::BTreeMap;
+use crate::{Family, Height, OutPoint, ScriptHash, Timestamp, TxSeen, TxSeenWithValue, V};
+use anyhow::{Context, Result};
+use block_changes::BlockChanges;
+use elements::{confidential, BlockHash};
+use futures_util::stream::BoxStream;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    str::FromStr,
+};
+
+mod block_changes;
 
 #[cfg(feature = "db")]
 pub mod db;
@@ -9,18 +16,75 @@ pub mod db;
 #[cfg(feature = "db")]
 mod reorg_data;
 
+mod hll;
+
+mod siphash;
+
+#[cfg(feature = "profiling")]
+pub(crate) mod profile;
+
 pub mod memory;
 
+#[cfg(feature = "db")]
+pub(crate) mod utxo_snapshot;
+
+/// Keys of the metadata written by waterfalls with [`Store::write_metadata`]
+pub mod metadata {
+    /// Version of the layout of the DB column families
+    pub const SCHEMA_VERSION: &str = "schema_version";
+
+    /// Name of the network the data has been indexed for
+    pub const INDEXED_NETWORK: &str = "indexed_network";
+
+    /// First height with indexed history, big endian, set when the UTXO set has been imported
+    /// from a snapshot: the transactions of the blocks below it are missing
+    pub const HISTORY_START_HEIGHT: &str = "history_start_height";
+}
+
 pub enum AnyStore {
     #[cfg(feature = "db")]
     Db(db::DBStore),
     Mem(memory::MemoryStore),
 }
 impl AnyStore {
-    pub(crate) fn stats(&self) -> Option<String> {
+    pub(crate) fn set_future_height_policy(&mut self, policy: FutureHeightPolicy) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.set_future_height_policy(policy),
+            AnyStore::Mem(m) => m.set_future_height_policy(policy),
+        }
+    }
+
+    pub(crate) fn set_duplicate_entries_policy(&mut self, policy: DuplicateEntriesPolicy) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.set_duplicate_entries_policy(policy),
+            AnyStore::Mem(m) => m.set_duplicate_entries_policy(policy),
+        }
+    }
+
+    /// Write the heights spending the outputs, see [`Store::get_spent_heights`]
+    pub(crate) fn set_index_spent_heights(&mut self, index_spent_heights: bool) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.set_index_spent_heights(index_spent_heights),
+            AnyStore::Mem(m) => m.set_index_spent_heights(index_spent_heights),
+        }
+    }
+
+    /// Keep the summaries of only the last `keep_heights` blocks, or all of them if None
+    pub(crate) fn set_block_summary_keep_heights(&mut self, keep_heights: Option<u32>) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.set_block_summary_keep_heights(keep_heights),
+            AnyStore::Mem(m) => m.set_block_summary_keep_heights(keep_heights),
+        }
+    }
+
+    pub(crate) fn rocksdb_stats(&self) -> Option<String> {
         match self {
             #[cfg(feature = "db")]
-            AnyStore::Db(dbstore) => dbstore.stats(),
+            AnyStore::Db(dbstore) => dbstore.rocksdb_stats(),
             AnyStore::Mem(_) => None,
         }
     }
@@ -29,37 +93,661 @@ impl AnyStore {
 pub trait Store {
     /// Hash the given script
     ///
-    /// It's in the trait cause it can be salted with some random values contained in the
-    /// concrete implementation to avoid attacker brute force collisions
+    /// It's in the trait cause it's keyed with a random salt contained in the concrete
+    /// implementation, to avoid attacker brute force collisions.
+    ///
+    /// Both stores hash with SipHash-1-3 keyed with a random salt, persisted by [`db::DBStore`]
+    /// so that hashes are stable across restarts: two nodes indexing the same chain have
+    /// different `ScriptHash` namespaces, so hashes are never meaningful outside the node that
+    /// computed them.
     fn hash(&self, script: &[u8]) -> ScriptHash;
 
+    /// Parse the address, which must belong to `family`, and hash its script pubkey
+    ///
+    /// Fails with [`StoreError::InvalidAddress`] if the address can't be parsed
+    fn get_script_hash_for_address(&self, address: &str, family: Family) -> Result<ScriptHash> {
+        let script_pubkey = match family {
+            Family::Bitcoin => bitcoin::Address::from_str(address)
+                .map(|a| a.assume_checked().script_pubkey().to_bytes())
+                .ok(),
+            Family::Elements => elements::Address::from_str(address)
+                .map(|a| a.script_pubkey().to_bytes())
+                .ok(),
+        };
+        let script_pubkey =
+            script_pubkey.ok_or_else(|| StoreError::InvalidAddress(address.to_string()))?;
+        Ok(self.hash(&script_pubkey))
+    }
+
     /// Iterate over blocks metadata to preload those in memory
     fn iter_hash_ts(&self) -> Box<dyn Iterator<Item = BlockMeta> + '_>;
 
+    /// Number of blocks whose metadata is stored
+    fn count_indexed_blocks(&self) -> Result<u64> {
+        Ok(self.iter_hash_ts().count() as u64)
+    }
+
     /// Get given outpoints from the UTXO set to compute the mempool history
+    ///
+    /// There is no limit on the number of outpoints, callers serving requests enforce their own
     fn get_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<ScriptHash>>>;
 
+    /// Get the given outpoints that are unspent and belong to one of `scripts`, in the same
+    /// order as `outpoints`
+    fn get_utxos_filtered(
+        &self,
+        outpoints: &[OutPoint],
+        scripts: &[ScriptHash],
+    ) -> Result<Vec<OutPoint>> {
+        let scripts: HashSet<_> = scripts.iter().collect();
+        Ok(self
+            .get_utxos(outpoints)?
+            .into_iter()
+            .zip(outpoints)
+            .filter(|(script, _)| script.as_ref().is_some_and(|s| scripts.contains(s)))
+            .map(|(_, outpoint)| *outpoint)
+            .collect())
+    }
+
+    /// Like [`Store::get_utxos`], with the value and asset commitments the outputs were created
+    /// with, see [`Store::get_commitments`]
+    fn get_utxos_full(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Utxo>>> {
+        let script_hashes = self.get_utxos(outpoints)?;
+        let commitments = self.get_commitments(outpoints)?;
+        Ok(script_hashes
+            .into_iter()
+            .zip(commitments)
+            .map(|(script_hash, commitments)| {
+                let (value, asset) =
+                    commitments.unwrap_or((confidential::Value::Null, confidential::Asset::Null));
+                script_hash.map(|script_hash| Utxo {
+                    script_hash,
+                    value,
+                    asset,
+                })
+            })
+            .collect())
+    }
+
+    /// Get the value and asset commitments of the given unspent outputs, written by
+    /// [`Store::update`] and removed with the outputs when spent. None if spent or not created by
+    /// an indexed block, like the outputs imported with [`Store::bulk_import`].
+    fn get_commitments(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Commitments>>>;
+
     /// Get history of multiple (usually 20 like the gap limit) scripts hash at once
     fn get_history(&self, scripts: &[ScriptHash]) -> Result<Vec<Vec<TxSeen>>>;
 
+    /// Like [`Store::get_history`] but without the entries above `max_height`, so that multiple
+    /// paged queries can be consistent with the tip at the time of the first page.
+    fn get_history_until(
+        &self,
+        scripts: &[ScriptHash],
+        max_height: Height,
+    ) -> Result<Vec<Vec<TxSeen>>> {
+        let mut history = self.get_history(scripts)?;
+        for entries in history.iter_mut() {
+            entries.retain(|e| e.height <= max_height);
+        }
+        Ok(history)
+    }
+
+    /// Like [`Store::get_history`] but only with the entries at `min_height` or above, for
+    /// clients already synced up to `min_height`. Filtered before cloning the entries.
+    fn get_history_from(
+        &self,
+        scripts: &[ScriptHash],
+        min_height: Height,
+    ) -> Result<Vec<Vec<TxSeen>>>;
+
+    /// The history entries of `script` in `page`, with the height where the next page starts,
+    /// None if there are no more entries.
+    ///
+    /// Entries at the same height are never split across pages, so a page may have more than
+    /// `limit` entries. The DB store reads the whole history of the script from disk for every
+    /// page, only the decoding is limited to the page.
+    fn get_history_page(
+        &self,
+        script: ScriptHash,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)> {
+        let entries = self
+            .get_history_from(&[script], page.from_height.unwrap_or(0))?
+            .pop()
+            .unwrap_or_default();
+        paginate_history(entries.into_iter().map(Ok), page.limit)
+    }
+
+    /// The history entries of `script` with height in `from..=to`
+    ///
+    /// All the entries of a script are stored together, so this is [`Store::get_history_from`]
+    /// with the entries above `to` removed, not a range seek.
+    fn get_history_between_heights(
+        &self,
+        script: ScriptHash,
+        from: Height,
+        to: Height,
+    ) -> Result<Vec<TxSeen>> {
+        let mut entries = self
+            .get_history_from(&[script], from)?
+            .pop()
+            .unwrap_or_default();
+        entries.retain(|e| e.height <= to);
+        Ok(entries)
+    }
+
+    /// The history of a group of scripts, like the ones of an account, as a single list sorted
+    /// by height and [`V`]. A transaction touching more than one script of the group is kept
+    /// once, with its first entry in that order.
+    fn get_history_merged(&self, scripts: &[ScriptHash]) -> Result<Vec<TxSeen>> {
+        let mut merged: Vec<_> = self.get_history(scripts)?.into_iter().flatten().collect();
+        merged.sort_by_key(|e| (e.height, e.v.raw()));
+        let mut seen = HashSet::new();
+        merged.retain(|e| seen.insert(e.txid));
+        Ok(merged)
+    }
+
+    /// Like [`Store::get_history`] for a single script, but yielding the entries one at a time
+    /// instead of collecting all of them in memory
+    fn stream_history(&self, script: ScriptHash) -> BoxStream<'static, Result<TxSeen>>;
+
     /// Check whether multiple scripts have any history without decoding full entries.
     fn has_history(&self, scripts: &[ScriptHash]) -> Result<Vec<bool>>;
 
+    /// Like [`Store::get_history`] but keeping only the receive entries whose output is still
+    /// in the UTXO set.
+    fn get_unspent_history(&self, scripts: &[ScriptHash]) -> Result<Vec<Vec<TxSeen>>> {
+        let mut history = self.get_history(scripts)?;
+        for entries in history.iter_mut() {
+            entries.retain(|e| e.v.vout().is_some());
+        }
+        let outpoints: Vec<_> = history
+            .iter()
+            .flatten()
+            .filter_map(|e| e.outpoint())
+            .collect();
+        let mut unspent = self.get_utxos(&outpoints)?.into_iter().map(|u| u.is_some());
+        for entries in history.iter_mut() {
+            entries.retain(|_| unspent.next().unwrap_or(false));
+        }
+        Ok(history)
+    }
+
+    /// Like [`Store::get_history`] for a single script, adding to every entry the value received
+    /// by the output or spent by the input it refers to.
+    ///
+    /// Values are known only if indexed with [`Store::insert_values`] and not confidential.
+    fn get_history_with_values(&self, script: ScriptHash) -> Result<Vec<TxSeenWithValue>> {
+        let history = self.get_history(&[script])?.pop().unwrap_or_default();
+        self.add_values(history)
+    }
+
+    /// Add to every entry of `history` the value received by the output or spent by the input it
+    /// refers to, see [`Store::get_history_with_values`]
+    fn add_values(&self, history: Vec<TxSeen>) -> Result<Vec<TxSeenWithValue>> {
+        let outputs: Vec<_> = history.iter().filter_map(|e| e.outpoint()).collect();
+        let inputs: Vec<_> = history
+            .iter()
+            .filter_map(|e| e.v.vin().map(|vin| OutPoint::new(e.txid, vin)))
+            .collect();
+        let mut output_values = self.get_values(&outputs)?.into_iter();
+        let mut spent_values = self.get_spent_values(&inputs)?.into_iter();
+        Ok(history
+            .into_iter()
+            .map(|tx_seen| {
+                let value_sat = match tx_seen.v {
+                    V::Vout(_) => output_values.next().flatten(),
+                    V::Vin(_) => spent_values.next().flatten(),
+                    V::Undefined => None,
+                };
+                TxSeenWithValue { tx_seen, value_sat }
+            })
+            .collect())
+    }
+
+    /// The confirmations of the history `entries` at the indexed tip, `tip - height + 1`, so that
+    /// clients don't have to compute them. Zero for entries above the tip or if nothing is indexed.
+    ///
+    /// The tip is read at call time, the entries should be read before so that none is above it.
+    fn get_confirmations(&self, entries: &[TxSeen]) -> Result<Vec<u32>> {
+        let tip = self.tip_block_meta()?.map(|meta| meta.height());
+        Ok(entries
+            .iter()
+            .map(|e| tip.map_or(0, |tip| (tip + 1).saturating_sub(e.height)))
+            .collect())
+    }
+
+    /// Store the explicit values of the outputs created in a block (`created`) and of the outputs
+    /// spent by its inputs (`spent`, keyed by spending txid and input index).
+    ///
+    /// Values are fixed by the transactions, so they don't need to be rolled back on reorg.
+    fn insert_values(
+        &self,
+        created: &BTreeMap<OutPoint, u64>,
+        spent: &BTreeMap<OutPoint, u64>,
+    ) -> Result<()>;
+
+    /// Get the values of the given outputs, None if not indexed
+    fn get_values(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<u64>>>;
+
+    /// Get the values spent by the given inputs, identified by spending txid and input index
+    fn get_spent_values(&self, inputs: &[OutPoint]) -> Result<Vec<Option<u64>>>;
+
+    /// Get the heights of the blocks spending the given outputs, written by [`Store::update`]
+    /// and removed by [`Store::reorg`]. None if unspent, or if created and spent in the same block
+    /// since such outputs never reach the UTXO set.
+    ///
+    /// Fails with [`StoreError::SpentHeightsNotIndexed`] unless the store is set to index them,
+    /// outputs spent by blocks indexed before enabling it have no spent height.
+    fn get_spent_heights(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Height>>>;
+
+    /// Store the explicit asset of elements outputs, indexed together with the values
+    fn insert_assets(&self, created: &BTreeMap<OutPoint, elements::AssetId>) -> Result<()>;
+
+    /// Get the assets of the given outputs, None if not indexed
+    fn get_assets(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<elements::AssetId>>>;
+
+    /// Replace the asset history entries at `height` with `history`: the transactions creating or
+    /// spending outputs with an explicit asset, indexed together with the values.
+    ///
+    /// Unlike the values and the assets of the outputs the entries depend on the block they are
+    /// in, so they are removed by [`Store::reorg`] and [`Store::clear_at_height`].
+    fn insert_asset_history(
+        &self,
+        height: Height,
+        history: &BTreeMap<elements::AssetId, Vec<TxSeen>>,
+    ) -> Result<()>;
+
+    /// A page of the asset history entries of `asset`, sorted by height, see
+    /// [`Store::get_history_page`]
+    fn get_asset_history_page(
+        &self,
+        asset: elements::AssetId,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)>;
+
+    /// Store an arbitrary value under `key`, like operator notes or markers, overwriting the
+    /// previous one. Keys used by waterfalls are listed in [`metadata`].
+    fn write_metadata(&self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Get the value stored with [`Store::write_metadata`], None if missing
+    fn read_metadata(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// The first height with indexed history, None if the history is complete from genesis.
+    /// See [`metadata::HISTORY_START_HEIGHT`].
+    fn history_start_height(&self) -> Result<Option<Height>> {
+        self.read_metadata(metadata::HISTORY_START_HEIGHT)?
+            .map(|value| Ok(Height::from_be_bytes(value.as_slice().try_into()?)))
+            .transpose()
+    }
+
+    /// Confirmed balance of each script, computed from its outputs in the UTXO set.
+    ///
+    /// Reorgs are reflected since they restore the UTXO set.
+    fn get_balance(&self, scripts: &[ScriptHash]) -> Result<Vec<Balance>> {
+        let mut result = Vec::with_capacity(scripts.len());
+        for outpoints in self.get_utxos_by_script(scripts)? {
+            let values = self.get_values(&outpoints)?;
+            let assets = self.get_assets(&outpoints)?;
+            let mut balance = Balance::default();
+            for (value, asset) in values.into_iter().zip(assets) {
+                match (value, asset) {
+                    (Some(value), Some(asset)) => {
+                        *balance.assets.entry(asset).or_default() += value
+                    }
+                    (Some(value), None) => balance.value_sat += value,
+                    (None, _) => balance.confidential += 1,
+                }
+            }
+            result.push(balance);
+        }
+        Ok(result)
+    }
+
+    /// Count the unspent outputs of each script
+    fn count_utxos_for_script(&self, scripts: &[ScriptHash]) -> Result<Vec<usize>> {
+        Ok(self
+            .get_utxos_by_script(scripts)?
+            .iter()
+            .map(Vec::len)
+            .collect())
+    }
+
+    /// The unspent outputs of each script, see [`Store::iter_utxos_for_script`]
+    fn get_utxos_by_script(&self, scripts: &[ScriptHash]) -> Result<Vec<Vec<OutPoint>>> {
+        scripts
+            .iter()
+            .map(|script_hash| {
+                self.iter_utxos_for_script(*script_hash)
+                    .map(|utxo| utxo.map(|(outpoint, _)| outpoint))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The changes of the unspent outputs of each script since the block at `height` included, to
+    /// update a set of unspent outputs known before that block without fetching it again.
+    ///
+    /// `created` lists the outputs created at `height` or after which are still unspent, `spent`
+    /// the outputs created before `height` and spent at `height` or after, as told by
+    /// [`Store::get_spent_heights`], so it fails if they are not indexed. Outputs both created and
+    /// spent since `height` are in neither list, like the ones both created and spent before it.
+    fn get_utxos_changed_since(
+        &self,
+        scripts: &[ScriptHash],
+        height: Height,
+    ) -> Result<Vec<UtxoDelta>> {
+        let history = self.get_history(scripts)?;
+        let outpoints: Vec<_> = history
+            .iter()
+            .flatten()
+            .filter_map(|e| e.outpoint())
+            .collect();
+        let mut unspent = self.get_utxos(&outpoints)?.into_iter().map(|u| u.is_some());
+        let mut spent_heights = self.get_spent_heights(&outpoints)?.into_iter();
+        Ok(history
+            .iter()
+            .map(|entries| {
+                let mut delta = UtxoDelta::default();
+                for (entry, outpoint) in entries.iter().filter_map(|e| Some((e, e.outpoint()?))) {
+                    let is_unspent = unspent.next().unwrap_or(false);
+                    // outputs spent in the block creating them have no spent height
+                    let spent_height = spent_heights.next().flatten().unwrap_or(entry.height);
+                    if is_unspent {
+                        if entry.height >= height {
+                            delta.created.push(outpoint);
+                        }
+                    } else if entry.height < height && spent_height >= height {
+                        delta.spent.push(outpoint);
+                    }
+                }
+                delta
+            })
+            .collect())
+    }
+
+    /// Iterate the unspent outputs of `script_hash`, with the script hash they belong to
+    ///
+    /// They are read from a secondary index of the UTXO set by script, kept by [`Store::update`],
+    /// [`Store::reorg`] and [`Store::bulk_import`], so the cost doesn't depend on the length of
+    /// the history of the script.
+    fn iter_utxos_for_script(
+        &self,
+        script_hash: ScriptHash,
+    ) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_>;
+
+    /// The index following the last one with history, for the external and internal chains of
+    /// `descriptor`, scanning `gap_limit` indexes at a time until a batch without history.
+    ///
+    /// The chains are the paths of a multipath descriptor like `<0;1>`, the first one is the
+    /// external chain and the second the internal one. `internal` is `None` if the descriptor has
+    /// a single path. Only confirmed history is considered.
+    fn first_unused_index(
+        &self,
+        descriptor: &crate::be::Descriptor,
+        gap_limit: u32,
+    ) -> Result<FirstUnusedIndex> {
+        anyhow::ensure!(gap_limit > 0, "the gap limit must be positive");
+        let chains = descriptor
+            .clone()
+            .into_single_descriptors()
+            .context("invalid descriptor")?;
+        anyhow::ensure!(
+            chains.len() <= 2,
+            "expected at most an external and an internal chain, the descriptor has {} paths",
+            chains.len()
+        );
+        let mut first_unused = Vec::with_capacity(chains.len());
+        for desc in chains.iter() {
+            let mut first_unused_for_chain = 0;
+            for batch_start in (0..).step_by(gap_limit as usize) {
+                let mut scripts = Vec::with_capacity(gap_limit as usize);
+                for index in batch_start..batch_start + gap_limit {
+                    let script_pubkey = desc
+                        .script_pubkey_at_derivation_index(index)
+                        .with_context(|| format!("can't derive index {index}"))?;
+                    scripts.push(self.hash(&script_pubkey));
+                }
+                let used = self.has_history(&scripts)?;
+                match used.iter().rposition(|used| *used) {
+                    Some(i) => first_unused_for_chain = batch_start + i as u32 + 1,
+                    None => break,
+                }
+            }
+            first_unused.push(first_unused_for_chain);
+        }
+        Ok(FirstUnusedIndex {
+            external: first_unused[0],
+            internal: first_unused.get(1).copied(),
+        })
+    }
+
     /// update the store with all the data from the last block
+    ///
+    /// Applying again the block already indexed at its height is a no-op returning no changed
+    /// scripts
     fn update(
         &self,
         block_meta: &BlockMeta,
         utxo_spent: Vec<(u32, OutPoint, crate::be::Txid)>,
         history_map: BTreeMap<ScriptHash, Vec<TxSeen>>, // We want this sorted because when inserted in the write batch it's faster (see benches and test guaranteeing encoding order match struct ordering)
-        utxo_created: BTreeMap<OutPoint, ScriptHash>, // We want this sorted because when inserted in the write batch it's faster (see benches and test guaranteeing encoding order match struct ordering)
+        utxo_created: BTreeMap<OutPoint, Utxo>, // We want this sorted because when inserted in the write batch it's faster (see benches and test guaranteeing encoding order match struct ordering)
     ) -> Result<Vec<ScriptHash>>;
 
+    /// Index `block` at the height of `block_meta`: derive the history, the created and the spent
+    /// outputs from its transactions and pass them to [`Store::update`].
+    ///
+    /// With `index_values` the values and assets of the outputs and the asset history are stored
+    /// too, before the block is marked indexed.
+    fn apply_block(
+        &self,
+        block: &crate::be::Block,
+        block_meta: &BlockMeta,
+        index_values: bool,
+    ) -> Result<AppliedBlock> {
+        let mut changes = BlockChanges::new(self, block, block_meta, index_values);
+        if index_values {
+            // If the process stops before the update the values are rewritten when the block is
+            // indexed again
+            let previous_outputs: Vec<_> = changes
+                .spent_from_previous_blocks
+                .iter()
+                .map(|(_, o)| *o)
+                .collect();
+            let values = self.get_values(&previous_outputs)?;
+            for ((input_key, _), value) in changes.spent_from_previous_blocks.iter().zip(values) {
+                if let Some(value) = value {
+                    changes.values_spent.insert(*input_key, value);
+                }
+            }
+            let assets = self.get_assets(&previous_outputs)?;
+            for ((input_key, _), asset) in changes.spent_from_previous_blocks.iter().zip(assets) {
+                if let Some(asset) = asset {
+                    changes.assets_spent.insert(*input_key, asset);
+                }
+            }
+            self.insert_values(&changes.values_created, &changes.values_spent)?;
+            self.insert_assets(&changes.assets_created)?;
+            self.insert_asset_history(
+                block_meta.height(),
+                &changes.asset_history(block_meta.height()),
+            )?;
+        }
+        // Written before the update like the values, so that they are there once the block is
+        // marked indexed
+        self.insert_coinbase_txids(block_meta.height(), &changes.coinbase_txids)?;
+        let changed_script_hashes = self.update(
+            block_meta,
+            changes.utxo_spent,
+            changes.history_map,
+            changes.utxo_created,
+        )?;
+        Ok(AppliedBlock {
+            txids: changes.txids,
+            changed_script_hashes,
+        })
+    }
+
+    /// Remove the data indexed at the given height, to index that block again.
+    ///
+    /// Only the tip can be cleared: a missing block in the middle would break the chain of block
+    /// metadata loaded at startup. The block is undone with its reorg data like [`Store::reorg`],
+    /// so the outputs it spent are restored; it fails if the reorg data has been pruned.
+    fn clear_at_height(&self, height: Height) -> Result<()> {
+        let tip = self.tip_block_meta()?.map(|meta| meta.height());
+        if tip != Some(height) {
+            anyhow::bail!("can clear only the tip height {tip:?}, not {height}");
+        }
+        self.reorg(height)?;
+        Ok(())
+    }
+
     /// Reorg, reinsert the last block unspent utxos
     /// height: the height of the block that was reorged (needs to be rolled back)
-    fn reorg(&self, height: Height);
+    ///
+    /// Returns what has been rolled back, built from the reorg data of the block
+    fn reorg(&self, height: Height) -> Result<ReorgSummary>;
+
+    /// Insert or replace the metadata of the block at the height of `meta`
+    ///
+    /// Returns true if there was no metadata at that height, false if it has been replaced
+    fn upsert_block_meta(&self, meta: &BlockMeta) -> Result<bool>;
+
+    /// Get the summary of the block at the given height, None if not indexed or already pruned
+    /// by the configured retention
+    fn get_block_summary(&self, height: Height) -> Result<Option<BlockSummary>>;
+
+    /// Get the metadata of the last indexed block, if any
+    fn tip_block_meta(&self) -> Result<Option<BlockMeta>>;
+
+    /// Get the metadata of the indexed block with the given hash, None if not indexed
+    fn get_block_meta_by_hash(&self, hash: BlockHash) -> Result<Option<BlockMeta>>;
+
+    /// Get the metadata of the indexed blocks with height greater or equal than `height`, in
+    /// ascending height order
+    fn get_all_block_metas_since(&self, height: Height) -> Result<Vec<BlockMeta>>;
+
+    /// Whether the data to roll back the block at the given height is available
+    fn has_reorg_data(&self, height: Height) -> Result<bool>;
+
+    /// Unwind indexed blocks until the tip is the block with hash `new_tip_hash`, usually the
+    /// common ancestor between the indexed chain and the new best chain.
+    ///
+    /// Fails without changing anything if the block is not indexed or if the reorg data to roll
+    /// back one of the blocks after it is not available. Returns the number of blocks rewound.
+    fn apply_reorg_up_to(&self, new_tip_hash: BlockHash) -> Result<u32> {
+        let new_tip = self
+            .get_block_meta_by_hash(new_tip_hash)?
+            .ok_or_else(|| anyhow::anyhow!("can't reorg to unknown block {new_tip_hash}"))?;
+        let tip_height = self.tip_block_meta()?.map_or(0, |tip| tip.height());
+        let heights = new_tip.height() + 1..=tip_height;
+        for height in heights.clone().rev() {
+            if !self.has_reorg_data(height)? {
+                anyhow::bail!(
+                    "missing reorg data for height {height}, can't reorg to block {new_tip_hash} \
+                    at height {}",
+                    new_tip.height()
+                );
+            }
+        }
+        for height in heights.rev() {
+            let summary = self.reorg(height)?;
+            log::info!(
+                "reorg: rolled back height {} affecting {} scripts and {} txs",
+                summary.height,
+                summary.affected_scripts.len(),
+                summary.affected_txids.len()
+            );
+        }
+        Ok(tip_height - new_tip.height())
+    }
+
+    /// Replace the txids of the coinbase transactions of the block at `height`, kept in a
+    /// separate index since they are not part of any script history.
+    ///
+    /// Like the asset history they depend on the block, so they are removed by [`Store::reorg`]
+    /// and [`Store::clear_at_height`].
+    fn insert_coinbase_txids(&self, height: Height, txids: &[crate::be::Txid]) -> Result<()>;
+
+    /// Get the txids of the coinbase transactions of the block at `height`
+    fn get_coinbase_txids_at_height(&self, height: Height) -> Result<Vec<crate::be::Txid>>;
+
+    /// Persist the changes to the mempool so that they survive a restart.
+    ///
+    /// No-op for stores not configured to persist the mempool.
+    fn persist_mempool_changes(
+        &self,
+        added: &[(crate::be::Txid, &crate::be::MempoolTx)],
+        removed: &[crate::be::Txid],
+    ) -> Result<()>;
+
+    /// Load the mempool transactions persisted with [`Store::persist_mempool_changes`]
+    fn load_persisted_mempool(&self) -> Result<Vec<(crate::be::Txid, crate::be::MempoolTx)>>;
 
     /// Called when the initial block download is finished
     fn ibd_finished(&self);
+
+    /// Make the writes done so far durable, so that they survive a crash of the machine
+    fn flush(&self) -> Result<()>;
+
+    /// Approximate number of distinct transactions in the history index.
+    ///
+    /// Computed with a HyperLogLog++ estimator, the error is usually below 1%. Rolled back
+    /// transactions are still counted.
+    fn count_distinct_txids(&self) -> Result<u64>;
+
+    /// Statistics about the content of the store, cheap enough to be computed on every request
+    fn stats(&self) -> StoreStats;
+
+    /// Iterate the whole UTXO set
+    fn iter_utxos(&self) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_>;
+
+    /// Iterate the history of every script
+    fn iter_history(&self) -> Box<dyn Iterator<Item = Result<(ScriptHash, Vec<TxSeen>)>> + '_>;
+
+    /// Write `utxos`, `history` and `block_metas` directly, for example to migrate the content
+    /// of another store read with [`Store::iter_utxos`] and [`Store::iter_history`].
+    ///
+    /// Unlike [`Store::update`] nothing is derived from the given data: no spent outputs are
+    /// resolved and no reorg data or block summaries are written, the history entries are
+    /// appended to the existing ones as they are. Heights in `block_metas` must be contiguous and
+    /// follow the indexed tip, if any.
+    fn bulk_import(
+        &self,
+        utxos: BTreeMap<OutPoint, ScriptHash>,
+        history: BTreeMap<ScriptHash, Vec<TxSeen>>,
+        block_metas: Vec<BlockMeta>,
+    ) -> Result<()>;
+
+    /// Compare the utxos and the history with the ones of `other`, for example to verify a
+    /// migrated index. Script hashes are compared as they are, so both stores must use the same
+    /// salt.
+    ///
+    /// Each store is scanned once and looked up in the other in chunks, only the differences are
+    /// kept in memory, at most [`MAX_STORE_DIFFERENCES`].
+    fn diff_with_store(&self, other: &dyn Store) -> Result<StoreDiff> {
+        let mut diff = StoreDiff {
+            only_in_self: utxos_not_in(self, other, MAX_STORE_DIFFERENCES)?,
+            ..Default::default()
+        };
+        diff.only_in_other = utxos_not_in(other, self, diff.remaining())?;
+        let remaining = diff.remaining();
+        for (script_hash, entries, _) in history_differences(self, other, remaining, |_, _| true)? {
+            diff.history_differences.push((script_hash, entries));
+        }
+        // scripts with a different history in both stores are already reported
+        let remaining = diff.remaining();
+        for (script_hash, _, entries) in
+            history_differences(other, self, remaining, |_, entries| entries.is_empty())?
+        {
+            diff.history_differences.push((script_hash, entries));
+        }
+        diff.truncated = diff.remaining() == 0;
+        Ok(diff)
+    }
+
+    /// Return the history entries having a height above the indexed tip, which should never
+    /// happen and means either a caller bug or a corrupted index.
+    fn validate(&self) -> Result<Vec<(ScriptHash, TxSeen)>>;
 }
 
 impl Store for AnyStore {
@@ -79,7 +767,17 @@ impl Store for AnyStore {
         }
     }
 
+    fn count_indexed_blocks(&self) -> Result<u64> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.count_indexed_blocks(),
+            AnyStore::Mem(m) => m.count_indexed_blocks(),
+        }
+    }
+
     fn get_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<ScriptHash>>> {
+        #[cfg(feature = "profiling")]
+        let _timer = profile::start("get_utxos");
         match self {
             #[cfg(feature = "db")]
             AnyStore::Db(d) => d.get_utxos(outpoints),
@@ -88,6 +786,8 @@ impl Store for AnyStore {
     }
 
     fn get_history(&self, scripts: &[ScriptHash]) -> Result<Vec<Vec<TxSeen>>> {
+        #[cfg(feature = "profiling")]
+        let _timer = profile::start("get_history");
         match self {
             #[cfg(feature = "db")]
             AnyStore::Db(d) => d.get_history(scripts),
@@ -95,6 +795,38 @@ impl Store for AnyStore {
         }
     }
 
+    fn get_history_from(
+        &self,
+        scripts: &[ScriptHash],
+        min_height: Height,
+    ) -> Result<Vec<Vec<TxSeen>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_history_from(scripts, min_height),
+            AnyStore::Mem(m) => m.get_history_from(scripts, min_height),
+        }
+    }
+
+    fn get_history_page(
+        &self,
+        script: ScriptHash,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_history_page(script, page),
+            AnyStore::Mem(m) => m.get_history_page(script, page),
+        }
+    }
+
+    fn stream_history(&self, script: ScriptHash) -> BoxStream<'static, Result<TxSeen>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.stream_history(script),
+            AnyStore::Mem(m) => m.stream_history(script),
+        }
+    }
+
     fn has_history(&self, scripts: &[ScriptHash]) -> Result<Vec<bool>> {
         match self {
             #[cfg(feature = "db")]
@@ -103,13 +835,115 @@ impl Store for AnyStore {
         }
     }
 
+    fn insert_values(
+        &self,
+        created: &BTreeMap<OutPoint, u64>,
+        spent: &BTreeMap<OutPoint, u64>,
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.insert_values(created, spent),
+            AnyStore::Mem(m) => m.insert_values(created, spent),
+        }
+    }
+
+    fn get_values(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<u64>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_values(outpoints),
+            AnyStore::Mem(m) => m.get_values(outpoints),
+        }
+    }
+
+    fn get_spent_values(&self, inputs: &[OutPoint]) -> Result<Vec<Option<u64>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_spent_values(inputs),
+            AnyStore::Mem(m) => m.get_spent_values(inputs),
+        }
+    }
+
+    fn get_spent_heights(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Height>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_spent_heights(outpoints),
+            AnyStore::Mem(m) => m.get_spent_heights(outpoints),
+        }
+    }
+
+    fn get_commitments(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<Commitments>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_commitments(outpoints),
+            AnyStore::Mem(m) => m.get_commitments(outpoints),
+        }
+    }
+
+    fn insert_assets(&self, created: &BTreeMap<OutPoint, elements::AssetId>) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.insert_assets(created),
+            AnyStore::Mem(m) => m.insert_assets(created),
+        }
+    }
+
+    fn get_assets(&self, outpoints: &[OutPoint]) -> Result<Vec<Option<elements::AssetId>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_assets(outpoints),
+            AnyStore::Mem(m) => m.get_assets(outpoints),
+        }
+    }
+
+    fn insert_asset_history(
+        &self,
+        height: Height,
+        history: &BTreeMap<elements::AssetId, Vec<TxSeen>>,
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.insert_asset_history(height, history),
+            AnyStore::Mem(m) => m.insert_asset_history(height, history),
+        }
+    }
+
+    fn get_asset_history_page(
+        &self,
+        asset: elements::AssetId,
+        page: HistoryPage,
+    ) -> Result<(Vec<TxSeen>, Option<Height>)> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_asset_history_page(asset, page),
+            AnyStore::Mem(m) => m.get_asset_history_page(asset, page),
+        }
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.write_metadata(key, value),
+            AnyStore::Mem(m) => m.write_metadata(key, value),
+        }
+    }
+
+    fn read_metadata(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.read_metadata(key),
+            AnyStore::Mem(m) => m.read_metadata(key),
+        }
+    }
+
     fn update(
         &self,
         block_meta: &BlockMeta,
         utxo_spent: Vec<(u32, OutPoint, crate::be::Txid)>,
         history_map: BTreeMap<ScriptHash, Vec<TxSeen>>,
-        utxo_created: BTreeMap<OutPoint, ScriptHash>,
+        utxo_created: BTreeMap<OutPoint, Utxo>,
     ) -> Result<Vec<ScriptHash>> {
+        #[cfg(feature = "profiling")]
+        let _timer = profile::start("update");
         match self {
             #[cfg(feature = "db")]
             AnyStore::Db(d) => d.update(block_meta, utxo_spent, history_map, utxo_created),
@@ -117,7 +951,17 @@ impl Store for AnyStore {
         }
     }
 
-    fn reorg(&self, height: Height) {
+    fn clear_at_height(&self, height: Height) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.clear_at_height(height),
+            AnyStore::Mem(m) => m.clear_at_height(height),
+        }
+    }
+
+    fn reorg(&self, height: Height) -> Result<ReorgSummary> {
+        #[cfg(feature = "profiling")]
+        let _timer = profile::start("reorg");
         match self {
             #[cfg(feature = "db")]
             AnyStore::Db(d) => d.reorg(height),
@@ -125,23 +969,621 @@ impl Store for AnyStore {
         }
     }
 
-    fn ibd_finished(&self) {
+    fn insert_coinbase_txids(&self, height: Height, txids: &[crate::be::Txid]) -> Result<()> {
         match self {
             #[cfg(feature = "db")]
-            AnyStore::Db(d) => d.ibd_finished(),
-            AnyStore::Mem(m) => m.ibd_finished(),
+            AnyStore::Db(d) => d.insert_coinbase_txids(height, txids),
+            AnyStore::Mem(m) => m.insert_coinbase_txids(height, txids),
         }
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct BlockMeta {
-    pub height: Height,
-    pub hash: BlockHash,
-    pub timestamp: Timestamp,
-}
+    fn get_coinbase_txids_at_height(&self, height: Height) -> Result<Vec<crate::be::Txid>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_coinbase_txids_at_height(height),
+            AnyStore::Mem(m) => m.get_coinbase_txids_at_height(height),
+        }
+    }
 
-impl BlockMeta {
+    fn upsert_block_meta(&self, meta: &BlockMeta) -> Result<bool> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.upsert_block_meta(meta),
+            AnyStore::Mem(m) => m.upsert_block_meta(meta),
+        }
+    }
+
+    fn get_block_summary(&self, height: Height) -> Result<Option<BlockSummary>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_block_summary(height),
+            AnyStore::Mem(m) => m.get_block_summary(height),
+        }
+    }
+
+    fn tip_block_meta(&self) -> Result<Option<BlockMeta>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.tip_block_meta(),
+            AnyStore::Mem(m) => m.tip_block_meta(),
+        }
+    }
+
+    fn get_block_meta_by_hash(&self, hash: BlockHash) -> Result<Option<BlockMeta>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_block_meta_by_hash(hash),
+            AnyStore::Mem(m) => m.get_block_meta_by_hash(hash),
+        }
+    }
+
+    fn get_all_block_metas_since(&self, height: Height) -> Result<Vec<BlockMeta>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.get_all_block_metas_since(height),
+            AnyStore::Mem(m) => m.get_all_block_metas_since(height),
+        }
+    }
+
+    fn has_reorg_data(&self, height: Height) -> Result<bool> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.has_reorg_data(height),
+            AnyStore::Mem(m) => m.has_reorg_data(height),
+        }
+    }
+
+    fn persist_mempool_changes(
+        &self,
+        added: &[(crate::be::Txid, &crate::be::MempoolTx)],
+        removed: &[crate::be::Txid],
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.persist_mempool_changes(added, removed),
+            AnyStore::Mem(m) => m.persist_mempool_changes(added, removed),
+        }
+    }
+
+    fn load_persisted_mempool(&self) -> Result<Vec<(crate::be::Txid, crate::be::MempoolTx)>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.load_persisted_mempool(),
+            AnyStore::Mem(m) => m.load_persisted_mempool(),
+        }
+    }
+
+    fn ibd_finished(&self) {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.ibd_finished(),
+            AnyStore::Mem(m) => m.ibd_finished(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.flush(),
+            AnyStore::Mem(m) => m.flush(),
+        }
+    }
+
+    fn count_distinct_txids(&self) -> Result<u64> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.count_distinct_txids(),
+            AnyStore::Mem(m) => m.count_distinct_txids(),
+        }
+    }
+
+    fn stats(&self) -> StoreStats {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.stats(),
+            AnyStore::Mem(m) => m.stats(),
+        }
+    }
+
+    fn iter_utxos(&self) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.iter_utxos(),
+            AnyStore::Mem(m) => m.iter_utxos(),
+        }
+    }
+
+    fn iter_utxos_for_script(
+        &self,
+        script_hash: ScriptHash,
+    ) -> Box<dyn Iterator<Item = Result<(OutPoint, ScriptHash)>> + '_> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.iter_utxos_for_script(script_hash),
+            AnyStore::Mem(m) => m.iter_utxos_for_script(script_hash),
+        }
+    }
+
+    fn iter_history(&self) -> Box<dyn Iterator<Item = Result<(ScriptHash, Vec<TxSeen>)>> + '_> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.iter_history(),
+            AnyStore::Mem(m) => m.iter_history(),
+        }
+    }
+
+    fn bulk_import(
+        &self,
+        utxos: BTreeMap<OutPoint, ScriptHash>,
+        history: BTreeMap<ScriptHash, Vec<TxSeen>>,
+        block_metas: Vec<BlockMeta>,
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.bulk_import(utxos, history, block_metas),
+            AnyStore::Mem(m) => m.bulk_import(utxos, history, block_metas),
+        }
+    }
+
+    fn validate(&self) -> Result<Vec<(ScriptHash, TxSeen)>> {
+        match self {
+            #[cfg(feature = "db")]
+            AnyStore::Db(d) => d.validate(),
+            AnyStore::Mem(m) => m.validate(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    InvalidAddress(String),
+    /// A block spends an output which is not in the utxo set
+    MissingUtxo(OutPoint),
+    /// The history entries to remove while reorging are not the last ones of the script
+    ReorgHistoryMismatch(ScriptHash),
+    /// The spent heights are requested but the store doesn't index them
+    SpentHeightsNotIndexed,
+}
+
+/// Returned by [`Store::first_unused_index`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FirstUnusedIndex {
+    pub external: u32,
+    pub internal: Option<u32>,
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for StoreError {}
+
+/// Returned by [`Store::get_balance`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Balance {
+    /// Sum of the explicit values of the outputs without an asset, like bitcoin ones
+    pub value_sat: u64,
+    /// Sum of the explicit values of elements outputs by asset
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub assets: BTreeMap<elements::AssetId, u64>,
+    /// Number of outputs with unknown value, because confidential or indexed without values, so
+    /// the sums are a lower bound when this is not zero
+    pub confidential: usize,
+}
+
+/// Statistics about the content of a store, see [`Store::stats`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct StoreStats {
+    /// Approximate number of distinct transactions indexed
+    pub approximate_tx_count: u64,
+    /// Number of unspent outputs, estimated by RocksDB in the DB store
+    pub utxos: u64,
+    /// Number of scripts with history, estimated by RocksDB in the DB store
+    pub scripts_with_history: u64,
+    /// Number of history entries, None in the DB store where it would need a full scan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_entries: Option<u64>,
+    /// Number of blocks that can be rolled back with the available reorg data
+    pub reorg_data_depth: u64,
+}
+
+/// Maximum number of differences reported by [`Store::diff_with_store`]
+pub const MAX_STORE_DIFFERENCES: usize = 1000;
+
+// number of utxos or scripts looked up at once in the other store while diffing
+const DIFF_CHUNK: usize = 1000;
+
+/// Returned by [`Store::apply_block`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedBlock {
+    /// The txids of the block transactions, in block order
+    pub txids: Vec<crate::be::Txid>,
+    /// The scripts whose history changed, as returned by [`Store::update`]
+    pub changed_script_hashes: Vec<ScriptHash>,
+}
+
+/// Returned by [`Store::diff_with_store`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    /// Utxos missing in the other store or belonging to a different script there
+    pub only_in_self: Vec<OutPoint>,
+    /// Utxos missing in this store or belonging to a different script here
+    pub only_in_other: Vec<OutPoint>,
+    /// Scripts with a different history in the two stores, with the history in this store.
+    /// The history is empty for scripts having history only in the other store.
+    pub history_differences: Vec<(ScriptHash, Vec<TxSeen>)>,
+    /// Whether [`MAX_STORE_DIFFERENCES`] has been reached, there may be more differences
+    pub truncated: bool,
+}
+
+impl StoreDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty()
+            && self.only_in_other.is_empty()
+            && self.history_differences.is_empty()
+    }
+
+    fn remaining(&self) -> usize {
+        MAX_STORE_DIFFERENCES.saturating_sub(
+            self.only_in_self.len() + self.only_in_other.len() + self.history_differences.len(),
+        )
+    }
+}
+
+/// The utxos of `a` missing in `b` or belonging to a different script there, at most `limit`
+fn utxos_not_in<A, B>(a: &A, b: &B, limit: usize) -> Result<Vec<OutPoint>>
+where
+    A: Store + ?Sized,
+    B: Store + ?Sized,
+{
+    let mut result = vec![];
+    let mut utxos = a.iter_utxos();
+    while result.len() < limit {
+        let chunk = utxos
+            .by_ref()
+            .take(DIFF_CHUNK)
+            .collect::<Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+        let outpoints: Vec<_> = chunk.iter().map(|(outpoint, _)| *outpoint).collect();
+        for ((outpoint, script_hash), in_b) in chunk.into_iter().zip(b.get_utxos(&outpoints)?) {
+            if in_b != Some(script_hash) {
+                result.push(outpoint);
+            }
+        }
+    }
+    result.truncate(limit);
+    Ok(result)
+}
+
+/// The scripts with a different history in `a` and `b` for which `report` holds, with their
+/// history in `a` and `b`, at most `limit`
+fn history_differences<A, B>(
+    a: &A,
+    b: &B,
+    limit: usize,
+    report: impl Fn(&[TxSeen], &[TxSeen]) -> bool,
+) -> Result<Vec<(ScriptHash, Vec<TxSeen>, Vec<TxSeen>)>>
+where
+    A: Store + ?Sized,
+    B: Store + ?Sized,
+{
+    let mut result = vec![];
+    let mut history = a.iter_history();
+    while result.len() < limit {
+        let chunk = history
+            .by_ref()
+            .take(DIFF_CHUNK)
+            .collect::<Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+        let scripts: Vec<_> = chunk.iter().map(|(script_hash, _)| *script_hash).collect();
+        for ((script_hash, in_a), in_b) in chunk.into_iter().zip(b.get_history(&scripts)?) {
+            if in_a != in_b && report(&in_a, &in_b) {
+                result.push((script_hash, in_a, in_b));
+            }
+        }
+    }
+    result.truncate(limit);
+    Ok(result)
+}
+
+/// What has been rolled back by [`Store::reorg`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReorgSummary {
+    /// Height of the rolled back block
+    pub height: Height,
+    /// Scripts whose history or utxos changed
+    pub affected_scripts: BTreeSet<ScriptHash>,
+    /// Transactions removed from the history
+    pub affected_txids: BTreeSet<crate::be::Txid>,
+    /// Outputs spent in the block, back in the UTXO set
+    pub utxos_restored: Vec<OutPoint>,
+    /// Outputs created in the block, removed from the UTXO set
+    pub utxos_removed: Vec<OutPoint>,
+}
+
+impl ReorgSummary {
+    pub(crate) fn new(
+        height: Height,
+        spent: &[(OutPoint, ScriptHash)],
+        history: &BTreeMap<ScriptHash, Vec<TxSeen>>,
+        utxos_created: &BTreeMap<OutPoint, ScriptHash>,
+    ) -> Self {
+        let affected_scripts = history
+            .keys()
+            .chain(spent.iter().map(|(_, s)| s))
+            .chain(utxos_created.values())
+            .copied()
+            .collect();
+        let affected_txids = history.values().flatten().map(|e| e.txid).collect();
+        ReorgSummary {
+            height,
+            affected_scripts,
+            affected_txids,
+            utxos_restored: spent.iter().map(|(o, _)| *o).collect(),
+            utxos_removed: utxos_created.keys().copied().collect(),
+        }
+    }
+}
+
+/// Counters of what a block changed in the index, available through
+/// [`Store::get_block_summary`] for a retention independent of the reorg data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct BlockSummary {
+    /// Number of history entries added, including the spending ones
+    pub history_entries: u32,
+    /// Number of scripts with new history entries
+    pub scripts_changed: u32,
+    /// Number of outputs added to the UTXO set
+    pub utxos_created: u32,
+    /// Number of outputs removed from the UTXO set
+    pub utxos_spent: u32,
+}
+
+impl BlockSummary {
+    pub(crate) fn new(
+        utxos_spent: usize,
+        history: &BTreeMap<ScriptHash, Vec<TxSeen>>,
+        utxos_created: usize,
+    ) -> Self {
+        BlockSummary {
+            history_entries: history.values().map(|v| v.len() as u32).sum(),
+            scripts_changed: history.len() as u32,
+            utxos_created: utxos_created as u32,
+            utxos_spent: utxos_spent as u32,
+        }
+    }
+
+    #[cfg(feature = "db")]
+    pub(crate) fn to_bytes(self) -> [u8; 16] {
+        let mut result = [0u8; 16];
+        for (chunk, value) in result.chunks_mut(4).zip([
+            self.history_entries,
+            self.scripts_changed,
+            self.utxos_created,
+            self.utxos_spent,
+        ]) {
+            chunk.copy_from_slice(&value.to_be_bytes());
+        }
+        result
+    }
+
+    #[cfg(feature = "db")]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let values: Vec<u32> = bytes
+            .chunks(4)
+            .map(|c| c.try_into().map(u32::from_be_bytes))
+            .collect::<Result<_, _>>()?;
+        match values[..] {
+            [history_entries, scripts_changed, utxos_created, utxos_spent] => Ok(BlockSummary {
+                history_entries,
+                scripts_changed,
+                utxos_created,
+                utxos_spent,
+            }),
+            _ => anyhow::bail!("invalid block summary length {}", bytes.len()),
+        }
+    }
+}
+
+/// Height of the summary or reorg data to prune after indexing `height`, when keeping only the
+/// last `keep_heights` of them
+fn height_to_prune(height: Height, keep_heights: Option<u32>) -> Option<Height> {
+    height.checked_sub(keep_heights?)
+}
+
+/// What `Store::update` does with history entries having a height above the block being indexed
+#[derive(Clone, clap::ValueEnum, Debug, Default, PartialEq, Eq, Copy)]
+pub enum FutureHeightPolicy {
+    /// Store the entries as they are
+    #[default]
+    Allow,
+    /// Lower the height of the entries to the height of the block being indexed
+    Clamp,
+    /// Fail the update
+    Reject,
+}
+
+/// Split the outputs passed to [`Store::update`] in their script hashes and their commitments,
+/// which are stored apart
+pub(crate) fn split_created_utxos(
+    utxo_created: BTreeMap<OutPoint, Utxo>,
+) -> (BTreeMap<OutPoint, ScriptHash>, Vec<(OutPoint, Commitments)>) {
+    let commitments = utxo_created
+        .iter()
+        .map(|(outpoint, utxo)| (*outpoint, (utxo.value, utxo.asset)))
+        .collect();
+    let script_hashes = utxo_created
+        .into_iter()
+        .map(|(outpoint, utxo)| (outpoint, utxo.script_hash))
+        .collect();
+    (script_hashes, commitments)
+}
+
+/// Apply the given policy to the history entries above the height of the block being indexed
+pub(crate) fn apply_future_height_policy(
+    policy: FutureHeightPolicy,
+    block_meta: &BlockMeta,
+    history_map: &mut BTreeMap<ScriptHash, Vec<TxSeen>>,
+) -> Result<()> {
+    if policy == FutureHeightPolicy::Allow {
+        return Ok(());
+    }
+    let height = block_meta.height();
+    for (script_hash, entries) in history_map.iter_mut() {
+        for entry in entries.iter_mut().filter(|e| e.height > height) {
+            match policy {
+                FutureHeightPolicy::Allow => (),
+                FutureHeightPolicy::Clamp => {
+                    log::warn!(
+                        "clamping {} height {} to {height} for script hash {script_hash}",
+                        entry.txid,
+                        entry.height
+                    );
+                    entry.height = height;
+                }
+                FutureHeightPolicy::Reject => anyhow::bail!(
+                    "{} has height {} above the indexed block {height} for script hash {script_hash}",
+                    entry.txid,
+                    entry.height
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What `Store::update` does with history entries of a script identical to a previous one of the
+/// same script, which means a caller bug. Entries of the same transaction with a different `v`,
+/// like multiple outputs paying the same script, are not duplicates.
+#[derive(Clone, clap::ValueEnum, Debug, Default, PartialEq, Eq, Copy)]
+pub enum DuplicateEntriesPolicy {
+    /// Store the entries as they are
+    Keep,
+    /// Store only the first of the identical entries
+    #[default]
+    Remove,
+    /// Like `Remove` but logging a warning for every removed entry
+    Warn,
+}
+
+/// Apply the given policy to the identical history entries of the same script, returns the number
+/// of removed entries
+pub(crate) fn apply_duplicate_entries_policy(
+    policy: DuplicateEntriesPolicy,
+    history_map: &mut BTreeMap<ScriptHash, Vec<TxSeen>>,
+) -> usize {
+    if policy == DuplicateEntriesPolicy::Keep {
+        return 0;
+    }
+    let mut removed = 0;
+    for (script_hash, entries) in history_map.iter_mut() {
+        if entries.len() < 2 {
+            continue;
+        }
+        let mut seen = BTreeSet::new();
+        entries.retain(|e| {
+            if seen.insert((e.txid, e.height, e.v.raw())) {
+                return true;
+            }
+            if policy == DuplicateEntriesPolicy::Warn {
+                log::warn!(
+                    "removing duplicate entry {} height {} v {:?} for script hash {script_hash}",
+                    e.txid,
+                    e.height,
+                    e.v
+                );
+            }
+            removed += 1;
+            false
+        });
+    }
+    removed
+}
+
+/// A page of the history of a script, see [`Store::get_history_page`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryPage {
+    /// Only the entries at this height or above
+    pub from_height: Option<Height>,
+    /// The number of entries after which the page ends at the next height change
+    pub limit: Option<usize>,
+}
+
+/// Collect `entries`, sorted by height, until `limit` is reached and the height changes.
+///
+/// Returns the collected entries and the height of the first entry left out, if any.
+pub(crate) fn paginate_history<I>(
+    entries: I,
+    limit: Option<usize>,
+) -> Result<(Vec<TxSeen>, Option<Height>)>
+where
+    I: Iterator<Item = Result<TxSeen>>,
+{
+    let mut page: Vec<TxSeen> = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let full = limit.is_some_and(|limit| page.len() >= limit);
+        if full && page.last().is_some_and(|last| last.height != entry.height) {
+            return Ok((page, Some(entry.height)));
+        }
+        page.push(entry);
+    }
+    Ok((page, None))
+}
+
+/// Fail if the heights of `block_metas` are not contiguous or don't follow `tip`, or don't start
+/// from genesis if there is no tip
+pub(crate) fn check_contiguous_heights(
+    block_metas: &[BlockMeta],
+    tip: Option<Height>,
+) -> Result<()> {
+    let mut expected = tip.map_or(0, |tip| tip + 1);
+    for meta in block_metas {
+        if meta.height() != expected {
+            anyhow::bail!(
+                "expected block meta at height {expected}, got {}",
+                meta.height()
+            );
+        }
+        expected = meta.height() + 1;
+    }
+    Ok(())
+}
+
+/// Whether an entry at `height` is above the given tip, every entry is when nothing is indexed
+pub(crate) fn is_above_tip(height: Height, tip: Option<Height>) -> bool {
+    tip.is_none_or(|tip| height > tip)
+}
+
+/// Genesis block hashes of mainnet, testnet3, testnet4, signet and regtest
+const BITCOIN_GENESIS_HASHES: [&str; 5] = [
+    "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+    "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+    "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+];
+
+/// Genesis block hashes of liquid, liquid testnet and elements regtest (default chain params)
+const ELEMENTS_GENESIS_HASHES: [&str; 3] = [
+    "1466275836220db2944ca059a3a10ef6fd2ea684b0688d2c379296888a206003",
+    "a771da8e52ee6ad581ed1e9a99825e5b3b7992225534eaa2ae23244fe26ab1c1",
+    "c7af03b0774a3498a574902bd41045c1633fd40b69ca163345c5d9c78bfd6af7",
+];
+
+#[derive(Clone, Debug)]
+pub struct BlockMeta {
+    pub height: Height,
+    pub hash: BlockHash,
+    pub timestamp: Timestamp,
+}
+
+impl BlockMeta {
     pub fn new(height: Height, hash: BlockHash, timestamp: Timestamp) -> BlockMeta {
         BlockMeta {
             height,
@@ -161,4 +1603,891 @@ impl BlockMeta {
     pub(crate) fn hash(&self) -> BlockHash {
         self.hash
     }
+
+    /// Whether this is the genesis block of one of the known networks of the given family
+    pub(crate) fn is_genesis(&self, family: Family) -> bool {
+        let known = match family {
+            Family::Bitcoin => &BITCOIN_GENESIS_HASHES[..],
+            Family::Elements => &ELEMENTS_GENESIS_HASHES[..],
+        };
+        self.height == 0
+            && known
+                .iter()
+                .any(|hash| BlockHash::from_str(hash).expect("static") == self.hash)
+    }
+}
+
+impl From<&BlockMeta> for crate::BlockMeta {
+    fn from(meta: &BlockMeta) -> Self {
+        crate::BlockMeta {
+            b: meta.hash(),
+            t: meta.timestamp(),
+            h: meta.height(),
+        }
+    }
+}
+
+/// Returned by [`Store::get_utxos_changed_since`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoDelta {
+    /// Unspent outputs created at the height or after
+    pub created: Vec<OutPoint>,
+    /// Outputs created before the height and spent since, see [`Store::get_utxos_changed_since`]
+    pub spent: Vec<OutPoint>,
+}
+
+/// The value and the asset commitments of an output, see [`Store::get_commitments`]
+pub type Commitments = (confidential::Value, confidential::Asset);
+
+/// An output created by a block, passed to [`Store::update`] and returned by
+/// [`Store::get_utxos_full`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utxo {
+    pub script_hash: ScriptHash,
+    /// The value commitment, blinded for confidential elements outputs
+    pub value: confidential::Value,
+    /// The asset commitment, blinded for confidential elements outputs and null for bitcoin ones
+    pub asset: confidential::Asset,
+}
+
+impl From<ScriptHash> for Utxo {
+    /// An output with null commitments, for which only the script is known
+    fn from(script_hash: ScriptHash) -> Self {
+        Utxo {
+            script_hash,
+            value: confidential::Value::Null,
+            asset: confidential::Asset::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elements::hashes::Hash;
+
+    #[test]
+    fn test_is_genesis() {
+        let meta =
+            |height, hash: &str| BlockMeta::new(height, BlockHash::from_str(hash).unwrap(), 0);
+        for hash in BITCOIN_GENESIS_HASHES {
+            assert!(meta(0, hash).is_genesis(Family::Bitcoin));
+            assert!(!meta(0, hash).is_genesis(Family::Elements));
+            assert!(!meta(1, hash).is_genesis(Family::Bitcoin));
+        }
+        for hash in ELEMENTS_GENESIS_HASHES {
+            assert!(meta(0, hash).is_genesis(Family::Elements));
+            assert!(!meta(0, hash).is_genesis(Family::Bitcoin));
+        }
+
+        for network in [
+            bitcoin::Network::Bitcoin,
+            bitcoin::Network::Testnet,
+            bitcoin::Network::Testnet4,
+            bitcoin::Network::Signet,
+            bitcoin::Network::Regtest,
+        ] {
+            let hash = bitcoin::constants::genesis_block(network).block_hash();
+            let hash = BlockHash::from_byte_array(hash.to_byte_array());
+            assert!(
+                BlockMeta::new(0, hash, 0).is_genesis(Family::Bitcoin),
+                "{network}"
+            );
+        }
+
+        let other = BlockMeta::new(0, BlockHash::all_zeros(), 0);
+        assert!(!other.is_genesis(Family::Bitcoin));
+        assert!(!other.is_genesis(Family::Elements));
+    }
+
+    #[test]
+    fn test_get_script_hash_for_address() {
+        let store = memory::MemoryStore::new();
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let script_pubkey = bitcoin::Address::from_str(address)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+        assert_eq!(
+            store
+                .get_script_hash_for_address(address, Family::Bitcoin)
+                .unwrap(),
+            store.hash(script_pubkey.as_bytes())
+        );
+
+        let address = "ex1qq6krj23yx9s4xjeas453huxx8azrk942qrxsvh";
+        let script_pubkey = elements::Address::from_str(address)
+            .unwrap()
+            .script_pubkey();
+        assert_eq!(
+            store
+                .get_script_hash_for_address(address, Family::Elements)
+                .unwrap(),
+            store.hash(script_pubkey.as_bytes())
+        );
+
+        for (address, family) in [
+            (address, Family::Bitcoin),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                Family::Elements,
+            ),
+            ("not an address", Family::Bitcoin),
+        ] {
+            let err = store
+                .get_script_hash_for_address(address, family)
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<StoreError>(),
+                Some(&StoreError::InvalidAddress(address.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_first_unused_index() {
+        let store = memory::MemoryStore::new();
+        let descriptor = crate::be::bitcoin_descriptor("wpkh(tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/<0;1>/*)").unwrap();
+        let external = descriptor
+            .clone()
+            .into_single_descriptors()
+            .unwrap()
+            .remove(0);
+        let txid = crate::be::Txid::from_array([1; 32]);
+        let history_map = (0..=3)
+            .map(|index| {
+                let script_pubkey = external.script_pubkey_at_derivation_index(index).unwrap();
+                let tx_seen = TxSeen::new(txid, 1, V::Vout(index));
+                (store.hash(&script_pubkey), vec![tx_seen])
+            })
+            .collect();
+        store
+            .update(
+                &BlockMeta::new(1, BlockHash::all_zeros(), 0),
+                vec![],
+                history_map,
+                BTreeMap::new(),
+            )
+            .unwrap();
+
+        let expected = FirstUnusedIndex {
+            external: 4,
+            internal: Some(0),
+        };
+        assert_eq!(store.first_unused_index(&descriptor, 2).unwrap(), expected);
+        assert_eq!(store.first_unused_index(&descriptor, 20).unwrap(), expected);
+        assert!(store.first_unused_index(&descriptor, 0).is_err());
+
+        // the chain is given by the position of the path, not by the text of the descriptor
+        let xpub = "tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M";
+        let single_path = crate::be::bitcoin_descriptor(&format!("wpkh({xpub}/0/*)")).unwrap();
+        assert_eq!(
+            store.first_unused_index(&single_path, 20).unwrap(),
+            FirstUnusedIndex {
+                external: 4,
+                internal: None,
+            }
+        );
+        let swapped = crate::be::bitcoin_descriptor(&format!("wpkh({xpub}/<1;0>/*)")).unwrap();
+        assert_eq!(
+            store.first_unused_index(&swapped, 20).unwrap(),
+            FirstUnusedIndex {
+                external: 0,
+                internal: Some(4),
+            }
+        );
+        let three_paths =
+            crate::be::bitcoin_descriptor(&format!("wpkh({xpub}/<0;1;2>/*)")).unwrap();
+        assert!(store.first_unused_index(&three_paths, 20).is_err());
+    }
+
+    #[test]
+    fn test_diff_with_store() {
+        let store = memory::MemoryStore::with_salt([0; 16]);
+        let other = memory::MemoryStore::with_salt([0; 16]);
+        let block_hash = BlockHash::all_zeros();
+        let txid = crate::be::Txid::from_array([1; 32]);
+        let outpoint = |vout| OutPoint::new(txid, vout);
+        let tx_seen = |vout| TxSeen::new(txid, 1, V::Vout(vout));
+        store
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                BTreeMap::from([(5, vec![tx_seen(0)]), (6, vec![tx_seen(1)])]),
+                BTreeMap::from([(outpoint(0), Utxo::from(5)), (outpoint(1), Utxo::from(6))]),
+            )
+            .unwrap();
+        assert!(store.diff_with_store(&store).unwrap().is_empty());
+
+        other
+            .update(
+                &BlockMeta::new(1, block_hash, 0),
+                vec![],
+                BTreeMap::from([(5, vec![tx_seen(0)]), (7, vec![tx_seen(2)])]),
+                BTreeMap::from([(outpoint(0), Utxo::from(5)), (outpoint(2), Utxo::from(7))]),
+            )
+            .unwrap();
+        let diff = store.diff_with_store(&other).unwrap();
+        assert_eq!(
+            diff,
+            StoreDiff {
+                only_in_self: vec![outpoint(1)],
+                only_in_other: vec![outpoint(2)],
+                history_differences: vec![(6, vec![tx_seen(1)]), (7, vec![])],
+                truncated: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let store = memory::MemoryStore::with_salt([0; 16]);
+        let txid = |i| crate::be::Txid::from_array([i; 32]);
+        let block_meta = |height| BlockMeta::new(height, BlockHash::all_zeros(), height);
+        store
+            .update(
+                &block_meta(0),
+                vec![],
+                BTreeMap::from([(5, vec![TxSeen::new(txid(1), 0, V::Vout(0))])]),
+                BTreeMap::from([(OutPoint::new(txid(1), 0), Utxo::from(5))]),
+            )
+            .unwrap();
+        store
+            .update(
+                &block_meta(1),
+                vec![(0, OutPoint::new(txid(1), 0), txid(2))],
+                BTreeMap::from([(6, vec![TxSeen::new(txid(2), 1, V::Vout(0))])]),
+                BTreeMap::from([(OutPoint::new(txid(2), 0), Utxo::from(6))]),
+            )
+            .unwrap();
+
+        let import = |target: &dyn Store| {
+            let utxos = store.iter_utxos().collect::<Result<_>>().unwrap();
+            let history = store.iter_history().collect::<Result<_>>().unwrap();
+            let block_metas = store.get_all_block_metas_since(0).unwrap();
+            target.bulk_import(utxos, history, block_metas)
+        };
+
+        let target = memory::MemoryStore::with_salt([0; 16]);
+        import(&target).unwrap();
+        assert!(store.diff_with_store(&target).unwrap().is_empty());
+        assert_eq!(target.tip_block_meta().unwrap().unwrap().height(), 1);
+        assert_eq!(target.count_distinct_txids().unwrap(), 2);
+        assert!(import(&target).is_err(), "heights must follow the tip");
+
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let target = db::DBStore::open(tempdir.path(), 64, false, 0).unwrap();
+            import(&target).unwrap();
+            assert!(store.diff_with_store(&target).unwrap().is_empty());
+            assert_eq!(target.tip_block_meta().unwrap().unwrap().height(), 1);
+        }
+
+        assert!(check_contiguous_heights(&[block_meta(0), block_meta(2)], None).is_err());
+        assert!(check_contiguous_heights(&[block_meta(1), block_meta(2)], None).is_err());
+        assert!(check_contiguous_heights(&[block_meta(0), block_meta(1)], None).is_ok());
+        assert!(check_contiguous_heights(&[block_meta(2), block_meta(3)], Some(1)).is_ok());
+        assert!(check_contiguous_heights(&[], Some(1)).is_ok());
+    }
+
+    #[test]
+    fn test_get_history_page() {
+        let txid = |i| crate::be::Txid::from_array([i; 32]);
+        let tx_seen = |i, height| TxSeen::new(txid(i), height, V::Vout(0));
+        let check = |store: &dyn Store, heights: [Height; 3]| {
+            // entries at heights 1, 2, 2, 3
+            for height in heights {
+                let entries = match height {
+                    2 => vec![tx_seen(2, 2), tx_seen(3, 2)],
+                    _ => vec![tx_seen(height as u8, height)],
+                };
+                store
+                    .update(
+                        &BlockMeta::new(height, BlockHash::all_zeros(), 0),
+                        vec![],
+                        BTreeMap::from([(7, entries)]),
+                        BTreeMap::new(),
+                    )
+                    .unwrap();
+            }
+            let page = |from_height, limit| {
+                store
+                    .get_history_page(7, HistoryPage { from_height, limit })
+                    .unwrap()
+            };
+            let heights = |entries: Vec<TxSeen>| -> Vec<_> {
+                entries.into_iter().map(|e| e.height).collect()
+            };
+
+            let (entries, next) = page(None, None);
+            assert_eq!((heights(entries), next), (vec![1, 2, 2, 3], None));
+            let (entries, next) = page(None, Some(1));
+            assert_eq!((heights(entries), next), (vec![1], Some(2)));
+            // entries at the same height are not split
+            let (entries, next) = page(Some(2), Some(1));
+            assert_eq!((heights(entries), next), (vec![2, 2], Some(3)));
+            let (entries, next) = page(Some(3), Some(1));
+            assert_eq!((heights(entries), next), (vec![3], None));
+            assert_eq!(page(Some(4), Some(1)), (vec![], None));
+            assert_eq!(
+                store.get_history_page(8, HistoryPage::default()).unwrap(),
+                (vec![], None)
+            );
+        };
+
+        check(&memory::MemoryStore::new(), [1, 2, 3]);
+        // the memory store keeps the history sorted even if the blocks are not indexed in order
+        check(&memory::MemoryStore::new(), [1, 3, 2]);
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(
+                &db::DBStore::open(tempdir.path(), 64, false, 0).unwrap(),
+                [1, 2, 3],
+            );
+        }
+    }
+
+    #[test]
+    fn test_asset_history() {
+        let asset = |i| elements::AssetId::from_slice(&[i; 32]).unwrap();
+        let tx_seen =
+            |i, height| TxSeen::new(crate::be::Txid::from_array([i; 32]), height, V::Vout(0));
+        let check = |store: &dyn Store| {
+            store.ibd_finished();
+            for height in 1..=3 {
+                store
+                    .update(
+                        &BlockMeta::new(height, BlockHash::all_zeros(), 0),
+                        vec![],
+                        BTreeMap::new(),
+                        BTreeMap::new(),
+                    )
+                    .unwrap();
+                let history = BTreeMap::from([(asset(1), vec![tx_seen(height as u8, height)])]);
+                store.insert_asset_history(height, &history).unwrap();
+            }
+            let page = |asset, from_height, limit| {
+                let page = HistoryPage { from_height, limit };
+                let (entries, next) = store.get_asset_history_page(asset, page).unwrap();
+                let heights: Vec<_> = entries.into_iter().map(|e| e.height).collect();
+                (heights, next)
+            };
+            assert_eq!(page(asset(1), None, None), (vec![1, 2, 3], None));
+            assert_eq!(page(asset(1), None, Some(1)), (vec![1], Some(2)));
+            assert_eq!(page(asset(1), Some(2), None), (vec![2, 3], None));
+            assert_eq!(page(asset(2), None, None), (vec![], None));
+
+            // indexing a height again replaces its entries
+            let history = BTreeMap::from([
+                (asset(0), vec![tx_seen(4, 2)]),
+                (asset(2), vec![tx_seen(2, 2), tx_seen(4, 2)]),
+            ]);
+            store.insert_asset_history(2, &history).unwrap();
+            assert_eq!(page(asset(1), None, None), (vec![1, 3], None));
+            assert_eq!(page(asset(2), None, None), (vec![2, 2], None));
+            assert_eq!(page(asset(0), None, None), (vec![2], None));
+
+            store.reorg(3).unwrap();
+            assert_eq!(page(asset(1), None, None), (vec![1], None));
+            store.clear_at_height(2).unwrap();
+            assert_eq!(page(asset(2), None, None), (vec![], None));
+            assert_eq!(page(asset(0), None, None), (vec![], None));
+            assert_eq!(page(asset(1), None, None), (vec![1], None));
+        };
+
+        check(&memory::MemoryStore::new());
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(&db::DBStore::open(tempdir.path(), 64, false, 10).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_get_utxos_full() {
+        use elements::{
+            hex::FromHex,
+            secp256k1_zkp::{Generator, PedersenCommitment},
+        };
+        let outpoint = |i| OutPoint::new(crate::be::Txid::from_array([i; 32]), 0);
+        let asset = elements::AssetId::from_slice(&[7; 32]).unwrap();
+        // the generator point, valid both as a value and as an asset commitment
+        let g = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let g = Vec::<u8>::from_hex(g).unwrap();
+        let commitment = PedersenCommitment::from_slice(&[&[0x08][..], &g].concat()).unwrap();
+        let generator = Generator::from_slice(&[&[0x0a][..], &g].concat()).unwrap();
+        let explicit = Utxo {
+            script_hash: 10,
+            value: confidential::Value::Explicit(1000),
+            asset: confidential::Asset::Explicit(asset),
+        };
+        let blinded = Utxo {
+            script_hash: 20,
+            value: confidential::Value::Confidential(commitment),
+            asset: confidential::Asset::Confidential(generator),
+        };
+        let check = |store: &dyn Store| {
+            store.ibd_finished();
+            let created = BTreeMap::from([(outpoint(1), explicit), (outpoint(2), blinded)]);
+            store
+                .update(
+                    &BlockMeta::new(1, BlockHash::all_zeros(), 0),
+                    vec![],
+                    BTreeMap::new(),
+                    created,
+                )
+                .unwrap();
+
+            assert_eq!(
+                store
+                    .get_utxos_full(&[outpoint(1), outpoint(2), outpoint(3)])
+                    .unwrap(),
+                vec![Some(explicit), Some(blinded), None]
+            );
+            assert_eq!(
+                store.get_utxos(&[outpoint(1), outpoint(3)]).unwrap(),
+                vec![Some(10), None]
+            );
+
+            // the commitments are removed with the spent output and restored by a reorg
+            let spending_txid = crate::be::Txid::from_array([9; 32]);
+            store
+                .update(
+                    &BlockMeta::new(2, BlockHash::from_byte_array([2; 32]), 0),
+                    vec![(0, outpoint(1), spending_txid)],
+                    BTreeMap::new(),
+                    BTreeMap::new(),
+                )
+                .unwrap();
+            assert_eq!(store.get_utxos_full(&[outpoint(1)]).unwrap(), vec![None]);
+            assert_eq!(store.get_commitments(&[outpoint(1)]).unwrap(), vec![None]);
+            store.reorg(2).unwrap();
+            assert_eq!(
+                store.get_utxos_full(&[outpoint(1)]).unwrap(),
+                vec![Some(explicit)]
+            );
+            store.reorg(1).unwrap();
+            assert_eq!(
+                store.get_commitments(&[outpoint(1), outpoint(2)]).unwrap(),
+                vec![None, None]
+            );
+        };
+
+        check(&memory::MemoryStore::new());
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(&db::DBStore::open(tempdir.path(), 64, false, 0).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_get_block_meta_by_hash() {
+        let hash = |i| BlockHash::from_byte_array([i; 32]);
+        let check = |store: &dyn Store| {
+            store.ibd_finished();
+            for height in 0..3 {
+                let meta = BlockMeta::new(height, hash(height as u8), height);
+                store
+                    .update(&meta, vec![], BTreeMap::new(), BTreeMap::new())
+                    .unwrap();
+            }
+            let height_of = |i| {
+                store
+                    .get_block_meta_by_hash(hash(i))
+                    .unwrap()
+                    .map(|m| (m.height(), m.timestamp()))
+            };
+            assert_eq!(height_of(1), Some((1, 1)));
+            assert_eq!(height_of(2), Some((2, 2)));
+            assert_eq!(height_of(9), None);
+
+            store.reorg(2).unwrap();
+            assert_eq!(height_of(2), None);
+            store
+                .upsert_block_meta(&BlockMeta::new(1, hash(9), 9))
+                .unwrap();
+            assert_eq!(height_of(1), None);
+            assert_eq!(height_of(9), Some((1, 9)));
+        };
+
+        check(&memory::MemoryStore::new());
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(&db::DBStore::open(tempdir.path(), 64, false, 10).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_update_twice() {
+        let txid = |i| crate::be::Txid::from_array([i; 32]);
+        let (a, b) = (OutPoint::new(txid(1), 0), OutPoint::new(txid(2), 0));
+        let check = |store: &dyn Store| {
+            store.ibd_finished();
+            let first = BlockMeta::new(1, BlockHash::from_byte_array([1; 32]), 0);
+            let history = BTreeMap::from([(7, vec![TxSeen::new(a.txid, 1, V::Vout(0))])]);
+            store
+                .update(
+                    &first,
+                    vec![],
+                    history,
+                    BTreeMap::from([(a, Utxo::from(7))]),
+                )
+                .unwrap();
+
+            let second = BlockMeta::new(2, BlockHash::from_byte_array([2; 32]), 0);
+            let apply_second = || {
+                let history = BTreeMap::from([(8, vec![TxSeen::new(b.txid, 2, V::Vout(0))])]);
+                store.update(
+                    &second,
+                    vec![(0, a, b.txid)],
+                    history,
+                    BTreeMap::from([(b, Utxo::from(8))]),
+                )
+            };
+            assert_eq!(apply_second().unwrap(), vec![7, 8]);
+            let history = store.get_history(&[7, 8]).unwrap();
+            let utxos = store.get_utxos(&[a, b]).unwrap();
+
+            assert_eq!(apply_second().unwrap(), Vec::<ScriptHash>::new());
+            assert_eq!(store.get_history(&[7, 8]).unwrap(), history);
+            assert_eq!(store.get_utxos(&[a, b]).unwrap(), utxos);
+
+            // the block is applied again after a reorg removed it
+            store.reorg(2).unwrap();
+            assert_eq!(apply_second().unwrap(), vec![7, 8]);
+            assert_eq!(store.get_history(&[7, 8]).unwrap(), history);
+        };
+
+        check(&memory::MemoryStore::new());
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(&db::DBStore::open(tempdir.path(), 64, false, 10).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_flush() {
+        let store = AnyStore::Mem(memory::MemoryStore::new());
+        store.flush().unwrap();
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let store = AnyStore::Db(db::DBStore::open(tempdir.path(), 64, false, 0).unwrap());
+            store
+                .upsert_block_meta(&BlockMeta::new(0, BlockHash::all_zeros(), 0))
+                .unwrap();
+            store.flush().unwrap();
+            drop(store);
+            let store = db::DBStore::open_read_only(tempdir.path(), 64).unwrap();
+            assert_eq!(store.iter_hash_ts().count(), 1);
+            store.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_get_confirmations() {
+        let store = memory::MemoryStore::new();
+        let entry = |height| TxSeen::new(crate::be::Txid::all_zeros(), height, V::Undefined);
+        let entries = [entry(0), entry(5), entry(9), entry(10), entry(11)];
+        assert_eq!(store.get_confirmations(&entries).unwrap(), vec![0; 5]);
+
+        store
+            .upsert_block_meta(&BlockMeta::new(10, BlockHash::all_zeros(), 0))
+            .unwrap();
+        assert_eq!(
+            store.get_confirmations(&entries).unwrap(),
+            vec![11, 6, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_get_utxos_changed_since() {
+        let mut store = memory::MemoryStore::new();
+        store.set_index_spent_heights(true);
+        let txid = |i| crate::be::Txid::from_array([i; 32]);
+        let (a, b, c, d) = (
+            OutPoint::new(txid(1), 0),
+            OutPoint::new(txid(2), 0),
+            OutPoint::new(txid(3), 0),
+            OutPoint::new(txid(4), 0),
+        );
+        let receive =
+            |outpoint: OutPoint, height| TxSeen::new(outpoint.txid, height, V::Vout(outpoint.vout));
+        let block = |height,
+                     spent: Vec<(u32, OutPoint, crate::be::Txid)>,
+                     created: Vec<(OutPoint, ScriptHash)>| {
+            let mut history: BTreeMap<ScriptHash, Vec<TxSeen>> = BTreeMap::new();
+            for (outpoint, script_hash) in created.iter() {
+                history
+                    .entry(*script_hash)
+                    .or_default()
+                    .push(receive(*outpoint, height));
+            }
+            let meta = BlockMeta::new(height, BlockHash::from_byte_array([height as u8; 32]), 0);
+            store
+                .update(
+                    &meta,
+                    spent,
+                    history,
+                    created.into_iter().map(|(o, s)| (o, s.into())).collect(),
+                )
+                .unwrap();
+        };
+        // script 5 receives a, e and b, then c spending a, c is spent by the tx creating d for
+        // script 6. e is spent by the tx creating b
+        let e = OutPoint::new(txid(5), 0);
+        block(1, vec![], vec![(a, 5), (e, 5)]);
+        block(2, vec![(0, e, txid(2))], vec![(b, 5)]);
+        block(3, vec![(0, a, txid(3))], vec![(c, 5)]);
+        block(4, vec![(0, c, txid(4))], vec![(d, 6)]);
+        // f is created and spent in the same block, it never reaches the UTXO set
+        let f = OutPoint::new(txid(6), 0);
+        let history =
+            BTreeMap::from([(5, vec![receive(f, 5), TxSeen::new(txid(7), 5, V::Vin(0))])]);
+        let meta = BlockMeta::new(5, BlockHash::from_byte_array([5; 32]), 0);
+        store
+            .update(&meta, vec![], history, BTreeMap::new())
+            .unwrap();
+
+        let delta = |created, spent| UtxoDelta { created, spent };
+        assert_eq!(
+            store.get_utxos_changed_since(&[5, 6], 2).unwrap(),
+            vec![delta(vec![b], vec![a, e]), delta(vec![d], vec![])]
+        );
+        // e was created and spent before height 3
+        assert_eq!(
+            store.get_utxos_changed_since(&[5, 6], 3).unwrap(),
+            vec![delta(vec![], vec![a]), delta(vec![d], vec![])]
+        );
+        assert_eq!(
+            store.get_utxos_changed_since(&[5, 6], 0).unwrap(),
+            vec![delta(vec![b], vec![]), delta(vec![d], vec![])]
+        );
+        assert_eq!(
+            store.get_utxos_changed_since(&[5, 6], 4).unwrap(),
+            vec![delta(vec![], vec![c]), delta(vec![d], vec![])]
+        );
+        assert_eq!(
+            store.get_utxos_changed_since(&[5, 6], 5).unwrap(),
+            vec![UtxoDelta::default(); 2]
+        );
+
+        // a is unspent again once the block spending it is rolled back
+        store
+            .apply_reorg_up_to(BlockHash::from_byte_array([2; 32]))
+            .unwrap();
+        assert_eq!(
+            store.get_utxos_changed_since(&[5], 2).unwrap(),
+            vec![delta(vec![b], vec![e])]
+        );
+
+        // without the index the spent outputs can't be told apart
+        store.set_index_spent_heights(false);
+        let err = store.get_utxos_changed_since(&[5], 2).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StoreError>(),
+            Some(&StoreError::SpentHeightsNotIndexed)
+        );
+    }
+
+    #[test]
+    fn test_apply_block() {
+        use bitcoin::{
+            absolute::LockTime, transaction::Version, Amount, ScriptBuf, Sequence, TxIn, TxOut,
+            WPubkeyHash, Witness,
+        };
+
+        let script = |i| ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([i; 20]));
+        let tx = |previous_output, script_pubkey| bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::from(vec![1]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey,
+            }],
+        };
+        let block = |txdata| {
+            crate::be::Block::Bitcoin(Box::new(bitcoin::Block {
+                header: bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header,
+                txdata,
+            }))
+        };
+        let coinbase = tx(bitcoin::OutPoint::null(), script(1));
+        let spending = tx(
+            bitcoin::OutPoint::new(coinbase.compute_txid(), 0),
+            script(2),
+        );
+        let coinbase_txid: crate::be::Txid = coinbase.compute_txid().into();
+        let spending_txid: crate::be::Txid = spending.compute_txid().into();
+        let meta = BlockMeta::new(1, BlockHash::from_byte_array([1; 32]), 0);
+
+        let store = memory::MemoryStore::with_salt([0; 16]);
+        let applied = store
+            .apply_block(&block(vec![coinbase, spending]), &meta, true)
+            .unwrap();
+        assert_eq!(applied.txids, vec![coinbase_txid, spending_txid]);
+
+        let expected = memory::MemoryStore::with_salt([0; 16]);
+        let (hash_1, hash_2) = (
+            expected.hash(script(1).as_bytes()),
+            expected.hash(script(2).as_bytes()),
+        );
+        let changed = expected
+            .update(
+                &meta,
+                vec![],
+                BTreeMap::from([
+                    (
+                        hash_1,
+                        vec![
+                            TxSeen::new(coinbase_txid, 1, V::Vout(0)),
+                            TxSeen::new(spending_txid, 1, V::Vin(0)),
+                        ],
+                    ),
+                    (hash_2, vec![TxSeen::new(spending_txid, 1, V::Vout(0))]),
+                ]),
+                BTreeMap::from([(OutPoint::new(spending_txid, 0), hash_2.into())]),
+            )
+            .unwrap();
+        assert_eq!(applied.changed_script_hashes, changed);
+        assert!(store.diff_with_store(&expected).unwrap().is_empty());
+        assert_eq!(
+            store.get_coinbase_txids_at_height(1).unwrap(),
+            vec![coinbase_txid]
+        );
+
+        assert_eq!(
+            store
+                .get_spent_values(&[OutPoint::new(spending_txid, 0)])
+                .unwrap(),
+            vec![Some(1000)]
+        );
+
+        // the coinbase output is spent in the same block
+        assert_eq!(
+            store.get_utxos_by_script(&[hash_1, hash_2]).unwrap(),
+            vec![vec![], vec![OutPoint::new(spending_txid, 0)]]
+        );
+        store.reorg(1).unwrap();
+        assert_eq!(
+            store.get_utxos_by_script(&[hash_1, hash_2]).unwrap(),
+            vec![vec![], vec![]]
+        );
+    }
+
+    #[test]
+    fn test_get_history_merged() {
+        let store = memory::MemoryStore::new();
+        let shared = crate::be::Txid::from_array([1; 32]);
+        let other = crate::be::Txid::from_array([2; 32]);
+        let history_map = BTreeMap::from([
+            (5, vec![TxSeen::new(shared, 1, V::Vout(1))]),
+            (
+                6,
+                vec![
+                    TxSeen::new(shared, 1, V::Vout(0)),
+                    TxSeen::new(other, 1, V::Vin(0)),
+                ],
+            ),
+        ]);
+        store
+            .update(
+                &BlockMeta::new(1, BlockHash::all_zeros(), 0),
+                vec![],
+                history_map,
+                BTreeMap::new(),
+            )
+            .unwrap();
+
+        let merged = store.get_history_merged(&[5, 6, 7]).unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                TxSeen::new(other, 1, V::Vin(0)),
+                TxSeen::new(shared, 1, V::Vout(0)),
+            ]
+        );
+        assert!(store.get_history_merged(&[7]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clear_at_height() {
+        let script_hash = 5;
+        let txid = |i| crate::be::Txid::from_array([i; 32]);
+        let outpoint = |i| OutPoint::new(txid(i), 0);
+        let check = |store: &dyn Store| {
+            store.ibd_finished();
+            // fund at block 1, spend at block 2
+            let index_block_2 = || {
+                let history = BTreeMap::from([(
+                    script_hash,
+                    vec![
+                        TxSeen::new(txid(2), 2, V::Vin(0)),
+                        TxSeen::new(txid(2), 2, V::Vout(0)),
+                    ],
+                )]);
+                store
+                    .update(
+                        &BlockMeta::new(2, BlockHash::all_zeros(), 0),
+                        vec![(0, outpoint(1), txid(2))],
+                        history,
+                        BTreeMap::from([(outpoint(2), script_hash.into())]),
+                    )
+                    .unwrap();
+            };
+            store
+                .update(
+                    &BlockMeta::new(1, BlockHash::all_zeros(), 0),
+                    vec![],
+                    BTreeMap::from([(script_hash, vec![TxSeen::new(txid(1), 1, V::Vout(0))])]),
+                    BTreeMap::from([(outpoint(1), script_hash.into())]),
+                )
+                .unwrap();
+            index_block_2();
+
+            // only the tip can be cleared
+            assert!(store.clear_at_height(1).is_err());
+            assert!(store.clear_at_height(3).is_err());
+            assert_eq!(store.get_history(&[script_hash]).unwrap()[0].len(), 3);
+
+            store.clear_at_height(2).unwrap();
+            let heights: Vec<_> = store.get_history(&[script_hash]).unwrap()[0]
+                .iter()
+                .map(|e| e.height)
+                .collect();
+            assert_eq!(heights, vec![1]);
+            // the output spent by the cleared block is unspent again
+            assert_eq!(
+                store.get_utxos(&[outpoint(1), outpoint(2)]).unwrap(),
+                vec![Some(script_hash), None]
+            );
+            assert_eq!(store.tip_block_meta().unwrap().unwrap().height(), 1);
+
+            // the block can be indexed again
+            index_block_2();
+            assert_eq!(store.get_history(&[script_hash]).unwrap()[0].len(), 3);
+            assert_eq!(
+                store.get_utxos(&[outpoint(1), outpoint(2)]).unwrap(),
+                vec![None, Some(script_hash)]
+            );
+        };
+
+        check(&memory::MemoryStore::new());
+        #[cfg(feature = "db")]
+        {
+            let tempdir = tempfile::TempDir::new().unwrap();
+            check(&db::DBStore::open(tempdir.path(), 64, false, 10).unwrap());
+        }
+    }
 }
@@ -0,0 +1,84 @@
+//! Timing of the store operations for performance investigation.
+//!
+//! Compiled only with the `profiling` feature and disabled until [`set_enabled`] is called, so
+//! that there is no overhead unless an operator asks for it. The histograms are kept in their own
+//! registry and exposed on `/debug/profile` instead of `/metrics`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, Registry};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref STORE_OPERATION_HISTOGRAM: HistogramVec = {
+        // from 10µs to ~2.6s
+        let buckets = prometheus::exponential_buckets(0.000_01, 4.0, 10).expect("static");
+        let histogram = HistogramVec::new(
+            HistogramOpts::new(
+                "waterfalls_store_operation_duration_seconds",
+                "The store operations latencies in seconds.",
+            )
+            .buckets(buckets),
+            &["operation"],
+        )
+        .expect("static");
+        REGISTRY
+            .register(Box::new(histogram.clone()))
+            .expect("registered once");
+        histogram
+    };
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Start timing `operation`, the time is recorded when the returned timer is dropped
+pub(crate) fn start(operation: &'static str) -> Option<HistogramTimer> {
+    ENABLED.load(Ordering::Relaxed).then(|| {
+        STORE_OPERATION_HISTOGRAM
+            .with_label_values(&[operation])
+            .start_timer()
+    })
+}
+
+/// The histograms in the prometheus text format
+pub(crate) fn encode() -> Result<Vec<u8>, prometheus::Error> {
+    let mut buffer = vec![];
+    prometheus::TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use elements::hashes::Hash;
+
+    use super::*;
+    use crate::store::{memory::MemoryStore, AnyStore, BlockMeta, Store};
+
+    #[test]
+    fn test_profile_store_operations() {
+        set_enabled(true);
+        let store = AnyStore::Mem(MemoryStore::new());
+        let block_meta = BlockMeta::new(0, elements::BlockHash::all_zeros(), 0);
+        store
+            .update(&block_meta, vec![], BTreeMap::new(), BTreeMap::new())
+            .unwrap();
+        store.get_history(&[1]).unwrap();
+        store.get_utxos(&[]).unwrap();
+        store.reorg(0).unwrap();
+
+        for operation in ["update", "get_history", "get_utxos", "reorg"] {
+            let histogram = STORE_OPERATION_HISTOGRAM.with_label_values(&[operation]);
+            assert!(histogram.get_sample_count() >= 1, "{operation}");
+        }
+        let text = String::from_utf8(encode().unwrap()).unwrap();
+        assert!(text.contains("waterfalls_store_operation_duration_seconds_bucket"));
+        assert!(text.contains("operation=\"reorg\""));
+    }
+}
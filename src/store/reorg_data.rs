@@ -1,18 +1,28 @@
 use anyhow::Result;
 use std::collections::BTreeMap;
 
-use crate::{store::TxSeen, OutPoint, ScriptHash};
+use crate::{
+    store::{Commitments, TxSeen},
+    OutPoint, ScriptHash,
+};
 
 use super::db::{vec_tx_seen_from_be_bytes, vec_tx_seen_to_be_bytes};
 
-/// Data for handling reorgs up to 1 block.
-/// If the process halts right before a reorg, this will be lost and a reindex must happen.
+/// Data for rolling back one indexed block.
+///
+/// Written in the reorg CF in the same batch as the block changes and deleted in the same batch
+/// as the rollback, so it survives restarts. It's kept for the last `reorg_data_keep_heights`
+/// blocks and not written during IBD.
 #[derive(Debug, Default)]
 pub(super) struct ReorgData {
     /// Input spent in the last block. These are usually deleted from the db when a block is found.
     /// When there is a reorg we reinsert them in the db.
     pub(super) spent: Vec<(OutPoint, ScriptHash)>,
 
+    /// Value and asset commitments of the `spent` outputs, deleted with them and reinserted on
+    /// reorg. Outputs without commitments, like the imported ones, are missing.
+    pub(super) spent_commitments: Vec<(OutPoint, Commitments)>,
+
     /// History changes from the last block. Contains the script hashes and their corresponding
     /// TxSeen entries that were added in the last block. When there is a reorg we remove
     /// these entries from the history.
@@ -27,9 +37,12 @@ impl ReorgData {
     /// Serialize ReorgData to bytes using consensus encoding.
     ///
     /// Format:
-    /// - Version (u8): 1
+    /// - Version (u8): 2
     /// - Spent count (u32)
     /// - For each spent: OutPoint (36 bytes) + ScriptHash (8 bytes)
+    /// - Spent commitments count (u32)
+    /// - For each spent commitment: OutPoint (36 bytes) + consensus encoded confidential Value and
+    ///   Asset
     /// - History count (u32)
     /// - For each history entry: ScriptHash (8 bytes) + Vec<TxSeen> length (u32) + serialized TxSeen data
     /// - UTXOs created count (u32)
@@ -40,7 +53,7 @@ impl ReorgData {
         let mut bytes = Vec::new();
 
         // Version byte for future compatibility
-        bytes.push(1u8);
+        bytes.push(2u8);
 
         // Serialize spent
         (self.spent.len() as u32).consensus_encode(&mut bytes)?;
@@ -49,6 +62,14 @@ impl ReorgData {
             script_hash.consensus_encode(&mut bytes)?;
         }
 
+        // Serialize spent_commitments
+        (self.spent_commitments.len() as u32).consensus_encode(&mut bytes)?;
+        for (outpoint, (value, asset)) in &self.spent_commitments {
+            outpoint.consensus_encode(&mut bytes)?;
+            value.consensus_encode(&mut bytes)?;
+            asset.consensus_encode(&mut bytes)?;
+        }
+
         // Serialize history
         (self.history.len() as u32).consensus_encode(&mut bytes)?;
         for (script_hash, tx_seen_vec) in &self.history {
@@ -80,7 +101,7 @@ impl ReorgData {
 
         // Read and verify version
         let version = u8::consensus_decode(&mut cursor)?;
-        if version != 1 {
+        if version != 2 {
             anyhow::bail!("Unknown ReorgData version: {}", version);
         }
 
@@ -93,6 +114,16 @@ impl ReorgData {
             spent.push((outpoint, script_hash));
         }
 
+        // Deserialize spent_commitments
+        let spent_commitments_count = u32::consensus_decode(&mut cursor)? as usize;
+        let mut spent_commitments = Vec::with_capacity(spent_commitments_count);
+        for _ in 0..spent_commitments_count {
+            let outpoint = OutPoint::consensus_decode(&mut cursor)?;
+            let value = elements::confidential::Value::consensus_decode(&mut cursor)?;
+            let asset = elements::confidential::Asset::consensus_decode(&mut cursor)?;
+            spent_commitments.push((outpoint, (value, asset)));
+        }
+
         // Deserialize history
         let history_count = u32::consensus_decode(&mut cursor)? as usize;
         let mut history = BTreeMap::new();
@@ -117,6 +148,7 @@ impl ReorgData {
 
         Ok(Self {
             spent,
+            spent_commitments,
             history,
             utxos_created,
         })
@@ -140,6 +172,14 @@ mod test {
         let outpoint2 = OutPoint::new(txid2, 1);
         reorg_data.spent.push((outpoint1, 123456789u64));
         reorg_data.spent.push((outpoint2, 987654321u64));
+        let asset = elements::AssetId::from_slice(&[7u8; 32]).unwrap();
+        reorg_data.spent_commitments.push((
+            outpoint1,
+            (
+                elements::confidential::Value::Explicit(1000),
+                elements::confidential::Asset::Explicit(asset),
+            ),
+        ));
 
         // Add some history entries
         let script_hash1 = 111111u64;
@@ -175,7 +215,7 @@ mod test {
         let bytes = reorg_data.to_bytes().expect("serialization should succeed");
 
         assert!(!bytes.is_empty(), "Serialized data should not be empty");
-        assert_eq!(bytes.len(), 315);
+        assert_eq!(bytes.len(), 315 + 4 + 36 + 9 + 33);
 
         // Deserialize from bytes
         let deserialized = ReorgData::from_bytes(&bytes).expect("deserialization should succeed");
@@ -186,6 +226,7 @@ mod test {
             assert_eq!(original.0, deserialized.0,);
             assert_eq!(original.1, deserialized.1,);
         }
+        assert_eq!(reorg_data.spent_commitments, deserialized.spent_commitments);
 
         // Verify history
         assert_eq!(reorg_data.history.len(), deserialized.history.len());
@@ -224,15 +265,17 @@ mod test {
         let empty = ReorgData::default();
         let bytes = empty.to_bytes().expect("serialization should succeed");
 
-        // Should have version byte + 3 zero counts (spent, history, utxos_created)
+        // Should have version byte + 4 zero counts (spent, spent_commitments, history,
+        // utxos_created)
         assert_eq!(
             bytes.len(),
-            1 + 4 + 4 + 4,
-            "Empty ReorgData should be 13 bytes"
+            1 + 4 + 4 + 4 + 4,
+            "Empty ReorgData should be 17 bytes"
         );
 
         let deserialized = ReorgData::from_bytes(&bytes).expect("deserialization should succeed");
         assert!(deserialized.spent.is_empty());
+        assert!(deserialized.spent_commitments.is_empty());
         assert!(deserialized.history.is_empty());
         assert!(deserialized.utxos_created.is_empty());
 
@@ -0,0 +1,162 @@
+//! SipHash-1-3 with a given key, the function of the std `DefaultHasher`, which doesn't allow
+//! setting the key.
+//!
+//! Script hashes are computed with it keyed by the store salt: unlike FxHash, without knowing the
+//! key it's not possible to find scripts whose hashes collide.
+
+use std::hash::Hasher;
+
+/// Random 128-bit key of the script hashes of a store
+pub(crate) type Salt = [u8; 16];
+
+#[derive(Debug, Clone)]
+pub(crate) struct SipHasher13 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// Bytes written and not yet compressed, little endian
+    tail: u64,
+    tail_len: usize,
+    /// Total number of bytes written
+    len: usize,
+}
+
+impl SipHasher13 {
+    /// Hasher keyed with `salt`, its halves read as little endian `k0` and `k1`
+    pub(crate) fn new_with_salt(salt: &Salt) -> Self {
+        let (k0, k1) = salt.split_at(8);
+        Self::new_with_keys(
+            u64::from_le_bytes(k0.try_into().expect("8 bytes")),
+            u64::from_le_bytes(k1.try_into().expect("8 bytes")),
+        )
+    }
+
+    pub(crate) fn new_with_keys(k0: u64, k1: u64) -> Self {
+        Self {
+            v0: k0 ^ 0x736f6d6570736575,
+            v1: k1 ^ 0x646f72616e646f6d,
+            v2: k0 ^ 0x6c7967656e657261,
+            v3: k1 ^ 0x7465646279746573,
+            tail: 0,
+            tail_len: 0,
+            len: 0,
+        }
+    }
+
+    fn sip_round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    /// One compression round per message word
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.sip_round();
+        self.v0 ^= word;
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.tail |= (byte as u64) << (8 * self.tail_len);
+        self.tail_len += 1;
+        if self.tail_len == 8 {
+            self.compress(self.tail);
+            self.tail = 0;
+            self.tail_len = 0;
+        }
+    }
+}
+
+impl Hasher for SipHasher13 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.len += bytes.len();
+        let mut bytes = bytes;
+        while self.tail_len != 0 && !bytes.is_empty() {
+            self.push_byte(bytes[0]);
+            bytes = &bytes[1..];
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.compress(u64::from_le_bytes(word.try_into().expect("8 bytes")));
+        }
+        for byte in words.remainder() {
+            self.push_byte(*byte);
+        }
+    }
+
+    /// Three finalization rounds
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        state.compress(((self.len as u64 & 0xff) << 56) | self.tail);
+        state.v2 ^= 0xff;
+        for _ in 0..3 {
+            state.sip_round();
+        }
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siphash13_reference_vectors() {
+        // SipHash-1-3 of the messages `00 01 02 ..` of length `len`, with the key `00 01 .. 0f`
+        // of the reference implementation test vectors and with the zero key
+        const VECTORS: [(usize, u64, u64); 8] = [
+            (0, 0xabac0158050fc4dc, 0xd1fba762150c532c),
+            (1, 0xc9f49bf37d57ca93, 0x68a914128e01e473),
+            (7, 0xd3927d989bb11140, 0x2f098ab0c751325a),
+            (8, 0x369095118d299a8e, 0xead411e67ebe2eea),
+            (9, 0x25a48eb36c063de4, 0x75927f9d95124362),
+            (15, 0xd320d86d2a519956, 0xf30eb725bb91c9ea),
+            (16, 0xcc4fdd1a7d908b66, 0x8972188433a5c5b7),
+            (63, 0x9d199062b7bbb3a8, 0x385d3e39e5f37359),
+        ];
+        let data: Vec<u8> = (0..64).collect();
+        let salt: Salt = std::array::from_fn(|i| i as u8);
+        for (len, keyed, unkeyed) in VECTORS {
+            let mut hasher = SipHasher13::new_with_salt(&salt);
+            hasher.write(&data[..len]);
+            assert_eq!(hasher.finish(), keyed, "len {len}");
+
+            let mut hasher = SipHasher13::new_with_keys(0, 0);
+            hasher.write(&data[..len]);
+            assert_eq!(hasher.finish(), unkeyed, "len {len}");
+
+            // split writes hash like a single one
+            let mut split = SipHasher13::new_with_salt(&salt);
+            split.write(&data[..len / 3]);
+            split.write(&data[len / 3..len]);
+            assert_eq!(split.finish(), keyed, "len {len}");
+        }
+    }
+
+    #[test]
+    fn test_siphash13_keyed() {
+        let hash = |k0, k1| {
+            let mut hasher = SipHasher13::new_with_keys(k0, k1);
+            hasher.write(b"script");
+            hasher.finish()
+        };
+        assert_ne!(hash(0, 0), hash(1, 0));
+        assert_ne!(hash(0, 0), hash(0, 1));
+        assert_eq!(hash(1, 2), hash(1, 2));
+
+        let mut salt = [0u8; 16];
+        salt[0] = 1;
+        salt[8] = 2;
+        let mut hasher = SipHasher13::new_with_salt(&salt);
+        hasher.write(b"script");
+        assert_eq!(hasher.finish(), hash(1, 2));
+    }
+}
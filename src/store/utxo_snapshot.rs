@@ -0,0 +1,211 @@
+//! Export the UTXO set of a store in a file and import it in an empty store, to start a node from
+//! a snapshot instead of indexing from genesis.
+//!
+//! The file contains a version byte, the script hash salt, the block metas and the number of
+//! UTXOs, followed by a `(txid, vout, script_hash)` record per UTXO, so that it can be written
+//! and read without holding the whole UTXO set in memory. The histories are not exported: a node
+//! started from a snapshot knows only the transactions of the blocks indexed after it, the store
+//! records where its history starts so that responses can flag the missing part.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use anyhow::Result;
+use elements::{
+    encode::{Decodable, Encodable},
+    hashes::Hash,
+};
+
+use crate::{Height, OutPoint, ScriptHash};
+
+use super::{metadata, siphash::Salt, BlockMeta, Store};
+
+// version 1 has script hashes computed with SipHash-1-3 and a 128-bit salt
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// UTXOs written in the store at once while importing
+const IMPORT_CHUNK_SIZE: usize = 100_000;
+
+/// Write the block metas and the UTXO set of `store`, whose scripts are hashed with `salt`.
+///
+/// Returns the number of UTXOs written.
+pub(crate) fn export_utxos<S: Store + ?Sized>(
+    store: &S,
+    salt: Salt,
+    w: &mut (impl Write + Seek),
+) -> Result<u64> {
+    SNAPSHOT_VERSION.consensus_encode(&mut *w)?;
+    w.write_all(&salt)?;
+    let block_metas: Vec<_> = store.iter_hash_ts().collect();
+    (block_metas.len() as u64).consensus_encode(&mut *w)?;
+    for meta in block_metas.iter() {
+        meta.height().consensus_encode(&mut *w)?;
+        w.write_all(meta.hash().as_ref())?;
+        meta.timestamp().consensus_encode(&mut *w)?;
+    }
+
+    // the count is known only at the end, it replaces this placeholder
+    let count_position = w.stream_position()?;
+    0u64.consensus_encode(&mut *w)?;
+    let mut count = 0u64;
+    for utxo in store.iter_utxos() {
+        let (outpoint, script_hash) = utxo?;
+        outpoint.consensus_encode(&mut *w)?;
+        script_hash.consensus_encode(&mut *w)?;
+        count += 1;
+    }
+    let end = w.stream_position()?;
+    w.seek(SeekFrom::Start(count_position))?;
+    count.consensus_encode(&mut *w)?;
+    w.seek(SeekFrom::Start(end))?;
+    w.flush()?;
+    Ok(count)
+}
+
+/// A snapshot written by [`export_utxos`], read up to the UTXO records
+pub(crate) struct UtxoSnapshot<R> {
+    salt: Salt,
+    block_metas: Vec<BlockMeta>,
+    count: u64,
+    r: R,
+}
+
+impl<R: Read> UtxoSnapshot<R> {
+    pub(crate) fn read(mut r: R) -> Result<Self> {
+        let version = u8::consensus_decode(&mut r)?;
+        if version != SNAPSHOT_VERSION {
+            anyhow::bail!("unknown utxo snapshot version {version}");
+        }
+        let mut salt = Salt::default();
+        r.read_exact(&mut salt)?;
+        let block_metas = (0..u64::consensus_decode(&mut r)?)
+            .map(|_| {
+                let height = Height::consensus_decode(&mut r)?;
+                let mut hash = [0u8; 32];
+                r.read_exact(&mut hash)?;
+                let timestamp = u32::consensus_decode(&mut r)?;
+                Ok(BlockMeta::new(
+                    height,
+                    elements::BlockHash::from_byte_array(hash),
+                    timestamp,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let count = u64::consensus_decode(&mut r)?;
+        Ok(Self {
+            salt,
+            block_metas,
+            count,
+            r,
+        })
+    }
+
+    /// The salt the scripts of the snapshot are hashed with, the store importing it must use the
+    /// same for its script hashes to match
+    pub(crate) fn salt(&self) -> Salt {
+        self.salt
+    }
+
+    /// Write the UTXOs and the block metas of the snapshot in `store`, which must be empty.
+    ///
+    /// The block metas are written last, so a failed import leaves the store without a tip.
+    /// The history starts after the snapshot tip, which is recorded in
+    /// [`metadata::HISTORY_START_HEIGHT`]. Returns the number of UTXOs imported.
+    pub(crate) fn import<S: Store + ?Sized>(mut self, store: &S) -> Result<u64> {
+        if store.tip_block_meta()?.is_some() || store.iter_utxos().next().is_some() {
+            anyhow::bail!("utxos can be imported only in an empty store");
+        }
+        let mut chunk = BTreeMap::new();
+        for _ in 0..self.count {
+            let outpoint = OutPoint::consensus_decode(&mut self.r)?;
+            let script_hash = ScriptHash::consensus_decode(&mut self.r)?;
+            chunk.insert(outpoint, script_hash);
+            if chunk.len() == IMPORT_CHUNK_SIZE {
+                store.bulk_import(std::mem::take(&mut chunk), BTreeMap::new(), vec![])?;
+            }
+        }
+        if let Some(tip) = self.block_metas.last() {
+            let start = tip.height() + 1;
+            store.write_metadata(metadata::HISTORY_START_HEIGHT, &start.to_be_bytes())?;
+        }
+        store.bulk_import(chunk, BTreeMap::new(), self.block_metas)?;
+        Ok(self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        store::{db::DBStore, memory::MemoryStore},
+        TxSeen, V,
+    };
+
+    #[test]
+    fn test_utxo_snapshot_roundtrip() {
+        let salt = [7; 16];
+        let store = MemoryStore::with_salt(salt);
+        let outpoint = |i| OutPoint::new(crate::be::Txid::from_array([i; 32]), i as u32);
+        let utxos = BTreeMap::from([
+            (outpoint(1), store.hash(b"a")),
+            (outpoint(2), store.hash(b"b")),
+            (outpoint(3), store.hash(b"a")),
+        ]);
+        let history = BTreeMap::from([(
+            store.hash(b"a"),
+            vec![TxSeen::new(outpoint(1).txid, 1, V::Vout(1))],
+        )]);
+        let block_metas: Vec<_> = (0..3)
+            .map(|h| BlockMeta::new(h, elements::BlockHash::from_byte_array([h as u8; 32]), h))
+            .collect();
+        store
+            .bulk_import(utxos.clone(), history, block_metas)
+            .unwrap();
+
+        let mut file = Cursor::new(vec![]);
+        assert_eq!(export_utxos(&store, salt, &mut file).unwrap(), 3);
+        file.set_position(0);
+
+        let snapshot = UtxoSnapshot::read(&mut file).unwrap();
+        assert_eq!(snapshot.salt(), salt);
+        let imported = MemoryStore::with_salt(snapshot.salt());
+        assert_eq!(snapshot.import(&imported).unwrap(), 3);
+        let imported_utxos: BTreeMap<_, _> = imported.iter_utxos().collect::<Result<_>>().unwrap();
+        assert_eq!(imported_utxos, utxos);
+        assert_eq!(imported.hash(b"a"), store.hash(b"a"));
+        let heights: Vec<_> = imported.iter_hash_ts().map(|m| m.height()).collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+        assert!(imported.get_history(&[store.hash(b"a")]).unwrap()[0].is_empty());
+        assert_eq!(store.history_start_height().unwrap(), None);
+        assert_eq!(imported.history_start_height().unwrap(), Some(3));
+
+        file.set_position(0);
+        let snapshot = UtxoSnapshot::read(&mut file).unwrap();
+        assert!(
+            snapshot.import(&imported).is_err(),
+            "the store is not empty"
+        );
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut db = DBStore::open(tempdir.path(), 64, false, 0).unwrap();
+        file.set_position(0);
+        let snapshot = UtxoSnapshot::read(&mut file).unwrap();
+        db.set_salt(snapshot.salt()).unwrap();
+        assert_eq!(snapshot.import(&db).unwrap(), 3);
+        let db_utxos: BTreeMap<_, _> = db.iter_utxos().collect::<Result<_>>().unwrap();
+        assert_eq!(db_utxos, utxos);
+        assert_eq!(db.hash(b"a"), store.hash(b"a"));
+        assert_eq!(db.tip_block_meta().unwrap().map(|m| m.height()), Some(2));
+        assert_eq!(db.history_start_height().unwrap(), Some(3));
+        assert!(db.set_salt([1; 16]).is_err());
+
+        let mut truncated = Cursor::new(file.into_inner());
+        truncated.get_mut().pop();
+        let snapshot = UtxoSnapshot::read(&mut truncated).unwrap();
+        assert!(snapshot.import(&MemoryStore::with_salt(salt)).is_err());
+    }
+}
@@ -650,6 +650,63 @@ impl WaterfallClient {
         Ok(response)
     }
 
+    /// Open the `/v1/stream` of the given addresses
+    pub async fn stream_addresses(
+        &self,
+        addresses: &[be::Address],
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = format!("{}/v1/stream", self.base_url);
+        let addresses: Vec<_> = addresses.iter().map(|a| a.to_string()).collect();
+
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("addresses", addresses.join(","))])
+            .send()
+            .await?;
+
+        let status = response.status().as_u16();
+        if status != 200 {
+            let body = response.text().await?;
+            bail!("stream response is not 200 but: {status} body is: {body}");
+        }
+
+        Ok(response)
+    }
+
+    /// Open the `/script/:script/subscribe` stream of `script_pubkey`
+    pub async fn subscribe_script(
+        &self,
+        script_pubkey: &[u8],
+    ) -> anyhow::Result<reqwest::Response> {
+        let script = hex_simd::encode_to_string(script_pubkey, hex_simd::AsciiCase::Lower);
+        let url = format!("{}/script/{script}/subscribe", self.base_url);
+
+        let response = self.client.get(&url).send().await?;
+
+        let status = response.status().as_u16();
+        if status != 200 {
+            let body = response.text().await?;
+            bail!("subscribe_script response is not 200 but: {status} body is: {body}");
+        }
+
+        Ok(response)
+    }
+
+    pub async fn block_events(&self) -> anyhow::Result<reqwest::Response> {
+        let url = format!("{}/events/blocks", self.base_url);
+
+        let response = self.client.get(&url).send().await?;
+
+        let status = response.status().as_u16();
+        if status != 200 {
+            let body = response.text().await?;
+            bail!("block_events response is not 200 but: {status} body is: {body}");
+        }
+
+        Ok(response)
+    }
+
     pub async fn wait_waterfalls_non_empty(
         &self,
         bitcoin_desc: &str,
@@ -695,6 +752,30 @@ impl WaterfallClient {
         be::BlockHeader::from_str(&text, self.family)
     }
 
+    pub async fn headers(&self, start: u32, count: u32) -> anyhow::Result<Vec<be::BlockHeader>> {
+        let url = format!("{}/headers?start={start}&count={count}", self.base_url);
+        let response = self.client.get(&url).send().await?;
+        let status_code = response.status().as_u16();
+        if status_code != 200 {
+            bail!("headers response is not 200 but: {}", status_code);
+        }
+        let bytes = response.bytes().await?;
+        let mut cursor = &bytes[..];
+        let mut headers = vec![];
+        while !cursor.is_empty() {
+            let header = match self.family {
+                Family::Bitcoin => be::BlockHeader::Bitcoin(Box::new(
+                    bitcoin::consensus::Decodable::consensus_decode(&mut cursor)?,
+                )),
+                Family::Elements => be::BlockHeader::Elements(Box::new(
+                    elements::encode::Decodable::consensus_decode(&mut cursor)?,
+                )),
+            };
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
     pub async fn server_recipient(&self) -> anyhow::Result<Recipient> {
         let url = format!("{}/v1/server_recipient", self.base_url);
         let response = self.client.get(&url).send().await?;
@@ -747,10 +828,26 @@ impl WaterfallClient {
     }
 
     pub async fn address_txs(&self, address: &be::Address) -> anyhow::Result<String> {
-        let url = format!("{}/address/{}/txs", self.base_url, address);
+        Ok(self.address_txs_page(address, "").await?.0)
+    }
+
+    /// Get `/address/:address/txs?{query}`, returns the body and the `Link` header of the next
+    /// page, if any
+    pub async fn address_txs_page(
+        &self,
+        address: &be::Address,
+        query: &str,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let url = format!("{}/address/{}/txs?{}", self.base_url, address, query);
         println!("url: {}", url);
         let response = self.client.get(&url).send().await?;
         let status_code = response.status().as_u16();
+        let link = response
+            .headers()
+            .get(reqwest::header::LINK)
+            .map(|link| link.to_str())
+            .transpose()?
+            .map(|link| link.to_string());
         let text = response.text().await?;
 
         if status_code != 200 {
@@ -760,7 +857,7 @@ impl WaterfallClient {
                 text
             );
         }
-        Ok(text)
+        Ok((text, link))
     }
 
     pub async fn fee_estimates(&self) -> anyhow::Result<HashMap<u16, f64>> {
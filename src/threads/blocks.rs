@@ -1,20 +1,20 @@
 use crate::{
     be::Family,
     fetch::{ChainStatus, Client},
-    server::{Error, State, SubscriptionEvent},
+    server::{BlockEvent, Error, ReorgGuard, State, SubscriptionEvent},
     store::{BlockMeta, Store},
-    OutPoint, TxSeen, V,
 };
-use elements::Txid;
 use std::{
-    collections::{BTreeMap, HashSet},
     future::Future,
-    str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
 use tokio::time::sleep;
 
+/// During the initial block download the store is flushed every this many blocks, then after
+/// every block
+const FLUSH_EVERY_BLOCKS: u32 = 1000;
+
 pub(crate) async fn blocks_infallible(
     shared_state: Arc<State>,
     client: Client,
@@ -22,6 +22,7 @@ pub(crate) async fn blocks_infallible(
     initial_sync_tx: tokio::sync::oneshot::Sender<()>,
     shutdown_signal: impl Future<Output = ()>,
     logs_rocksdb_stat_every_minutes: u64,
+    index_values: bool,
 ) {
     if let Err(e) = index(
         shared_state,
@@ -30,6 +31,7 @@ pub(crate) async fn blocks_infallible(
         initial_sync_tx,
         shutdown_signal,
         logs_rocksdb_stat_every_minutes,
+        index_values,
     )
     .await
     {
@@ -37,12 +39,17 @@ pub(crate) async fn blocks_infallible(
     }
 }
 
-async fn get_next_block_to_index(
+/// The next block to index, None if there isn't one yet or if the tip has been rolled back.
+///
+/// On a rollback `reorg_guard` is set, the caller keeps it until the block replacing the rolled
+/// back ones is applied, so that requests don't see the shorter chain in between.
+async fn get_next_block_to_index<'a>(
     last_indexed: &mut Option<BlockMeta>,
     client: &Client,
     family: Family,
-    state: &Arc<State>,
+    state: &'a Arc<State>,
     initial_sync_tx: &mut Option<tokio::sync::oneshot::Sender<()>>,
+    reorg_guard: &mut Option<ReorgGuard<'a>>,
 ) -> Option<BlockMeta> {
     match last_indexed.as_ref() {
         Some(last) => {
@@ -61,6 +68,9 @@ async fn get_next_block_to_index(
                         panic!("TEST CRASH: Simulating crash before reorg processing (reorg data will be lost)");
                     }
 
+                    // kept across consecutive rollbacks, replacing it would clear the flag
+                    reorg_guard.get_or_insert_with(|| state.start_reorg());
+                    let reorged_block = crate::BlockMeta::from(last);
                     let reorged_height = last.height;
                     let previous_height = reorged_height - 1;
                     let previous_block_meta = {
@@ -73,21 +83,35 @@ async fn get_next_block_to_index(
                         BlockMeta::new(previous_height, hash, ts)
                     };
                     log::info!(
-                        "reorg: rolling back to previous block {:?}, calling store.reorg()",
+                        "reorg: rolling back to previous block {:?}",
                         previous_block_meta
                     );
+                    let rewound = state
+                        .store
+                        .apply_reorg_up_to(previous_block_meta.hash())
+                        .unwrap_or_else(|e| {
+                            error_panic!(
+                                "reorg failed: {e}. If the reorg data is missing the server likely \
+                                restarted after indexing a block that was later reorged. \
+                                A reindex may be required."
+                            )
+                        });
+                    crate::WATERFALLS_REORGED_BLOCKS_COUNTER.inc_by(rewound.into());
                     *last_indexed = Some(previous_block_meta);
-                    state.store.reorg(reorged_height);
                     state
                         .notify_all_subscriptions(SubscriptionEvent::Reorg)
                         .await;
+                    state.record_reorg(reorged_block.clone()).await;
+                    state.publish_block_event(BlockEvent::Reorg(reorged_block));
                     log::info!(
-                        "reorg: store.reorg() completed, will re-fetch block at height {}",
+                        "reorg: rollback completed, will re-fetch block at height {}",
                         reorged_height
                     );
                     None
                 }
                 Ok(ChainStatus::Tip) => {
+                    // the new chain is not longer than the rolled back one
+                    *reorg_guard = None;
                     // Signal initial sync completion the first time we hit the tip
                     if let Some(tx) = initial_sync_tx.take() {
                         let _ = tx.send(());
@@ -130,6 +154,7 @@ pub async fn index(
     initial_sync_tx: tokio::sync::oneshot::Sender<()>,
     shutdown_signal: impl Future<Output = ()>,
     logs_rocksdb_stat_every_minutes: u64,
+    index_values: bool,
 ) -> Result<(), Error> {
     let db = &state.store;
 
@@ -145,8 +170,6 @@ pub async fn index(
     log::info!("last indexed block is: {last_indexed:?}");
     let initial_height = last_indexed.as_ref().map(|b| b.height).unwrap_or(0);
 
-    let skip_outpoint = generate_skip_outpoint();
-
     let mut txs_count = 0u64;
     let mut initial_sync_tx = Some(initial_sync_tx);
 
@@ -155,15 +178,17 @@ pub async fn index(
     let mut last_rocksdb_stats_logging = Instant::now();
     let rocksdb_stats_interval = Duration::from_secs(logs_rocksdb_stat_every_minutes * 60);
     let mut signal = std::pin::pin!(shutdown_signal);
+    let mut reorg_guard = None;
 
     loop {
         let block_to_index = loop {
             tokio::select! {
                 _ = &mut signal => {
                     log::info!("blocks thread received shutdown signal");
+                    flush(db);
                     return Ok(());
                 }
-                result = get_next_block_to_index(&mut last_indexed, &client, family, &state, &mut initial_sync_tx) => {
+                result = get_next_block_to_index(&mut last_indexed, &client, family, &state, &mut initial_sync_tx, &mut reorg_guard) => {
                     if let Some(block) = result {
                         break block;
                     }
@@ -192,16 +217,12 @@ pub async fn index(
 
         // Log RocksDB stats at the specified interval (independent of initial sync)
         if last_rocksdb_stats_logging.elapsed() >= rocksdb_stats_interval {
-            if let Some(stats) = db.stats() {
+            if let Some(stats) = db.rocksdb_stats() {
                 log::info!("RocksDB Stats:\n{}", stats);
             }
             last_rocksdb_stats_logging = Instant::now();
         }
 
-        let mut history_map = BTreeMap::new();
-        let mut utxo_created = BTreeMap::new();
-        let mut utxo_spent = vec![];
-
         let block = match client.block(block_to_index.hash, family).await {
             Ok(block) => block,
             Err(e) => {
@@ -211,83 +232,41 @@ pub async fn index(
             }
         };
 
-        for tx in block.transactions_iter() {
-            txs_count += 1;
-            let txid = tx.txid();
-            for (j, output) in tx.outputs_iter().enumerate() {
-                if !output.skip_utxo() {
-                    // Use an empty-bytes hash as a placeholder: outputs that are spendable
-                    // but non-standard (e.g. bare OP_TRUE) won't pass skip_indexing() below,
-                    // so their real script hash never overwrites this. When spent, the
-                    // spending tx lands under this dummy hash that no wallet will ever query.
-                    let out_point = OutPoint::new(txid, j as u32);
-                    utxo_created.insert(out_point, db.hash(b""));
-                }
-                if output.skip_indexing() {
-                    continue;
-                }
-                let script_hash = db.hash(output.script_pubkey_bytes());
-                let el = history_map.entry(script_hash).or_insert(vec![]);
-                el.push(TxSeen::new(txid, block_to_index.height, V::Vout(j as u32)));
-
-                let out_point = OutPoint::new(txid, j as u32);
-                log::debug!("inserting {out_point}");
-                utxo_created.insert(out_point, script_hash);
-            }
-
-            if !tx.is_coinbase() {
-                for (vin, input) in tx.inputs_iter().enumerate() {
-                    if input.skip_indexing() {
-                        continue;
-                    }
-                    let previous_output = input.previous_output();
-                    match utxo_created.remove(&previous_output) {
-                        Some(script_hash) => {
-                            // also the spending tx must be indexed
-                            let el = history_map.entry(script_hash).or_insert(vec![]);
-                            el.push(TxSeen::new(txid, block_to_index.height, V::Vin(vin as u32)));
-                        }
-                        None => {
-                            log::debug!("removing {}", &previous_output);
-                            if !skip_outpoint.contains(&previous_output) {
-                                utxo_spent.push((vin as u32, previous_output, txid))
-                            }
-                        }
-                    }
-                }
-            }
-        }
         state.set_hash_ts(&block_to_index).await;
-        let changed_script_hashes = db
-            .update(&block_to_index, utxo_spent, history_map, utxo_created)
+        let timer = crate::BLOCK_APPLY_HISTOGRAM.start_timer();
+        let applied = db
+            .apply_block(&block, &block_to_index, index_values)
             .unwrap_or_else(|e| error_panic!("error updating db: {e}"));
+        timer.observe_duration();
+        // the new tip replacing the rolled back blocks is applied
+        reorg_guard = None;
+        txs_count += applied.txids.len() as u64;
+
+        // otherwise confirmed txs are also returned as unconfirmed until the next mempool sync
+        state.mempool.lock().await.remove(&applied.txids);
         state
-            .notify_subscription_scripts(SubscriptionEvent::Block, changed_script_hashes)
+            .notify_subscription_scripts(SubscriptionEvent::Block, applied.changed_script_hashes)
             .await;
+        state.publish_block_event(BlockEvent::Block((&block_to_index).into()));
 
         crate::BLOCKCHAIN_TIP.set(block_to_index.height as i64);
+        crate::set_tip_age(block_to_index.timestamp);
+        if initial_sync_tx.is_none() || block_to_index.height % FLUSH_EVERY_BLOCKS == 0 {
+            flush(db);
+        }
         last_indexed = Some(block_to_index);
     }
 }
 
-fn generate_skip_outpoint() -> HashSet<OutPoint> {
-    let mut skip_outpoint = HashSet::new();
-    let outpoint = |txid, vout| OutPoint::new(Txid::from_str(txid).expect("static").into(), vout);
-
-    // policy asset emission in testnet
-    let s = "0c52d2526a5c9f00e9fb74afd15dd3caaf17c823159a514f929ae25193a43a52";
-    skip_outpoint.insert(outpoint(s, 0));
-
-    // policy asset emission in regtest
-    let s = "50cdc410c9d0d61eeacc531f52d2c70af741da33af127c364e52ac1ee7c030a5";
-    skip_outpoint.insert(outpoint(s, 0));
-
-    skip_outpoint
+fn flush(db: &impl Store) {
+    if let Err(e) = db.flush() {
+        log::warn!("error flushing the store: {e:?}");
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+    use std::{collections::BTreeMap, net::SocketAddr, str::FromStr, sync::Arc};
 
     use age::x25519::Identity;
     use bitcoin::{NetworkKind, PrivateKey};
@@ -305,7 +284,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_reorg_truncates_blocks_hash_ts() {
-        let state = Arc::new(test_state());
+        let state = Arc::new(test_state(100));
         let hash_0 =
             BlockHash::from_str("0000000000000000000000000000000000000000000000000000000000000000")
                 .unwrap();
@@ -333,6 +312,7 @@ mod tests {
         let client = reorg_client().await;
         let mut last_indexed = Some(BlockMeta::new(2, hash_2, 300));
         let mut initial_sync_tx = None;
+        let mut reorg_guard = None;
 
         assert!(get_next_block_to_index(
             &mut last_indexed,
@@ -340,9 +320,14 @@ mod tests {
             Family::Bitcoin,
             &state,
             &mut initial_sync_tx,
+            &mut reorg_guard,
         )
         .await
         .is_none());
+        // requests are rejected until the new tip is applied
+        assert!(state.check_reorg_in_progress().is_err());
+        drop(reorg_guard);
+        assert!(state.check_reorg_in_progress().is_ok());
 
         let last_indexed = last_indexed.expect("last indexed block");
         assert_eq!(last_indexed.height, 1);
@@ -353,16 +338,77 @@ mod tests {
         assert_eq!(state.block_hash(2).await, None);
     }
 
-    fn test_state() -> State {
+    #[tokio::test]
+    async fn test_recent_reorgs_keeps_latest() {
+        let state = Arc::new(test_state(2));
+        let blocks: Vec<_> = (0..4u8)
+            .map(|i| {
+                let hash = BlockHash::from_str(&format!("{i:02x}").repeat(32)).unwrap();
+                BlockMeta::new(i as u32, hash, 100 * i as u32)
+            })
+            .collect();
+        for meta in blocks.iter() {
+            state.set_hash_ts(meta).await;
+            state
+                .store
+                .update(meta, vec![], BTreeMap::new(), BTreeMap::new())
+                .unwrap();
+        }
+
+        let client = reorg_client().await;
+        let mut last_indexed = blocks.last().cloned();
+        let mut initial_sync_tx = None;
+        let mut reorg_guard = None;
+        for _ in 0..3 {
+            assert!(get_next_block_to_index(
+                &mut last_indexed,
+                &client,
+                Family::Bitcoin,
+                &state,
+                &mut initial_sync_tx,
+                &mut reorg_guard,
+            )
+            .await
+            .is_none());
+            assert!(state.check_reorg_in_progress().is_err());
+        }
+        assert_eq!(last_indexed.map(|b| b.height), Some(0));
+
+        // blocks are reorged from the tip down, so block 3 is forgotten and block 1 comes first
+        let expected: Vec<crate::BlockMeta> = blocks[1..3].iter().map(Into::into).collect();
+        assert_eq!(state.recent_reorgs().await, expected);
+    }
+
+    #[test]
+    fn test_reject_requests_during_reorg() {
+        let state = test_state(100);
+        assert!(state.check_reorg_in_progress().is_ok());
+        {
+            let _guard = state.start_reorg();
+            assert!(matches!(
+                state.check_reorg_in_progress(),
+                Err(crate::server::Error::ReorgInProgress)
+            ));
+        }
+        assert!(state.check_reorg_in_progress().is_ok());
+    }
+
+    fn test_state(reorg_events_keep: usize) -> State {
         State::new(
             AnyStore::Mem(MemoryStore::new()),
             Identity::generate(),
             PrivateKey::generate(NetworkKind::Test),
             StateConfig {
                 max_addresses: 100,
+                max_descriptor_keys: 100,
                 max_txs_seen: 100,
+                max_outpoints_per_query: 10_000,
                 cache_control_seconds: 5,
                 derivation_cache_capacity: 1000,
+                max_req_per_second_per_ip: None,
+                rate_limit_ip_header: None,
+                reject_requests_during_reorg: true,
+                reorg_events_keep,
                 subscription_limits: SubscriptionLimits {
                     max_active_subscriptions: 100,
                     max_scripts_per_subscription: 100,
@@ -388,15 +434,17 @@ mod tests {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let address = listener.local_addr().unwrap();
         tokio::spawn(async move {
-            let (mut stream, _) = listener.accept().await.unwrap();
-            let mut buffer = [0; 1024];
-            let _ = stream.read(&mut buffer).await.unwrap();
-            stream
-                .write_all(
-                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
-                )
-                .await
-                .unwrap();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).await.unwrap();
+                stream
+                    .write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+            }
         });
         address
     }
@@ -110,6 +110,9 @@ async fn sync_mempool_once(
             let changed_script_hashes = {
                 let mut m = state.mempool.lock().await;
                 let changed_script_hashes = m.update(db, &removed, &txs);
+                if let Err(e) = db.persist_mempool_changes(&txs, &removed) {
+                    log::warn!("cannot persist mempool changes: {e}");
+                }
                 mempool_txids.clear();
                 mempool_txids.extend(m.txids_iter());
                 if is_big_delta {
@@ -199,6 +202,18 @@ async fn mempool_sync(
     let mut mempool_txids = HashSet::new();
     let support_verbose = client.mempool(true).await.is_ok();
     log::info!("mempool support verbose: {support_verbose}");
+    match client.mempool(support_verbose).await {
+        Ok(node_mempool) => match restore_persisted_mempool(&state.store, &node_mempool) {
+            Ok(restored) if !restored.is_empty() => {
+                log::info!("restored {} persisted mempool txs", restored.len());
+                // the first sync takes them from the cache instead of fetching them
+                state.mempool_cache.lock().await.extend(restored);
+            }
+            Ok(_) => (),
+            Err(e) => log::warn!("cannot restore persisted mempool: {e}"),
+        },
+        Err(e) => log::warn!("cannot get the node mempool to restore the persisted one: {e}"),
+    }
     let mut last_summary = Instant::now();
     let mut processing_since_last_summary = Duration::ZERO;
     let mut latest_stats = None;
@@ -235,9 +250,63 @@ async fn mempool_sync(
     }
 }
 
+/// Load the persisted mempool transactions still in the node mempool, deleting the others
+fn restore_persisted_mempool(
+    store: &impl Store,
+    node_mempool: &HashSet<crate::be::Txid>,
+) -> anyhow::Result<Vec<(crate::be::Txid, crate::be::MempoolTx)>> {
+    let (retained, stale): (Vec<_>, Vec<_>) = store
+        .load_persisted_mempool()?
+        .into_iter()
+        .partition(|(txid, _)| node_mempool.contains(txid));
+    let stale: Vec<_> = stale.into_iter().map(|(txid, _)| txid).collect();
+    store.persist_mempool_changes(&[], &stale)?;
+    Ok(retained)
+}
+
 #[derive(Clone, Copy)]
 struct MempoolSyncStats {
     tip: Option<u32>,
     mempool_txs: usize,
     processing_time: Duration,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn test_restore_persisted_mempool() {
+        use crate::store::db::DBStore;
+
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let retained_txid = crate::be::Txid::from_array([1; 32]);
+        let stale_txid = crate::be::Txid::from_array([2; 32]);
+        let tx = |hashes: &[u64]| crate::be::MempoolTx::from_parts(vec![], hashes.to_vec());
+        let retained_tx = tx(&[1, 2]);
+        let stale_tx = tx(&[3]);
+        {
+            let mut store = DBStore::open(tempdir.path(), 64, false, 6).unwrap();
+            store.set_persist_mempool(true);
+            store
+                .persist_mempool_changes(
+                    &[(retained_txid, &retained_tx), (stale_txid, &stale_tx)],
+                    &[],
+                )
+                .unwrap();
+        }
+
+        let mut store = DBStore::open(tempdir.path(), 64, false, 6).unwrap();
+        store.set_persist_mempool(true);
+        assert_eq!(store.load_persisted_mempool().unwrap().len(), 2);
+
+        let node_mempool = HashSet::from([retained_txid]);
+        let restored = restore_persisted_mempool(&store, &node_mempool).unwrap();
+        assert_eq!(restored, vec![(retained_txid, retained_tx.clone())]);
+        assert_eq!(
+            store.load_persisted_mempool().unwrap(),
+            vec![(retained_txid, retained_tx)]
+        );
+    }
+}
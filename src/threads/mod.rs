@@ -1,3 +1,4 @@
 pub(crate) mod blocks;
 pub(crate) mod mempool;
+pub(crate) mod utxo_check;
 pub(crate) mod zmq;
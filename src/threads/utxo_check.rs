@@ -0,0 +1,347 @@
+//! Periodically compare the UTXOs indexed for a sample of scripts with the ones in the node UTXO
+//! set, to detect an index drifting from the chain.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    be::{self, Family},
+    fetch::Client,
+    server::{Error, State},
+    store::{AnyStore, Store},
+    Height, OutPoint,
+};
+
+/// A script whose UTXOs differ between the store and the node
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UtxoMismatch {
+    pub(crate) script: Vec<u8>,
+    pub(crate) only_in_store: Vec<OutPoint>,
+    pub(crate) only_in_node: Vec<OutPoint>,
+}
+
+pub(crate) async fn utxo_check_infallible(
+    state: Arc<State>,
+    client: Client,
+    family: Family,
+    every: Duration,
+    sample_size: usize,
+    shutdown_signal: impl Future<Output = ()>,
+) {
+    let mut interval = tokio::time::interval(every);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    let mut signal = std::pin::pin!(shutdown_signal);
+
+    loop {
+        tokio::select! {
+            _ = &mut signal => {
+                log::info!("utxo check thread received shutdown signal");
+                return;
+            }
+            _ = interval.tick() => {
+                if let Err(e) = utxo_check(&state, &client, family, sample_size).await {
+                    log::warn!("utxo check failed: {e:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Check the scripts of the outputs of the indexed tip block
+async fn utxo_check(
+    state: &State,
+    client: &Client,
+    family: Family,
+    sample_size: usize,
+) -> Result<(), Error> {
+    let Some(tip) = state.store.tip_block_meta().map_err(store_error)? else {
+        return Ok(());
+    };
+    let block = client
+        .block(tip.hash(), family)
+        .await
+        .map_err(|e| Error::String(format!("{e:?}")))?;
+    let scripts = sample_scripts(&block, sample_size);
+    match check_utxos(&state.store, client, &scripts).await? {
+        None => log::info!("utxo check skipped, the node tip is not the indexed tip"),
+        Some(mismatches) if mismatches.is_empty() => {
+            log::info!("utxo check of {} scripts found no mismatch", scripts.len())
+        }
+        Some(mismatches) => {
+            for mismatch in mismatches.iter() {
+                log::error!(
+                    "utxo check mismatch for script {}, only in the index: {:?}, only in the node: {:?}",
+                    hex_simd::encode_to_string(&mismatch.script, hex_simd::AsciiCase::Lower),
+                    mismatch.only_in_store,
+                    mismatch.only_in_node,
+                );
+            }
+            crate::WATERFALLS_UTXO_CHECK_MISMATCHES_COUNTER.inc_by(mismatches.len() as u64);
+        }
+    }
+    Ok(())
+}
+
+/// Up to `sample_size` distinct scripts of the spendable outputs of `block`, spread across it
+fn sample_scripts(block: &be::Block, sample_size: usize) -> Vec<Vec<u8>> {
+    let mut seen = HashSet::new();
+    let mut scripts = vec![];
+    for tx in block.transactions_iter() {
+        for output in tx.outputs_iter() {
+            if output.skip_utxo() || output.skip_indexing() {
+                continue;
+            }
+            let script = output.script_pubkey_bytes();
+            if seen.insert(script.to_vec()) {
+                scripts.push(script.to_vec());
+            }
+        }
+    }
+    let step = scripts.len().div_ceil(sample_size).max(1);
+    scripts
+        .into_iter()
+        .step_by(step)
+        .take(sample_size)
+        .collect()
+}
+
+/// Compare the UTXOs of `scripts` in `store` with the ones in the node UTXO set.
+///
+/// The node scans the UTXO set at its tip, the result is None if it's not the indexed tip, for
+/// example while a block is being indexed, and the check should be done again later.
+pub(crate) async fn check_utxos(
+    store: &AnyStore,
+    client: &Client,
+    scripts: &[Vec<u8>],
+) -> Result<Option<Vec<UtxoMismatch>>, Error> {
+    let scanned = client
+        .scan_utxos(scripts)
+        .await
+        .map_err(|e| Error::String(format!("{e:?}")))?;
+    let indexed_tip = || -> Result<Option<Height>, Error> {
+        Ok(store
+            .tip_block_meta()
+            .map_err(store_error)?
+            .map(|meta| meta.height()))
+    };
+    if indexed_tip()? != Some(scanned.height) {
+        return Ok(None);
+    }
+
+    let mut in_node: HashMap<Vec<u8>, BTreeSet<OutPoint>> = HashMap::new();
+    for (script, outpoint) in scanned.unspents {
+        in_node.entry(script).or_default().insert(outpoint);
+    }
+    let none = BTreeSet::new();
+    let mut mismatches = vec![];
+    for script in scripts {
+        let in_store: BTreeSet<_> = store
+            .iter_utxos_for_script(store.hash(script))
+            .map(|utxo| utxo.map(|(outpoint, _)| outpoint))
+            .collect::<anyhow::Result<_>>()
+            .map_err(store_error)?;
+        let in_node = in_node.get(script).unwrap_or(&none);
+        if &in_store != in_node {
+            mismatches.push(UtxoMismatch {
+                script: script.clone(),
+                only_in_store: in_store.difference(in_node).cloned().collect(),
+                only_in_node: in_node.difference(&in_store).cloned().collect(),
+            });
+        }
+    }
+
+    // a block indexed while reading the store would show up as a mismatch
+    if indexed_tip()? != Some(scanned.height) {
+        return Ok(None);
+    }
+    Ok(Some(mismatches))
+}
+
+fn store_error(e: anyhow::Error) -> Error {
+    Error::String(format!("{e:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use elements::{hashes::Hash, BlockHash};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        server::{Arguments, Network},
+        store::{memory::MemoryStore, BlockMeta},
+        TxSeen, V,
+    };
+
+    /// Spawn a node answering every request with a `scantxoutset` reply finding `unspents` at
+    /// `height`
+    async fn spawn_scanning_node(height: Height, unspents: Vec<(Vec<u8>, OutPoint)>) -> Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let unspents: Vec<_> = unspents
+            .iter()
+            .map(|(script, outpoint)| {
+                serde_json::json!({
+                    "txid": outpoint.txid.to_string(),
+                    "vout": outpoint.vout,
+                    "scriptPubKey": hex_simd::encode_to_string(script, hex_simd::AsciiCase::Lower),
+                })
+            })
+            .collect();
+        let body = serde_json::json!({
+            "result": {"success": true, "height": height, "unspents": unspents},
+            "error": null,
+            "id": "scantxoutset",
+        })
+        .to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buf = [0u8; 1024];
+                    // the reply doesn't depend on the request, but it's read whole so that the
+                    // connection is not reset by closing it with unread data
+                    let complete = |request: &[u8]| {
+                        let text = String::from_utf8_lossy(request).to_lowercase();
+                        let Some((headers, body)) = text.split_once("\r\n\r\n") else {
+                            return false;
+                        };
+                        let content_length = headers
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .and_then(|len| len.trim().parse().ok())
+                            .unwrap_or(0);
+                        body.len() >= content_length
+                    };
+                    while !complete(&request) {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        let args = Arguments {
+            network: Network::BitcoinRegtest,
+            node_url: Some(format!("http://{addr}")),
+            rpc_user_password: Some("user:pass".to_string()),
+            request_timeout_seconds: 30,
+            ..Arguments::default()
+        };
+        Client::new(&args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_utxos() {
+        let store = AnyStore::Mem(MemoryStore::new());
+        let script = |i| vec![0x00, 0x14, i];
+        let outpoint = |i| OutPoint::new(crate::be::Txid::from_array([i; 32]), 0);
+        let history = BTreeMap::from([
+            (
+                store.hash(&script(1)),
+                vec![TxSeen::new(outpoint(1).txid, 1, V::Vout(0))],
+            ),
+            (
+                store.hash(&script(2)),
+                vec![TxSeen::new(outpoint(2).txid, 1, V::Vout(0))],
+            ),
+        ]);
+        let utxos = BTreeMap::from([
+            (outpoint(1), store.hash(&script(1)).into()),
+            (outpoint(2), store.hash(&script(2)).into()),
+        ]);
+        store
+            .update(
+                &BlockMeta::new(1, BlockHash::all_zeros(), 0),
+                vec![],
+                history,
+                utxos,
+            )
+            .unwrap();
+        let scripts = [script(1), script(2), script(3)];
+
+        let client =
+            spawn_scanning_node(1, vec![(script(1), outpoint(1)), (script(2), outpoint(2))]).await;
+        assert_eq!(
+            check_utxos(&store, &client, &scripts).await.unwrap(),
+            Some(vec![])
+        );
+
+        // the node misses the output of script 2 and has one more for script 3
+        let client =
+            spawn_scanning_node(1, vec![(script(1), outpoint(1)), (script(3), outpoint(3))]).await;
+        assert_eq!(
+            check_utxos(&store, &client, &scripts).await.unwrap(),
+            Some(vec![
+                UtxoMismatch {
+                    script: script(2),
+                    only_in_store: vec![outpoint(2)],
+                    only_in_node: vec![],
+                },
+                UtxoMismatch {
+                    script: script(3),
+                    only_in_store: vec![],
+                    only_in_node: vec![outpoint(3)],
+                },
+            ])
+        );
+
+        // the node scanned at another height, the UTXO sets are not comparable
+        let client = spawn_scanning_node(2, vec![]).await;
+        assert_eq!(check_utxos(&store, &client, &scripts).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_sample_scripts() {
+        use bitcoin::{transaction::Version, Amount, ScriptBuf, TxOut, WPubkeyHash};
+
+        let script = |i| ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([i; 20]));
+        let output = |script_pubkey| TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey,
+        };
+        let mut block = bitcoin::constants::genesis_block(bitcoin::Network::Regtest);
+        block.txdata = vec![bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![
+                output(script(1)),
+                output(script(2)),
+                output(script(1)),
+                output(ScriptBuf::new_op_return([1u8; 4])),
+                output(script(3)),
+            ],
+        }];
+        let block = be::Block::Bitcoin(Box::new(block));
+
+        let all = vec![
+            script(1).to_bytes(),
+            script(2).to_bytes(),
+            script(3).to_bytes(),
+        ];
+        assert_eq!(sample_scripts(&block, 10), all);
+        assert_eq!(
+            sample_scripts(&block, 2),
+            vec![all[0].clone(), all[2].clone()]
+        );
+    }
+}
@@ -216,6 +216,59 @@ async fn integration_addresses_txs_seen_truncation() {
     let address_txs: serde_json::Value = serde_json::from_str(&address_txs).unwrap();
     assert_eq!(address_txs.as_array().unwrap().len(), 4);
 
+    let heights: Vec<_> = address_txs.as_array().unwrap()[..3]
+        .iter()
+        .map(|tx| tx["status"]["block_height"].as_u64().unwrap())
+        .collect();
+    let (first_page, link) = test_env
+        .client()
+        .address_txs_page(&addr, "limit=2")
+        .await
+        .unwrap();
+    let first_page: serde_json::Value = serde_json::from_str(&first_page).unwrap();
+    assert_eq!(
+        first_page.as_array().unwrap().len(),
+        3,
+        "2 confirmed + mempool"
+    );
+    let next_height = heights[2];
+    assert_eq!(
+        link,
+        Some(format!(
+            "<txs?from_height={next_height}&limit=2>; rel=\"next\""
+        ))
+    );
+    let (last_page, link) = test_env
+        .client()
+        .address_txs_page(
+            &addr,
+            &format!("from_height={next_height}&limit=3&confirmations=true"),
+        )
+        .await
+        .unwrap();
+    let last_page: serde_json::Value = serde_json::from_str(&last_page).unwrap();
+    let txids: Vec<_> = last_page
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tx| tx["txid"].as_str().unwrap().to_string())
+        .collect();
+    let expected: Vec<_> = expected_txids[2..].iter().map(|t| t.to_string()).collect();
+    assert_eq!(txids, expected);
+    assert_eq!(link, None);
+    let confirmations: Vec<_> = last_page
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tx| tx["status"]["confirmations"].as_u64().unwrap())
+        .collect();
+    assert_eq!(confirmations, vec![3, 2, 1]);
+    assert!(test_env
+        .client()
+        .address_txs_page(&addr, "limit=4")
+        .await
+        .is_err());
+
     let err = test_env
         .client()
         .waterfalls_addresses_utxo_only(&[addr.clone()], true)
@@ -369,6 +422,131 @@ async fn do_test_subscribe_notifies_descriptor_change(test_env: waterfalls::test
     test_env.shutdown().await;
 }
 
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn integration_script_subscribe_bitcoin() {
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Bitcoin).await;
+    do_test_script_subscribe(test_env).await;
+}
+
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn integration_script_subscribe_liquid() {
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Elements).await;
+    do_test_script_subscribe(test_env).await;
+}
+
+#[cfg(feature = "test_env")]
+async fn do_test_script_subscribe(test_env: waterfalls::test_env::TestEnv) {
+    let addr = test_env.get_new_address(None);
+    let script_pubkey = match &addr {
+        be::Address::Bitcoin(addr) => addr.script_pubkey().to_bytes(),
+        be::Address::Elements(addr) => addr.script_pubkey().to_bytes(),
+    };
+    let response = test_env
+        .client()
+        .subscribe_script(&script_pubkey)
+        .await
+        .unwrap();
+    let mut sse = SseTestReader::new(response);
+    let tx_seen = |event: String| -> waterfalls::TxSeen {
+        let data = event.split("data: ").nth(1).unwrap();
+        serde_json::from_str(data.trim()).unwrap()
+    };
+
+    let txid = test_env.send_to(&addr, 10_000);
+    let unconfirmed = tx_seen(sse.next_event("tx").await);
+    assert_eq!(unconfirmed.txid, txid);
+    assert_eq!(unconfirmed.height, 0);
+
+    test_env.node_generate(1).await;
+    let confirmed = tx_seen(sse.next_event("tx").await);
+    assert_eq!(confirmed.txid, txid);
+    assert!(confirmed.height > 0);
+
+    test_env.shutdown().await;
+}
+
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn integration_block_events() {
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Bitcoin).await;
+    let response = test_env.client().block_events().await.unwrap();
+    assert_eq!(
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .unwrap(),
+        "text/event-stream"
+    );
+    let mut sse = SseTestReader::new(response);
+
+    let hashes = test_env.node_generate(1).await;
+    let event = sse.next_event("block").await;
+    assert!(
+        event.contains(&hashes[0].to_string()),
+        "unexpected SSE event: {event}"
+    );
+
+    test_env.shutdown().await;
+}
+
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn integration_stream_addresses_bitcoin() {
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Bitcoin).await;
+    do_test_stream_addresses(test_env).await;
+}
+
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn integration_stream_addresses_liquid() {
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Elements).await;
+    do_test_stream_addresses(test_env).await;
+}
+
+#[cfg(feature = "test_env")]
+async fn do_test_stream_addresses(test_env: waterfalls::test_env::TestEnv) {
+    let addresses = [
+        test_env.get_new_address(None),
+        test_env.get_new_address(None),
+    ];
+    let response = test_env
+        .client()
+        .stream_addresses(&addresses)
+        .await
+        .unwrap();
+    let mut sse = SseTestReader::new(response);
+    let event_data = |event: String| -> serde_json::Value {
+        let data = event.split("data: ").nth(1).unwrap();
+        serde_json::from_str(data.trim()).unwrap()
+    };
+
+    let txid = test_env.send_to(&addresses[1], 10_000);
+    let event = event_data(sse.next_event("tx").await);
+    assert_eq!(event["txid"], txid.to_string());
+    assert_eq!(event["height"], 0);
+    assert_eq!(event["script_index"], 1);
+    assert!(event.get("descriptor_index").is_none());
+
+    test_env.node_generate(1).await;
+    let event = event_data(sse.next_event("tx").await);
+    assert_eq!(event["txid"], txid.to_string());
+    assert!(event["height"].as_u64().unwrap() > 0);
+
+    test_env.shutdown().await;
+}
+
 #[cfg(feature = "test_env")]
 fn subscription_test_address(family: Family, descriptor: &str, index: u32) -> be::Address {
     match family {
@@ -457,10 +635,16 @@ impl SseTestReader {
     }
 
     async fn next_changed_event(&mut self) -> String {
+        self.next_event("changed").await
+    }
+
+    /// The next event named `name`, skipping the others
+    async fn next_event(&mut self, name: &str) -> String {
+        let prefix = format!("event: {name}\n");
         timeout(Duration::from_secs(10), async {
             loop {
                 if let Some(event) = self.pop_event() {
-                    if event.contains("event: changed") {
+                    if event.contains(&prefix) {
                         return event;
                     }
                     continue;
@@ -468,7 +652,7 @@ impl SseTestReader {
 
                 let Some(chunk) = self.response.chunk().await.unwrap() else {
                     panic!(
-                        "SSE stream ended before changed event, buffered: {}",
+                        "SSE stream ended before {name} event, buffered: {}",
                         self.buffer
                     );
                 };
@@ -477,7 +661,7 @@ impl SseTestReader {
             }
         })
         .await
-        .expect("timed out waiting for SSE changed event")
+        .unwrap_or_else(|_| panic!("timed out waiting for SSE {name} event"))
     }
 
     fn pop_event(&mut self) -> Option<String> {
@@ -708,7 +892,7 @@ async fn do_test(test_env: waterfalls::test_env::TestEnv) {
         .unwrap_err();
     assert_eq!(
         format!("{wrong_result:?}"),
-        "waterfalls response is not 200 but: 422 body is: CannotDecrypt"
+        "waterfalls response is not 200 but: 400 body is: CannotDecrypt"
     );
 
     // Test broadcast is working
@@ -932,6 +1116,35 @@ async fn test_fee_estimates_bitcoin() {
     test_env.shutdown().await;
 }
 
+#[cfg(feature = "test_env")]
+#[tokio::test]
+async fn test_headers_bitcoin() {
+    use bitcoind::bitcoincore_rpc::RpcApi;
+    let _ = env_logger::try_init();
+
+    let test_env = launch_memory(Family::Bitcoin).await;
+    let hashes = test_env.node_generate(5).await;
+    let tip_height = test_env.node().client.get_block_count().unwrap() as u32;
+
+    let start = tip_height - 4;
+    let headers = test_env.client().headers(start, 3).await.unwrap();
+    assert_eq!(headers.len(), 3);
+    for (header, hash) in headers.iter().zip(hashes.iter()) {
+        assert_eq!(header.block_hash(), *hash);
+        assert_eq!(test_env.client().header(*hash).await.unwrap(), *header);
+    }
+    assert_eq!(headers[1].prev_blockhash(), headers[0].block_hash());
+
+    // the range is truncated at the tip, served from the cache
+    let headers = test_env.client().headers(start, 100).await.unwrap();
+    assert_eq!(headers.len(), 5);
+    assert_eq!(headers[4].block_hash(), hashes[4]);
+
+    assert!(test_env.client().headers(0, 2001).await.is_err());
+
+    test_env.shutdown().await;
+}
+
 #[cfg(feature = "test_env")]
 #[tokio::test]
 async fn test_last_used_index_elements() {